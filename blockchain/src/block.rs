@@ -1,15 +1,22 @@
-use std::fmt;
+use std::{collections::HashSet, fmt};
 
-use bincode::{Encode, config};
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 use wallet_crypto::{
+    encoding::{Decodable, Encodable, EncodingError, Reader},
+    header::{difficulty_target, required_bits},
     keys::BlockchainHash,
+    merkle::{self, MerkleProof},
     transaction::{Transaction, TxOut},
 };
 
-use crate::blockchain::BlockchainError;
+// Headers live in wallet-crypto so light clients can check them too
+pub use wallet_crypto::header::{BLOCK_HEADER_SIZE, BlockHeader};
+
+use crate::{
+    blockchain::BlockchainError,
+    versionbits::{VERSION_BITS_TOP_BITS, VERSION_BITS_TOP_MASK},
+};
 
 // How far into the future a block timestamp is allowed to be (e.g., 2 hours for Bitcoin-like behavior)
 const TIMESTAMP_FUTURITY_TOLERANCE_SECS: u64 = 2 * 60 * 60; // 2 hours
@@ -18,6 +25,8 @@ const TIMESTAMP_FUTURITY_TOLERANCE_MILLIS: u128 =
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Block {
+    // versionbits.rs describes how miners signal for deployments with it
+    pub version: u32,
     pub height: u64,
     pub timestamp: u128,
     pub transactions: Vec<Transaction>,
@@ -30,20 +39,33 @@ pub struct Block {
 
 impl Block {
     pub async fn mine_new(
+        version: u32,
         height: u64,
         transactions: Vec<Transaction>,
         prev_block_hash: BlockchainHash,
+    ) -> Block {
+        let timestamp = Utc::now().timestamp_millis() as u128;
+        Block::mine_at(version, height, timestamp, transactions, prev_block_hash).await
+    }
+
+    /// Mines a block with `timestamp` (millis), the time its transactions were checked at.
+    pub async fn mine_at(
+        version: u32,
+        height: u64,
+        timestamp: u128,
+        transactions: Vec<Transaction>,
+        prev_block_hash: BlockchainHash,
     ) -> Block {
         let merkle_root = Block::calculate_merkle_root(&transactions).expect(
             "Genesis block Merkle root calculation should not fail with a coinbase transaction.",
         );
 
-        let timestamp = Utc::now().timestamp_millis() as u128;
         let nonce = 0;
         let hash = BlockchainHash::default();
-        let bits = 2;
+        let bits = required_bits(height);
 
         let mut block = Block {
+            version,
             height,
             timestamp,
             transactions,
@@ -74,9 +96,10 @@ impl Block {
         let prev_block_hash: BlockchainHash = BlockchainHash::default();
         let nonce = 0;
         let hash = BlockchainHash::default();
-        let bits = 2;
+        let bits = required_bits(height);
 
         let mut block = Block {
+            version: VERSION_BITS_TOP_BITS,
             height,
             timestamp,
             transactions,
@@ -92,33 +115,36 @@ impl Block {
         block
     }
 
-    pub fn calculate_hash(&self) -> BlockchainHash {
-        #[derive(Serialize, Encode)]
-        struct BlockHeaderForHashing {
-            height: u64,
-            timestamp: u128,
-            prev_block_hash: BlockchainHash,
-            merkle_root: BlockchainHash,
-            bits: u32,
-            nonce: u64,
-        }
-
-        let temp_block = BlockHeaderForHashing {
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            version: self.version,
             height: self.height,
-            timestamp: self.timestamp,
             prev_block_hash: self.prev_block_hash,
             merkle_root: self.merkle_root,
+            timestamp: self.timestamp,
             bits: self.bits,
             nonce: self.nonce,
-        };
-
-        let encoded_bytes = bincode::encode_to_vec(&temp_block, config::standard())
-            .expect("Failed to serialize block for hashing. This should not happen.");
+        }
+    }
 
-        let first_hash = Sha256::digest(&encoded_bytes); // Hash the bytes
-        let second_hash = Sha256::digest(first_hash);
+    /// A block with `header` and `transactions`, e.g. rebuilt from what a peer sent. Nothing
+    /// is checked.
+    pub fn from_header(header: BlockHeader, transactions: Vec<Transaction>) -> Block {
+        Block {
+            version: header.version,
+            height: header.height,
+            timestamp: header.timestamp,
+            transactions,
+            prev_block_hash: header.prev_block_hash,
+            merkle_root: header.merkle_root,
+            bits: header.bits,
+            nonce: header.nonce,
+            hash: header.hash(),
+        }
+    }
 
-        BlockchainHash::new(second_hash.into())
+    pub fn calculate_hash(&self) -> BlockchainHash {
+        self.header().hash()
     }
 
     pub fn calculate_merkle_root(transactions: &[Transaction]) -> Result<BlockchainHash, String> {
@@ -126,34 +152,22 @@ impl Block {
             return Err("Block must contain at least one transaction (coinbase)".to_string());
         }
 
-        let mut leaves: Vec<[u8; 32]> = transactions
-            .iter()
-            .map(|tx| *tx.calculate_id().as_ref()) // assuming tx.calculate_id() returns Hash([u8; 32])
-            .collect();
-
-        while leaves.len() > 1 {
-            if leaves.len() % 2 != 0 {
-                let last_hash = *leaves.last().unwrap(); // Get a copy of the last hash
-                leaves.push(last_hash); // Add the duplicate to the end
-            }
-
-            let mut next_level_hashes = Vec::new();
-
-            for i in (0..leaves.len()).step_by(2) {
-                let left_hash = leaves[i];
-                let right_hash = leaves[i + 1];
-
-                let mut hasher = Sha256::new();
-                hasher.update(left_hash);
-                hasher.update(right_hash);
-                let combined_hash_bytes: [u8; 32] = hasher.finalize().into();
-                next_level_hashes.push(combined_hash_bytes);
-            }
+        Ok(merkle::merkle_root(&Block::merkle_leaves(transactions))
+            .expect("There is at least one leaf"))
+    }
 
-            leaves = next_level_hashes;
-        }
+    // txids leave out signatures, the witness id commits to them as well
+    fn merkle_leaves(transactions: &[Transaction]) -> Vec<BlockchainHash> {
+        transactions
+            .iter()
+            .map(|tx| tx.calculate_witness_id())
+            .collect()
+    }
 
-        Ok(BlockchainHash::new(leaves[0]))
+    /// Branch proving that the transaction `tx_id` is part of this block.
+    pub fn merkle_proof(&self, tx_id: &BlockchainHash) -> Option<MerkleProof> {
+        let index = self.transactions.iter().position(|tx| tx.id == *tx_id)?;
+        MerkleProof::new(&Block::merkle_leaves(&self.transactions), index)
     }
 
     pub async fn mine_nonce(&mut self) -> () {
@@ -179,17 +193,53 @@ impl Block {
     }
 
     pub fn validate_block(&self) -> Result<(), BlockchainError> {
+        self.verify_version()?;
         self.verify_merkle_root()?;
+        self.verify_unique_transactions()?;
         self.verify_timestamp_plausibility()?;
         self.validate_proof_of_work()?;
+        self.verify_transactions_final()?;
+
+        Ok(())
+    }
+
+    // Blocks from before version bits signalling are no longer accepted
+    fn verify_version(&self) -> Result<(), BlockchainError> {
+        if self.version & VERSION_BITS_TOP_MASK != VERSION_BITS_TOP_BITS {
+            return Err(BlockchainError::InvalidBlock(format!(
+                "Block version {:#010x} is outdated",
+                self.version
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Every transaction's lock time must have passed at this block's height and time
+    fn verify_transactions_final(&self) -> Result<(), BlockchainError> {
+        let time_secs = (self.timestamp / 1000) as u64;
+
+        if let Some(tx) = self
+            .transactions
+            .iter()
+            .find(|tx| !tx.is_final(self.height, time_secs))
+        {
+            return Err(BlockchainError::InvalidBlock(format!(
+                "Transaction {} is not final at height {}",
+                tx.id, self.height
+            )));
+        }
 
         Ok(())
     }
 
     fn verify_merkle_root(&self) -> Result<(), BlockchainError> {
-        let calculated_merkle_root =
-            Block::calculate_merkle_root(&self.transactions).map_err(|e| {
-                BlockchainError::InvalidBlock(format!("Merkle root calculation failed: {}", e))
+        let leaves = Block::merkle_leaves(&self.transactions);
+        let (calculated_merkle_root, mutated) =
+            merkle::merkle_root_checked(&leaves).ok_or_else(|| {
+                BlockchainError::InvalidBlock(
+                    "Block must contain at least one transaction (coinbase)".to_string(),
+                )
             })?;
 
         if calculated_merkle_root != self.merkle_root {
@@ -197,6 +247,26 @@ impl Block {
                 "Merkle root mismatch".to_string(),
             ));
         }
+
+        // the header is fine, only this copy of the transaction list was tampered with
+        if mutated {
+            return Err(BlockchainError::MutatedBlock(format!(
+                "Block {} repeats transactions in its merkle tree",
+                self.hash
+            )));
+        }
+        Ok(())
+    }
+
+    fn verify_unique_transactions(&self) -> Result<(), BlockchainError> {
+        let mut tx_ids = HashSet::with_capacity(self.transactions.len());
+        if let Some(tx) = self.transactions.iter().find(|tx| !tx_ids.insert(tx.id)) {
+            return Err(BlockchainError::InvalidBlock(format!(
+                "Transaction {} appears more than once",
+                tx.id
+            )));
+        }
+
         Ok(())
     }
 
@@ -237,14 +307,7 @@ impl Block {
     }
 
     fn get_difficulty_target_from_bits(bits: u32) -> BlockchainHash {
-        // Placeholder for now:
-        // Example: If bits represents the number of leading zeros required (simpler model)
-        let mut target_bytes = [0xFF; 32];
-        let num_leading_zeros = bits as usize;
-        for i in 0..num_leading_zeros {
-            target_bytes[i / 8] &= !(1 << (7 - (i % 8)));
-        }
-        BlockchainHash::new(target_bytes)
+        difficulty_target(bits)
     }
 
     pub fn get_utxos<'a>(
@@ -257,6 +320,7 @@ impl Block {
             tx.outputs
                 .iter()
                 .enumerate()
+                .filter(|(_, tx_out)| !tx_out.script_pubkey.is_unspendable())
                 .map(move |(idx, tx_out)| ((tx.id, idx as u32), tx_out.clone()))
         });
 
@@ -274,9 +338,27 @@ impl Block {
     }
 }
 
+impl Encodable for Block {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.header().encode(out);
+        self.transactions.encode(out);
+    }
+}
+
+// The block hash is not encoded, it is recomputed from the header
+impl Decodable for Block {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        let header = BlockHeader::decode(reader)?;
+        let transactions = Vec::decode(reader)?;
+
+        Ok(Block::from_header(header, transactions))
+    }
+}
+
 impl fmt::Display for Block {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "Block #{}", self.height)?;
+        writeln!(f, "Version: {:#010x}", self.version)?;
         writeln!(f, "Timestamp: {}", self.timestamp)?;
         writeln!(f, "Data: {:?}", self.transactions)?;
         writeln!(
@@ -293,6 +375,7 @@ impl fmt::Display for Block {
 mod tests {
     use super::*;
     use hex;
+    use wallet_crypto::encoding;
 
     #[test]
     fn test_genesis_return_block() {
@@ -308,4 +391,77 @@ mod tests {
         let hex_hash = hex::encode(block.hash.as_ref());
         assert_eq!(hex_hash.len(), 64);
     }
+
+    #[tokio::test]
+    async fn test_duplicated_transactions_are_rejected() {
+        let transactions: Vec<_> = (1..=3)
+            .map(|fee| {
+                Transaction::coinbase_transaction("8dd45dc1a355c066d89e551db6cd9469513eb4dd", fee)
+            })
+            .collect();
+        let block = Block::mine_new(
+            VERSION_BITS_TOP_BITS,
+            1,
+            transactions.clone(),
+            BlockchainHash::default(),
+        )
+        .await;
+        assert!(block.validate_block().is_ok());
+
+        // [a, b, c, c] has the root of [a, b, c]
+        let mut mutated = block.clone();
+        mutated.transactions.push(transactions[2].clone());
+        assert!(matches!(
+            mutated.validate_block(),
+            Err(BlockchainError::MutatedBlock(_))
+        ));
+
+        let repeated = [transactions.clone(), vec![transactions[0].clone()]].concat();
+        let block = Block::mine_new(
+            VERSION_BITS_TOP_BITS,
+            1,
+            repeated,
+            BlockchainHash::default(),
+        )
+        .await;
+        assert!(matches!(
+            block.validate_block(),
+            Err(BlockchainError::InvalidBlock(_))
+        ));
+    }
+
+    #[test]
+    fn test_header_encoding_vector() {
+        let header = BlockHeader {
+            version: VERSION_BITS_TOP_BITS | 1 << 28,
+            height: 7,
+            prev_block_hash: BlockchainHash::new([0x01; 32]),
+            merkle_root: BlockchainHash::new([0x02; 32]),
+            timestamp: 1_700_000_000_000,
+            bits: 2,
+            nonce: 42,
+        };
+
+        let encoded = encoding::serialize(&header);
+        assert_eq!(encoded.len(), BLOCK_HEADER_SIZE);
+        let expected = concat!(
+            "00000030",                                                         // version
+            "0700000000000000",                                                 // height
+            "0101010101010101010101010101010101010101010101010101010101010101", // prev_block_hash
+            "0202020202020202020202020202020202020202020202020202020202020202", // merkle_root
+            "0068e5cf8b0100000000000000000000",                                 // timestamp
+            "02000000",                                                         // bits
+            "2a00000000000000",                                                 // nonce
+        );
+        assert_eq!(hex::encode(&encoded), expected);
+        assert_eq!(
+            header.hash().to_string(),
+            "348b96ffc95fa023ab298bfc9e56116df7165b44494d78fa68a89ecaf314c427"
+        );
+
+        let block = Block::genesis();
+        let encoded = encoding::serialize(&block);
+        assert_eq!(encoding::deserialize::<Block>(&encoded).unwrap(), block);
+        assert!(encoding::deserialize::<Block>(&[encoded.as_slice(), &[0]].concat()).is_err());
+    }
 }
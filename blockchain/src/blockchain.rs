@@ -3,24 +3,32 @@ use std::{
     sync::Arc,
 };
 
-use futures::future::try_join_all;
-use tokio::sync::RwLock;
+use chrono::Utc;
+use tokio::sync::{RwLock, broadcast};
 use wallet_crypto::{
-    keys::{BlockchainHash, PublicKeyHash, PublicKeyWithSignature, SignatureError},
-    scripts::Script,
+    header::{block_work, required_bits},
+    keys::{Address, BlockchainHash, PublicKeyHash, SignatureError},
+    scripts::{MAX_DATA_CARRIER_BYTES, Script},
+    timelock,
     transaction::{Transaction, TxOut, UTXO},
 };
 
 use crate::{
-    block::Block,
-    blockchain::utxo_set::UTXOSet,
+    block::{Block, BlockHeader},
+    chain_params::{ChainParams, Deployment},
+    events::{self, ChainEvent, EVENT_CHANNEL_SIZE, RemovalReason},
+    versionbits::{self, DeploymentState},
+    blockchain::utxo_set::{UTXOSet, UtxoEntry},
     data::storage::{self, Storage, StorageError},
 };
 
+mod signature_checks;
 mod utxo_set;
 
 const miner_addr: &'static str = "8dd45dc1a355c066d89e551db6cd9469513eb4dd";
 const minner_reward: u64 = 50;
+// Blocks whose timestamps the median time past is taken over
+const MEDIAN_TIME_SPAN: usize = 11;
 
 #[derive(Debug, thiserror::Error)]
 pub enum BlockchainError {
@@ -44,6 +52,8 @@ pub enum BlockchainError {
     InsufficientFunds,
     #[error("Double spend attempt for UTXO: {tx_id}:{out_idx}")]
     DoubleSpendAttempt { tx_id: BlockchainHash, out_idx: u32 },
+    #[error("Transaction spends UTXO {tx_id}:{out_idx} more than once")]
+    DuplicateInput { tx_id: BlockchainHash, out_idx: u32 },
     #[error("Invalid transaction fee: {0}")]
     InvalidFee(String),
     #[error("Mempool error {0}")]
@@ -52,6 +62,16 @@ pub enum BlockchainError {
     InvalidBlock(String),
     #[error("Invalid proof of work: {0}")]
     InvalidProofOfWork(String),
+    // The transactions were altered without changing the merkle root: the header may still
+    // belong to a valid block, so it must not be marked invalid.
+    #[error("Mutated block: {0}")]
+    MutatedBlock(String),
+    // Valid blocks fail this too, e.g. one that lost a race for the same height.
+    #[error("Block does not extend the tip: {0}")]
+    DoesNotExtendTip(String),
+    // A valid branch that isn't worth switching to.
+    #[error("Branch has no more work than the chain: {0}")]
+    InsufficientWork(String),
 }
 
 impl From<storage::StorageError> for BlockchainError {
@@ -70,8 +90,14 @@ pub struct Blockchain<S: Storage> {
     current_tip_hash: BlockchainHash,
     current_tip_block: Block,
     mempool: HashMap<BlockchainHash, Transaction>,
-    utxo_set: Arc<RwLock<UTXOSet<TxOut>>>,
+    utxo_set: Arc<RwLock<UTXOSet<UtxoEntry>>>,
     storage: S,
+    params: ChainParams,
+    // version of every block by height, for deployment signalling
+    block_versions: Vec<u32>,
+    // timestamp of every block by height, for the median time past
+    block_times: Vec<u128>,
+    events: broadcast::Sender<ChainEvent>,
 }
 
 impl<S: Storage> Blockchain<S> {
@@ -82,9 +108,50 @@ impl<S: Storage> Blockchain<S> {
             storage,
             current_tip_hash: BlockchainHash::default(),
             current_tip_block: Block::genesis(),
+            params: ChainParams::default(),
+            block_versions: Vec::new(),
+            block_times: Vec::new(),
+            events: broadcast::channel(EVENT_CHANNEL_SIZE).0,
         }
     }
 
+    /// Uses the consensus parameters of another network, before `init`.
+    pub fn with_params(mut self, params: ChainParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// State of every deployment for the next block.
+    pub fn deployment_states(&self) -> Vec<(&Deployment, DeploymentState)> {
+        let next_height = self.block_versions.len() as u64;
+        self.params
+            .deployments
+            .iter()
+            .map(|deployment| {
+                let state = versionbits::deployment_state(
+                    &self.params,
+                    deployment,
+                    &self.block_versions,
+                    next_height,
+                );
+                (deployment, state)
+            })
+            .collect()
+    }
+
+    /// Whether the rules of deployment `name` apply to the next block.
+    pub fn is_deployment_active(&self, name: &str) -> bool {
+        self.deployment_states()
+            .iter()
+            .any(|(deployment, state)| {
+                deployment.name == name && *state == DeploymentState::Active
+            })
+    }
+
     pub async fn init(mut self) -> Result<Self, BlockchainError> {
         match self.storage.get_latest_block().await {
             Ok(block) => {
@@ -160,18 +227,35 @@ impl<S: Storage> Blockchain<S> {
             ));
         }
 
-        {
-            let mut utxo_set = self.utxo_set.write().await;
-            self.validate_double_spend_inputs(&tx, &mut utxo_set.reserved)
-                .await?;
-        }
-
-        self.validate_transaction(&tx).await?;
-        self.mempool.insert(tx.id.clone(), tx.clone());
+        let spent_outputs = self.admit_transaction(&tx).await?;
+        self.publish_transaction_added(&tx, &spent_outputs);
 
         Ok(tx)
     }
 
+    // Validates `tx` for the next block, reserves its inputs and puts it in the mempool.
+    // Returns the outputs it spends.
+    async fn admit_transaction(&mut self, tx: &Transaction) -> Result<Vec<TxOut>, BlockchainError> {
+        // Mempool transactions must be valid in the next block, including their timelocks
+        let next_height = self.last_block().height + 1;
+        self.validate_transaction(tx, next_height, self.median_time_past())
+            .await?;
+
+        let spent_outputs: Vec<TxOut> = {
+            let mut utxo_set = self.utxo_set.write().await;
+            self.validate_double_spend_inputs(tx, &mut utxo_set.reserved)
+                .await?;
+            tx.inputs
+                .iter()
+                .filter_map(|tx_in| utxo_set.get(&(tx_in.prev_tx_id, tx_in.prev_out_idx)))
+                .map(|entry| entry.output.clone())
+                .collect()
+        };
+
+        self.mempool.insert(tx.id, tx.clone());
+        Ok(spent_outputs)
+    }
+
     fn validate_coinbase_transaction(
         &self,
         tx: &Transaction,
@@ -189,15 +273,13 @@ impl<S: Storage> Blockchain<S> {
             }
         }
 
-        let block_reward = 50_000_000_000u64;
-
         let total_output_value: u64 = tx.outputs.iter().map(|o| o.value).sum();
 
-        if total_output_value > block_reward + total_fees_in_block {
+        if total_output_value > minner_reward + total_fees_in_block {
             return Err(BlockchainError::InvalidCoinbase(format!(
                 "Coinbase output value exceeds allowed limit: {} > {}",
                 total_output_value,
-                block_reward + total_fees_in_block
+                minner_reward + total_fees_in_block
             )));
         }
 
@@ -215,12 +297,18 @@ impl<S: Storage> Blockchain<S> {
 
             // Doubse spend attempt
             // imput must not be used in uncommited transactions and inputs must be unique
-            if reservations.contains(&utxo_key) || !used_utxos.insert(utxo_key) {
+            if reservations.contains(&utxo_key) {
                 return Err(BlockchainError::DoubleSpendAttempt {
                     tx_id: tx_in.prev_tx_id.clone(),
                     out_idx: tx_in.prev_out_idx,
                 });
             }
+            if !used_utxos.insert(utxo_key) {
+                return Err(BlockchainError::DuplicateInput {
+                    tx_id: tx_in.prev_tx_id.clone(),
+                    out_idx: tx_in.prev_out_idx,
+                });
+            }
         }
 
         reservations.extend(used_utxos);
@@ -228,63 +316,33 @@ impl<S: Storage> Blockchain<S> {
         Ok(())
     }
 
-    async fn validate_transaction(&self, tx: &Transaction) -> Result<u64, BlockchainError> {
-        // verify transaction
-        tx.verify_signatures()?;
-
-        let mut total_input_value: u64 = 0;
-
-        let utxo_set = self.utxo_set.read().await;
-
-        // Verify inputs
-        for tx_in in &tx.inputs {
-            let utxo_key = (tx_in.prev_tx_id, tx_in.prev_out_idx);
-
-            let prev_utxo =
-                utxo_set
-                    .get(&utxo_key)
-                    .ok_or_else(|| BlockchainError::UtxoNotFound {
-                        tx_id: tx_in.prev_tx_id.clone(),
-                        out_idx: tx_in.prev_out_idx,
-                    })?;
-
-            // Script validation (P2PKH focus)
-            match &prev_utxo.script_pubkey {
-                Script::PayToPublicKeyHash { pub_key_hash } => {
-                    let public_key: PublicKeyWithSignature = (&tx_in.script_sig).get_verifier()?;
-
-                    if &public_key.pub_key_hash != pub_key_hash {
-                        return Err(BlockchainError::InvalidPublicKey(
-                            "Public key hash mismatch in P2PKH script".to_string(),
-                        ));
-                    }
-                } // _ => return Err(BlockchainError::InvalidScript("Unsupported script type".to_string())),
-            }
-
-            total_input_value += prev_utxo.value;
-        }
-
-        // Verify outputs
-        let total_output_value: u64 = tx.outputs.iter().map(|o| o.value).sum();
-        for tx_out in &tx.outputs {
-            if tx_out.value == 0 {
-                return Err(BlockchainError::InvalidTransaction(
-                    "Transaction output value cannot be zero".to_string(),
-                ));
-            }
-        }
+    /// Validates a transaction for inclusion in a block at `height` whose time locks are
+    /// checked against `lock_time` (millis), the median time past before it.
+    async fn validate_transaction(
+        &self,
+        tx: &Transaction,
+        height: u64,
+        lock_time: u128,
+    ) -> Result<u64, BlockchainError> {
+        let (fee, spent_outputs) = {
+            let utxo_set = self.utxo_set.read().await;
+            check_transaction(tx, &utxo_set, height, lock_time)?
+        };
 
-        // Verify total input value >= total output value (fees)
-        if total_input_value < total_output_value {
-            return Err(BlockchainError::InsufficientFunds);
-        }
+        // every input must satisfy the output it spends
+        tx.verify_signatures(&spent_outputs)?;
 
-        let fee = total_input_value - total_output_value;
         Ok(fee)
     }
 
-    pub async fn mine_pending_transactions(&mut self) -> Result<(), BlockchainError> {
-        let mut transactions: Vec<Transaction> = self.mempool.drain().map(|(_, tx)| tx).collect();
+    /// Mines the mempool into a new block, paying the reward to `miner_address`
+    /// (or the node's default miner address).
+    pub async fn mine_pending_transactions(
+        &mut self,
+        miner_address: Option<PublicKeyHash>,
+    ) -> Result<Block, BlockchainError> {
+        // they leave the mempool once the block connects, as a received block's would
+        let transactions: Vec<Transaction> = self.mempool.values().cloned().collect();
 
         let mut reserved_utxo = HashSet::new();
 
@@ -294,67 +352,353 @@ impl<S: Storage> Blockchain<S> {
                 .await?;
         }
 
-        let validation_futures: Vec<_> = transactions
-            .iter()
-            .map(|tx| {
-                self.validate_transaction(tx)
-            })
-            .collect();
-
-        let all_fees: Vec<u64> = try_join_all(validation_futures).await?;
+        let last_block = self.last_block();
+        let next_height = last_block.height + 1;
+        // timelocks are checked against the median time past, which the block must exceed
+        let lock_time = self.median_time_past();
+        let block_time = (Utc::now().timestamp_millis() as u128).max(lock_time + 1);
+
+        // Cheap checks first, under a single read lock; signatures are then verified in parallel
+        let (all_fees, spent_outputs): (Vec<u64>, Vec<Vec<TxOut>>) = {
+            let utxo_set = self.utxo_set.read().await;
+            transactions
+                .iter()
+                .map(|tx| check_transaction(tx, &utxo_set, next_height, lock_time))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .unzip()
+        };
+
+        let mut transactions =
+            signature_checks::verify_transactions(transactions, spent_outputs).await?;
 
         let fees = all_fees.iter().sum::<u64>();
+        let miner_address = miner_address
+            .map_or_else(|| miner_addr.to_string(), |address| address.to_string_owned());
         let coinbase_transaction = Transaction::coinbase_transaction(
-            miner_addr,
+            &miner_address,
             fees + minner_reward,
         );
         self.validate_coinbase_transaction(&coinbase_transaction, fees)?;
 
         transactions.insert(0, coinbase_transaction);
 
+        let version = versionbits::compute_block_version(
+            &self.params,
+            &self.block_versions,
+            next_height,
+        );
+        let block = Block::mine_at(
+            version,
+            next_height,
+            block_time,
+            transactions,
+            last_block.hash,
+        )
+        .await;
+
+        // the transactions were checked above, before mining
+        self.check_block_header(&block)?;
+        self.connect_block(block).await
+    }
+
+    /// Validates a block received from a peer and makes it the new tip. It must extend the
+    /// current tip.
+    pub async fn add_block(&mut self, block: Block) -> Result<Block, BlockchainError> {
+        self.check_block_header(&block)?;
+        self.check_block_transactions(&block).await?;
+        self.connect_block(block).await
+    }
+
+    // Continuity with the tip, then everything that needs only the block itself
+    fn check_block_header(&self, block: &Block) -> Result<(), BlockchainError> {
         let last_block = self.last_block();
-        let block = Block::mine_new(last_block.height + 1, transactions, last_block.hash).await;
 
-        // 1. block continuity checks
         if block.height != last_block.height + 1 {
-            return Err(BlockchainError::InvalidBlock(format!(
+            return Err(BlockchainError::DoesNotExtendTip(format!(
                 "Block height is incorrect"
             )));
         }
 
         if block.prev_block_hash != last_block.hash {
-            return Err(BlockchainError::InvalidBlock(format!(
+            return Err(BlockchainError::DoesNotExtendTip(format!(
                 "Block hash do not match previous hash"
             )));
         }
 
-        // 2. validate blocks
-        block.validate_block()?;
-
-        // 3. UTXO set update
-        let (utxo_add, utxo_remove) = block.get_utxos();
-        let mut utxo_set = self.utxo_set.write().await;
+        // otherwise a miner could backdate the outputs it confirms past their relative locks
+        let median_time_past = self.median_time_past();
+        if block.timestamp <= median_time_past {
+            return Err(BlockchainError::InvalidBlock(format!(
+                "Block timestamp {} is not above the median time past {}",
+                block.timestamp, median_time_past
+            )));
+        }
 
-        for utxo in utxo_remove {
-            utxo_set.remove(&utxo);
+        // before the hash, which only meets the target the block picked for itself
+        let expected_bits = required_bits(block.height);
+        if block.bits != expected_bits {
+            return Err(BlockchainError::InvalidProofOfWork(format!(
+                "Block bits {} differ from the required {}",
+                block.bits, expected_bits
+            )));
         }
 
-        for (key, value) in utxo_add {
-            utxo_set.insert(key, value);
+        block.validate_block()
+    }
+
+    // Same checks as mining does for the mempool: one coinbase first, no double spends,
+    // spendable inputs, and every signature
+    async fn check_block_transactions(&self, block: &Block) -> Result<(), BlockchainError> {
+        let (coinbase, transactions) = block
+            .transactions
+            .split_first()
+            .filter(|(coinbase, _)| coinbase.is_coinbase())
+            .ok_or_else(|| {
+                BlockchainError::InvalidCoinbase("First transaction is not a coinbase".to_string())
+            })?;
+
+        let mut reserved_utxo = HashSet::new();
+        for tx in transactions {
+            if tx.is_coinbase() {
+                return Err(BlockchainError::InvalidCoinbase(format!(
+                    "Transaction {} is a second coinbase",
+                    tx.id
+                )));
+            }
+            self.validate_double_spend_inputs(tx, &mut reserved_utxo)
+                .await?;
         }
 
-        // 4. Persistance
+        let lock_time = self.median_time_past();
+        let (all_fees, spent_outputs): (Vec<u64>, Vec<Vec<TxOut>>) = {
+            let utxo_set = self.utxo_set.read().await;
+            transactions
+                .iter()
+                .map(|tx| check_transaction(tx, &utxo_set, block.height, lock_time))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .unzip()
+        };
+
+        signature_checks::verify_transactions(transactions.to_vec(), spent_outputs).await?;
+
+        self.validate_coinbase_transaction(coinbase, all_fees.iter().sum())
+    }
+
+    // Applies a validated block on top of the tip
+    async fn connect_block(&mut self, block: Block) -> Result<Block, BlockchainError> {
+        // 1. UTXO set and in memory state
+        let spent = self.apply_block(&block).await;
+
+        // 2. Mempool transactions that are now confirmed or conflict with the block are
+        // dropped, along with their input reservations
+        let mut utxo_set = self.utxo_set.write().await;
+        let mut removed = Vec::new();
+        self.mempool.retain(|tx_id, tx| {
+            let reason = if block
+                .transactions
+                .iter()
+                .any(|confirmed| confirmed.id == *tx_id)
+            {
+                RemovalReason::Confirmed
+            } else if tx
+                .inputs
+                .iter()
+                .any(|tx_in| spent.contains_key(&(tx_in.prev_tx_id, tx_in.prev_out_idx)))
+            {
+                RemovalReason::Conflict
+            } else {
+                return true;
+            };
+
+            for tx_in in &tx.inputs {
+                utxo_set
+                    .reserved
+                    .remove(&(tx_in.prev_tx_id, tx_in.prev_out_idx));
+            }
+            removed.push((*tx_id, reason));
+            false
+        });
+        drop(utxo_set);
+
+        // 3. Persistance
         let block = self.storage.save_block(block).await?;
         // may be need to save height too?
         self.storage
             .set_latest_block_hash(block.hash.as_ref().clone())
             .await?;
 
-        // 6. Update in memory state
+        // 4. Events
+        self.publish(ChainEvent::NewTip {
+            hash: block.hash,
+            height: block.height,
+        });
+        for (tx_id, reason) in removed {
+            self.publish(ChainEvent::TransactionRemoved { tx_id, reason });
+        }
+        self.publish_block_activity(&block, &spent);
+
+        Ok(block)
+    }
+
+    // Moves the UTXO set and the tip onto `block`, without storing it. Returns the outputs
+    // the block spent.
+    async fn apply_block(&mut self, block: &Block) -> HashMap<(BlockchainHash, u32), TxOut> {
+        let (utxo_add, utxo_remove) = block.get_utxos();
+        let time = self.median_time_past();
+        let mut utxo_set = self.utxo_set.write().await;
+
+        let mut spent = HashMap::new();
+        for utxo in utxo_remove {
+            if let Some(entry) = utxo_set.remove(&utxo) {
+                spent.insert(utxo, entry.output);
+            }
+        }
+
+        for (key, output) in utxo_add {
+            utxo_set.insert(
+                key,
+                UtxoEntry {
+                    output,
+                    height: block.height,
+                    time,
+                },
+            );
+        }
+        drop(utxo_set);
+
+        self.block_versions.push(block.version);
+        self.block_times.push(block.timestamp);
         self.current_tip_hash = block.hash;
-        self.current_tip_block = block;
+        self.current_tip_block = block.clone();
+        spent
+    }
 
-        Ok(())
+    /// Replaces the blocks after the parent of `branch` with `branch`, which must have more
+    /// work than they do. The whole branch is validated before anything is stored, so the
+    /// chain stays as it was if any block is invalid. Transactions of the replaced blocks go
+    /// back to the mempool, and the mempool is checked again against the new chain.
+    pub async fn reorganize(&mut self, branch: Vec<Block>) -> Result<Block, BlockchainError> {
+        let fork_height = match branch.first() {
+            Some(first) if first.height > 0 => first.height - 1,
+            _ => {
+                return Err(BlockchainError::InvalidBlock(
+                    "Branch must start after genesis".to_string(),
+                ));
+            }
+        };
+        let fork_point = self
+            .storage
+            .load_blocks_by_height(fork_height, 1)
+            .await?
+            .pop()
+            .filter(|block| block.hash == branch[0].prev_block_hash)
+            .ok_or_else(|| {
+                BlockchainError::DoesNotExtendTip("Branch does not fork off the chain".to_string())
+            })?;
+
+        let tip_height = self.last_block().height;
+        let replaced = self
+            .storage
+            .load_blocks_by_height(fork_height + 1, tip_height - fork_height)
+            .await?;
+        let (branch_work, replaced_work) = (chain_work(&branch), chain_work(&replaced));
+        if branch_work <= replaced_work {
+            return Err(BlockchainError::InsufficientWork(format!(
+                "{} against {} after height {}",
+                branch_work, replaced_work, fork_height
+            )));
+        }
+
+        // validated on the state at the fork point, the current one is kept until it's done
+        let (utxo_set, block_versions, block_times) = self.load_utxo_set(fork_height).await?;
+        let current_utxo_set = std::mem::replace(&mut *self.utxo_set.write().await, utxo_set);
+        let current_versions = std::mem::replace(&mut self.block_versions, block_versions);
+        let current_times = std::mem::replace(&mut self.block_times, block_times);
+        let current_tip = std::mem::replace(&mut self.current_tip_block, fork_point);
+        self.current_tip_hash = self.current_tip_block.hash;
+
+        // storage is switched in one go once the whole branch applied; until then a failure
+        // only has to restore the state in memory
+        let applied = async {
+            let mut spent_by_block = Vec::with_capacity(branch.len());
+            for block in &branch {
+                self.check_block_header(block)?;
+                self.check_block_transactions(block).await?;
+                spent_by_block.push(self.apply_block(block).await);
+            }
+            self.storage.switch_branch(branch.clone()).await?;
+
+            Ok::<_, BlockchainError>(spent_by_block)
+        }
+        .await;
+        let spent_by_block = match applied {
+            Ok(spent_by_block) => spent_by_block,
+            Err(err) => {
+                *self.utxo_set.write().await = current_utxo_set;
+                self.block_versions = current_versions;
+                self.block_times = current_times;
+                self.current_tip_hash = current_tip.hash;
+                self.current_tip_block = current_tip;
+                return Err(err);
+            }
+        };
+        let tip = self.last_block().clone();
+        println!(
+            "Reorganized from height {} to {} at height {}",
+            current_tip.height, tip.height, fork_height
+        );
+
+        // the mempool is rebuilt from the replaced blocks and itself, in that order, on a
+        // UTXO set without reservations
+        let confirmed: HashSet<_> = branch
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .map(|tx| tx.id)
+            .collect();
+        let pending = std::mem::take(&mut self.mempool);
+        let returning = replaced
+            .iter()
+            .flat_map(|block| block.transactions.iter().skip(1))
+            .cloned()
+            .map(|tx| (tx, false));
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        for (tx, was_pending) in returning.chain(pending.into_values().map(|tx| (tx, true))) {
+            if confirmed.contains(&tx.id) {
+                if was_pending {
+                    removed.push((tx.id, RemovalReason::Confirmed));
+                }
+                continue;
+            }
+            match self.admit_transaction(&tx).await {
+                Ok(spent_outputs) if !was_pending => added.push((tx, spent_outputs)),
+                Ok(_) => {}
+                Err(_) if was_pending => removed.push((tx.id, RemovalReason::Reorg)),
+                Err(_) => {}
+            }
+        }
+
+        self.publish(ChainEvent::Reorg {
+            disconnected: replaced.iter().map(|block| block.hash).collect(),
+            connected: branch.iter().map(|block| block.hash).collect(),
+        });
+        self.publish(ChainEvent::NewTip {
+            hash: tip.hash,
+            height: tip.height,
+        });
+        for (block, spent) in branch.iter().zip(&spent_by_block) {
+            self.publish_block_activity(block, spent);
+        }
+        for (tx_id, reason) in removed {
+            self.publish(ChainEvent::TransactionRemoved { tx_id, reason });
+        }
+        for (tx, spent_outputs) in added {
+            self.publish_transaction_added(&tx, &spent_outputs);
+        }
+
+        Ok(tip)
     }
 
     pub async fn get_blocks(&self) -> Result<Vec<Block>, BlockchainError> {
@@ -370,19 +714,131 @@ impl<S: Storage> Blockchain<S> {
         Ok(all_blocks)
     }
 
-    pub async fn get_utxos_by_address(&self, address: PublicKeyHash) -> Vec<UTXO> {
+    /// Up to `count` headers from height `from` on, for light clients and syncing peers.
+    pub async fn get_headers(
+        &self,
+        from: u64,
+        count: u64,
+    ) -> Result<Vec<BlockHeader>, BlockchainError> {
+        let blocks = self.storage.load_blocks_by_height(from, count).await?;
+        Ok(blocks.iter().map(Block::header).collect())
+    }
+
+    /// Up to `count` blocks from height `from` on.
+    pub async fn get_blocks_by_height(
+        &self,
+        from: u64,
+        count: u64,
+    ) -> Result<Vec<Block>, BlockchainError> {
+        Ok(self.storage.load_blocks_by_height(from, count).await?)
+    }
+
+    /// Header of the block at the tip of the chain.
+    pub fn tip(&self) -> BlockHeader {
+        self.last_block().header()
+    }
+
+    /// Unconfirmed transaction `tx_id`, if it is in the mempool.
+    pub fn mempool_transaction(&self, tx_id: &BlockchainHash) -> Option<&Transaction> {
+        self.mempool.get(tx_id)
+    }
+
+    pub fn mempool_transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.mempool.values()
+    }
+
+    /// Events from now on, see events.rs.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChainEvent> {
+        self.events.subscribe()
+    }
+
+    fn publish(&self, event: ChainEvent) {
+        // nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    fn publish_transaction_added(&self, tx: &Transaction, spent_outputs: &[TxOut]) {
+        self.publish(ChainEvent::TransactionAdded { tx_id: tx.id });
+        for address in events::addresses(tx.outputs.iter().chain(spent_outputs)) {
+            self.publish(ChainEvent::AddressActivity {
+                address,
+                tx_id: tx.id,
+                height: None,
+            });
+        }
+    }
+
+    // Activity of every transaction in a connected block; `spent` are the outputs it spent
+    fn publish_block_activity(&self, block: &Block, spent: &HashMap<(BlockchainHash, u32), TxOut>) {
+        for tx in &block.transactions {
+            let spent_outputs = tx
+                .inputs
+                .iter()
+                .filter_map(|tx_in| spent.get(&(tx_in.prev_tx_id, tx_in.prev_out_idx)));
+            for address in events::addresses(tx.outputs.iter().chain(spent_outputs)) {
+                self.publish(ChainEvent::AddressActivity {
+                    address,
+                    tx_id: tx.id,
+                    height: Some(block.height),
+                });
+            }
+        }
+    }
+
+    pub async fn get_block(&self, hash: &BlockchainHash) -> Result<Option<Block>, BlockchainError> {
+        Ok(self.storage.load_block(*hash.as_ref()).await?)
+    }
+
+    /// Confirmed block that contains the transaction `tx_id`, if any.
+    pub async fn find_block_with_transaction(
+        &self,
+        tx_id: &BlockchainHash,
+    ) -> Result<Option<Block>, BlockchainError> {
+        let mut receiver = self.storage.stream_blocks_by_height().await?;
+
+        while let Some(block_result) = receiver.recv().await {
+            let block = block_result?;
+            if block.transactions.iter().any(|tx| tx.id == *tx_id) {
+                return Ok(Some(block));
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub async fn get_utxos_by_address(&self, address: Address) -> Vec<UTXO> {
         let utxo_set = self.utxo_set.read().await;
         utxo_set.get_utxos_by_address(address)
     }
 
     pub async fn rebuild_utxo_set(&mut self) -> Result<(), BlockchainError> {
-        let mut block_receiver = self.storage.stream_blocks_by_height().await?;
-        let mut utxo_set = self.utxo_set.write().await;
+        let (utxo_set, block_versions, block_times) = self.load_utxo_set(u64::MAX).await?;
+        self.utxo_set.write().await.data = utxo_set.data;
+        self.block_versions = block_versions;
+        self.block_times = block_times;
 
-        utxo_set.clear();
+        println!("UTXO set rebuilt successfully via streaming.");
+        Ok(())
+    }
+
+    // UTXO set, block versions and block times after the stored block at `height`
+    async fn load_utxo_set(
+        &self,
+        height: u64,
+    ) -> Result<(UTXOSet<UtxoEntry>, Vec<u32>, Vec<u128>), BlockchainError> {
+        let mut block_receiver = self.storage.stream_blocks_by_height().await?;
+        let mut utxo_set = UTXOSet::new();
+        let mut block_versions = Vec::new();
+        let mut block_times = Vec::new();
 
         while let Some(block_res) = block_receiver.recv().await {
             let block = block_res?;
+            if block.height > height {
+                break;
+            }
+            let time = median_time_past(&block_times);
+            block_versions.push(block.version);
+            block_times.push(block.timestamp);
 
             for tx in &block.transactions {
                 if !tx.is_coinbase() {
@@ -391,18 +847,408 @@ impl<S: Storage> Blockchain<S> {
                     }
                 }
 
-                // Add new UTXOs
+                // Add new UTXOs, unspendable outputs are never tracked
                 for (idx, tx_out) in tx.outputs.iter().enumerate() {
-                    utxo_set.insert((tx.id.clone(), idx as u32), tx_out.clone());
+                    if tx_out.script_pubkey.is_unspendable() {
+                        continue;
+                    }
+
+                    utxo_set.insert(
+                        (tx.id, idx as u32),
+                        UtxoEntry {
+                            output: tx_out.clone(),
+                            height: block.height,
+                            time,
+                        },
+                    );
                 }
             }
         }
 
-        println!("UTXO set rebuilt successfully via streaming.");
-        Ok(())
+        Ok((utxo_set, block_versions, block_times))
+    }
+
+    /// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks up to the tip (millis). The
+    /// next block's timestamp must be above it, and its transactions' time locks are checked
+    /// against it rather than against a timestamp the miner picks (BIP 113).
+    pub fn median_time_past(&self) -> u128 {
+        median_time_past(&self.block_times)
     }
 
     fn last_block(&self) -> &Block {
         &self.current_tip_block
     }
 }
+
+// Median of the last `MEDIAN_TIME_SPAN` of `block_times`, zero before the genesis block
+fn median_time_past(block_times: &[u128]) -> u128 {
+    let mut recent = block_times[block_times.len().saturating_sub(MEDIAN_TIME_SPAN)..].to_vec();
+    recent.sort_unstable();
+    recent.get(recent.len() / 2).copied().unwrap_or(0)
+}
+
+// Work it takes to produce `blocks`
+fn chain_work(blocks: &[Block]) -> u128 {
+    blocks.iter().map(|block| block_work(block.bits)).sum()
+}
+
+/// Everything about a transaction except its signatures: the spent outputs exist, timelocks
+/// are satisfied at `height` and median time past `lock_time` (millis) and the outputs are
+/// valid. Returns the fee and the spent outputs in input order.
+fn check_transaction(
+    tx: &Transaction,
+    utxo_set: &UTXOSet<UtxoEntry>,
+    height: u64,
+    lock_time: u128,
+) -> Result<(u64, Vec<TxOut>), BlockchainError> {
+    let time_secs = (lock_time / 1000) as u64;
+
+    if tx.id != tx.calculate_id() {
+        return Err(BlockchainError::InvalidTransaction(format!(
+            "Transaction id {} does not match its contents",
+            tx.id
+        )));
+    }
+
+    if !tx.is_final(height, time_secs) {
+        return Err(BlockchainError::InvalidTransaction(format!(
+            "Transaction is locked until {}",
+            tx.lock_time
+        )));
+    }
+
+    let mut total_input_value: u64 = 0;
+    let mut spent_outputs = Vec::with_capacity(tx.inputs.len());
+
+    // Verify inputs
+    for tx_in in &tx.inputs {
+        let utxo_key = (tx_in.prev_tx_id, tx_in.prev_out_idx);
+
+        let prev_utxo =
+            utxo_set
+                .get(&utxo_key)
+                .ok_or_else(|| BlockchainError::UtxoNotFound {
+                    tx_id: tx_in.prev_tx_id.clone(),
+                    out_idx: tx_in.prev_out_idx,
+                })?;
+
+        // Relative timelock, counted from the block that confirmed the spent output
+        if !timelock::sequence_lock_satisfied(
+            tx_in.sequence,
+            prev_utxo.height,
+            (prev_utxo.time / 1000) as u64,
+            height,
+            time_secs,
+        ) {
+            return Err(BlockchainError::InvalidTransaction(format!(
+                "Input {}:{} is still locked by its sequence",
+                tx_in.prev_tx_id, tx_in.prev_out_idx
+            )));
+        }
+
+        spent_outputs.push(prev_utxo.output.clone());
+        total_input_value += prev_utxo.output.value;
+    }
+
+    // Verify outputs
+    let total_output_value: u64 = tx.outputs.iter().map(|o| o.value).sum();
+    for tx_out in &tx.outputs {
+        match &tx_out.script_pubkey {
+            // data outputs carry no value, so they are exempt from the zero-value rule
+            Script::DataCarrier { data } if data.len() > MAX_DATA_CARRIER_BYTES => {
+                return Err(BlockchainError::InvalidTransaction(format!(
+                    "Data carrier output exceeds {} bytes",
+                    MAX_DATA_CARRIER_BYTES
+                )));
+            }
+            Script::DataCarrier { .. } => {}
+            _ if tx_out.value == 0 => {
+                return Err(BlockchainError::InvalidTransaction(
+                    "Transaction output value cannot be zero".to_string(),
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    // Verify total input value >= total output value (fees)
+    if total_input_value < total_output_value {
+        return Err(BlockchainError::InsufficientFunds);
+    }
+
+    let fee = total_input_value - total_output_value;
+    Ok((fee, spent_outputs))
+}
+
+#[cfg(test)]
+mod tests {
+    use wallet_crypto::{
+        keys::KeyPair,
+        timelock::SEQUENCE_FINAL,
+        transaction::{DraftTransaction, UnsignedTxIn},
+    };
+
+    use crate::{chain_params::Network, data::storage::SledStorage};
+
+    use super::*;
+
+    async fn regtest_chain() -> (Blockchain<SledStorage>, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("blockchain-{}", rand::random::<u64>()));
+        let storage = SledStorage::new(dir.to_str().unwrap()).unwrap();
+        let blockchain = Blockchain::new(storage)
+            .with_params(ChainParams::for_network(Network::Regtest))
+            .init()
+            .await
+            .unwrap();
+        (blockchain, dir)
+    }
+
+    #[tokio::test]
+    async fn test_overpaying_coinbase_is_rejected() {
+        let (mut blockchain, dir) = regtest_chain().await;
+        let tip = blockchain.last_block().clone();
+        let version =
+            versionbits::compute_block_version(&blockchain.params, &blockchain.block_versions, 1);
+
+        let coinbase = Transaction::coinbase_transaction(miner_addr, minner_reward + 1);
+        let block = Block::mine_new(version, 1, vec![coinbase], tip.hash).await;
+        assert!(matches!(
+            blockchain.add_block(block).await,
+            Err(BlockchainError::InvalidCoinbase(_))
+        ));
+
+        let coinbase = Transaction::coinbase_transaction(miner_addr, minner_reward);
+        let block = Block::mine_new(version, 1, vec![coinbase], tip.hash).await;
+        blockchain.add_block(block).await.unwrap();
+
+        drop(blockchain);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_block_with_easier_bits_is_rejected() {
+        let (mut blockchain, dir) = regtest_chain().await;
+        let tip = blockchain.last_block().clone();
+        let version =
+            versionbits::compute_block_version(&blockchain.params, &blockchain.block_versions, 1);
+
+        let coinbase = Transaction::coinbase_transaction(miner_addr, minner_reward);
+        let mut block = Block::mine_new(version, 1, vec![coinbase], tip.hash).await;
+        // any hash meets a target of no leading zeros
+        block.bits = 0;
+        block.hash = block.calculate_hash();
+        assert!(matches!(
+            blockchain.add_block(block).await,
+            Err(BlockchainError::InvalidProofOfWork(_))
+        ));
+
+        drop(blockchain);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_time_locks_use_the_median_time_past() {
+        let (mut blockchain, dir) = regtest_chain().await;
+        let key = KeyPair::generate();
+        let funding = blockchain
+            .mine_pending_transactions(Some(key.public_key.to_address()))
+            .await
+            .unwrap();
+        let version =
+            versionbits::compute_block_version(&blockchain.params, &blockchain.block_versions, 2);
+        let coinbase = || Transaction::coinbase_transaction(miner_addr, minner_reward);
+
+        // backdated to the median time past, as if to age the outputs it confirms
+        let median_time_past = blockchain.median_time_past();
+        let block =
+            Block::mine_at(version, 2, median_time_past, vec![coinbase()], funding.hash).await;
+        assert!(matches!(
+            blockchain.add_block(block).await,
+            Err(BlockchainError::InvalidBlock(_))
+        ));
+
+        // stamped an hour ahead to unlock a spend locked for half an hour
+        let now = Utc::now().timestamp_millis() as u128;
+        let locked = DraftTransaction::new(
+            vec![UnsignedTxIn {
+                prev_tx_id: funding.transactions[0].id,
+                prev_out_idx: 0,
+                sequence: SEQUENCE_FINAL - 1,
+            }],
+            vec![TxOut {
+                value: minner_reward - 1,
+                script_pubkey: funding.transactions[0].outputs[0].script_pubkey.clone(),
+            }],
+        )
+        .with_lock_time((now / 1000) as u32 + 30 * 60)
+        .sign(&key, &funding.transactions[0].outputs)
+        .unwrap();
+        let block = Block::mine_at(
+            version,
+            2,
+            now + 60 * 60 * 1000,
+            vec![
+                Transaction::coinbase_transaction(miner_addr, minner_reward + 1),
+                locked,
+            ],
+            funding.hash,
+        )
+        .await;
+        assert!(matches!(
+            blockchain.add_block(block).await,
+            Err(BlockchainError::InvalidTransaction(err)) if err.contains("locked until")
+        ));
+
+        let block = Block::mine_at(
+            version,
+            2,
+            median_time_past + 1,
+            vec![coinbase()],
+            funding.hash,
+        )
+        .await;
+        blockchain.add_block(block).await.unwrap();
+
+        drop(blockchain);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_input_is_not_a_double_spend() {
+        let (mut blockchain, dir) = regtest_chain().await;
+        let key = KeyPair::generate();
+        let funding = blockchain
+            .mine_pending_transactions(Some(key.public_key.to_address()))
+            .await
+            .unwrap();
+        let funding_output = &funding.transactions[0].outputs[0];
+        let spend = |inputs: usize, value: u64| {
+            let input = UnsignedTxIn {
+                prev_tx_id: funding.transactions[0].id,
+                prev_out_idx: 0,
+                sequence: SEQUENCE_FINAL,
+            };
+            DraftTransaction::new(
+                vec![input; inputs],
+                vec![TxOut {
+                    value,
+                    script_pubkey: funding_output.script_pubkey.clone(),
+                }],
+            )
+            .sign(&key, &vec![funding_output.clone(); inputs])
+            .unwrap()
+        };
+
+        assert!(matches!(
+            blockchain
+                .add_transaction(spend(2, minner_reward * 2 - 1))
+                .await,
+            Err(BlockchainError::DuplicateInput { .. })
+        ));
+
+        blockchain
+            .add_transaction(spend(1, minner_reward - 1))
+            .await
+            .unwrap();
+        assert!(matches!(
+            blockchain
+                .add_transaction(spend(1, minner_reward - 2))
+                .await,
+            Err(BlockchainError::DoubleSpendAttempt { .. })
+        ));
+
+        drop(blockchain);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_reorganize_to_a_heavier_branch() {
+        let (mut blockchain, dir) = regtest_chain().await;
+        let (mut other, other_dir) = regtest_chain().await;
+
+        // one block with a pending spend of its coinbase, against two blocks elsewhere
+        let key = KeyPair::generate();
+        let replaced = blockchain
+            .mine_pending_transactions(Some(key.public_key.to_address()))
+            .await
+            .unwrap();
+        let coinbase = &replaced.transactions[0];
+        let tx = DraftTransaction::new(
+            vec![UnsignedTxIn {
+                prev_tx_id: coinbase.id,
+                prev_out_idx: 0,
+                sequence: SEQUENCE_FINAL,
+            }],
+            vec![TxOut {
+                value: coinbase.outputs[0].value - 1,
+                script_pubkey: coinbase.outputs[0].script_pubkey.clone(),
+            }],
+        )
+        .sign(&key, &coinbase.outputs)
+        .unwrap();
+        blockchain.add_transaction(tx.clone()).await.unwrap();
+        let branch = vec![
+            other.mine_pending_transactions(None).await.unwrap(),
+            other.mine_pending_transactions(None).await.unwrap(),
+        ];
+        let mut events = blockchain.subscribe();
+
+        assert!(matches!(
+            blockchain.reorganize(branch[..1].to_vec()).await,
+            Err(BlockchainError::InsufficientWork(_))
+        ));
+        let mut invalid = branch.clone();
+        invalid[1].bits = 0;
+        invalid[1].hash = invalid[1].calculate_hash();
+        assert!(matches!(
+            blockchain.reorganize(invalid).await,
+            Err(BlockchainError::InvalidProofOfWork(_))
+        ));
+        assert_eq!(blockchain.last_block().hash, replaced.hash);
+        assert_eq!(blockchain.mempool_transactions().count(), 1);
+
+        let tip = blockchain.reorganize(branch.clone()).await.unwrap();
+        assert_eq!(tip.hash, branch[1].hash);
+        assert_eq!(
+            events.recv().await.unwrap(),
+            ChainEvent::Reorg {
+                disconnected: vec![replaced.hash],
+                connected: vec![branch[0].hash, branch[1].hash],
+            }
+        );
+        assert_eq!(
+            events.recv().await.unwrap(),
+            ChainEvent::NewTip {
+                hash: tip.hash,
+                height: 2
+            }
+        );
+        let removed = loop {
+            if let ChainEvent::TransactionRemoved { tx_id, reason } = events.recv().await.unwrap() {
+                break (tx_id, reason);
+            }
+        };
+        assert_eq!(removed, (tx.id, RemovalReason::Reorg));
+        assert_eq!(blockchain.mempool_transactions().count(), 0);
+
+        let stored = blockchain
+            .storage
+            .load_blocks_by_height(1, 10)
+            .await
+            .unwrap();
+        let stored: Vec<_> = stored.iter().map(|block| block.hash).collect();
+        assert_eq!(stored, vec![branch[0].hash, branch[1].hash]);
+        assert_eq!(
+            blockchain.storage.get_latest_block().await.unwrap().hash,
+            tip.hash
+        );
+        assert_eq!(
+            blockchain.storage.get_latest_block_hash().await.unwrap(),
+            Some(*tip.hash.as_ref())
+        );
+
+        drop((blockchain, other));
+        let _ = std::fs::remove_dir_all(dir);
+        let _ = std::fs::remove_dir_all(other_dir);
+    }
+}
@@ -0,0 +1,155 @@
+// Signature verification for whole blocks.
+//
+// Block validation first does the cheap checks (UTXO lookups, timelocks, amounts) for every
+// transaction and gathers one `SignatureCheck` per input. The checks are independent of each
+// other, so they run across the rayon thread pool; Schnorr signatures are batched per worker.
+// Async callers go through `verify_transactions`, which waits for the pool on a blocking
+// thread instead of stalling the runtime.
+
+use rayon::prelude::*;
+use tokio::task;
+use wallet_crypto::{
+    keys::SignatureError,
+    schnorr::SchnorrBatch,
+    transaction::{Transaction, TxOut},
+};
+
+/// One input of a transaction together with the output it spends.
+#[derive(Debug, Clone, Copy)]
+pub struct SignatureCheck<'a> {
+    pub tx: &'a Transaction,
+    pub input_index: usize,
+    pub spent_output: &'a TxOut,
+}
+
+impl<'a> SignatureCheck<'a> {
+    /// The checks of every input of `tx`; `spent_outputs` are in input order.
+    pub fn for_transaction(
+        tx: &'a Transaction,
+        spent_outputs: &'a [TxOut],
+    ) -> impl Iterator<Item = SignatureCheck<'a>> {
+        spent_outputs
+            .iter()
+            .enumerate()
+            .map(move |(input_index, spent_output)| SignatureCheck {
+                tx,
+                input_index,
+                spent_output,
+            })
+    }
+
+    fn run(&self, schnorr_batch: &mut SchnorrBatch) -> Result<(), SignatureError> {
+        self.tx
+            .verify_input_deferred(self.input_index, self.spent_output, schnorr_batch)
+            .map_err(|err| {
+                SignatureError::InvalidScript(format!(
+                    "Input {} of transaction {}: {}",
+                    self.input_index, self.tx.id, err
+                ))
+            })
+    }
+}
+
+/// Verifies all checks on the rayon pool. Stops at the first invalid signature.
+pub fn verify_all(checks: &[SignatureCheck]) -> Result<(), SignatureError> {
+    let schnorr_batches = checks
+        .par_iter()
+        .try_fold(SchnorrBatch::new, |mut schnorr_batch, check| {
+            check.run(&mut schnorr_batch)?;
+            Ok(schnorr_batch)
+        })
+        .collect::<Result<Vec<_>, SignatureError>>()?;
+
+    schnorr_batches
+        .par_iter()
+        .filter(|schnorr_batch| !schnorr_batch.is_empty())
+        .try_for_each(SchnorrBatch::verify)
+}
+
+/// Verifies every input of `transactions` with `verify_all` on a blocking thread, then hands
+/// the transactions back. `spent_outputs` holds the outputs each transaction spends.
+pub async fn verify_transactions(
+    transactions: Vec<Transaction>,
+    spent_outputs: Vec<Vec<TxOut>>,
+) -> Result<Vec<Transaction>, SignatureError> {
+    task::spawn_blocking(move || {
+        let checks: Vec<_> = transactions
+            .iter()
+            .zip(&spent_outputs)
+            .flat_map(|(tx, spent_outputs)| SignatureCheck::for_transaction(tx, spent_outputs))
+            .collect();
+        verify_all(&checks)?;
+        Ok(transactions)
+    })
+    .await
+    .expect("Signature checks don't panic")
+}
+
+#[cfg(test)]
+mod tests {
+    use wallet_crypto::{
+        keys::{BlockchainHash, KeyPair, Signature},
+        scripts::Script,
+        timelock::SEQUENCE_FINAL,
+        transaction::{DraftTransaction, UnsignedTxIn},
+    };
+
+    use super::*;
+
+    fn signed_transaction(key: &KeyPair, spent_output: &TxOut) -> Transaction {
+        let draft = DraftTransaction::new(
+            vec![UnsignedTxIn {
+                prev_tx_id: BlockchainHash::default(),
+                prev_out_idx: 0,
+                sequence: SEQUENCE_FINAL,
+            }],
+            vec![spent_output.clone()],
+        );
+        draft.sign(key, std::slice::from_ref(spent_output)).unwrap()
+    }
+
+    #[test]
+    fn test_verify_all_checks_every_input() {
+        let mut transactions = Vec::new();
+        let mut spent_outputs = Vec::new();
+        for idx in 0..8 {
+            let key = KeyPair::generate();
+            let script_pubkey = if idx % 2 == 0 {
+                Script::PayToPublicKeyHash {
+                    pub_key_hash: key.public_key.to_address(),
+                }
+            } else {
+                Script::PayToSchnorrKey {
+                    x_only_key: key.x_only_public_key(),
+                }
+            };
+            let spent_output = TxOut {
+                value: 10,
+                script_pubkey,
+            };
+            transactions.push(signed_transaction(&key, &spent_output));
+            spent_outputs.push(vec![spent_output]);
+        }
+
+        let checks = |transactions: &[Transaction]| -> Result<(), SignatureError> {
+            let checks: Vec<_> = transactions
+                .iter()
+                .zip(&spent_outputs)
+                .flat_map(|(tx, spent)| SignatureCheck::for_transaction(tx, spent))
+                .collect();
+            verify_all(&checks)
+        };
+        assert!(checks(&transactions).is_ok());
+
+        // swapping the script_sigs of two Schnorr inputs invalidates the block
+        let mut tampered = transactions.clone();
+        let script_sig = tampered[1].inputs[0].script_sig.clone();
+        tampered[1].inputs[0].script_sig = tampered[3].inputs[0].script_sig.clone();
+        tampered[3].inputs[0].script_sig = script_sig;
+        assert!(checks(&tampered).is_err());
+
+        let mut tampered = transactions;
+        tampered[4].inputs[0].script_sig = Signature::from_bytes(&[0u8; 98]);
+        assert!(checks(&tampered).is_err());
+    }
+}
@@ -1,14 +1,24 @@
 use std::collections::{HashMap, HashSet};
 
-use wallet_crypto::{keys::{BlockchainHash, PublicKeyHash}, transaction::{TxOut, UTXO}};
+use wallet_crypto::{keys::{Address, BlockchainHash}, transaction::{TxOut, UTXO}};
 
 pub trait TxOutRecipient {
-    fn get_address(&self) -> PublicKeyHash;
+    fn get_address(&self) -> Option<Address>;
     fn get_received_amount(&self) -> u64;
 }
 
 type Key = (BlockchainHash, u32);
 
+/// Unspent output along with the block that confirmed it, needed to enforce relative timelocks.
+#[derive(Debug, Clone)]
+pub struct UtxoEntry {
+    pub output: TxOut,
+    pub height: u64,
+    // median time past of the chain the confirming block extended (millis), which relative
+    // time locks count from
+    pub time: u128,
+}
+
 #[derive(Debug, Clone)]
 pub struct UTXOSet<TxOut> {
     pub data: HashMap<Key, TxOut>,
@@ -27,10 +37,6 @@ impl<TxOut> UTXOSet<TxOut> {
         self.data.get(key)
     }
 
-    pub fn clear(&mut self) {
-        self.data.clear();
-    }
-
     pub fn remove(&mut self, key: &Key) -> Option<TxOut> {
         self.reserved.remove(key);
         self.data.remove(key)
@@ -42,10 +48,9 @@ impl<TxOut> UTXOSet<TxOut> {
 }
 
 impl<TxOut: TxOutRecipient + Clone> UTXOSet<TxOut> {
-    pub fn get_utxos_by_address(&self, address: PublicKeyHash) -> Vec<UTXO> {
+    pub fn get_utxos_by_address(&self, address: Address) -> Vec<UTXO> {
         self.data.iter().fold(vec![], |mut acc, ((tx, idx), val)| {
-            let recipient = val.get_address();
-            if recipient == address {
+            if val.get_address() == Some(address) {
                 acc.push(UTXO {
                     prev_tx_id: tx.clone(),
                     prev_out_idx: *idx,
@@ -59,13 +64,21 @@ impl<TxOut: TxOutRecipient + Clone> UTXOSet<TxOut> {
 }
 
 impl TxOutRecipient for TxOut {
-    fn get_address(&self) -> PublicKeyHash {
-        match self.script_pubkey {
-            wallet_crypto::scripts::Script::PayToPublicKeyHash { pub_key_hash } => pub_key_hash,
-        }
+    fn get_address(&self) -> Option<Address> {
+        self.script_pubkey.address()
     }
     
     fn get_received_amount(&self) -> u64 {
         self.value
     }
 }
+
+impl TxOutRecipient for UtxoEntry {
+    fn get_address(&self) -> Option<Address> {
+        self.output.get_address()
+    }
+
+    fn get_received_amount(&self) -> u64 {
+        self.output.get_received_amount()
+    }
+}
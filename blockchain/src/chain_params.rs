@@ -0,0 +1,132 @@
+// Consensus parameters that differ between networks.
+//
+// New rules are rolled out as deployments (see versionbits.rs): each one gets a version bit
+// and a height range in which miners can signal for it, configured separately per network.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// Deployments that never time out.
+pub const NO_TIMEOUT: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Main,
+    Test,
+    Regtest,
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "main" => Ok(Network::Main),
+            "test" => Ok(Network::Test),
+            "regtest" => Ok(Network::Regtest),
+            other => Err(format!(
+                "Unknown network {}, expected main, test or regtest",
+                other
+            )),
+        }
+    }
+}
+
+impl Network {
+    /// Start of every peer-to-peer message, so nodes of different networks can't talk to
+    /// each other by mistake.
+    pub fn magic(self) -> [u8; 4] {
+        match self {
+            Network::Main => [0xb1, 0x0c, 0xc4, 0x1a],
+            Network::Test => [0xb1, 0x0c, 0xc4, 0x7e],
+            Network::Regtest => [0xb1, 0x0c, 0xc4, 0x9f],
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Network::Main => "main",
+            Network::Test => "test",
+            Network::Regtest => "regtest",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A rule change miners signal readiness for with `bit` of the block version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Deployment {
+    pub name: &'static str,
+    pub bit: u8,
+    /// Signalling counts from the first period starting at or after this height.
+    pub start_height: u64,
+    /// A deployment not locked in by the period starting at this height fails.
+    pub timeout_height: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainParams {
+    pub network: Network,
+    /// Blocks per signalling period; deployment states only change at period boundaries.
+    pub signalling_window: u64,
+    /// Signalling blocks within one period that lock a deployment in.
+    pub activation_threshold: u64,
+    pub deployments: Vec<Deployment>,
+}
+
+impl ChainParams {
+    pub fn for_network(network: Network) -> Self {
+        match network {
+            // 95% of a two week period, as in Bitcoin
+            Network::Main => ChainParams {
+                network,
+                signalling_window: 2016,
+                activation_threshold: 1916,
+                deployments: vec![Deployment {
+                    name: "testdummy",
+                    bit: 28,
+                    start_height: 2016,
+                    timeout_height: 2016 * 26,
+                }],
+            },
+            Network::Test => ChainParams {
+                network,
+                signalling_window: 2016,
+                activation_threshold: 1512,
+                deployments: vec![Deployment {
+                    name: "testdummy",
+                    bit: 28,
+                    start_height: 0,
+                    timeout_height: 2016 * 26,
+                }],
+            },
+            Network::Regtest => ChainParams {
+                network,
+                signalling_window: 144,
+                activation_threshold: 108,
+                deployments: vec![Deployment {
+                    name: "testdummy",
+                    bit: 28,
+                    start_height: 0,
+                    timeout_height: NO_TIMEOUT,
+                }],
+            },
+        }
+    }
+
+    pub fn deployment(&self, name: &str) -> Option<&Deployment> {
+        self.deployments
+            .iter()
+            .find(|deployment| deployment.name == name)
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams::for_network(Network::Main)
+    }
+}
@@ -1,14 +1,11 @@
-use bincode::{
-    self,
-    config::standard,
-    error::{DecodeError, EncodeError},
-};
-use sled::{Db, Error as SledError, IVec};
+use sled::{Batch, Db, Error as SledError, IVec};
 use tokio::{
     sync::mpsc,
     task::{self, JoinError},
 };
 
+use wallet_crypto::encoding::{self, EncodingError};
+
 use crate::block::Block;
 
 type Hash = [u8; 32];
@@ -17,10 +14,8 @@ type Hash = [u8; 32];
 pub enum StorageError {
     #[error("Sled database error: {0}")]
     Sled(SledError),
-    #[error("Serialization error: {0}")]
-    Serialization(bincode::error::EncodeError),
-    #[error("Deserialization error: {0}")]
-    Deserialization(bincode::error::DecodeError),
+    #[error("Encoding error: {0}")]
+    Encoding(EncodingError),
     #[error("Task join error: {0}")]
     JoinError(JoinError),
     #[error("Block not found")]
@@ -33,15 +28,9 @@ impl From<SledError> for StorageError {
     }
 }
 
-impl From<EncodeError> for StorageError {
-    fn from(err: EncodeError) -> Self {
-        StorageError::Serialization(err)
-    }
-}
-
-impl From<DecodeError> for StorageError {
-    fn from(err: DecodeError) -> Self {
-        StorageError::Deserialization(err)
+impl From<EncodingError> for StorageError {
+    fn from(err: EncodingError) -> Self {
+        StorageError::Encoding(err)
     }
 }
 
@@ -63,6 +52,18 @@ pub trait Storage {
     async fn stream_blocks_by_height(
         &self,
     ) -> Result<mpsc::Receiver<Result<Block, StorageError>>, StorageError>;
+
+    /// Up to `count` consecutive blocks starting at height `from`.
+    async fn load_blocks_by_height(
+        &self,
+        from: u64,
+        count: u64,
+    ) -> Result<Vec<Block>, StorageError>;
+
+    /// Makes `branch`, consecutive blocks forking off the stored chain, part of it and its
+    /// last block the latest. The blocks it replaces drop out of the height index but stay
+    /// loadable by hash. A failure leaves the chain as it was.
+    async fn switch_branch(&self, branch: Vec<Block>) -> Result<(), StorageError>;
 }
 
 pub struct SledStorage {
@@ -136,9 +137,7 @@ impl Storage for SledStorage {
 
         task::spawn_blocking(move || {
             // iVec to not fully clone data on 2 inserts
-            let value_bytes: IVec = bincode::serde::encode_to_vec(&block, standard())
-                .map_err(StorageError::Serialization)?
-                .into();
+            let value_bytes: IVec = encoding::serialize_versioned(&block).into();
 
             // Store by height
             let height_key = SledStorage::format_height_key(block.height);
@@ -162,9 +161,7 @@ impl Storage for SledStorage {
             let hash_key = SledStorage::format_hash_key(&hash);
             match db.get(hash_key)? {
                 Some(data) => {
-                    let (block, _) =
-                        bincode::serde::decode_from_slice::<Block, _>(&data, standard())
-                            .map_err(StorageError::Deserialization)?;
+                    let block = encoding::deserialize_versioned::<Block>(&data)?;
                     Ok(Some(block))
                 }
                 None => Ok::<Option<Block>, StorageError>(None),
@@ -189,21 +186,84 @@ impl Storage for SledStorage {
                     iter_res
                         .map_err(StorageError::Sled)
                         .and_then(|(_key, value)| {
-                            bincode::serde::decode_from_slice::<Block, _>(&value, standard())
-                                .map(|res| res.0)
-                                .map_err(StorageError::Deserialization)
+                            encoding::deserialize_versioned::<Block>(&value)
+                                .map_err(StorageError::Encoding)
                         });
 
                 if let Err(_) = tx.blocking_send(block_result) {
                     break;
                 }
             }
-        })
-        .await?;
+        });
 
+        // not awaiting the scan: with more blocks than the channel holds it would never finish
         Ok(rx)
     }
 
+    async fn load_blocks_by_height(
+        &self,
+        from: u64,
+        count: u64,
+    ) -> Result<Vec<Block>, StorageError> {
+        const HEIGHT_PREFIX: &[u8; 7] = b"height_";
+
+        let db = self.db.clone();
+
+        task::spawn_blocking(move || {
+            db.range(SledStorage::format_height_key(from)..)
+                .take_while(|entry| {
+                    entry
+                        .as_ref()
+                        .map_or(true, |(key, _)| key.starts_with(HEIGHT_PREFIX))
+                })
+                .take(count as usize)
+                .map(|entry| {
+                    let (_key, value) = entry?;
+                    Ok(encoding::deserialize_versioned::<Block>(&value)?)
+                })
+                .collect::<Result<Vec<_>, StorageError>>()
+        })
+        .await?
+    }
+
+    async fn switch_branch(&self, branch: Vec<Block>) -> Result<(), StorageError> {
+        const HEIGHT_PREFIX: &[u8; 7] = b"height_";
+
+        let db = self.db.clone();
+
+        task::spawn_blocking(move || {
+            let Some(tip) = branch.last() else {
+                return Ok(());
+            };
+
+            // blocks stored by hash alone are not part of the chain, so these writes can
+            // go first
+            let mut switch = Batch::default();
+            for block in &branch {
+                let value_bytes: IVec = encoding::serialize_versioned(block).into();
+                db.insert(
+                    SledStorage::format_hash_key(block.hash.as_ref()),
+                    value_bytes.clone(),
+                )?;
+                switch.insert(&SledStorage::format_height_key(block.height), value_bytes);
+            }
+
+            // the branch overwrites the heights up to its tip, the rest of the old chain goes
+            for entry in db.range(SledStorage::format_height_key(tip.height + 1)..) {
+                let (key, _value) = entry?;
+                if !key.starts_with(HEIGHT_PREFIX) {
+                    break;
+                }
+                switch.remove(key);
+            }
+            switch.insert(b"latest_block_hash", tip.hash.as_ref());
+            db.apply_batch(switch)?;
+
+            Ok::<(), StorageError>(())
+        })
+        .await?
+    }
+
     async fn get_latest_block(&self) -> Result<Block, StorageError> {
         const HEIGHT_PREFIX: &[u8; 7] = b"height_";
 
@@ -214,9 +274,7 @@ impl Storage for SledStorage {
 
             match result {
                 Some(Ok((_key, value))) => {
-                    bincode::serde::decode_from_slice::<Block, _>(&value, standard())
-                        .map(|res| res.0)
-                        .map_err(StorageError::Deserialization)
+                    encoding::deserialize_versioned::<Block>(&value).map_err(StorageError::Encoding)
                 }
                 Some(Err(e)) => Err(StorageError::Sled(e)), // An error occurred during sled operation
                 None => Err(StorageError::BlockNotFound),   // No blocks found with the prefix
@@ -272,5 +330,24 @@ pub mod mock_storage {
         ) -> Result<mpsc::Receiver<Result<Block, StorageError>>, StorageError> {
             todo!()
         }
+
+        async fn load_blocks_by_height(
+            &self,
+            from: u64,
+            count: u64,
+        ) -> Result<Vec<Block>, StorageError> {
+            let mut blocks: Vec<_> = self
+                .blocks
+                .values()
+                .filter(|block| block.height >= from && block.height - from < count)
+                .cloned()
+                .collect();
+            blocks.sort_by_key(|block| block.height);
+            Ok(blocks)
+        }
+
+        async fn switch_branch(&self, _: Vec<Block>) -> Result<(), StorageError> {
+            Ok(())
+        }
     }
 }
@@ -0,0 +1,205 @@
+// Changes to the chain and the mempool, published as they happen for clients that would
+// otherwise poll.
+//
+// `Blockchain::subscribe` hands out a receiver of every event published after the call. A
+// receiver that falls more than `EVENT_CHANNEL_SIZE` events behind loses the oldest ones
+// (`RecvError::Lagged`) and should reload whatever it shows.
+//
+// When the chain switches to a heavier branch, `Reorg` comes first, then `NewTip` for the new
+// tip and the activity of the connected blocks. Transactions of the disconnected blocks go
+// back to the mempool with the usual `TransactionAdded`.
+
+use serde::{Serialize, Serializer};
+use wallet_crypto::{
+    keys::{Address, BlockchainHash},
+    transaction::TxOut,
+};
+
+pub const EVENT_CHANNEL_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChainEvent {
+    /// A block was connected on top of the chain.
+    NewTip {
+        hash: BlockchainHash,
+        height: u64,
+    },
+    /// The chain switched branch: `disconnected` blocks were replaced by `connected` ones,
+    /// both from the lowest height up.
+    Reorg {
+        disconnected: Vec<BlockchainHash>,
+        connected: Vec<BlockchainHash>,
+    },
+    TransactionAdded {
+        tx_id: BlockchainHash,
+    },
+    TransactionRemoved {
+        tx_id: BlockchainHash,
+        reason: RemovalReason,
+    },
+    /// A transaction paying to or spending from `address` entered the mempool, or a block
+    /// at `height`.
+    AddressActivity {
+        #[serde(serialize_with = "serialize_address")]
+        address: Address,
+        tx_id: BlockchainHash,
+        // none while in the mempool
+        height: Option<u64>,
+    },
+}
+
+/// Why a transaction left the mempool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RemovalReason {
+    // included in a block
+    Confirmed,
+    // spends an output a block spent first
+    Conflict,
+    // no longer valid on the branch the chain switched to
+    Reorg,
+}
+
+impl ChainEvent {
+    /// The `type` it serializes with.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ChainEvent::NewTip { .. } => "new_tip",
+            ChainEvent::Reorg { .. } => "reorg",
+            ChainEvent::TransactionAdded { .. } => "transaction_added",
+            ChainEvent::TransactionRemoved { .. } => "transaction_removed",
+            ChainEvent::AddressActivity { .. } => "address_activity",
+        }
+    }
+}
+
+/// Addresses of `outputs`, each once, for the activity of a transaction: its own outputs
+/// and the ones it spends.
+pub(crate) fn addresses<'a>(outputs: impl IntoIterator<Item = &'a TxOut>) -> Vec<Address> {
+    let mut addresses = Vec::new();
+    for address in outputs
+        .into_iter()
+        .filter_map(|output| output.script_pubkey.address())
+    {
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+    addresses
+}
+
+fn serialize_address<S: Serializer>(address: &Address, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&address.to_string_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use wallet_crypto::{
+        keys::KeyPair,
+        scripts::Script,
+        timelock::SEQUENCE_FINAL,
+        transaction::{DraftTransaction, UnsignedTxIn},
+    };
+
+    use super::*;
+    use crate::{
+        blockchain::Blockchain,
+        chain_params::{ChainParams, Network},
+        data::storage::SledStorage,
+    };
+
+    #[tokio::test]
+    async fn test_mempool_and_tip_events() {
+        let dir = std::env::temp_dir().join(format!("events-{}", rand::random::<u64>()));
+        let storage = SledStorage::new(dir.to_str().unwrap()).unwrap();
+        let mut blockchain = Blockchain::new(storage)
+            .with_params(ChainParams::for_network(Network::Regtest))
+            .init()
+            .await
+            .unwrap();
+        let mut events = blockchain.subscribe();
+
+        let key = KeyPair::generate();
+        let miner = key.public_key.to_address();
+        let block = blockchain
+            .mine_pending_transactions(Some(miner))
+            .await
+            .unwrap();
+        let coinbase = &block.transactions[0];
+        assert_eq!(
+            events.recv().await.unwrap(),
+            ChainEvent::NewTip {
+                hash: block.hash,
+                height: 1
+            }
+        );
+        assert_eq!(
+            events.recv().await.unwrap(),
+            ChainEvent::AddressActivity {
+                address: miner.into(),
+                tx_id: coinbase.id,
+                height: Some(1)
+            }
+        );
+
+        let recipient = KeyPair::generate().public_key.to_address();
+        let spent_output = coinbase.outputs[0].clone();
+        let tx = DraftTransaction::new(
+            vec![UnsignedTxIn {
+                prev_tx_id: coinbase.id,
+                prev_out_idx: 0,
+                sequence: SEQUENCE_FINAL,
+            }],
+            vec![TxOut {
+                value: spent_output.value - 1,
+                script_pubkey: Script::PayToPublicKeyHash {
+                    pub_key_hash: recipient,
+                },
+            }],
+        );
+        let tx = tx.sign(&key, &[spent_output]).unwrap();
+        blockchain.add_transaction(tx.clone()).await.unwrap();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            ChainEvent::TransactionAdded { tx_id: tx.id }
+        );
+        // the recipient, then the spender
+        for address in [recipient.into(), miner.into()] {
+            assert_eq!(
+                events.recv().await.unwrap(),
+                ChainEvent::AddressActivity {
+                    address,
+                    tx_id: tx.id,
+                    height: None
+                }
+            );
+        }
+
+        let block = blockchain.mine_pending_transactions(None).await.unwrap();
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ChainEvent::NewTip { height: 2, .. }
+        ));
+        assert_eq!(
+            events.recv().await.unwrap(),
+            ChainEvent::TransactionRemoved {
+                tx_id: tx.id,
+                reason: RemovalReason::Confirmed
+            }
+        );
+        assert_eq!(blockchain.mempool_transactions().count(), 0);
+        assert_eq!(
+            serde_json::to_value(ChainEvent::AddressActivity {
+                address: recipient.into(),
+                tx_id: tx.id,
+                height: Some(block.height)
+            })
+            .unwrap()["address"],
+            recipient.to_string_owned()
+        );
+
+        drop(blockchain);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -1,3 +1,6 @@
 pub mod block;
 pub mod blockchain;
+pub mod chain_params;
 pub mod data;
+pub mod events;
+pub mod versionbits;
@@ -0,0 +1,154 @@
+// Soft-fork signalling through block version bits (BIP 9, with heights instead of times).
+//
+// Versions whose top three bits are 001 carry up to 29 signalling bits. A deployment moves
+// through its states once per signalling period:
+//
+//   Defined  -> Started   when the period starts at or after `start_height`
+//   Started  -> LockedIn  when at least `activation_threshold` blocks of the previous period
+//                         signalled its bit
+//   LockedIn -> Active    one period later, so nodes have time to upgrade
+//   Defined / Started -> Failed  when the period starts at or after `timeout_height`
+//
+// Nodes enforce a deployment's rules only for blocks where it is Active.
+
+use serde::Serialize;
+
+use crate::chain_params::{ChainParams, Deployment};
+
+pub const VERSION_BITS_TOP_BITS: u32 = 0x2000_0000;
+pub const VERSION_BITS_TOP_MASK: u32 = 0xE000_0000;
+pub const MAX_VERSION_BIT: u8 = 28;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeploymentState {
+    Defined,
+    Started,
+    LockedIn,
+    Active,
+    Failed,
+}
+
+/// Whether a block with `version` signals for `bit`.
+pub fn signals(version: u32, bit: u8) -> bool {
+    bit <= MAX_VERSION_BIT
+        && version & VERSION_BITS_TOP_MASK == VERSION_BITS_TOP_BITS
+        && version & (1 << bit) != 0
+}
+
+/// State of `deployment` for the block at `height`. `versions[h]` is the version of the
+/// block at height h and must cover every block before `height`'s period.
+pub fn deployment_state(
+    params: &ChainParams,
+    deployment: &Deployment,
+    versions: &[u32],
+    height: u64,
+) -> DeploymentState {
+    let window = params.signalling_window;
+    let mut state = DeploymentState::Defined;
+
+    for period in 1..=height / window {
+        let period_start = period * window;
+        let previous_period = &versions[((period - 1) * window) as usize..period_start as usize];
+
+        state = match state {
+            DeploymentState::Defined if period_start >= deployment.timeout_height => {
+                DeploymentState::Failed
+            }
+            DeploymentState::Defined if period_start >= deployment.start_height => {
+                DeploymentState::Started
+            }
+            DeploymentState::Started => {
+                let signalling = previous_period
+                    .iter()
+                    .filter(|version| signals(**version, deployment.bit))
+                    .count() as u64;
+
+                if signalling >= params.activation_threshold {
+                    DeploymentState::LockedIn
+                } else if period_start >= deployment.timeout_height {
+                    DeploymentState::Failed
+                } else {
+                    DeploymentState::Started
+                }
+            }
+            DeploymentState::LockedIn => DeploymentState::Active,
+            state => state,
+        };
+    }
+
+    state
+}
+
+/// Version for a new block at `height`: it signals every deployment that is started or
+/// locked in, so signalling continues until activation.
+pub fn compute_block_version(params: &ChainParams, versions: &[u32], height: u64) -> u32 {
+    params
+        .deployments
+        .iter()
+        .filter(|deployment| {
+            matches!(
+                deployment_state(params, deployment, versions, height),
+                DeploymentState::Started | DeploymentState::LockedIn
+            )
+        })
+        .fold(VERSION_BITS_TOP_BITS, |version, deployment| {
+            version | (1 << deployment.bit)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chain_params::Network;
+
+    use super::*;
+
+    #[test]
+    fn test_deployment_state_machine() {
+        let deployment = Deployment {
+            name: "test",
+            bit: 1,
+            start_height: 4,
+            timeout_height: 16,
+        };
+        let params = ChainParams {
+            network: Network::Regtest,
+            signalling_window: 4,
+            activation_threshold: 3,
+            deployments: vec![deployment.clone()],
+        };
+        let signalling = VERSION_BITS_TOP_BITS | 0b10;
+        let state = |versions: &[u32]| {
+            deployment_state(&params, &deployment, versions, versions.len() as u64)
+        };
+
+        // period 0 is always defined, signalling only counts once started
+        let mut versions = vec![signalling; 4];
+        assert_eq!(state(&versions[..3]), DeploymentState::Defined);
+        assert_eq!(state(&versions), DeploymentState::Started);
+
+        // 2 of 4 is below the threshold
+        versions.extend([signalling, signalling, VERSION_BITS_TOP_BITS, 1 << 1]);
+        assert_eq!(state(&versions), DeploymentState::Started);
+        assert_eq!(
+            compute_block_version(&params, &versions, versions.len() as u64),
+            signalling
+        );
+
+        versions.extend([signalling, VERSION_BITS_TOP_BITS, signalling, signalling]);
+        assert_eq!(state(&versions), DeploymentState::LockedIn);
+
+        versions.extend([VERSION_BITS_TOP_BITS; 4]);
+        assert_eq!(state(&versions), DeploymentState::Active);
+        assert_eq!(
+            compute_block_version(&params, &versions, versions.len() as u64),
+            VERSION_BITS_TOP_BITS
+        );
+
+        // without enough signalling before the timeout the deployment fails for good
+        let mut versions = vec![VERSION_BITS_TOP_BITS; 16];
+        assert_eq!(state(&versions), DeploymentState::Failed);
+        versions.extend([signalling; 8]);
+        assert_eq!(state(&versions), DeploymentState::Failed);
+    }
+}
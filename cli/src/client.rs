@@ -0,0 +1,135 @@
+use std::error::Error;
+
+use blockchain::{block::Block, chain_params::Network};
+use reqwest::{Client, Response};
+use serde::{Deserialize, de::DeserializeOwned};
+use wallet_crypto::{
+    keys::{Address, BlockchainHash, PublicKeyHash},
+    transaction::{Transaction, UTXO},
+};
+
+pub type CliResult<T> = Result<T, Box<dyn Error>>;
+
+/// Thin client for a node's REST API.
+pub struct NodeClient {
+    url: String,
+    http: Client,
+}
+
+impl NodeClient {
+    pub fn new(url: &str) -> Self {
+        NodeClient {
+            url: url.trim_end_matches('/').to_string(),
+            http: Client::new(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn blocks(&self) -> CliResult<Vec<Block>> {
+        let response = self.http.get(format!("{}/blocks", self.url)).send().await?;
+        Self::json(response).await
+    }
+
+    /// The network the node runs, from its `GET /tip`.
+    pub async fn network(&self) -> CliResult<Network> {
+        #[derive(Deserialize)]
+        struct Tip {
+            network: Network,
+        }
+
+        let response = self.http.get(format!("{}/tip", self.url)).send().await?;
+        Ok(Self::json::<Tip>(response).await?.network)
+    }
+
+    pub async fn height(&self) -> CliResult<u64> {
+        let blocks = self.blocks().await?;
+        let last_block = blocks.last().ok_or("Node has no blocks")?;
+        Ok(last_block.height)
+    }
+
+    pub async fn utxos(&self, address: Address) -> CliResult<Vec<UTXO>> {
+        let response = self
+            .http
+            .get(format!("{}/utxo/{}", self.url, address))
+            .send()
+            .await?;
+        Self::json(response).await
+    }
+
+    pub async fn balance(&self, address: Address) -> CliResult<u64> {
+        Ok(self
+            .utxos(address)
+            .await?
+            .iter()
+            .map(|utxo| utxo.value)
+            .sum())
+    }
+
+    pub async fn submit_transaction(&self, tx: &Transaction) -> CliResult<()> {
+        let response = self
+            .http
+            .post(format!("{}/transactions", self.url))
+            .json(tx)
+            .send()
+            .await?;
+        Self::json::<String>(response).await?;
+        Ok(())
+    }
+
+    /// Mines the mempool into a block whose reward goes to `miner`,
+    /// or to the node's default miner address.
+    pub async fn mine(&self, miner: Option<PublicKeyHash>) -> CliResult<()> {
+        let miner = miner
+            .map(|address| address.to_string_owned())
+            .unwrap_or_default();
+        let response = self
+            .http
+            .post(format!("{}/mine", self.url))
+            .json(&miner)
+            .send()
+            .await?;
+        Self::json::<String>(response).await?;
+        Ok(())
+    }
+
+    /// Confirmed transaction with `id`, if any.
+    pub async fn find_transaction(&self, id: &BlockchainHash) -> CliResult<Option<Transaction>> {
+        Ok(self
+            .blocks()
+            .await?
+            .into_iter()
+            .flat_map(|block| block.transactions)
+            .find(|tx| tx.id == *id))
+    }
+
+    /// Confirmed transaction spending the output `tx_id:out_idx`, if any.
+    pub async fn find_spending_transaction(
+        &self,
+        tx_id: &BlockchainHash,
+        out_idx: u32,
+    ) -> CliResult<Option<Transaction>> {
+        Ok(self
+            .blocks()
+            .await?
+            .into_iter()
+            .flat_map(|block| block.transactions)
+            .find(|tx| {
+                tx.inputs
+                    .iter()
+                    .any(|input| input.prev_tx_id == *tx_id && input.prev_out_idx == out_idx)
+            }))
+    }
+
+    async fn json<T: DeserializeOwned>(response: Response) -> CliResult<T> {
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("Node responded with {}: {}", status, body).into());
+        }
+
+        Ok(response.json().await?)
+    }
+}
@@ -1,3 +1,76 @@
-fn main() {
-    println!("Hello, world!");
+use client::{CliResult, NodeClient};
+
+mod client;
+mod psbt;
+mod swap;
+
+const USAGE: &str = "Usage:
+  cli swap <chain-a-node-url> <chain-b-node-url> [amount]
+      Runs an HTLC atomic swap between fresh wallets on two chains
+  cli keygen
+      Prints a new address and its secret key
+  cli psbt create <node-url> <from-address> <recipient> <amount> <fee>
+  cli psbt decode <psbt>
+  cli psbt update <psbt> <input> <redeem-script-hex>
+  cli psbt sign <psbt> [key-file]
+  cli psbt combine <psbt>...
+  cli psbt finalize <psbt>
+  cli psbt broadcast <node-url> <psbt>
+      Partially signed transactions (base64) for offline and multi-party signing;
+      sign reads hex secret keys, one per line, from key-file or stdin";
+
+const DEFAULT_SWAP_AMOUNT: u64 = 20;
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    if let Err(e) = run(&args).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: &[String]) -> CliResult<()> {
+    match args {
+        [command, chain_a, chain_b, rest @ ..] if command == "swap" && rest.len() <= 1 => {
+            let amount = match rest.first() {
+                Some(amount) => amount.parse()?,
+                None => DEFAULT_SWAP_AMOUNT,
+            };
+
+            swap::run(&NodeClient::new(chain_a), &NodeClient::new(chain_b), amount).await
+        }
+        [command] if command == "keygen" => {
+            psbt::keygen();
+            Ok(())
+        }
+        [command, subcommand, rest @ ..] if command == "psbt" => run_psbt(subcommand, rest).await,
+        _ => Err(USAGE.into()),
+    }
+}
+
+async fn run_psbt(subcommand: &str, args: &[String]) -> CliResult<()> {
+    match (subcommand, args) {
+        ("create", [node, from, recipient, amount, fee]) => {
+            psbt::create(
+                &NodeClient::new(node),
+                from,
+                recipient,
+                amount.parse()?,
+                fee.parse()?,
+            )
+            .await
+        }
+        ("decode", [psbt]) => psbt::decode(psbt),
+        ("update", [psbt, input, redeem_script]) => {
+            psbt::update(psbt, input.parse()?, redeem_script)
+        }
+        ("sign", [psbt]) => psbt::sign(psbt, None),
+        ("sign", [psbt, key_file]) => psbt::sign(psbt, Some(key_file)),
+        ("combine", psbts) if !psbts.is_empty() => psbt::combine(psbts),
+        ("finalize", [psbt]) => psbt::finalize(psbt),
+        ("broadcast", [node, psbt]) => psbt::broadcast(&NodeClient::new(node), psbt).await,
+        _ => Err(USAGE.into()),
+    }
 }
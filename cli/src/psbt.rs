@@ -0,0 +1,194 @@
+// PSBT commands for cold and multi-party signing.
+//
+// Only `create` and `broadcast` talk to a node. `sign` needs nothing but the PSBT and a
+// secret key, so it can run on a machine that never goes online; PSBTs move between
+// machines as base64 strings. Coins of a P2SH address need their redeem script added with
+// `update` before anyone can sign them. Secret keys are read from a file or stdin, never
+// from the command line, where other users could see them.
+
+use std::io::Read;
+
+use wallet_crypto::{
+    keys::{Address, KeyPair},
+    psbt::PartiallySignedTransaction,
+    scripts::Script,
+    signer::KeyStore,
+    timelock::SEQUENCE_FINAL,
+    transaction::{DraftTransaction, TxOut, UnsignedTxIn},
+};
+
+use crate::client::{CliResult, NodeClient};
+
+/// Prints a fresh key pair, e.g. for a cold wallet.
+pub fn keygen() {
+    let key = KeyPair::generate();
+    println!("Address:    {}", key.public_key.to_address());
+    println!("Schnorr:    {}", Address::from(key.x_only_public_key()));
+    println!("Secret key: {}", hex::encode(key.secret_key.0.to_bytes()));
+}
+
+/// Pays `amount` to `recipient` from every coin of `from`, with change back to `from`.
+pub async fn create(
+    node: &NodeClient,
+    from: &str,
+    recipient: &str,
+    amount: u64,
+    fee: u64,
+) -> CliResult<()> {
+    let from = Address::try_from_string(from)?;
+    let recipient = Address::try_from_string(recipient)?;
+
+    let utxos = node.utxos(from).await?;
+    let available: u64 = utxos.iter().map(|utxo| utxo.value).sum();
+    if available < amount + fee {
+        return Err(format!("Insufficient funds: {} available", available).into());
+    }
+
+    let inputs = utxos
+        .iter()
+        .map(|utxo| UnsignedTxIn {
+            prev_tx_id: utxo.prev_tx_id,
+            prev_out_idx: utxo.prev_out_idx,
+            sequence: SEQUENCE_FINAL,
+        })
+        .collect();
+    let spent_outputs = utxos
+        .iter()
+        .map(|utxo| TxOut {
+            value: utxo.value,
+            script_pubkey: from.script_pubkey(),
+        })
+        .collect();
+
+    let mut outputs = vec![TxOut {
+        value: amount,
+        script_pubkey: recipient.script_pubkey(),
+    }];
+    let change = available - amount - fee;
+    if change > 0 {
+        outputs.push(TxOut {
+            value: change,
+            script_pubkey: from.script_pubkey(),
+        });
+    }
+
+    let psbt = PartiallySignedTransaction::with_spent_outputs(
+        DraftTransaction::new(inputs, outputs),
+        spent_outputs,
+    )?;
+    println!("{}", psbt.to_base64());
+
+    Ok(())
+}
+
+pub fn decode(psbt: &str) -> CliResult<()> {
+    let psbt = PartiallySignedTransaction::from_base64(psbt)?;
+
+    for (idx, (input, info)) in psbt.draft.inputs.iter().zip(&psbt.inputs).enumerate() {
+        let state = if info.final_script_sig.is_some() {
+            "finalized".to_string()
+        } else {
+            format!("{} signatures", info.partial_signatures.len())
+        };
+        let value = info
+            .spent_output
+            .as_ref()
+            .map_or("unknown value".to_string(), |output| {
+                output.value.to_string()
+            });
+
+        println!(
+            "Input {}: {}:{} ({}), {}",
+            idx, input.prev_tx_id, input.prev_out_idx, value, state
+        );
+    }
+
+    for (idx, output) in psbt.draft.outputs.iter().enumerate() {
+        let destination = output
+            .script_pubkey
+            .address()
+            .map_or("non-standard script".to_string(), |address| {
+                address.to_string()
+            });
+        println!("Output {}: {} to {}", idx, output.value, destination);
+    }
+
+    Ok(())
+}
+
+/// Adds the redeem script of a P2SH input, given as hex; prints the updated PSBT.
+pub fn update(psbt: &str, input_index: usize, redeem_script: &str) -> CliResult<()> {
+    let mut psbt = PartiallySignedTransaction::from_base64(psbt)?;
+
+    let redeem_script = Script::from_bytes(&hex::decode(redeem_script)?)?;
+    psbt.set_redeem_script(input_index, redeem_script)?;
+    println!("{}", psbt.to_base64());
+
+    Ok(())
+}
+
+/// Signs with hex secret keys, one per line, read from `key_file` or else stdin; prints the
+/// updated PSBT.
+pub fn sign(psbt: &str, key_file: Option<&str>) -> CliResult<()> {
+    let mut psbt = PartiallySignedTransaction::from_base64(psbt)?;
+
+    let secret_keys = match key_file {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input)?;
+            input
+        }
+    };
+
+    let mut keys = KeyStore::new();
+    for secret_key in secret_keys.split_whitespace() {
+        let bytes = hex::decode(secret_key)?;
+        if bytes.len() != 32 {
+            return Err("Secret key must be 32 bytes of hex".into());
+        }
+        keys.add(KeyPair::from_private_key(&bytes));
+    }
+
+    let signed = psbt.sign(&keys)?;
+    eprintln!("Signed inputs {:?}", signed);
+    println!("{}", psbt.to_base64());
+
+    Ok(())
+}
+
+pub fn combine(psbts: &[String]) -> CliResult<()> {
+    let (first, rest) = psbts.split_first().ok_or("No PSBTs to combine")?;
+
+    let mut combined = PartiallySignedTransaction::from_base64(first)?;
+    for psbt in rest {
+        combined.combine(&PartiallySignedTransaction::from_base64(psbt)?)?;
+    }
+    println!("{}", combined.to_base64());
+
+    Ok(())
+}
+
+pub fn finalize(psbt: &str) -> CliResult<()> {
+    let mut psbt = PartiallySignedTransaction::from_base64(psbt)?;
+
+    let unfinalized = psbt.finalize()?;
+    if !unfinalized.is_empty() {
+        eprintln!("Inputs {:?} still need signatures", unfinalized);
+    }
+    println!("{}", psbt.to_base64());
+
+    Ok(())
+}
+
+/// Finalizes, extracts and submits the transaction.
+pub async fn broadcast(node: &NodeClient, psbt: &str) -> CliResult<()> {
+    let mut psbt = PartiallySignedTransaction::from_base64(psbt)?;
+    psbt.finalize()?;
+
+    let tx = psbt.extract()?;
+    node.submit_transaction(&tx).await?;
+    println!("Submitted transaction {}", tx.id);
+
+    Ok(())
+}
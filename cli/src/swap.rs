@@ -0,0 +1,206 @@
+// Cross-chain atomic swap between two nodes running separate chains.
+//
+// Alice owns coins on chain A and wants Bob's coins on chain B:
+//   1. Alice picks a secret and locks coins on A to its hash: Bob can claim them with the
+//      secret, Alice gets them back after a long timeout.
+//   2. Bob checks Alice's HTLC and locks coins on B to the same hash: Alice can claim them,
+//      Bob gets them back after a shorter timeout.
+//   3. Alice claims on B, which reveals the secret on chain B.
+//   4. Bob reads the secret from Alice's claim and claims on A.
+// Either both claims happen or, after the timeouts, both sides can refund.
+//
+// The two nodes must run different networks: nodes of one network end up peers of each
+// other, merging their chains so that every step happens on both. The swap refuses to start
+// if both nodes report the same network.
+
+use wallet_crypto::{
+    htlc::{self, HtlcContract},
+    keys::{Address, KeyPair, PublicKeyHash},
+    scripts::Script,
+    transaction::{Transaction, UTXO},
+};
+
+use crate::client::{CliResult, NodeClient};
+
+const SWAP_FEE: u64 = 1;
+// Blocks until Bob may refund on chain B; Alice's refund on chain A waits twice as long,
+// so Bob always has time to claim after the secret is revealed.
+const SWAP_TIMEOUT_BLOCKS: u32 = 10;
+
+struct Party {
+    name: &'static str,
+    key: KeyPair,
+}
+
+impl Party {
+    fn new(name: &'static str) -> Self {
+        Party {
+            name,
+            key: KeyPair::generate(),
+        }
+    }
+
+    fn address(&self) -> PublicKeyHash {
+        self.key.public_key.to_address()
+    }
+
+    fn script(&self) -> Script {
+        Script::PayToPublicKeyHash {
+            pub_key_hash: self.address(),
+        }
+    }
+}
+
+pub async fn run(chain_a: &NodeClient, chain_b: &NodeClient, amount: u64) -> CliResult<()> {
+    check_separate_chains(chain_a, chain_b).await?;
+
+    let alice = Party::new("Alice");
+    let bob = Party::new("Bob");
+
+    println!(
+        "Funding {} on {} and {} on {}",
+        alice.name,
+        chain_a.url(),
+        bob.name,
+        chain_b.url()
+    );
+    chain_a.mine(Some(alice.address())).await?;
+    chain_b.mine(Some(bob.address())).await?;
+
+    // 1. Alice locks coins on chain A
+    let (preimage, payment_hash) = htlc::generate_preimage();
+    let contract_a = HtlcContract::new(
+        payment_hash,
+        bob.address(),
+        alice.address(),
+        chain_a.height().await? as u32 + 2 * SWAP_TIMEOUT_BLOCKS,
+    );
+    let htlc_a = lock(chain_a, &alice, &contract_a, amount).await?;
+    println!(
+        "{} locked {} on chain A until height {}",
+        alice.name, amount, contract_a.timeout
+    );
+
+    // 2. Bob checks Alice's HTLC on chain A, then locks coins on chain B to the same hash
+    confirmed_htlc(chain_a, &htlc_a, &contract_a, amount).await?;
+    let contract_b = HtlcContract::new(
+        contract_a.payment_hash,
+        alice.address(),
+        bob.address(),
+        chain_b.height().await? as u32 + SWAP_TIMEOUT_BLOCKS,
+    );
+    let htlc_b = lock(chain_b, &bob, &contract_b, amount).await?;
+    println!(
+        "{} locked {} on chain B until height {}",
+        bob.name, amount, contract_b.timeout
+    );
+
+    // 3. Alice checks Bob's HTLC on chain B, then claims it, revealing the secret
+    confirmed_htlc(chain_b, &htlc_b, &contract_b, amount).await?;
+    let claim_b = htlc::claim_transaction(
+        &alice.key,
+        &htlc_b,
+        &contract_b,
+        &preimage,
+        SWAP_FEE,
+        alice.script(),
+    )?;
+    confirm(chain_b, &claim_b).await?;
+    println!("{} claimed on chain B in {}", alice.name, claim_b.id);
+
+    // 4. Bob learns the secret from chain B and claims Alice's HTLC on chain A
+    let claim = chain_b
+        .find_spending_transaction(&htlc_b.prev_tx_id, htlc_b.prev_out_idx)
+        .await?
+        .ok_or("Claim of chain B HTLC not found")?;
+    let revealed =
+        htlc::extract_preimage(&claim, &htlc_b.prev_tx_id, htlc_b.prev_out_idx, &contract_b)
+            .ok_or("Claim on chain B does not reveal the secret")?;
+
+    if chain_a.height().await? >= contract_a.timeout as u64 {
+        return Err("Chain A HTLC timed out before it could be claimed".into());
+    }
+
+    let claim_a = htlc::claim_transaction(
+        &bob.key,
+        &htlc_a,
+        &contract_a,
+        &revealed,
+        SWAP_FEE,
+        bob.script(),
+    )?;
+    confirm(chain_a, &claim_a).await?;
+    println!("{} claimed on chain A in {}", bob.name, claim_a.id);
+
+    println!(
+        "Swap complete: {} has {} on chain B, {} has {} on chain A",
+        alice.name,
+        chain_b.balance(Address::from(alice.address())).await?,
+        bob.name,
+        chain_a.balance(Address::from(bob.address())).await?,
+    );
+
+    Ok(())
+}
+
+// Fails if both nodes run the same network, whose nodes would share one chain
+async fn check_separate_chains(chain_a: &NodeClient, chain_b: &NodeClient) -> CliResult<()> {
+    let network = chain_a.network().await?;
+    if network == chain_b.network().await? {
+        return Err(format!(
+            "{} and {} both run the {} network, run them on different networks",
+            chain_a.url(),
+            chain_b.url(),
+            network
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+// Funds `contract` with `amount` of the party's coins and waits for confirmation
+async fn lock(
+    chain: &NodeClient,
+    party: &Party,
+    contract: &HtlcContract,
+    amount: u64,
+) -> CliResult<UTXO> {
+    let utxos = chain.utxos(Address::from(party.address())).await?;
+    let funding = htlc::funding_transaction(&party.key, &utxos, contract, amount, SWAP_FEE)?;
+    confirm(chain, &funding).await?;
+
+    confirmed_htlc(
+        chain,
+        &htlc::find_htlc_output(&funding, contract)?,
+        contract,
+        amount,
+    )
+    .await
+}
+
+// The counterparty's view: the HTLC must be on chain, locked to the agreed contract and amount
+async fn confirmed_htlc(
+    chain: &NodeClient,
+    htlc_output: &UTXO,
+    contract: &HtlcContract,
+    amount: u64,
+) -> CliResult<UTXO> {
+    let funding = chain
+        .find_transaction(&htlc_output.prev_tx_id)
+        .await?
+        .ok_or("HTLC funding transaction is not confirmed")?;
+    let htlc_output = htlc::find_htlc_output(&funding, contract)?;
+
+    if htlc_output.value < amount {
+        return Err(format!("HTLC holds {}, expected {}", htlc_output.value, amount).into());
+    }
+
+    Ok(htlc_output)
+}
+
+// Submits `tx` and mines it right away, the reward goes to the node's default miner
+async fn confirm(chain: &NodeClient, tx: &Transaction) -> CliResult<()> {
+    chain.submit_transaction(tx).await?;
+    chain.mine(None).await
+}
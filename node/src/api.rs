@@ -1,3 +1,6 @@
+pub mod admin;
 pub mod blockchain;
+pub mod events;
 pub mod peers;
 pub mod types;
+pub mod wire;
@@ -0,0 +1,212 @@
+// Routes that change what the node connects to: adding and removing peers and lifting bans.
+//
+// They answer only requests carrying `Authorization: Bearer <token>`. The token is
+// NODE_ADMIN_TOKEN or, when that is unset, a random one the node writes to `admin_token` in
+// its data directory on every start, so local tools can read it and nobody else can guess it.
+// The file is readable by the node's user only, and tokens are compared in constant time.
+
+use std::{fs::File, io::Write, path::Path, sync::Arc};
+
+use axum::{
+    Router,
+    extract::{Request, State},
+    http::header::AUTHORIZATION,
+    middleware::{self, Next},
+    response::Response,
+    routing::{delete, post},
+};
+use subtle::ConstantTimeEq;
+
+use crate::api::{
+    peers::{clear_bans, delete_ban, delete_peer, post_peer},
+    types::{NodeError, NodeState},
+};
+
+const ADMIN_TOKEN_FILE: &str = "admin_token";
+
+/// NODE_ADMIN_TOKEN, or a new random token written to the data directory.
+pub fn load_admin_token(data_dir: &Path) -> Arc<str> {
+    if let Ok(token) = std::env::var("NODE_ADMIN_TOKEN") {
+        return token.into();
+    }
+
+    let token = hex::encode(rand::random::<[u8; 32]>());
+    let path = data_dir.join(ADMIN_TOKEN_FILE);
+    match write_owner_only(&path, &token) {
+        Ok(()) => println!("Admin token written to {}", path.display()),
+        Err(err) => eprintln!("Failed to write the admin token: {}", err),
+    }
+    token.into()
+}
+
+// Writes `contents` to `path`, readable and writable by the owner only
+fn write_owner_only(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = File::options();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path)?;
+    // the mode only applies to a new file, not to one left by an earlier start
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents.as_bytes())
+}
+
+/// The admin routes, each requiring `token`.
+pub fn admin_routes(token: Arc<str>) -> Router<NodeState> {
+    Router::new()
+        .route("/peers", post(post_peer))
+        .route("/peers/{id}", delete(delete_peer))
+        .route("/bans", delete(clear_bans))
+        .route("/bans/{ip}", delete(delete_ban))
+        .route_layer(middleware::from_fn_with_state(token, require_token))
+}
+
+async fn require_token(
+    State(token): State<Arc<str>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, NodeError> {
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| bool::from(given.as_bytes().ct_eq(token.as_bytes())));
+    if !authorized {
+        return Err(NodeError::Unauthorized);
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{body::Body, http::StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::simulator::Simulator;
+
+    const TOKEN: &str = "secret";
+    const SETTLE_TIME: Duration = Duration::from_secs(5);
+
+    // Sends a request to the admin routes of the node behind `state`, returns status and body
+    async fn admin_request(
+        state: &NodeState,
+        method: &str,
+        uri: &str,
+        token: Option<&str>,
+        body: Option<&str>,
+    ) -> (StatusCode, String) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json");
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = request
+            .body(Body::from(body.unwrap_or_default().to_string()))
+            .unwrap();
+
+        let response = admin_routes(TOKEN.into())
+            .with_state(state.clone())
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_token_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("admin-token-{}", rand::random::<u64>()));
+        std::fs::write(&path, "old token").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_owner_only(&path, TOKEN).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), TOKEN);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_admin_routes_need_the_token() {
+        let simulator = Simulator::new(1).await;
+        let state = simulator.state(0);
+        let ip = "10.0.0.9".parse().unwrap();
+        state.p2p.ban(ip, "Invalid proof of work");
+
+        for token in [None, Some("wrong")] {
+            let (status, _) = admin_request(state, "DELETE", "/bans", token, None).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+        assert!(state.p2p.is_banned(ip));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_add_and_remove_peers() {
+        let mut simulator = Simulator::new(2).await;
+        let state = simulator.state(0).clone();
+
+        let (status, body) = admin_request(
+            &state,
+            "POST",
+            "/peers",
+            Some(TOKEN),
+            Some("\"127.0.0.1:1\""),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "\"Connecting to 127.0.0.1:1\"");
+
+        simulator.connect(0, 1);
+        simulator.run_for(SETTLE_TIME).await;
+        let peer = state.p2p.peers()[0].clone();
+        let (status, _) = admin_request(
+            &state,
+            "DELETE",
+            &format!("/peers/{}", peer.id),
+            Some(TOKEN),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        simulator.run_for(SETTLE_TIME).await;
+        assert!(state.p2p.peer(peer.id).is_none());
+
+        let (status, _) = admin_request(&state, "DELETE", "/peers/42", Some(TOKEN), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lift_bans() {
+        let simulator = Simulator::new(1).await;
+        let state = simulator.state(0);
+        let (first, second) = ("10.0.0.8".parse().unwrap(), "10.0.0.9".parse().unwrap());
+        state.p2p.ban(first, "Invalid proof of work");
+        state.p2p.ban(second, "Invalid proof of work");
+
+        let (status, _) = admin_request(state, "DELETE", "/bans/10.0.0.8", Some(TOKEN), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!state.p2p.is_banned(first));
+        let (status, _) = admin_request(state, "DELETE", "/bans/10.0.0.8", Some(TOKEN), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = admin_request(state, "DELETE", "/bans", Some(TOKEN), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "\"Lifted 1 bans\"");
+        assert!(!state.p2p.is_banned(second));
+    }
+}
@@ -1,18 +1,23 @@
 use axum::{
     Json,
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode
 };
 use axum_macros::debug_handler;
-use blockchain::block::Block;
+use blockchain::block::{Block, BlockHeader};
 use wallet_crypto::{
-    keys::PublicKeyHash,
+    keys::{Address, BlockchainHash, PublicKeyHash},
+    merkle::TransactionProof,
     transaction::{Transaction, UTXO},
 };
 
 use crate::{
-    api::types::{NodeError, NodeState},
-    broadcast::{broadcast_block, broadcast_transaction},
+    api::{
+        types::{ChainTip, DeploymentInfo, HeightRange, NodeError, NodeState, TransactionData},
+        wire::Wire,
+    },
+    relay::{Accepted, accept_block, accept_transaction},
+    sync::{MAX_BLOCKS_PER_REQUEST, MAX_HEADERS_PER_REQUEST, SyncProgress},
 };
 
 #[debug_handler]
@@ -24,38 +29,138 @@ pub async fn get_blocks(
     Ok(Json(blocks))
 }
 
+/// Headers by height, at most `MAX_HEADERS_PER_REQUEST`.
+#[debug_handler]
+pub async fn get_headers(
+    State(NodeState { blockchain, .. }): State<NodeState>,
+    Query(range): Query<HeightRange>,
+) -> Result<Json<Vec<BlockHeader>>, NodeError> {
+    let count = range
+        .count
+        .unwrap_or(MAX_HEADERS_PER_REQUEST)
+        .min(MAX_HEADERS_PER_REQUEST);
+
+    let blockchain = blockchain.read().await;
+    let headers = blockchain.get_headers(range.from, count).await?;
+    Ok(Json(headers))
+}
+
+/// Blocks by height in the binary wire format, at most `MAX_BLOCKS_PER_REQUEST`.
+#[debug_handler]
+pub async fn get_raw_blocks(
+    State(NodeState { blockchain, .. }): State<NodeState>,
+    Query(range): Query<HeightRange>,
+) -> Result<Wire<Vec<Block>>, NodeError> {
+    let count = range
+        .count
+        .unwrap_or(MAX_BLOCKS_PER_REQUEST)
+        .min(MAX_BLOCKS_PER_REQUEST);
+
+    let blockchain = blockchain.read().await;
+    let blocks = blockchain.get_blocks_by_height(range.from, count).await?;
+    Ok(Wire(blocks))
+}
+
+#[debug_handler]
+pub async fn get_tip(State(NodeState { blockchain, .. }): State<NodeState>) -> Json<ChainTip> {
+    let blockchain = blockchain.read().await;
+    let tip = blockchain.tip();
+    Json(ChainTip {
+        height: tip.height,
+        hash: tip.hash(),
+        network: blockchain.params().network,
+    })
+}
+
+#[debug_handler]
+pub async fn get_sync_progress(
+    State(NodeState { sync, .. }): State<NodeState>,
+) -> Json<SyncProgress> {
+    Json(sync.progress().await)
+}
+
 #[debug_handler]
 pub async fn post_transaction(
-    State(NodeState { blockchain, peers }): State<NodeState>,
+    State(state): State<NodeState>,
     Json(tx): Json<Transaction>,
 ) -> Result<Json<String>, NodeError> {
-    let mut blockchain = blockchain.write().await;
-    let peers = peers.lock().await;
+    submit_transaction(&state, tx).await
+}
+
+/// Same as `post_transaction`, with the transaction in the binary wire format.
+#[debug_handler]
+pub async fn post_raw_transaction(
+    State(state): State<NodeState>,
+    Wire(tx): Wire<Transaction>,
+) -> Result<Json<String>, NodeError> {
+    submit_transaction(&state, tx).await
+}
+
+async fn submit_transaction(state: &NodeState, tx: Transaction) -> Result<Json<String>, NodeError> {
+    let message = match accept_transaction(state, tx, None).await? {
+        Accepted::AlreadyKnown => "Transaction already known",
+        _ => "Transaction added",
+    };
+    Ok(Json(message.to_string()))
+}
+
+/// Blocks in the binary wire format, e.g. mined elsewhere. A block must extend our tip.
+#[debug_handler]
+pub async fn post_block(
+    State(state): State<NodeState>,
+    Wire(block): Wire<Block>,
+) -> Result<Json<String>, NodeError> {
+    let message = match accept_block(&state, block, None).await? {
+        Accepted::Added => "Block added",
+        Accepted::AlreadyKnown => "Block already known",
+        Accepted::Ahead => "Block is ahead of our chain, syncing",
+    };
+    Ok(Json(message.to_string()))
+}
+
+#[debug_handler]
+pub async fn get_raw_block(
+    State(NodeState { blockchain, .. }): State<NodeState>,
+    Path(hash): Path<String>,
+) -> Result<Wire<Block>, NodeError> {
+    let hash = BlockchainHash::try_from_string(&hash)
+        .map_err(|_| NodeError::BadRequest("Block hash is incorrect hash value".to_string()))?;
 
-    let tx = blockchain.add_transaction(tx).await?;
-    broadcast_transaction(&peers, &tx).await;
+    let blockchain = blockchain.read().await;
+    let block = blockchain
+        .get_block(&hash)
+        .await?
+        .ok_or_else(|| NodeError::NotFound(format!("Block {} not found", hash)))?;
 
-    Ok(Json("Transaction added".to_string()))
+    Ok(Wire(block))
 }
 
 #[debug_handler]
 pub async fn mine_block(
-    State(NodeState { blockchain, peers }): State<NodeState>,
+    State(NodeState {
+        blockchain,
+        seen,
+        p2p,
+        ..
+    }): State<NodeState>,
     Json(address): Json<String>,
 ) -> Result<(StatusCode, Json<String>), NodeError> {
-    let mut blockchain = blockchain.write().await;
-    blockchain.mine_pending_transactions().await.unwrap();
-
-    let peers = peers.lock().await;
-    broadcast_block(
-        &peers,
-        &blockchain
-            .get_blocks()
-            .await?
-            .last()
-            .expect("Last block could not be found"),
-    )
-    .await;
+    // an empty address keeps the node's default miner address
+    let miner_address = match address.as_str() {
+        "" => None,
+        address => Some(PublicKeyHash::try_from_string(address).map_err(|_| {
+            NodeError::BadRequest("Miner address is incorrect hash value".to_string())
+        })?),
+    };
+
+    let block = blockchain
+        .write()
+        .await
+        .mine_pending_transactions(miner_address)
+        .await?;
+
+    seen.lock().await.insert(block.hash);
+    p2p.announce_block(&block, None);
 
     Ok((StatusCode::OK, Json("Block created".to_string())))
 }
@@ -66,7 +171,89 @@ pub async fn get_utxo_by_address(
     Path(address): Path<String>,
 ) -> Result<Json<Vec<UTXO>>, NodeError> {
     let blockchain = blockchain.read().await;
-    let address = PublicKeyHash::try_from_string(&address).map_err(|_| NodeError::BadRequest("Address is incorrect hash value".to_string()))?;
+    let address = Address::try_from_string(&address).map_err(|_| NodeError::BadRequest("Address is incorrect hash value".to_string()))?;
     let utxos = blockchain.get_utxos_by_address(address).await;
     Ok(Json(utxos))
 }
+
+#[debug_handler]
+pub async fn get_transaction_data(
+    State(NodeState { blockchain, .. }): State<NodeState>,
+    Path(tx_id): Path<String>,
+) -> Result<Json<TransactionData>, NodeError> {
+    let tx_id = BlockchainHash::try_from_string(&tx_id)
+        .map_err(|_| NodeError::BadRequest("Transaction id is incorrect hash value".to_string()))?;
+
+    let blockchain = blockchain.read().await;
+    let block = blockchain
+        .find_block_with_transaction(&tx_id)
+        .await?
+        .ok_or_else(|| NodeError::NotFound(format!("Transaction {} is not confirmed", tx_id)))?;
+
+    let tx = block
+        .transactions
+        .iter()
+        .find(|tx| tx.id == tx_id)
+        .expect("Block was found by this transaction");
+
+    Ok(Json(TransactionData {
+        tx_id,
+        block_hash: block.hash,
+        block_height: block.height,
+        timestamp: block.timestamp,
+        data: tx.data_payloads().into_iter().map(hex::encode).collect(),
+    }))
+}
+
+/// Merkle branch of a confirmed transaction together with its block header, for light
+/// clients that check payments without downloading blocks.
+#[debug_handler]
+pub async fn get_transaction_proof(
+    State(NodeState { blockchain, .. }): State<NodeState>,
+    Path(tx_id): Path<String>,
+) -> Result<Json<TransactionProof>, NodeError> {
+    let tx_id = BlockchainHash::try_from_string(&tx_id)
+        .map_err(|_| NodeError::BadRequest("Transaction id is incorrect hash value".to_string()))?;
+
+    let blockchain = blockchain.read().await;
+    let block = blockchain
+        .find_block_with_transaction(&tx_id)
+        .await?
+        .ok_or_else(|| NodeError::NotFound(format!("Transaction {} is not confirmed", tx_id)))?;
+
+    let proof = block
+        .merkle_proof(&tx_id)
+        .expect("Block was found by this transaction");
+    let transaction = block
+        .transactions
+        .iter()
+        .find(|tx| tx.id == tx_id)
+        .expect("Block was found by this transaction")
+        .clone();
+
+    Ok(Json(TransactionProof {
+        transaction,
+        header: block.header(),
+        proof,
+    }))
+}
+
+#[debug_handler]
+pub async fn get_deployments(
+    State(NodeState { blockchain, .. }): State<NodeState>,
+) -> Json<Vec<DeploymentInfo>> {
+    let blockchain = blockchain.read().await;
+    let deployments = blockchain
+        .deployment_states()
+        .into_iter()
+        .map(|(deployment, state)| DeploymentInfo {
+            name: deployment.name,
+            bit: deployment.bit,
+            start_height: deployment.start_height,
+            timeout_height: deployment.timeout_height,
+            state,
+        })
+        .collect();
+
+    Json(deployments)
+}
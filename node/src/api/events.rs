@@ -0,0 +1,156 @@
+// Chain events pushed to clients as they happen (see blockchain/src/events.rs), so wallets
+// don't have to poll for new blocks and balances.
+//
+// `GET /events` is a server-sent event stream of every event, named by its type. The
+// WebSocket at `/events/ws` sends the same events as JSON text messages, except that
+// address activity only goes out for the addresses the client subscribed to with
+// `{"subscribe": "<address>"}` (and dropped with `{"unsubscribe": "<address>"}`). Both
+// send a `lagged` event when the client fell too far behind to get every event; it should
+// then reload what it shows.
+
+use std::{collections::HashSet, convert::Infallible};
+
+use axum::{
+    extract::{
+        State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    response::{
+        Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use blockchain::events::ChainEvent;
+use futures_util::{Stream, stream};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+use wallet_crypto::keys::Address;
+
+use crate::api::types::NodeState;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SubscriptionRequest {
+    Subscribe(String),
+    Unsubscribe(String),
+}
+
+pub async fn get_events(
+    State(NodeState { blockchain, .. }): State<NodeState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = blockchain.read().await.subscribe();
+    let events = stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(event) => Event::default()
+                .event(event.kind())
+                .json_data(&event)
+                .expect("Events serialize"),
+            Err(RecvError::Lagged(missed)) => {
+                Event::default().event("lagged").data(missed.to_string())
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok(event), receiver))
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+pub async fn get_events_socket(
+    State(NodeState { blockchain, .. }): State<NodeState>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let receiver = blockchain.read().await.subscribe();
+    upgrade.on_upgrade(|socket| send_events(socket, receiver))
+}
+
+async fn send_events(mut socket: WebSocket, mut receiver: broadcast::Receiver<ChainEvent>) {
+    let mut addresses = HashSet::new();
+    loop {
+        let text = tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(ChainEvent::AddressActivity { address, .. }) if !addresses.contains(&address) => {
+                    continue;
+                }
+                Ok(event) => serde_json::to_string(&event).expect("Events serialize"),
+                Err(RecvError::Lagged(missed)) => json!({ "type": "lagged", "missed": missed }).to_string(),
+                Err(RecvError::Closed) => return,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(request))) => match subscribe(&mut addresses, &request) {
+                    Ok(()) => continue,
+                    Err(err) => json!({ "type": "error", "message": err }).to_string(),
+                },
+                // pings are answered by axum
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        };
+
+        if socket.send(Message::Text(text.into())).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn subscribe(addresses: &mut HashSet<Address>, request: &str) -> Result<(), String> {
+    let request = serde_json::from_str(request)
+        .map_err(|err| format!("Expected a subscribe or unsubscribe request: {}", err))?;
+    let parse = |address: &str| {
+        Address::try_from_string(address).map_err(|err| format!("Invalid address: {}", err))
+    };
+
+    match request {
+        SubscriptionRequest::Subscribe(address) => {
+            addresses.insert(parse(&address)?);
+        }
+        SubscriptionRequest::Unsubscribe(address) => {
+            addresses.remove(&parse(&address)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{Router, body::Body, http::Request, routing::get};
+    use futures_util::StreamExt;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::simulator::Simulator;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sse_subscriber_gets_new_tips() {
+        let simulator = Simulator::new(1).await;
+        let app = Router::new()
+            .route("/events", get(get_events))
+            .with_state(simulator.state(0).clone());
+        let response = app
+            .oneshot(Request::get("/events").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+        let block = simulator.mine(0, None).await;
+        let mut body = response.into_body().into_data_stream();
+        let mut received = String::new();
+        while !received.contains("\n\n") {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.next())
+                .await
+                .expect("An event arrives")
+                .unwrap()
+                .unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+
+        let expected = format!(
+            "event: new_tip\ndata: {{\"type\":\"new_tip\",\"hash\":{},\"height\":1}}\n\n",
+            serde_json::to_string(&block.hash).unwrap()
+        );
+        assert!(received.starts_with(&expected), "{}", received);
+    }
+}
@@ -1,10 +1,116 @@
-use axum::{Json, extract::State, response::IntoResponse};
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    Json,
+    extract::{Path, State},
+};
 use axum_macros::debug_handler;
 
-use crate::api::types::NodeState;
+use crate::{
+    api::types::{ConnectionState, NodeError, NodeState, PeerInfo},
+    p2p::{
+        addresses::AddressSource,
+        bans::Ban,
+        manager::connect,
+        peer::{Direction, PeerId},
+    },
+};
+
+#[debug_handler]
+pub async fn get_peers(State(NodeState { p2p, .. }): State<NodeState>) -> Json<Vec<PeerInfo>> {
+    let connected = p2p.peers().into_iter().map(|peer| PeerInfo {
+        id: Some(peer.id),
+        address: peer.address,
+        state: ConnectionState::Connected,
+        direction: peer.direction,
+        connection: Some(peer.connection_info(p2p.misbehavior(peer.address.ip()))),
+    });
+    let connecting = p2p.connecting().into_iter().map(|address| PeerInfo {
+        id: None,
+        address,
+        state: ConnectionState::Connecting,
+        direction: Direction::Outbound,
+        connection: None,
+    });
+
+    Json(connected.chain(connecting).collect())
+}
+
+/// Adds a `host:port` to the configured peers and connects to it.
+#[debug_handler]
+pub async fn post_peer(
+    State(state): State<NodeState>,
+    Json(host): Json<String>,
+) -> Result<Json<String>, NodeError> {
+    let addresses = state
+        .p2p
+        .add_host(&host, AddressSource::Config)
+        .await
+        .map_err(|err| NodeError::BadRequest(format!("Could not resolve {}: {}", host, err)))?;
+
+    let outcomes: Vec<_> = addresses
+        .into_iter()
+        .map(|address| {
+            if state.p2p.is_banned(address.ip()) {
+                format!("{} is banned", address)
+            } else if connect(&state, address) {
+                format!("Connecting to {}", address)
+            } else {
+                format!("Already connected to {}", address)
+            }
+        })
+        .collect();
+    Ok(Json(outcomes.join(", ")))
+}
+
+/// Disconnects and forgets a peer, given by its id in the peer list or by its address.
+#[debug_handler]
+pub async fn delete_peer(
+    State(NodeState { p2p, .. }): State<NodeState>,
+    Path(id): Path<String>,
+) -> Result<Json<String>, NodeError> {
+    let address = match id.parse::<PeerId>() {
+        Ok(id) => {
+            let peer = p2p
+                .peer(id)
+                .ok_or_else(|| NodeError::NotFound(format!("No peer with id {}", id)))?;
+            peer.listen_address().unwrap_or(peer.address)
+        }
+        Err(_) => id.parse::<SocketAddr>().map_err(|_| {
+            NodeError::BadRequest(format!("{} is neither a peer id nor an address", id))
+        })?,
+    };
+    if !p2p.remove_address(address) {
+        return Err(NodeError::NotFound(format!(
+            "{} is not a known peer",
+            address
+        )));
+    }
+
+    Ok(Json(format!("Removed {}", address)))
+}
+
+#[debug_handler]
+pub async fn get_bans(State(NodeState { p2p, .. }): State<NodeState>) -> Json<Vec<Ban>> {
+    Json(p2p.bans())
+}
 
 #[debug_handler]
-pub async fn get_peers(State(NodeState { peers, .. }): State<NodeState>) -> impl IntoResponse {
-    let peers = peers.lock().await;
-    Json(peers.clone())
+pub async fn clear_bans(State(NodeState { p2p, .. }): State<NodeState>) -> Json<String> {
+    Json(format!("Lifted {} bans", p2p.clear_bans()))
+}
+
+#[debug_handler]
+pub async fn delete_ban(
+    State(NodeState { p2p, .. }): State<NodeState>,
+    Path(ip): Path<String>,
+) -> Result<Json<String>, NodeError> {
+    let ip: IpAddr = ip
+        .parse()
+        .map_err(|_| NodeError::BadRequest(format!("{} is not an IP address", ip)))?;
+    if !p2p.unban(ip) {
+        return Err(NodeError::NotFound(format!("{} is not banned", ip)));
+    }
+
+    Ok(Json(format!("Lifted the ban on {}", ip)))
 }
@@ -2,17 +2,32 @@ use axum::response::IntoResponse;
 use axum::{Json, http::StatusCode};
 use blockchain::{
     blockchain::{Blockchain, BlockchainError},
+    chain_params::Network,
     data::storage::SledStorage,
+    versionbits::DeploymentState,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
+use wallet_crypto::keys::BlockchainHash;
 use tokio::sync::{Mutex, RwLock};
 
+use crate::{
+    p2p::{
+        manager::PeerManager,
+        peer::{ConnectionInfo, Direction, PeerId},
+    },
+    seen::SeenSet,
+    sync::ChainSync,
+};
+
 #[derive(Clone)]
 pub struct NodeState {
     pub blockchain: Arc<RwLock<Blockchain<SledStorage>>>,
-    pub peers: Arc<Mutex<Vec<String>>>,
+    pub p2p: Arc<PeerManager>,
+    // blocks and transactions already received, so relayed copies are not processed again
+    pub seen: Arc<Mutex<SeenSet>>,
+    pub sync: Arc<ChainSync>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -24,7 +39,9 @@ pub enum NodeError {
     #[error("Invalid request: {0}")]
     BadRequest(String),
 
-    #[allow(dead_code)]
+    #[error("Not found: {0}")]
+    NotFound(String),
+
     #[error("Unauthorized access")]
     Unauthorized,
 
@@ -45,6 +62,7 @@ impl IntoResponse for NodeError {
                 format!("Blockchain error: {}", e),
             ),
             NodeError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            NodeError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
             NodeError::Unauthorized => (
                 StatusCode::UNAUTHORIZED,
                 "Authentication required".to_string(),
@@ -76,3 +94,59 @@ pub struct NewTransaction {
     pub timestamp: u128,
     pub signature: Option<Vec<u8>>,
 }
+
+/// Data-carrier payloads of a confirmed transaction, with the block that timestamps them.
+#[derive(Serialize)]
+pub struct TransactionData {
+    pub tx_id: BlockchainHash,
+    pub block_hash: BlockchainHash,
+    pub block_height: u64,
+    pub timestamp: u128,
+    // hex encoded payloads, in output order
+    pub data: Vec<String>,
+}
+
+/// A soft-fork deployment and its state for the next block.
+#[derive(Serialize)]
+pub struct DeploymentInfo {
+    pub name: &'static str,
+    pub bit: u8,
+    pub start_height: u64,
+    pub timeout_height: u64,
+    pub state: DeploymentState,
+}
+
+/// Height and hash of the block at the tip of a node's chain, and the network it is on.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChainTip {
+    pub height: u64,
+    pub hash: BlockchainHash,
+    pub network: Network,
+}
+
+/// A range of blocks by height, e.g. `?from=100&count=50`.
+#[derive(Deserialize)]
+pub struct HeightRange {
+    #[serde(default)]
+    pub from: u64,
+    pub count: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    // dialed, the handshake isn't done yet
+    Connecting,
+    Connected,
+}
+
+/// An entry of the peer list; connection details are only there once connected.
+#[derive(Serialize)]
+pub struct PeerInfo {
+    pub id: Option<PeerId>,
+    pub address: SocketAddr,
+    pub state: ConnectionState,
+    pub direction: Direction,
+    #[serde(flatten)]
+    pub connection: Option<ConnectionInfo>,
+}
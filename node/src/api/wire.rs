@@ -0,0 +1,44 @@
+// Request and response bodies in the canonical binary encoding (`wallet_crypto::encoding`),
+// for node-to-node traffic. Bodies start with the encoding version byte.
+
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use wallet_crypto::encoding::{self, Decodable, Encodable};
+
+use crate::api::types::NodeError;
+
+pub const WIRE_CONTENT_TYPE: &str = "application/octet-stream";
+
+pub struct Wire<T>(pub T);
+
+impl<T, S> FromRequest<S> for Wire<T>
+where
+    T: Decodable,
+    S: Send + Sync,
+{
+    type Rejection = NodeError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(|err| NodeError::BadRequest(err.to_string()))?;
+
+        encoding::deserialize_versioned(&bytes)
+            .map(Wire)
+            .map_err(|err| NodeError::BadRequest(format!("Invalid binary body: {}", err)))
+    }
+}
+
+impl<T: Encodable> IntoResponse for Wire<T> {
+    fn into_response(self) -> Response {
+        (
+            [(header::CONTENT_TYPE, WIRE_CONTENT_TYPE)],
+            encoding::serialize_versioned(&self.0),
+        )
+            .into_response()
+    }
+}
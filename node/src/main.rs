@@ -1,7 +1,11 @@
 use http::Method;
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, path::Path, sync::Arc};
 
-use ::blockchain::{blockchain::Blockchain, data::storage::SledStorage};
+use ::blockchain::{
+    blockchain::Blockchain,
+    chain_params::{ChainParams, Network},
+    data::storage::SledStorage,
+};
 use api::blockchain;
 use axum::{
     Router,
@@ -11,15 +15,36 @@ use serde_json::from_str;
 use tokio::sync::{Mutex, RwLock};
 use tower_http::cors::{Any, CorsLayer};
 
-use crate::api::{peers::get_peers, types::NodeState};
+use crate::{
+    api::{
+        admin::{admin_routes, load_admin_token},
+        events::{get_events, get_events_socket},
+        peers::{get_bans, get_peers},
+        types::NodeState,
+    },
+    p2p::{
+        addresses::{AddressBook, AddressSource},
+        bans::BanList,
+        manager::{PeerManager, accept_peers, maintain_connections},
+    },
+    seen::SeenSet,
+    sync::ChainSync,
+};
 
 mod api;
-mod broadcast;
+mod p2p;
+mod relay;
+mod seen;
+#[cfg(test)]
+mod simulator;
+mod sync;
 
 pub async fn load_peers_from_config(path: &str) -> Vec<String> {
-    let file_content = tokio::fs::read_to_string(path)
-        .await
-        .expect("Failed to read configuration file");
+    // peers can also be found through the address book, seeds and gossip
+    let Ok(file_content) = tokio::fs::read_to_string(path).await else {
+        eprintln!("No peers file at {}", path);
+        return Vec::new();
+    };
     from_str::<Vec<String>>(&file_content)
         .expect("Invalid JSON format")
         .into_iter()
@@ -27,46 +52,111 @@ pub async fn load_peers_from_config(path: &str) -> Vec<String> {
         .collect()
 }
 
+// Node settings come from the environment so several nodes (e.g. two chains for an
+// atomic swap) can run side by side: NODE_PORT, NODE_P2P_PORT, NODE_DATA_DIR,
+// NODE_PEERS_FILE, NODE_SEEDS (comma-separated host:port list), NODE_NETWORK (main,
+// test or regtest) and NODE_ADMIN_TOKEN (see api/admin.rs).
+fn env_or(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
 #[tokio::main]
 async fn main() {
-    let peers =
-        load_peers_from_config("/Users/morlovs/Projects/rust/rust_chain/node/peers.json").await;
-    let storage = SledStorage::new("/Users/morlovs/Projects/rust/rust_chain/node/data").unwrap();
+    let port: u16 = env_or("NODE_PORT", "8989")
+        .parse()
+        .expect("NODE_PORT must be a port number");
+    let p2p_port: u16 = env_or("NODE_P2P_PORT", "9989")
+        .parse()
+        .expect("NODE_P2P_PORT must be a port number");
+    let peers = load_peers_from_config(&env_or("NODE_PEERS_FILE", "peers.json")).await;
+    let seeds: Vec<String> = env_or("NODE_SEEDS", "")
+        .split(',')
+        .map(str::trim)
+        .filter(|seed| !seed.is_empty())
+        .map(String::from)
+        .collect();
+    let data_dir = env_or("NODE_DATA_DIR", "data");
+    let storage = SledStorage::new(&data_dir).unwrap();
+    let addresses = AddressBook::load(Path::new(&data_dir).join("addresses.json"));
+    let bans = BanList::load(Path::new(&data_dir).join("bans.json"));
+    let admin_token = load_admin_token(Path::new(&data_dir));
+    let network: Network = env_or("NODE_NETWORK", "main")
+        .parse()
+        .expect("NODE_NETWORK must be main, test or regtest");
 
-    let blockchain = Blockchain::new(storage);
+    let blockchain = Blockchain::new(storage).with_params(ChainParams::for_network(network));
     let blockchain = blockchain.init().await.unwrap();
 
     let blockchain = Arc::new(RwLock::new(blockchain));
     let state = NodeState {
         blockchain,
-        peers: Arc::new(Mutex::new(peers)),
+        p2p: Arc::new(PeerManager::new(
+            network.magic(),
+            p2p_port,
+            addresses,
+            seeds,
+            bans,
+        )),
+        seen: Arc::new(Mutex::new(SeenSet::default())),
+        sync: Arc::new(ChainSync::new()),
     };
+    tokio::spawn(state.sync.clone().run(state.clone()));
+
+    let p2p_listener = tokio::net::TcpListener::bind(("0.0.0.0", p2p_port))
+        .await
+        .unwrap();
+    println!("Accepting peers on port {}", p2p_port);
+    tokio::spawn(accept_peers(state.clone(), p2p_listener));
+    state.p2p.add_addresses(&peers, AddressSource::Config).await;
+    tokio::spawn(maintain_connections(state.clone()));
 
     let cors = CorsLayer::new()
         // allow `GET` and `POST` when accessing the resource
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::OPTIONS])
         // allow requests from any origin
         .allow_origin(Any)
         .allow_headers(Any);
 
     let app = Router::new()
         .route("/", get(root))
-        .route("/blocks", get(blockchain::get_blocks))
+        .route(
+            "/blocks",
+            get(blockchain::get_blocks).post(blockchain::post_block),
+        )
+        .route("/blocks/raw", get(blockchain::get_raw_blocks))
+        .route("/blocks/{hash}/raw", get(blockchain::get_raw_block))
+        .route("/headers", get(blockchain::get_headers))
+        .route("/tip", get(blockchain::get_tip))
+        .route("/sync", get(blockchain::get_sync_progress))
         .route("/transactions", post(blockchain::post_transaction))
+        .route("/transactions/raw", post(blockchain::post_raw_transaction))
+        .route(
+            "/transactions/{id}/data",
+            get(blockchain::get_transaction_data),
+        )
+        .route(
+            "/transactions/{id}/proof",
+            get(blockchain::get_transaction_proof),
+        )
         .route("/mine", post(blockchain::mine_block))
         .route("/utxo/{address}", get(blockchain::get_utxo_by_address))
+        .route("/deployments", get(blockchain::get_deployments))
+        .route("/events", get(get_events))
+        .route("/events/ws", get(get_events_socket))
         .route("/peers", get(get_peers))
+        .route("/bans", get(get_bans))
+        .merge(admin_routes(admin_token))
         .layer(cors)
         .with_state(state);
 
     // .route("/mine", post(mine_block))
     // .route("/balance/:address", get(get_balance));
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 8989));
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
     println!("🚀 Listening on http://{}", addr);
 
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8989").await.unwrap();
+    // run our app with hyper, listening globally on the configured port
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await.unwrap();
     axum::serve(listener, app).await.unwrap();
 }
 
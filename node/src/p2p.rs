@@ -0,0 +1,7 @@
+pub mod addresses;
+pub mod bans;
+pub mod compact;
+pub mod handler;
+pub mod manager;
+pub mod message;
+pub mod peer;
@@ -0,0 +1,290 @@
+// Addresses of other nodes, and how connecting to them went.
+//
+// Addresses come from the peers file, seed nodes, `addr` messages and nodes that connect to
+// us. Outbound connections are picked from here: an address that failed is retried with
+// exponential backoff and forgotten after `MAX_FAILURES` failures in a row, one that worked
+// before is preferred over one never tried. The book is saved as JSON in the data
+// directory, so a restarted node can find the network without its configured peers.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rand::seq::IteratorRandom;
+use serde::{Deserialize, Serialize};
+
+// Addresses kept at most; gossip can't grow the book beyond this
+pub(super) const MAX_ENTRIES: usize = 2000;
+// Failures in a row after which an address is forgotten, unless it is configured
+const MAX_FAILURES: u32 = 10;
+// Wait after a failure before the first retry; doubles with every further failure
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_BACKOFF_DOUBLINGS: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressSource {
+    // ordered by how much the address is trusted when picking connections
+    Gossip,
+    Inbound,
+    Seed,
+    Config,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressEntry {
+    pub address: SocketAddr,
+    pub source: AddressSource,
+    // unix seconds
+    pub last_attempt: Option<u64>,
+    pub last_success: Option<u64>,
+    pub successes: u32,
+    pub failures: u32,
+    pub failures_in_row: u32,
+}
+
+impl AddressEntry {
+    fn new(address: SocketAddr, source: AddressSource) -> Self {
+        AddressEntry {
+            address,
+            source,
+            last_attempt: None,
+            last_success: None,
+            successes: 0,
+            failures: 0,
+            failures_in_row: 0,
+        }
+    }
+
+    fn retry_at(&self) -> u64 {
+        let Some(last_attempt) = self.last_attempt else {
+            return 0;
+        };
+        match self.failures_in_row {
+            0 => last_attempt + RETRY_DELAY.as_secs(),
+            failures => {
+                let doublings = (failures - 1).min(MAX_BACKOFF_DOUBLINGS);
+                last_attempt + (RETRY_DELAY.as_secs() << doublings)
+            }
+        }
+    }
+}
+
+pub struct AddressBook {
+    path: PathBuf,
+    entries: HashMap<SocketAddr, AddressEntry>,
+    changed: bool,
+}
+
+impl AddressBook {
+    /// Book saved at `path`; starts empty if there is no readable file yet.
+    pub fn load(path: PathBuf) -> Self {
+        let entries: Vec<AddressEntry> = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                eprintln!(
+                    "Ignoring unreadable address book {}: {}",
+                    path.display(),
+                    err
+                );
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+
+        AddressBook {
+            path,
+            entries: entries
+                .into_iter()
+                .map(|entry| (entry.address, entry))
+                .collect(),
+            changed: false,
+        }
+    }
+
+    /// Writes the book if it changed since the last save.
+    pub fn save(&mut self) {
+        if !self.changed {
+            return;
+        }
+
+        let entries: Vec<_> = self.entries.values().collect();
+        let json = serde_json::to_string_pretty(&entries).expect("Entries serialize");
+        // written aside first, so a crash can't leave half a file
+        let temp_path = self.path.with_extension("json.tmp");
+        let written =
+            std::fs::write(&temp_path, json).and_then(|_| std::fs::rename(&temp_path, &self.path));
+        match written {
+            Ok(()) => self.changed = false,
+            Err(err) => eprintln!("Failed to save the address book: {}", err),
+        }
+    }
+
+    /// Adds an address not known yet; false if it was known or there is no room.
+    pub fn add(&mut self, address: SocketAddr, source: AddressSource) -> bool {
+        if address.port() == 0 || address.ip().is_unspecified() {
+            return false;
+        }
+        if let Some(entry) = self.entries.get_mut(&address) {
+            // e.g. a gossiped address that is also in the peers file
+            if source > entry.source {
+                entry.source = source;
+                self.changed = true;
+            }
+            return false;
+        }
+        if self.entries.len() >= MAX_ENTRIES && !self.evict_one() {
+            return false;
+        }
+
+        self.entries
+            .insert(address, AddressEntry::new(address, source));
+        self.changed = true;
+        true
+    }
+
+    /// Forgets `address`; false if it wasn't known.
+    pub fn remove(&mut self, address: &SocketAddr) -> bool {
+        let removed = self.entries.remove(address).is_some();
+        self.changed |= removed;
+        removed
+    }
+
+    pub fn mark_attempt(&mut self, address: &SocketAddr, now: u64) {
+        if let Some(entry) = self.entries.get_mut(address) {
+            entry.last_attempt = Some(now);
+            self.changed = true;
+        }
+    }
+
+    pub fn mark_success(&mut self, address: &SocketAddr, now: u64) {
+        if let Some(entry) = self.entries.get_mut(address) {
+            entry.last_success = Some(now);
+            entry.successes += 1;
+            entry.failures_in_row = 0;
+            self.changed = true;
+        }
+    }
+
+    /// Records the end of an outbound connection: a failure unless the handshake succeeded
+    /// since the last attempt.
+    pub fn mark_closed(&mut self, address: &SocketAddr) {
+        let Some(entry) = self.entries.get_mut(address) else {
+            return;
+        };
+        if entry.last_success >= entry.last_attempt {
+            return;
+        }
+
+        entry.failures += 1;
+        entry.failures_in_row += 1;
+        self.changed = true;
+        if entry.failures_in_row >= MAX_FAILURES && entry.source != AddressSource::Config {
+            self.entries.remove(address);
+        }
+    }
+
+    /// An address to connect to that is not in backoff, preferring trusted sources and
+    /// addresses that worked before.
+    pub fn select(&self, skip: impl Fn(&SocketAddr) -> bool, now: u64) -> Option<SocketAddr> {
+        let eligible = self
+            .entries
+            .values()
+            .filter(|entry| entry.retry_at() <= now && !skip(&entry.address));
+        let rank = |entry: &AddressEntry| (entry.source, entry.last_success.is_some());
+
+        let best = eligible.clone().map(rank).max()?;
+        eligible
+            .filter(|entry| rank(entry) == best)
+            .map(|entry| entry.address)
+            .choose(&mut rand::thread_rng())
+    }
+
+    /// Up to `count` random addresses that are not failing, to share with peers.
+    pub fn sample(&self, count: usize) -> Vec<SocketAddr> {
+        self.entries
+            .values()
+            .filter(|entry| entry.failures_in_row == 0)
+            .map(|entry| entry.address)
+            .choose_multiple(&mut rand::thread_rng(), count)
+    }
+
+    // Makes room by dropping the address that failed most in a row, if any failed
+    fn evict_one(&mut self) -> bool {
+        let worst = self
+            .entries
+            .values()
+            .filter(|entry| entry.failures_in_row > 0 && entry.source != AddressSource::Config)
+            .max_by_key(|entry| entry.failures_in_row)
+            .map(|entry| entry.address);
+
+        match worst {
+            Some(address) => {
+                self.entries.remove(&address);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Clock is after 1970")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_and_persistence() {
+        let path = std::env::temp_dir().join(format!("addresses-{}.json", rand::random::<u64>()));
+        let configured: SocketAddr = "127.0.0.1:9989".parse().unwrap();
+        let gossiped: SocketAddr = "10.0.0.1:9989".parse().unwrap();
+
+        let mut book = AddressBook::load(path.clone());
+        assert!(book.add(gossiped, AddressSource::Gossip));
+        assert!(book.add(configured, AddressSource::Config));
+        assert!(!book.add(gossiped, AddressSource::Gossip));
+        assert_eq!(book.select(|_| false, 0), Some(configured));
+        assert_eq!(
+            book.select(|address| *address == configured, 0),
+            Some(gossiped)
+        );
+
+        // a failed address waits, twice as long after every failure
+        book.mark_attempt(&configured, 100);
+        book.mark_closed(&configured);
+        assert_eq!(book.select(|_| false, 129), Some(gossiped));
+        assert_eq!(book.select(|_| false, 130), Some(configured));
+        book.mark_attempt(&configured, 130);
+        book.mark_closed(&configured);
+        assert_eq!(book.select(|_| false, 189), Some(gossiped));
+        assert_eq!(book.sample(10), vec![gossiped]);
+
+        // a connection that got through the handshake is not a failure
+        book.mark_attempt(&configured, 200);
+        book.mark_success(&configured, 200);
+        book.mark_closed(&configured);
+        assert_eq!(book.entries[&configured].failures_in_row, 0);
+        assert_eq!(book.entries[&configured].successes, 1);
+
+        // failing addresses are forgotten, unless configured
+        for attempt in 0..MAX_FAILURES as u64 {
+            book.mark_attempt(&gossiped, 300 + attempt);
+            book.mark_closed(&gossiped);
+        }
+        assert!(!book.entries.contains_key(&gossiped));
+
+        book.save();
+        let loaded = AddressBook::load(path.clone());
+        assert_eq!(loaded.entries.len(), 1);
+        assert_eq!(loaded.entries[&configured].last_success, Some(200));
+        std::fs::remove_file(path).unwrap();
+    }
+}
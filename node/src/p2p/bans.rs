@@ -0,0 +1,168 @@
+// Misbehavior scores and the IPs banned for them.
+//
+// Every invalid block or transaction a peer sends raises the score of its IP by how surely
+// the peer is at fault: no honest node relays a block with bad proof of work, but one may
+// relay a transaction spending an output we haven't seen yet. Scores are kept per IP by the
+// peer manager, so reconnecting doesn't reset them. An IP reaching `BAN_THRESHOLD` is
+// disconnected and banned for `BAN_DURATION`, which refuses connections both ways.
+// Bans are saved as JSON in the data directory so a restart doesn't lift them.
+
+use std::{collections::HashMap, net::IpAddr, path::PathBuf, time::Duration};
+
+use blockchain::blockchain::BlockchainError;
+use serde::{Deserialize, Serialize};
+
+pub const BAN_THRESHOLD: u32 = 100;
+// For claiming a height without the headers to show for it. Not a ban on its own: the
+// height may have come from a block the peer dropped in a reorganisation.
+pub const FALSE_HEIGHT_SCORE: u32 = 50;
+const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Score for sending a block that failed with `err`.
+pub fn invalid_block_score(err: &BlockchainError) -> u32 {
+    match err {
+        // a race for the same height, or our own storage failing
+        BlockchainError::DoesNotExtendTip(_)
+        | BlockchainError::InsufficientWork(_)
+        | BlockchainError::StorageError(_)
+        | BlockchainError::InconsistentStorage
+        | BlockchainError::MempoolError(_)
+        | BlockchainError::BusinessError(_) => 0,
+        _ => BAN_THRESHOLD,
+    }
+}
+
+/// Score for sending a transaction that failed with `err`.
+pub fn invalid_transaction_score(err: &BlockchainError) -> u32 {
+    match err {
+        BlockchainError::SignatureError(_)
+        | BlockchainError::InvalidPublicKey(_)
+        | BlockchainError::InvalidCoinbase(_)
+        | BlockchainError::InsufficientFunds
+        | BlockchainError::DuplicateInput { .. }
+        | BlockchainError::InvalidFee(_) => BAN_THRESHOLD,
+        // honest peers relay both sides of a conflict, each first seen by one of them
+        BlockchainError::DoubleSpendAttempt { .. } => 0,
+        // timelocks depend on our tip and clock, which the peer's may differ from
+        BlockchainError::InvalidTransaction(_) => 10,
+        _ => 0,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub ip: IpAddr,
+    // unix seconds
+    pub until: u64,
+    pub reason: String,
+}
+
+pub struct BanList {
+    path: PathBuf,
+    bans: HashMap<IpAddr, Ban>,
+}
+
+impl BanList {
+    /// Bans saved at `path`; none if there is no readable file yet.
+    pub fn load(path: PathBuf) -> Self {
+        let bans: Vec<Ban> = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                eprintln!("Ignoring unreadable ban list {}: {}", path.display(), err);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+
+        BanList {
+            path,
+            bans: bans.into_iter().map(|ban| (ban.ip, ban)).collect(),
+        }
+    }
+
+    // Bans change rarely, so every change is written right away
+    fn save(&self) {
+        let bans: Vec<_> = self.bans.values().collect();
+        let json = serde_json::to_string_pretty(&bans).expect("Bans serialize");
+        let temp_path = self.path.with_extension("json.tmp");
+        let written =
+            std::fs::write(&temp_path, json).and_then(|_| std::fs::rename(&temp_path, &self.path));
+        if let Err(err) = written {
+            eprintln!("Failed to save the ban list: {}", err);
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr, now: u64) -> bool {
+        self.bans.get(&ip).is_some_and(|ban| ban.until > now)
+    }
+
+    pub fn ban(&mut self, ip: IpAddr, now: u64, reason: &str) {
+        let ban = Ban {
+            ip,
+            until: now + BAN_DURATION.as_secs(),
+            reason: reason.to_string(),
+        };
+        self.bans.insert(ip, ban);
+        self.save();
+    }
+
+    /// Bans still in force, soonest to expire first.
+    pub fn list(&mut self, now: u64) -> Vec<Ban> {
+        let count = self.bans.len();
+        self.bans.retain(|_, ban| ban.until > now);
+        if self.bans.len() != count {
+            self.save();
+        }
+
+        let mut bans: Vec<_> = self.bans.values().cloned().collect();
+        bans.sort_by_key(|ban| ban.until);
+        bans
+    }
+
+    /// Lifts the ban on `ip`; false if it wasn't banned.
+    pub fn unban(&mut self, ip: IpAddr) -> bool {
+        let removed = self.bans.remove(&ip).is_some();
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    /// Lifts every ban and returns how many there were.
+    pub fn clear(&mut self) -> usize {
+        let count = self.bans.len();
+        self.bans.clear();
+        self.save();
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bans_expire_and_persist() {
+        let path = std::env::temp_dir().join(format!("bans-{}.json", rand::random::<u64>()));
+        let banned: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let mut bans = BanList::load(path.clone());
+        bans.ban(banned, 1000, "Invalid proof of work");
+        assert!(bans.is_banned(banned, 1000));
+        assert!(!bans.is_banned(other, 1000));
+
+        // saved on every change
+        let mut loaded = BanList::load(path.clone());
+        assert_eq!(loaded.list(1000)[0].reason, "Invalid proof of work");
+        let until = 1000 + BAN_DURATION.as_secs();
+        assert!(!loaded.is_banned(banned, until));
+        assert!(loaded.list(until).is_empty());
+        assert!(BanList::load(path.clone()).bans.is_empty());
+
+        bans.ban(other, 1000, "Double spend");
+        assert!(bans.unban(other));
+        assert!(!bans.unban(other));
+        assert_eq!(bans.clear(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,335 @@
+// Compact block relay: a new block is pushed as its header and a short id per transaction,
+// since the receiver usually has most of the transactions in its mempool already.
+//
+// A short id is the first 6 bytes of SHA-256(key || tx id). The key hashes the block hash
+// with a nonce the sender picks per compact block, so nobody can prepare transactions that
+// collide in every block. The coinbase, which no mempool holds, is sent in full
+// ("prefilled"). The receiver fills the other slots from its mempool and asks the sender
+// for the rest with `getblocktxn`. A rebuilt block that doesn't match its merkle root, e.g.
+// after a short id collision, is fetched in full instead.
+//
+// Nodes announce support with `SERVICE_COMPACT_BLOCKS`. Peers that set it get new blocks
+// pushed as `cmpctblock` right away, the others still get an `inv`.
+
+use std::collections::HashMap;
+
+use blockchain::block::{Block, BlockHeader};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use wallet_crypto::{
+    encoding::{self, Decodable, Encodable, EncodingError, Reader},
+    keys::BlockchainHash,
+    transaction::Transaction,
+};
+
+pub const SHORT_ID_SIZE: usize = 6;
+// Far more than blocks hold; bounds what a compact block can make us allocate
+const MAX_BLOCK_TRANSACTIONS: usize = 100_000;
+
+pub type ShortId = [u8; SHORT_ID_SIZE];
+
+#[derive(Debug, Error)]
+pub enum CompactBlockError {
+    #[error("Compact block with {0} transactions")]
+    TooManyTransactions(usize),
+    #[error("Prefilled transaction index {0} is out of range or repeated")]
+    InvalidIndex(u64),
+    #[error("Expected {expected} missing transactions, got {received}")]
+    WrongTransactionCount { expected: usize, received: usize },
+    #[error("Rebuilt block does not match its merkle root")]
+    MerkleMismatch,
+}
+
+/// A block as pushed to peers that support compact blocks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub nonce: u64,
+    // for every transaction not prefilled, in block order
+    pub short_ids: Vec<ShortId>,
+    pub prefilled: Vec<PrefilledTransaction>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefilledTransaction {
+    // position in the block
+    pub index: u64,
+    pub tx: Transaction,
+}
+
+/// Transactions of a block asked for with `getblocktxn`, by their position in the block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockTransactionsRequest {
+    pub block_hash: BlockchainHash,
+    pub indexes: Vec<u64>,
+}
+
+/// The answer to `getblocktxn`, in the order asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockTransactions {
+    pub block_hash: BlockchainHash,
+    pub transactions: Vec<Transaction>,
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block, nonce: u64) -> Self {
+        let key = short_id_key(&block.hash, nonce);
+        let (prefilled, short_ids) = match block.transactions.split_first() {
+            Some((coinbase, rest)) => (
+                vec![PrefilledTransaction {
+                    index: 0,
+                    tx: coinbase.clone(),
+                }],
+                rest.iter().map(|tx| short_id(&key, &tx.id)).collect(),
+            ),
+            None => (Vec::new(), Vec::new()),
+        };
+
+        CompactBlock {
+            header: block.header(),
+            nonce,
+            short_ids,
+            prefilled,
+        }
+    }
+}
+
+/// A block being rebuilt from a compact block.
+#[derive(Debug)]
+pub struct PartialBlock {
+    header: BlockHeader,
+    transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    /// Places the prefilled transactions, and fills the other slots with the transactions
+    /// of `mempool` that match their short id.
+    pub fn new<'a>(
+        compact: CompactBlock,
+        mempool: impl Iterator<Item = &'a Transaction>,
+    ) -> Result<Self, CompactBlockError> {
+        let count = compact.short_ids.len() + compact.prefilled.len();
+        if count > MAX_BLOCK_TRANSACTIONS {
+            return Err(CompactBlockError::TooManyTransactions(count));
+        }
+
+        let mut transactions = vec![None; count];
+        for prefilled in compact.prefilled {
+            match transactions.get_mut(prefilled.index as usize) {
+                Some(slot @ None) => *slot = Some(prefilled.tx),
+                _ => return Err(CompactBlockError::InvalidIndex(prefilled.index)),
+            }
+        }
+
+        // mempool transactions sharing a short id can't be told apart, both count as missing
+        let key = short_id_key(&compact.header.hash(), compact.nonce);
+        let mut by_short_id: HashMap<ShortId, Option<&Transaction>> = HashMap::new();
+        for tx in mempool {
+            by_short_id
+                .entry(short_id(&key, &tx.id))
+                .and_modify(|known| *known = None)
+                .or_insert(Some(tx));
+        }
+
+        let empty_slots = transactions.iter_mut().filter(|slot| slot.is_none());
+        for (slot, id) in empty_slots.zip(&compact.short_ids) {
+            *slot = by_short_id.get(id).copied().flatten().cloned();
+        }
+
+        Ok(PartialBlock {
+            header: compact.header,
+            transactions,
+        })
+    }
+
+    pub fn hash(&self) -> BlockchainHash {
+        self.header.hash()
+    }
+
+    /// Positions of the transactions the mempool didn't have.
+    pub fn missing(&self) -> Vec<u64> {
+        self.transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(index, _)| index as u64)
+            .collect()
+    }
+
+    /// Fills the missing transactions, given in the order of `missing`, and checks the
+    /// block against its merkle root.
+    pub fn complete(self, missing: Vec<Transaction>) -> Result<Block, CompactBlockError> {
+        let expected = self
+            .transactions
+            .iter()
+            .filter(|slot| slot.is_none())
+            .count();
+        if missing.len() != expected {
+            return Err(CompactBlockError::WrongTransactionCount {
+                expected,
+                received: missing.len(),
+            });
+        }
+
+        let mut missing = missing.into_iter();
+        let transactions: Vec<_> = self
+            .transactions
+            .into_iter()
+            .map(|slot| slot.or_else(|| missing.next()).expect("Counted above"))
+            .collect();
+        if Block::calculate_merkle_root(&transactions).ok() != Some(self.header.merkle_root) {
+            return Err(CompactBlockError::MerkleMismatch);
+        }
+
+        Ok(Block::from_header(self.header, transactions))
+    }
+}
+
+fn short_id_key(block_hash: &BlockchainHash, nonce: u64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(block_hash.as_ref());
+    hasher.update(nonce.to_le_bytes());
+    hasher.finalize().into()
+}
+
+fn short_id(key: &[u8; 32], tx_id: &BlockchainHash) -> ShortId {
+    let hash = Sha256::new()
+        .chain_update(key)
+        .chain_update(tx_id.as_ref())
+        .finalize();
+    hash[..SHORT_ID_SIZE]
+        .try_into()
+        .expect("SHA-256 is 32 bytes")
+}
+
+impl Encodable for CompactBlock {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.header.encode(out);
+        self.nonce.encode(out);
+        encoding::write_varint(out, self.short_ids.len() as u64);
+        for id in &self.short_ids {
+            out.extend_from_slice(id);
+        }
+        self.prefilled.encode(out);
+    }
+}
+
+impl Decodable for CompactBlock {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        let header = BlockHeader::decode(reader)?;
+        let nonce = reader.read_u64()?;
+        let count = reader.read_length(SHORT_ID_SIZE)?;
+        let short_ids = (0..count)
+            .map(|_| reader.read_array::<SHORT_ID_SIZE>())
+            .collect::<Result<_, _>>()?;
+
+        Ok(CompactBlock {
+            header,
+            nonce,
+            short_ids,
+            prefilled: Vec::decode(reader)?,
+        })
+    }
+}
+
+impl Encodable for PrefilledTransaction {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.index.encode(out);
+        self.tx.encode(out);
+    }
+}
+
+impl Decodable for PrefilledTransaction {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        Ok(PrefilledTransaction {
+            index: reader.read_u64()?,
+            tx: Transaction::decode(reader)?,
+        })
+    }
+}
+
+impl Encodable for BlockTransactionsRequest {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.block_hash.encode(out);
+        self.indexes.encode(out);
+    }
+}
+
+impl Decodable for BlockTransactionsRequest {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        Ok(BlockTransactionsRequest {
+            block_hash: BlockchainHash::decode(reader)?,
+            indexes: Vec::decode(reader)?,
+        })
+    }
+}
+
+impl Encodable for BlockTransactions {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.block_hash.encode(out);
+        self.transactions.encode(out);
+    }
+}
+
+impl Decodable for BlockTransactions {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        Ok(BlockTransactions {
+            block_hash: BlockchainHash::decode(reader)?,
+            transactions: Vec::decode(reader)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rebuild_from_mempool() {
+        let transactions: Vec<_> = (0..4u8)
+            .map(|i| {
+                let mut tx = Transaction::genesis_transaction();
+                tx.lock_time = i as u32 + 1;
+                tx.id = tx.calculate_id();
+                tx
+            })
+            .collect();
+        let mut coinbase = Transaction::genesis_transaction();
+        coinbase.id = coinbase.calculate_id();
+        let mut block_transactions = vec![coinbase];
+        block_transactions.extend(transactions.iter().cloned());
+        let block = Block::mine_new(1, 1, block_transactions, BlockchainHash::default()).await;
+
+        let compact = CompactBlock::new(&block, 42);
+        assert_eq!(compact.short_ids.len(), 4);
+        assert_eq!(
+            encoding::deserialize::<CompactBlock>(&encoding::serialize(&compact)).unwrap(),
+            compact
+        );
+
+        // the mempool has all but the third transaction
+        let mempool = [&transactions[0], &transactions[1], &transactions[3]];
+        let partial = PartialBlock::new(compact.clone(), mempool.into_iter()).unwrap();
+        assert_eq!(partial.missing(), vec![3]);
+        assert!(matches!(
+            PartialBlock::new(compact.clone(), mempool.into_iter())
+                .unwrap()
+                .complete(Vec::new()),
+            Err(CompactBlockError::WrongTransactionCount {
+                expected: 1,
+                received: 0
+            })
+        ));
+        assert_eq!(
+            partial.complete(vec![transactions[2].clone()]).unwrap(),
+            block
+        );
+
+        // a wrong transaction in a slot doesn't add up to the merkle root
+        let partial = PartialBlock::new(compact, mempool.into_iter()).unwrap();
+        assert!(matches!(
+            partial.complete(vec![transactions[0].clone()]),
+            Err(CompactBlockError::MerkleMismatch)
+        ));
+    }
+}
@@ -0,0 +1,240 @@
+// What the node does with each message a peer sends after the handshake.
+
+use std::sync::Arc;
+
+use blockchain::block::Block;
+use wallet_crypto::transaction::Transaction;
+
+use crate::{
+    api::types::NodeState,
+    p2p::{
+        bans::{BAN_THRESHOLD, invalid_block_score, invalid_transaction_score},
+        compact::{
+            BlockTransactions, BlockTransactionsRequest, CompactBlock, CompactBlockError,
+            PartialBlock,
+        },
+        message::{Inventory, Message},
+        peer::{Peer, PeerError},
+    },
+    relay::{Accepted, accept_block, accept_transaction},
+    sync::MAX_HEADERS_PER_REQUEST,
+};
+
+pub async fn handle_message(
+    state: &NodeState,
+    peer: &Arc<Peer>,
+    message: Message,
+) -> Result<(), PeerError> {
+    match message {
+        Message::Ping(nonce) => peer.send(Message::Pong(nonce)),
+        Message::Pong(nonce) => peer.pong(nonce),
+        Message::Inv(items) => {
+            let missing = missing_inventory(state, items).await;
+            if !missing.is_empty() {
+                peer.send(Message::GetData(missing));
+            }
+        }
+        Message::GetData(items) => send_inventory(state, peer, items).await,
+        Message::Block(block) => {
+            peer.update_best_height(block.height);
+            if let Some(block) = peer.deliver_block(block) {
+                accept_relayed_block(state, peer, block).await;
+            }
+        }
+        Message::Tx(tx) => {
+            let tx_id = tx.id;
+            if let Err(err) = accept_transaction(state, tx, Some(peer.id)).await {
+                eprintln!(
+                    "Rejected transaction {} from {}: {}",
+                    tx_id, peer.address, err
+                );
+                state
+                    .p2p
+                    .punish(peer, invalid_transaction_score(&err), &err.to_string());
+            }
+        }
+        Message::GetHeaders { from, count } => {
+            let count = count.min(MAX_HEADERS_PER_REQUEST);
+            let blockchain = state.blockchain.read().await;
+            match blockchain.get_headers(from, count).await {
+                Ok(headers) => peer.send(Message::Headers(headers)),
+                Err(err) => eprintln!("Could not load headers for {}: {}", peer.address, err),
+            }
+        }
+        Message::Headers(headers) => {
+            if let Some(last) = headers.last() {
+                peer.update_best_height(last.height);
+            }
+            // headers nobody asked for announce a longer chain
+            if peer.deliver_headers(headers).is_some() {
+                state.sync.wake();
+            }
+        }
+        Message::GetAddr => peer.send(Message::Addr(state.p2p.gossip_addresses(peer))),
+        Message::Addr(addresses) => state.p2p.learn_addresses(addresses, peer),
+        Message::CmpctBlock(compact) => receive_compact_block(state, peer, compact).await?,
+        Message::GetBlockTxn(request) => send_block_transactions(state, peer, request).await?,
+        Message::BlockTxn(response) => {
+            if let Some(partial) = peer.deliver_block_transactions(&response.block_hash) {
+                complete_compact_block(state, peer, partial, response.transactions).await?;
+            }
+        }
+        Message::Version(_) | Message::Verack => {
+            return Err(PeerError::Protocol(format!(
+                "{} after the handshake",
+                message.command()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+async fn accept_relayed_block(state: &NodeState, peer: &Peer, block: Block) {
+    let hash = block.hash;
+    match accept_block(state, block, Some(peer.id)).await {
+        Ok(Accepted::Added) => println!("Block {} added from {}", hash, peer.address),
+        Ok(_) => {}
+        Err(err) => {
+            eprintln!("Rejected block {} from {}: {}", hash, peer.address, err);
+            state
+                .p2p
+                .punish(peer, invalid_block_score(&err), &err.to_string());
+        }
+    }
+}
+
+// Rebuilds a pushed block from our mempool, asking the peer for what is missing
+async fn receive_compact_block(
+    state: &NodeState,
+    peer: &Peer,
+    compact: CompactBlock,
+) -> Result<(), PeerError> {
+    let header = compact.header;
+    // filling in transactions is only worth it for a block that could be valid
+    if let Err(err) = header.verify_proof_of_work() {
+        state.p2p.punish(peer, BAN_THRESHOLD, &err.to_string());
+        return Ok(());
+    }
+    peer.update_best_height(header.height);
+    if state.seen.lock().await.contains(&header.hash()) {
+        return Ok(());
+    }
+
+    let blockchain = state.blockchain.read().await;
+    let tip_height = blockchain.tip().height;
+    if header.height <= tip_height {
+        return Ok(());
+    }
+    if header.height > tip_height + 1 {
+        state.sync.wake();
+        return Ok(());
+    }
+    let partial = PartialBlock::new(compact, blockchain.mempool_transactions())
+        .map_err(|err| PeerError::Protocol(err.to_string()))?;
+    drop(blockchain);
+
+    if partial.missing().is_empty() {
+        complete_compact_block(state, peer, partial, Vec::new()).await
+    } else {
+        peer.request_block_transactions(partial);
+        Ok(())
+    }
+}
+
+async fn complete_compact_block(
+    state: &NodeState,
+    peer: &Peer,
+    partial: PartialBlock,
+    missing: Vec<Transaction>,
+) -> Result<(), PeerError> {
+    let hash = partial.hash();
+    let fetched = missing.len();
+    match partial.complete(missing) {
+        Ok(block) => {
+            println!(
+                "Rebuilt block {} from a compact block, {} of {} transactions fetched",
+                hash,
+                fetched,
+                block.transactions.len()
+            );
+            accept_relayed_block(state, peer, block).await;
+        }
+        // a short id collision, nobody's fault
+        Err(CompactBlockError::MerkleMismatch) => {
+            peer.send(Message::GetData(vec![Inventory::Block(hash)]))
+        }
+        Err(err) => return Err(PeerError::Protocol(err.to_string())),
+    }
+    Ok(())
+}
+
+// Answers `getblocktxn` for a block we pushed as a compact block
+async fn send_block_transactions(
+    state: &NodeState,
+    peer: &Peer,
+    request: BlockTransactionsRequest,
+) -> Result<(), PeerError> {
+    let blockchain = state.blockchain.read().await;
+    let Ok(Some(block)) = blockchain.get_block(&request.block_hash).await else {
+        return Ok(());
+    };
+
+    let transactions = request
+        .indexes
+        .iter()
+        .map(|&index| block.transactions.get(index as usize).cloned())
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| {
+            PeerError::Protocol(format!(
+                "getblocktxn for a transaction beyond block {}",
+                block.hash
+            ))
+        })?;
+    peer.send(Message::BlockTxn(BlockTransactions {
+        block_hash: block.hash,
+        transactions,
+    }));
+    Ok(())
+}
+
+// Announced blocks and transactions this node doesn't have yet
+async fn missing_inventory(state: &NodeState, items: Vec<Inventory>) -> Vec<Inventory> {
+    let mut missing = Vec::new();
+    for item in items {
+        let known = match item {
+            Inventory::Transaction(tx_id) => state.seen.lock().await.contains(&tx_id),
+            Inventory::Block(hash) => {
+                state.seen.lock().await.contains(&hash)
+                    || matches!(
+                        state.blockchain.read().await.get_block(&hash).await,
+                        Ok(Some(_))
+                    )
+            }
+        };
+        if !known {
+            missing.push(item);
+        }
+    }
+
+    missing
+}
+
+// Answers `getdata` with the blocks and transactions we have; the others are skipped
+async fn send_inventory(state: &NodeState, peer: &Peer, items: Vec<Inventory>) {
+    let blockchain = state.blockchain.read().await;
+    for item in items {
+        match item {
+            Inventory::Transaction(tx_id) => {
+                if let Some(tx) = blockchain.mempool_transaction(&tx_id) {
+                    peer.send(Message::Tx(tx.clone()));
+                }
+            }
+            Inventory::Block(hash) => {
+                if let Ok(Some(block)) = blockchain.get_block(&hash).await {
+                    peer.send(Message::Block(block));
+                }
+            }
+        }
+    }
+}
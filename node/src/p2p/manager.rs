@@ -0,0 +1,586 @@
+// The node's connected peers, and the listener and dialer that connect them.
+//
+// Outbound connections fill `MAX_OUTBOUND_PEERS` slots with addresses from the address book
+// (see addresses.rs). Peers share addresses: a new outbound peer is asked for some with
+// `getaddr`, and small `addr` messages, usually a node announcing itself, are passed on to
+// a few other peers. The seed nodes are only asked when there is nobody left to dial.
+//
+// Two nodes that both dial each other end up with two connections. The nonces from the
+// handshake identify the node behind a connection, and both sides keep the same one: the
+// connection opened by the node with the lower nonce.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use blockchain::block::Block;
+use rand::seq::IteratorRandom;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{
+    api::types::NodeState,
+    p2p::{
+        addresses::{AddressBook, AddressSource, unix_now},
+        bans::{BAN_THRESHOLD, Ban, BanList},
+        compact::CompactBlock,
+        message::{
+            Inventory, MAX_ADDRESSES, Message, PROTOCOL_VERSION, SERVICE_COMPACT_BLOCKS,
+            SERVICE_NETWORK, VersionMessage,
+        },
+        peer::{Direction, Peer, PeerError, PeerId, run_connection},
+    },
+};
+
+pub const MAX_INBOUND_PEERS: usize = 32;
+pub const MAX_OUTBOUND_PEERS: usize = 8;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// Seeds are asked again at most this often while the node has no peers
+const SEED_INTERVAL: Duration = Duration::from_secs(60);
+// `addr` messages with up to this many addresses are relayed, to this many peers
+const ADDR_RELAY_MAX: usize = 10;
+const ADDR_RELAY_PEERS: usize = 2;
+
+pub struct PeerManager {
+    magic: [u8; 4],
+    // identifies this node in handshakes
+    nonce: u64,
+    listen_port: u16,
+    next_id: AtomicU64,
+    peers: Mutex<HashMap<PeerId, Arc<Peer>>>,
+    addresses: Mutex<AddressBook>,
+    // outbound connections being dialed or in their handshake
+    connecting: Mutex<HashSet<SocketAddr>>,
+    // `host:port` of nodes that hand out addresses, e.g. DNS names with many records
+    seeds: Vec<String>,
+    bans: Mutex<BanList>,
+    // misbehavior scores by IP, see bans.rs
+    misbehavior: Mutex<HashMap<IpAddr, u32>>,
+}
+
+impl PeerManager {
+    pub fn new(
+        magic: [u8; 4],
+        listen_port: u16,
+        addresses: AddressBook,
+        seeds: Vec<String>,
+        bans: BanList,
+    ) -> Self {
+        PeerManager {
+            magic,
+            nonce: rand::random(),
+            listen_port,
+            next_id: AtomicU64::new(1),
+            peers: Mutex::new(HashMap::new()),
+            addresses: Mutex::new(addresses),
+            connecting: Mutex::new(HashSet::new()),
+            seeds,
+            bans: Mutex::new(bans),
+            misbehavior: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn magic(&self) -> [u8; 4] {
+        self.magic
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn next_id(&self) -> PeerId {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn version_message(&self, height: u64) -> VersionMessage {
+        VersionMessage {
+            version: PROTOCOL_VERSION,
+            services: SERVICE_NETWORK | SERVICE_COMPACT_BLOCKS,
+            height,
+            nonce: self.nonce,
+            listen_port: self.listen_port,
+            user_agent: format!("node/{}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+
+    /// Connected peers, oldest connection first.
+    pub fn peers(&self) -> Vec<Arc<Peer>> {
+        let mut peers: Vec<_> = self.peers.lock().unwrap().values().cloned().collect();
+        peers.sort_by_key(|peer| peer.id);
+        peers
+    }
+
+    pub fn peer(&self, id: PeerId) -> Option<Arc<Peer>> {
+        self.peers.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Outbound connections that aren't through their handshake yet.
+    pub fn connecting(&self) -> Vec<SocketAddr> {
+        let mut connecting: Vec<_> = self.connecting.lock().unwrap().iter().copied().collect();
+        connecting.sort();
+        connecting
+    }
+
+    pub fn inbound_count(&self) -> usize {
+        self.count(Direction::Inbound)
+    }
+
+    pub fn outbound_count(&self) -> usize {
+        self.count(Direction::Outbound)
+    }
+
+    fn count(&self, direction: Direction) -> usize {
+        self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|peer| peer.direction == direction)
+            .count()
+    }
+
+    /// Whether a peer is connected from or listening on `address`.
+    pub fn is_connected_to(&self, address: SocketAddr) -> bool {
+        self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .any(|peer| peer.address == address || peer.listen_address() == Some(address))
+    }
+
+    /// Resolves `host:port` names and adds every address they stand for to the book.
+    pub async fn add_addresses(&self, hosts: &[String], source: AddressSource) {
+        for host in hosts {
+            match self.add_host(host, source).await {
+                Ok(resolved) => println!("{} resolved to {} addresses", host, resolved.len()),
+                Err(err) => eprintln!("Could not resolve {}: {}", host, err),
+            }
+        }
+    }
+
+    /// Resolves one `host:port` into the book and returns its addresses.
+    pub async fn add_host(
+        &self,
+        host: &str,
+        source: AddressSource,
+    ) -> std::io::Result<Vec<SocketAddr>> {
+        let resolved: Vec<_> = tokio::net::lookup_host(host).await?.collect();
+        let mut addresses = self.addresses.lock().unwrap();
+        for address in &resolved {
+            addresses.add(*address, source);
+        }
+        Ok(resolved)
+    }
+
+    /// Forgets `address` and closes connections to it; false if there was nothing to
+    /// remove. Inbound peers may connect again, bans keep them out for good.
+    pub fn remove_address(&self, address: SocketAddr) -> bool {
+        let mut removed = self.addresses.lock().unwrap().remove(&address);
+        for peer in self.peers() {
+            if peer.address == address || peer.listen_address() == Some(address) {
+                peer.disconnect("Removed through the API");
+                removed = true;
+            }
+        }
+        removed
+    }
+
+    /// Addresses from the book to share with `peer`.
+    pub fn gossip_addresses(&self, peer: &Peer) -> Vec<SocketAddr> {
+        let mut addresses = self.addresses.lock().unwrap().sample(MAX_ADDRESSES);
+        addresses.retain(|address| Some(*address) != peer.listen_address());
+        addresses
+    }
+
+    /// Adds addresses a peer sent to the book; new ones are passed on if the message was
+    /// small.
+    pub fn learn_addresses(&self, addresses: Vec<SocketAddr>, source: &Peer) {
+        let new: Vec<_> = {
+            let mut book = self.addresses.lock().unwrap();
+            addresses
+                .iter()
+                .copied()
+                .filter(|address| book.add(*address, AddressSource::Gossip))
+                .collect()
+        };
+        if addresses.len() <= ADDR_RELAY_MAX && !new.is_empty() {
+            self.relay_addresses(new, source.id);
+        }
+    }
+
+    fn relay_addresses(&self, addresses: Vec<SocketAddr>, except: PeerId) {
+        let peers = self
+            .peers()
+            .into_iter()
+            .filter(|peer| peer.id != except)
+            .choose_multiple(&mut rand::thread_rng(), ADDR_RELAY_PEERS);
+        for peer in peers {
+            peer.send(Message::Addr(addresses.clone()));
+        }
+    }
+
+    pub fn broadcast(&self, message: &Message, except: Option<PeerId>) {
+        for peer in self.peers() {
+            if Some(peer.id) != except {
+                peer.send(message.clone());
+            }
+        }
+    }
+
+    /// Tells every peer but `source` about a new transaction.
+    pub fn announce(&self, inventory: Inventory, source: Option<PeerId>) {
+        self.broadcast(&Message::Inv(vec![inventory]), source);
+    }
+
+    /// Pushes a new block to every peer but `source` that supports compact blocks, and
+    /// announces it to the others.
+    pub fn announce_block(&self, block: &Block, source: Option<PeerId>) {
+        let compact = Message::CmpctBlock(CompactBlock::new(block, rand::random()));
+        let inv = Message::Inv(vec![Inventory::Block(block.hash)]);
+        for peer in self.peers() {
+            if Some(peer.id) == source {
+                continue;
+            }
+            if peer.version.services & SERVICE_COMPACT_BLOCKS != 0 {
+                peer.send(compact.clone());
+            } else {
+                peer.send(inv.clone());
+            }
+        }
+    }
+
+    /// Raises the misbehavior score of the IP of `peer`; once it reaches `BAN_THRESHOLD`
+    /// the IP is banned.
+    pub fn punish(&self, peer: &Peer, score: u32, reason: &str) {
+        if score == 0 {
+            return;
+        }
+        let ip = peer.address.ip();
+        let total = {
+            let mut misbehavior = self.misbehavior.lock().unwrap();
+            let total = misbehavior.entry(ip).or_default();
+            *total = total.saturating_add(score);
+            *total
+        };
+        eprintln!(
+            "Peer {} misbehaved, score {}: {}",
+            peer.address, total, reason
+        );
+        if total >= BAN_THRESHOLD {
+            self.ban(ip, reason);
+        }
+    }
+
+    /// Misbehavior score of `ip`, kept across its connections.
+    pub fn misbehavior(&self, ip: IpAddr) -> u32 {
+        self.misbehavior
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Bans `ip` and disconnects every peer behind it. The ban replaces its score, which
+    /// starts over once the ban ends.
+    pub fn ban(&self, ip: IpAddr, reason: &str) {
+        self.misbehavior.lock().unwrap().remove(&ip);
+        self.bans.lock().unwrap().ban(ip, unix_now(), reason);
+        println!("Banned {}: {}", ip, reason);
+        for peer in self.peers() {
+            if peer.address.ip() == ip {
+                peer.disconnect(&format!("Banned: {}", reason));
+            }
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.bans.lock().unwrap().is_banned(ip, unix_now())
+    }
+
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans.lock().unwrap().list(unix_now())
+    }
+
+    /// Lifts the ban on `ip`; false if it wasn't banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.bans.lock().unwrap().unban(ip)
+    }
+
+    /// Lifts every ban and returns how many there were.
+    pub fn clear_bans(&self) -> usize {
+        self.bans.lock().unwrap().clear()
+    }
+
+    pub(super) fn register(&self, peer: &Arc<Peer>) -> Result<(), PeerError> {
+        let mut peers = self.peers.lock().unwrap();
+        let duplicate = peers
+            .values()
+            .find(|connected| connected.version.nonce == peer.version.nonce)
+            .cloned();
+
+        if let Some(connected) = duplicate {
+            if !self.keeps(peer) || self.keeps(&connected) {
+                return Err(PeerError::Duplicate);
+            }
+            connected.disconnect("replaced by another connection to the same node");
+            peers.remove(&connected.id);
+        }
+
+        peers.insert(peer.id, peer.clone());
+        Ok(())
+    }
+
+    // Called once a connection passed the handshake
+    pub(super) fn peer_connected(&self, peer: &Peer) {
+        match peer.direction {
+            Direction::Outbound => {
+                self.connecting.lock().unwrap().remove(&peer.address);
+                self.addresses
+                    .lock()
+                    .unwrap()
+                    .mark_success(&peer.address, unix_now());
+                peer.send(Message::GetAddr);
+            }
+            // tell others about a node that accepts connections
+            Direction::Inbound => {
+                let Some(address) = peer.listen_address() else {
+                    return;
+                };
+                let added = self
+                    .addresses
+                    .lock()
+                    .unwrap()
+                    .add(address, AddressSource::Inbound);
+                if added {
+                    self.relay_addresses(vec![address], peer.id);
+                }
+            }
+        }
+    }
+
+    pub(super) fn unregister(&self, peer: &Peer) {
+        self.peers.lock().unwrap().remove(&peer.id);
+    }
+
+    // Which of two connections to the same node both sides keep
+    fn keeps(&self, peer: &Peer) -> bool {
+        let opened_by_us = peer.direction == Direction::Outbound;
+        opened_by_us == (self.nonce < peer.version.nonce)
+    }
+
+    // Reserves an outbound slot for the next address to dial, if a slot is free
+    fn next_outbound(&self) -> Option<SocketAddr> {
+        let mut connecting = self.connecting.lock().unwrap();
+        if self.outbound_count() + connecting.len() >= MAX_OUTBOUND_PEERS {
+            return None;
+        }
+
+        let now = unix_now();
+        let mut addresses = self.addresses.lock().unwrap();
+        let address = addresses.select(
+            |address| {
+                connecting.contains(address)
+                    || self.is_connected_to(*address)
+                    || self.is_banned(address.ip())
+            },
+            now,
+        )?;
+        addresses.mark_attempt(&address, now);
+        connecting.insert(address);
+        Some(address)
+    }
+
+    fn connection_finished(&self, address: SocketAddr, result: &Result<(), PeerError>) {
+        self.connecting.lock().unwrap().remove(&address);
+        let mut addresses = self.addresses.lock().unwrap();
+        match result {
+            // e.g. our own address, gossiped back to us
+            Err(PeerError::SelfConnection) => {
+                addresses.remove(&address);
+            }
+            // the node is up, we are just connected to it already
+            Err(PeerError::Duplicate) => {}
+            _ => addresses.mark_closed(&address),
+        }
+    }
+}
+
+/// Accepts connections from other nodes for as long as the node runs.
+pub async fn accept_peers(state: NodeState, listener: TcpListener) {
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                eprintln!("Failed to accept a peer: {}", err);
+                continue;
+            }
+        };
+
+        if state.p2p.is_banned(address.ip()) {
+            continue;
+        }
+        if state.p2p.inbound_count() >= MAX_INBOUND_PEERS {
+            eprintln!("Refusing {}, too many inbound peers", address);
+            continue;
+        }
+        tokio::spawn(run_connection(
+            state.clone(),
+            stream,
+            address,
+            Direction::Inbound,
+        ));
+    }
+}
+
+/// Keeps the outbound slots filled and the address book saved, for as long as the node runs.
+pub async fn maintain_connections(state: NodeState) {
+    let p2p = &state.p2p;
+    let mut last_seed_query: Option<Instant> = None;
+    let mut check = tokio::time::interval(CONNECTION_CHECK_INTERVAL);
+    loop {
+        check.tick().await;
+        while let Some(address) = p2p.next_outbound() {
+            tokio::spawn(connect_outbound(state.clone(), address));
+        }
+
+        let idle = p2p.outbound_count() == 0 && p2p.connecting.lock().unwrap().is_empty();
+        if idle && last_seed_query.is_none_or(|queried| queried.elapsed() >= SEED_INTERVAL) {
+            last_seed_query = Some(Instant::now());
+            p2p.add_addresses(&p2p.seeds, AddressSource::Seed).await;
+        }
+
+        p2p.addresses.lock().unwrap().save();
+    }
+}
+
+/// Dials `address` right away, outside the outbound slots; false if it is connected or
+/// being dialed already.
+pub fn connect(state: &NodeState, address: SocketAddr) -> bool {
+    let p2p = &state.p2p;
+    {
+        let mut connecting = p2p.connecting.lock().unwrap();
+        if connecting.contains(&address) || p2p.is_connected_to(address) {
+            return false;
+        }
+        connecting.insert(address);
+    }
+    p2p.addresses
+        .lock()
+        .unwrap()
+        .mark_attempt(&address, unix_now());
+    tokio::spawn(connect_outbound(state.clone(), address));
+    true
+}
+
+async fn connect_outbound(state: NodeState, address: SocketAddr) {
+    let result = match dial(address).await {
+        Ok(stream) => run_connection(state.clone(), stream, address, Direction::Outbound).await,
+        Err(err) => {
+            eprintln!("Could not connect to {}: {}", address, err);
+            Err(err)
+        }
+    };
+    state.p2p.connection_finished(address, &result);
+}
+
+async fn dial(address: SocketAddr) -> Result<TcpStream, PeerError> {
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+        .await
+        .map_err(|_| PeerError::Timeout("the connection"))??;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{p2p::addresses::MAX_ENTRIES, simulator::Simulator};
+
+    const SETTLE_TIME: Duration = Duration::from_secs(5);
+
+    #[tokio::test(start_paused = true)]
+    async fn test_misbehavior_outlives_connections_and_bans() {
+        let mut simulator = Simulator::new(2).await;
+        simulator.connect(1, 0);
+        simulator.run_for(SETTLE_TIME).await;
+        let p2p = simulator.state(0).p2p.clone();
+        let first = p2p.peers()[0].clone();
+        let ip = first.address.ip();
+
+        p2p.punish(&first, BAN_THRESHOLD / 2, "Invalid transaction");
+        assert_eq!(p2p.misbehavior(ip), BAN_THRESHOLD / 2);
+
+        // reconnecting keeps the score
+        simulator.partition(&[1]);
+        simulator.heal();
+        simulator.run_for(SETTLE_TIME).await;
+        let second = p2p.peers()[0].clone();
+        assert_ne!(second.id, first.id);
+        assert_eq!(p2p.misbehavior(ip), BAN_THRESHOLD / 2);
+
+        p2p.punish(&second, BAN_THRESHOLD / 2, "Invalid transaction");
+        assert!(p2p.is_banned(ip));
+        simulator.run_for(SETTLE_TIME).await;
+        assert!(p2p.peers().is_empty());
+
+        // and a banned address is refused when it connects again
+        simulator.partition(&[1]);
+        simulator.heal();
+        simulator.run_for(SETTLE_TIME).await;
+        assert!(p2p.peers().is_empty());
+        assert!(simulator.state(1).p2p.peers().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_learned_addresses_are_filtered_capped_and_relayed() {
+        let mut simulator = Simulator::new(3).await;
+        simulator.connect(1, 0);
+        simulator.connect(0, 2);
+        simulator.run_for(SETTLE_TIME).await;
+        let p2p = simulator.state(0).p2p.clone();
+        let source = p2p
+            .peers()
+            .into_iter()
+            .find(|peer| peer.direction == Direction::Inbound)
+            .unwrap();
+        let book = |node: usize| {
+            let p2p = &simulator.state(node).p2p;
+            p2p.addresses.lock().unwrap().sample(2 * MAX_ENTRIES)
+        };
+
+        let announced: SocketAddr = "10.0.1.1:9989".parse().unwrap();
+        let unusable: [SocketAddr; 2] = [
+            "0.0.0.0:9989".parse().unwrap(),
+            "10.0.1.2:0".parse().unwrap(),
+        ];
+        p2p.learn_addresses(vec![announced, unusable[0], unusable[1]], &source);
+        simulator.run_for(SETTLE_TIME).await;
+        assert!(book(0).contains(&announced));
+        assert!(!book(0).iter().any(|address| unusable.contains(address)));
+        // passed on to the other peer, not back to where it came from
+        assert!(book(2).contains(&announced));
+        assert!(!book(1).contains(&announced));
+
+        // a large message is kept but not relayed, and the book stops growing when full
+        let gossip = |batch: u8| -> Vec<SocketAddr> {
+            (0..MAX_ADDRESSES as u16)
+                .map(|idx| {
+                    SocketAddr::new(IpAddr::from([10, batch, (idx >> 8) as u8, idx as u8]), 9989)
+                })
+                .collect()
+        };
+        p2p.learn_addresses(gossip(2), &source);
+        simulator.run_for(SETTLE_TIME).await;
+        assert!(book(0).contains(&gossip(2)[0]));
+        assert!(!book(2).contains(&gossip(2)[0]));
+        for batch in 3..6 {
+            p2p.learn_addresses(gossip(batch), &source);
+        }
+        assert_eq!(book(0).len(), MAX_ENTRIES);
+    }
+}
@@ -0,0 +1,406 @@
+// Messages of the peer-to-peer protocol and how they are framed on the TCP stream.
+//
+// Every message is sent as
+//
+//   magic      4 bytes, identifies the network (`Network::magic`)
+//   command    12 bytes, ASCII name padded with NUL bytes
+//   length     u32, little endian, size of the payload
+//   checksum   first 4 bytes of the double SHA-256 of the payload
+//   payload    the message fields in the canonical encoding (`wallet_crypto::encoding`)
+//
+// Payloads carry no encoding version byte, the protocol version agreed in the handshake
+// covers them. Messages with an unknown command are skipped, so newer nodes can add
+// messages without breaking older ones.
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use blockchain::block::{Block, BlockHeader};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use wallet_crypto::{
+    encoding::{self, Decodable, Encodable, EncodingError, Reader},
+    keys::BlockchainHash,
+    transaction::Transaction,
+};
+
+use crate::p2p::compact::{BlockTransactions, BlockTransactionsRequest, CompactBlock};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+// Oldest protocol version this node still talks to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Capability bit of a node that stores the full chain and serves blocks to its peers.
+pub const SERVICE_NETWORK: u64 = 1;
+/// Capability bit of a node that accepts new blocks as `cmpctblock` (see compact.rs).
+pub const SERVICE_COMPACT_BLOCKS: u64 = 2;
+
+pub const HEADER_SIZE: usize = 4 + 12 + 4 + 4;
+pub const MAX_PAYLOAD_SIZE: usize = 32 * 1024 * 1024;
+pub const MAX_ADDRESSES: usize = 1000;
+
+const COMMAND_SIZE: usize = 12;
+// An IPv6 (or IPv4-mapped) address and a port
+const ADDRESS_SIZE: usize = 16 + 2;
+
+#[derive(Debug, Error)]
+pub enum MessageError {
+    #[error("Connection error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Message for another network, magic {0:02x?}")]
+    WrongMagic([u8; 4]),
+    #[error("Invalid command name")]
+    InvalidCommand,
+    #[error("Unknown command {0}")]
+    UnknownCommand(String),
+    #[error("Payload of {0} bytes is too large")]
+    PayloadTooLarge(usize),
+    #[error("Payload checksum mismatch in {0}")]
+    ChecksumMismatch(String),
+    #[error("Invalid {0} payload: {1}")]
+    InvalidPayload(String, EncodingError),
+}
+
+/// Sent first by both sides of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionMessage {
+    pub version: u32,
+    pub services: u64,
+    // height of the sender's chain tip
+    pub height: u64,
+    // random per node, so a node notices when it connected to itself
+    pub nonce: u64,
+    // port the sender accepts connections on, 0 if it doesn't
+    pub listen_port: u16,
+    pub user_agent: String,
+}
+
+/// A block or transaction announced with `inv` or asked for with `getdata`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Inventory {
+    Transaction(BlockchainHash),
+    Block(BlockchainHash),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Version(VersionMessage),
+    Verack,
+    Ping(u64),
+    Pong(u64),
+    Inv(Vec<Inventory>),
+    GetData(Vec<Inventory>),
+    Block(Block),
+    Tx(Transaction),
+    GetHeaders { from: u64, count: u64 },
+    Headers(Vec<BlockHeader>),
+    GetAddr,
+    Addr(Vec<SocketAddr>),
+    CmpctBlock(CompactBlock),
+    GetBlockTxn(BlockTransactionsRequest),
+    BlockTxn(BlockTransactions),
+}
+
+impl Message {
+    pub fn command(&self) -> &'static str {
+        match self {
+            Message::Version(_) => "version",
+            Message::Verack => "verack",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::Inv(_) => "inv",
+            Message::GetData(_) => "getdata",
+            Message::Block(_) => "block",
+            Message::Tx(_) => "tx",
+            Message::GetHeaders { .. } => "getheaders",
+            Message::Headers(_) => "headers",
+            Message::GetAddr => "getaddr",
+            Message::Addr(_) => "addr",
+            Message::CmpctBlock(_) => "cmpctblock",
+            Message::GetBlockTxn(_) => "getblocktxn",
+            Message::BlockTxn(_) => "blocktxn",
+        }
+    }
+
+    /// The message with its header, ready to be written to a peer.
+    pub fn to_frame(&self, magic: [u8; 4]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        self.encode_payload(&mut payload);
+
+        let mut command = [0u8; COMMAND_SIZE];
+        command[..self.command().len()].copy_from_slice(self.command().as_bytes());
+
+        let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&magic);
+        frame.extend_from_slice(&command);
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&checksum(&payload));
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        match self {
+            Message::Version(version) => version.encode(out),
+            Message::Verack | Message::GetAddr => {}
+            Message::Ping(nonce) | Message::Pong(nonce) => nonce.encode(out),
+            Message::Inv(items) | Message::GetData(items) => items.encode(out),
+            Message::Block(block) => block.encode(out),
+            Message::Tx(tx) => tx.encode(out),
+            Message::GetHeaders { from, count } => {
+                from.encode(out);
+                count.encode(out);
+            }
+            Message::Headers(headers) => headers.encode(out),
+            Message::Addr(addresses) => {
+                encoding::write_varint(out, addresses.len() as u64);
+                for address in addresses {
+                    encode_address(address, out);
+                }
+            }
+            Message::CmpctBlock(compact) => compact.encode(out),
+            Message::GetBlockTxn(request) => request.encode(out),
+            Message::BlockTxn(transactions) => transactions.encode(out),
+        }
+    }
+
+    fn decode_payload(command: &str, payload: &[u8]) -> Result<Self, MessageError> {
+        let mut reader = Reader::new(payload);
+        let message = match command {
+            "version" => VersionMessage::decode(&mut reader).map(Message::Version),
+            "verack" => Ok(Message::Verack),
+            "ping" => reader.read_u64().map(Message::Ping),
+            "pong" => reader.read_u64().map(Message::Pong),
+            "inv" => Vec::decode(&mut reader).map(Message::Inv),
+            "getdata" => Vec::decode(&mut reader).map(Message::GetData),
+            "block" => Block::decode(&mut reader).map(Message::Block),
+            "tx" => Transaction::decode(&mut reader).map(Message::Tx),
+            "getheaders" => reader.read_u64().and_then(|from| {
+                Ok(Message::GetHeaders {
+                    from,
+                    count: reader.read_u64()?,
+                })
+            }),
+            "headers" => Vec::decode(&mut reader).map(Message::Headers),
+            "getaddr" => Ok(Message::GetAddr),
+            "addr" => decode_addresses(&mut reader).map(Message::Addr),
+            "cmpctblock" => CompactBlock::decode(&mut reader).map(Message::CmpctBlock),
+            "getblocktxn" => {
+                BlockTransactionsRequest::decode(&mut reader).map(Message::GetBlockTxn)
+            }
+            "blocktxn" => BlockTransactions::decode(&mut reader).map(Message::BlockTxn),
+            unknown => return Err(MessageError::UnknownCommand(unknown.to_string())),
+        };
+
+        message
+            .and_then(|message| reader.finish().map(|_| message))
+            .map_err(|err| MessageError::InvalidPayload(command.to_string(), err))
+    }
+}
+
+/// Reads the next message from `reader`, with the number of bytes it took on the wire.
+/// After an `UnknownCommand` error the stream is still at a message boundary.
+pub async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    magic: [u8; 4],
+) -> Result<(Message, usize), MessageError> {
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+
+    let frame_magic: [u8; 4] = header[..4].try_into().expect("Header has a magic");
+    if frame_magic != magic {
+        return Err(MessageError::WrongMagic(frame_magic));
+    }
+    let command = parse_command(&header[4..16])?;
+    let length = u32::from_le_bytes(header[16..20].try_into().expect("Header has a length"));
+    let length = length as usize;
+    if length > MAX_PAYLOAD_SIZE {
+        return Err(MessageError::PayloadTooLarge(length));
+    }
+
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload).await?;
+    if checksum(&payload) != header[20..24] {
+        return Err(MessageError::ChecksumMismatch(command));
+    }
+
+    let message = Message::decode_payload(&command, &payload)?;
+    Ok((message, HEADER_SIZE + length))
+}
+
+// ASCII letters followed only by NUL padding
+fn parse_command(bytes: &[u8]) -> Result<String, MessageError> {
+    let len = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    let (name, padding) = bytes.split_at(len);
+    if name.is_empty()
+        || !name.iter().all(u8::is_ascii_alphanumeric)
+        || padding.iter().any(|&byte| byte != 0)
+    {
+        return Err(MessageError::InvalidCommand);
+    }
+
+    Ok(String::from_utf8_lossy(name).into_owned())
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let hash = Sha256::digest(Sha256::digest(payload));
+    hash[..4].try_into().expect("SHA-256 is 32 bytes")
+}
+
+impl Encodable for VersionMessage {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.version.encode(out);
+        self.services.encode(out);
+        self.height.encode(out);
+        self.nonce.encode(out);
+        out.extend_from_slice(&self.listen_port.to_le_bytes());
+        encoding::write_var_bytes(out, self.user_agent.as_bytes());
+    }
+}
+
+impl Decodable for VersionMessage {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        Ok(VersionMessage {
+            version: reader.read_u32()?,
+            services: reader.read_u64()?,
+            height: reader.read_u64()?,
+            nonce: reader.read_u64()?,
+            listen_port: reader.read_u16()?,
+            user_agent: String::from_utf8(reader.read_var_bytes()?.to_vec())
+                .map_err(|_| EncodingError::InvalidValue("User agent is not UTF-8".to_string()))?,
+        })
+    }
+}
+
+const INV_TRANSACTION: u32 = 1;
+const INV_BLOCK: u32 = 2;
+
+impl Encodable for Inventory {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let (kind, hash) = match self {
+            Inventory::Transaction(hash) => (INV_TRANSACTION, hash),
+            Inventory::Block(hash) => (INV_BLOCK, hash),
+        };
+        kind.encode(out);
+        hash.encode(out);
+    }
+}
+
+impl Decodable for Inventory {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        let kind = reader.read_u32()?;
+        let hash = BlockchainHash::decode(reader)?;
+        match kind {
+            INV_TRANSACTION => Ok(Inventory::Transaction(hash)),
+            INV_BLOCK => Ok(Inventory::Block(hash)),
+            other => Err(EncodingError::InvalidValue(format!(
+                "Unknown inventory type {}",
+                other
+            ))),
+        }
+    }
+}
+
+// IPv4 addresses are sent IPv4-mapped, so every address takes the same space
+fn encode_address(address: &SocketAddr, out: &mut Vec<u8>) {
+    let ip = match address.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    out.extend_from_slice(&ip.octets());
+    out.extend_from_slice(&address.port().to_le_bytes());
+}
+
+fn decode_addresses(reader: &mut Reader<'_>) -> Result<Vec<SocketAddr>, EncodingError> {
+    let count = reader.read_length(ADDRESS_SIZE)?;
+    if count > MAX_ADDRESSES {
+        return Err(EncodingError::LengthTooLarge(count as u64));
+    }
+
+    (0..count)
+        .map(|_| {
+            let ip = Ipv6Addr::from(reader.read_array::<16>()?);
+            let port = reader.read_u16()?;
+            let ip = ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4);
+            Ok(SocketAddr::new(ip, port))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAGIC: [u8; 4] = [0xb1, 0x0c, 0xc4, 0x9f];
+
+    #[tokio::test]
+    async fn test_framing() {
+        let messages = vec![
+            Message::Version(VersionMessage {
+                version: PROTOCOL_VERSION,
+                services: SERVICE_NETWORK,
+                height: 42,
+                nonce: 7,
+                listen_port: 9989,
+                user_agent: "node/0.1.0".to_string(),
+            }),
+            Message::Verack,
+            Message::Ping(1),
+            Message::Inv(vec![
+                Inventory::Block(BlockchainHash::new([1; 32])),
+                Inventory::Transaction(BlockchainHash::new([2; 32])),
+            ]),
+            Message::GetHeaders {
+                from: 10,
+                count: 2000,
+            },
+            Message::Block(Block::genesis()),
+            Message::Addr(vec![
+                "127.0.0.1:9989".parse().unwrap(),
+                "[2001:db8::1]:9989".parse().unwrap(),
+            ]),
+        ];
+
+        let stream: Vec<u8> = messages.iter().flat_map(|m| m.to_frame(MAGIC)).collect();
+        let mut reader = stream.as_slice();
+        for message in &messages {
+            let (read, size) = read_message(&mut reader, MAGIC).await.unwrap();
+            assert_eq!(&read, message);
+            assert_eq!(size, message.to_frame(MAGIC).len());
+        }
+
+        // other networks and corrupted payloads are rejected
+        let frame = Message::Ping(1).to_frame(MAGIC);
+        let mut other = [0u8; 4];
+        other.copy_from_slice(&frame[..4]);
+        other[3] ^= 1;
+        assert!(matches!(
+            read_message(&mut frame.as_slice(), other).await,
+            Err(MessageError::WrongMagic(_))
+        ));
+
+        let mut corrupted = frame.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            read_message(&mut corrupted.as_slice(), MAGIC).await,
+            Err(MessageError::ChecksumMismatch(_))
+        ));
+
+        // an unknown command is skipped whole, the next message is still readable
+        let mut unknown = frame.clone();
+        unknown[4..8].copy_from_slice(b"pang");
+        unknown.extend(Message::Verack.to_frame(MAGIC));
+        let mut reader = unknown.as_slice();
+        assert!(matches!(
+            read_message(&mut reader, MAGIC).await,
+            Err(MessageError::UnknownCommand(_))
+        ));
+        assert_eq!(
+            read_message(&mut reader, MAGIC).await.unwrap().0,
+            Message::Verack
+        );
+    }
+}
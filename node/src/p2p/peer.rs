@@ -0,0 +1,564 @@
+// One connection to another node, over TCP or, in the simulator, an in-memory stream.
+//
+// Both sides open with a `version` message and confirm the other's with a `verack`; nothing
+// else is accepted before that. Outgoing messages are queued and written by a separate
+// task, which also pings the peer every `PING_INTERVAL` and drops it when a ping stays
+// unanswered for `PING_TIMEOUT`. Incoming messages are handled one at a time, in the order
+// they arrive (see handler.rs).
+//
+// Sync asks a peer for headers and blocks through `request_headers` and `request_blocks`;
+// the answers are delivered to the waiting request instead of being handled as relayed data.
+// A compact block missing transactions waits on the peer the same way for its `blocktxn`.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use blockchain::block::{Block, BlockHeader};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ErrorKind},
+    sync::{Notify, mpsc, oneshot},
+};
+use wallet_crypto::keys::BlockchainHash;
+
+use crate::{
+    api::types::NodeState,
+    p2p::{
+        addresses::unix_now,
+        compact::{BlockTransactionsRequest, PartialBlock},
+        handler::handle_message,
+        manager::PeerManager,
+        message::{
+            Inventory, MIN_PROTOCOL_VERSION, Message, MessageError, VersionMessage, read_message,
+        },
+    },
+};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PING_TIMEOUT: Duration = Duration::from_secs(60);
+// Messages waiting to be written; a peer that falls this far behind is disconnected
+const SEND_QUEUE_SIZE: usize = 1000;
+
+pub type PeerId = u64;
+
+#[derive(Debug, Error)]
+pub enum PeerError {
+    #[error(transparent)]
+    Message(#[from] MessageError),
+    #[error("Connection error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Handshake failed: {0}")]
+    Handshake(String),
+    #[error("Connected to ourselves")]
+    SelfConnection,
+    #[error("Already connected to this node")]
+    Duplicate,
+    #[error("Address is banned")]
+    Banned,
+    #[error("Protocol violation: {0}")]
+    Protocol(String),
+    #[error("Timed out waiting for {0}")]
+    Timeout(&'static str),
+    #[error("{0}")]
+    Disconnected(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// What is known about an open connection, for the peer list.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub user_agent: String,
+    pub best_height: u64,
+    // unix seconds
+    pub connected_since: u64,
+    pub last_seen: u64,
+    // round trip of the last answered ping
+    pub latency_ms: Option<f64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub misbehavior: u32,
+}
+
+pub struct Peer {
+    pub id: PeerId,
+    pub address: SocketAddr,
+    pub direction: Direction,
+    // what the peer sent in its handshake
+    pub version: VersionMessage,
+    best_height: AtomicU64,
+    connected_since: u64,
+    // unix seconds of the last message received
+    last_seen: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    latency: Mutex<Option<Duration>>,
+    outbox: mpsc::Sender<Message>,
+    disconnect: Notify,
+    disconnect_reason: Mutex<Option<String>>,
+    // nonce and send time of the ping waiting for a pong
+    ping: Mutex<Option<(u64, Instant)>>,
+    requests: Mutex<PendingRequests>,
+}
+
+#[derive(Default)]
+struct PendingRequests {
+    // height of the first header asked for
+    headers: Option<(u64, oneshot::Sender<Vec<BlockHeader>>)>,
+    blocks: Option<BlockRequest>,
+    block_transactions: Option<PartialBlock>,
+}
+
+struct BlockRequest {
+    hashes: Vec<BlockchainHash>,
+    received: HashMap<BlockchainHash, Block>,
+    done: oneshot::Sender<Vec<Block>>,
+}
+
+impl Peer {
+    fn new(
+        id: PeerId,
+        address: SocketAddr,
+        direction: Direction,
+        version: VersionMessage,
+        outbox: mpsc::Sender<Message>,
+    ) -> Self {
+        Peer {
+            id,
+            address,
+            direction,
+            best_height: AtomicU64::new(version.height),
+            connected_since: unix_now(),
+            last_seen: AtomicU64::new(unix_now()),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            latency: Mutex::new(None),
+            version,
+            outbox,
+            disconnect: Notify::new(),
+            disconnect_reason: Mutex::new(None),
+            ping: Mutex::new(None),
+            requests: Mutex::new(PendingRequests::default()),
+        }
+    }
+
+    /// Address the peer accepts connections on, if it does.
+    pub fn listen_address(&self) -> Option<SocketAddr> {
+        (self.version.listen_port != 0)
+            .then(|| SocketAddr::new(self.address.ip(), self.version.listen_port))
+    }
+
+    /// Highest block the peer is known to have.
+    pub fn best_height(&self) -> u64 {
+        self.best_height.load(Ordering::Relaxed)
+    }
+
+    pub fn update_best_height(&self, height: u64) {
+        self.best_height.fetch_max(height, Ordering::Relaxed);
+    }
+
+    /// `misbehavior` is the score of the peer's IP, which the manager keeps.
+    pub fn connection_info(&self, misbehavior: u32) -> ConnectionInfo {
+        ConnectionInfo {
+            user_agent: self.version.user_agent.clone(),
+            best_height: self.best_height(),
+            connected_since: self.connected_since,
+            last_seen: self.last_seen.load(Ordering::Relaxed),
+            latency_ms: self
+                .latency
+                .lock()
+                .unwrap()
+                .map(|latency| latency.as_secs_f64() * 1000.0),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            misbehavior,
+        }
+    }
+
+    /// Queues `message` for the peer without waiting.
+    pub fn send(&self, message: Message) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.outbox.try_send(message) {
+            self.disconnect("send queue is full");
+        }
+    }
+
+    /// Closes the connection; the first reason given is the one reported.
+    pub fn disconnect(&self, reason: &str) {
+        self.disconnect_reason
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| reason.to_string());
+        self.disconnect.notify_one();
+    }
+
+    async fn disconnected(&self) -> String {
+        self.disconnect.notified().await;
+        self.disconnect_reason
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_default()
+    }
+
+    // The next ping to send, none while one is still unanswered
+    fn next_ping(&self) -> Result<Option<Message>, PeerError> {
+        let mut ping = self.ping.lock().unwrap();
+        match *ping {
+            Some((_, sent)) if sent.elapsed() > PING_TIMEOUT => Err(PeerError::Timeout("pong")),
+            Some(_) => Ok(None),
+            None => {
+                let nonce = rand::random();
+                *ping = Some((nonce, Instant::now()));
+                Ok(Some(Message::Ping(nonce)))
+            }
+        }
+    }
+
+    pub fn pong(&self, nonce: u64) {
+        let mut ping = self.ping.lock().unwrap();
+        if let Some((sent_nonce, sent)) = *ping
+            && sent_nonce == nonce
+        {
+            *self.latency.lock().unwrap() = Some(sent.elapsed());
+            *ping = None;
+        }
+    }
+
+    /// Asks for up to `count` headers from height `from` on. Fails if the connection closes
+    /// first; how long to wait is up to the caller.
+    pub async fn request_headers(
+        &self,
+        from: u64,
+        count: u64,
+    ) -> Result<Vec<BlockHeader>, PeerError> {
+        let (done, headers) = oneshot::channel();
+        self.requests.lock().unwrap().headers = Some((from, done));
+        self.send(Message::GetHeaders { from, count });
+
+        headers.await.map_err(|_| {
+            PeerError::Disconnected("Connection closed while waiting for headers".to_string())
+        })
+    }
+
+    /// Headers sent in answer to `request_headers`; gives them back if none were asked for.
+    pub fn deliver_headers(&self, headers: Vec<BlockHeader>) -> Option<Vec<BlockHeader>> {
+        let mut requests = self.requests.lock().unwrap();
+        let expected = match (&requests.headers, headers.first()) {
+            (Some(_), None) => true,
+            (Some((from, _)), Some(first)) => first.height == *from,
+            (None, _) => false,
+        };
+        if !expected {
+            return Some(headers);
+        }
+
+        let (_, done) = requests.headers.take().expect("Checked above");
+        let _ = done.send(headers);
+        None
+    }
+
+    /// Asks for the blocks `hashes`, returned in the same order.
+    pub async fn request_blocks(
+        &self,
+        hashes: Vec<BlockchainHash>,
+    ) -> Result<Vec<Block>, PeerError> {
+        let (done, blocks) = oneshot::channel();
+        let inventory = hashes.iter().copied().map(Inventory::Block).collect();
+        self.requests.lock().unwrap().blocks = Some(BlockRequest {
+            hashes,
+            received: HashMap::new(),
+            done,
+        });
+        self.send(Message::GetData(inventory));
+
+        blocks.await.map_err(|_| {
+            PeerError::Disconnected("Connection closed while waiting for blocks".to_string())
+        })
+    }
+
+    /// Asks for the transactions `partial` is missing; the block waits here until they
+    /// arrive or another compact block replaces it.
+    pub fn request_block_transactions(&self, partial: PartialBlock) {
+        let request = BlockTransactionsRequest {
+            block_hash: partial.hash(),
+            indexes: partial.missing(),
+        };
+        self.requests.lock().unwrap().block_transactions = Some(partial);
+        self.send(Message::GetBlockTxn(request));
+    }
+
+    /// The block waiting for the transactions of `block_hash`, if there is one.
+    pub fn deliver_block_transactions(&self, block_hash: &BlockchainHash) -> Option<PartialBlock> {
+        let mut requests = self.requests.lock().unwrap();
+        match &requests.block_transactions {
+            Some(partial) if partial.hash() == *block_hash => requests.block_transactions.take(),
+            _ => None,
+        }
+    }
+
+    /// A block sent in answer to `request_blocks`; gives it back if it wasn't asked for.
+    pub fn deliver_block(&self, block: Block) -> Option<Block> {
+        let mut requests = self.requests.lock().unwrap();
+        let Some(request) = requests.blocks.as_mut() else {
+            return Some(block);
+        };
+        if !request.hashes.contains(&block.hash) {
+            return Some(block);
+        }
+
+        request.received.insert(block.hash, block);
+        if request.received.len() == request.hashes.len() {
+            let mut request = requests.blocks.take().expect("Checked above");
+            let blocks = request
+                .hashes
+                .iter()
+                .map(|hash| request.received.remove(hash).expect("All blocks received"))
+                .collect();
+            let _ = request.done.send(blocks);
+        }
+        None
+    }
+}
+
+/// Runs a connection from handshake to close, logging why it ended.
+pub async fn run_connection<S>(
+    state: NodeState,
+    stream: S,
+    address: SocketAddr,
+    direction: Direction,
+) -> Result<(), PeerError>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let result = connection(&state, stream, address, direction).await;
+    match &result {
+        Ok(()) => println!("Peer {} closed the connection", address),
+        Err(err) => eprintln!("Disconnected from {}: {}", address, err),
+    }
+    result
+}
+
+async fn connection<S>(
+    state: &NodeState,
+    stream: S,
+    address: SocketAddr,
+    direction: Direction,
+) -> Result<(), PeerError>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let p2p = &state.p2p;
+    if p2p.is_banned(address.ip()) {
+        return Err(PeerError::Banned);
+    }
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    let height = state.blockchain.read().await.tip().height;
+    let version = Message::Version(p2p.version_message(height));
+    writer.write_all(&version.to_frame(p2p.magic())).await?;
+    let version = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut reader, &mut writer, p2p))
+        .await
+        .map_err(|_| PeerError::Timeout("the handshake"))??;
+
+    let (outbox, queue) = mpsc::channel(SEND_QUEUE_SIZE);
+    let peer = Arc::new(Peer::new(
+        p2p.next_id(),
+        address,
+        direction,
+        version,
+        outbox,
+    ));
+    p2p.register(&peer)?;
+    p2p.peer_connected(&peer);
+    println!(
+        "Connected to {} ({:?}, {}, height {})",
+        address, direction, peer.version.user_agent, peer.version.height
+    );
+    if peer.best_height() > height {
+        state.sync.wake();
+    }
+
+    let writer_task = tokio::spawn(write_loop(writer, queue, peer.clone(), p2p.magic()));
+    let result = read_loop(state, &mut reader, &peer).await;
+
+    p2p.unregister(&peer);
+    // waiting requests fail instead of running into their timeout
+    *peer.requests.lock().unwrap() = PendingRequests::default();
+    writer_task.abort();
+    result
+}
+
+// Exchanges version and verack, returns the peer's version
+async fn handshake(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    p2p: &PeerManager,
+) -> Result<VersionMessage, PeerError> {
+    let mut version = None;
+    let mut verack = false;
+    while version.is_none() || !verack {
+        let (message, _) = read_message(reader, p2p.magic()).await?;
+        match message {
+            Message::Version(theirs) if version.is_none() => {
+                if theirs.nonce == p2p.nonce() {
+                    return Err(PeerError::SelfConnection);
+                }
+                if theirs.version < MIN_PROTOCOL_VERSION {
+                    return Err(PeerError::Handshake(format!(
+                        "protocol version {} is too old",
+                        theirs.version
+                    )));
+                }
+                writer
+                    .write_all(&Message::Verack.to_frame(p2p.magic()))
+                    .await?;
+                version = Some(theirs);
+            }
+            Message::Verack if !verack => verack = true,
+            other => {
+                return Err(PeerError::Protocol(format!(
+                    "{} during the handshake",
+                    other.command()
+                )));
+            }
+        }
+    }
+
+    Ok(version.expect("Loop ends with a version"))
+}
+
+async fn read_loop(
+    state: &NodeState,
+    reader: &mut (impl AsyncRead + Unpin),
+    peer: &Arc<Peer>,
+) -> Result<(), PeerError> {
+    let magic = state.p2p.magic();
+    loop {
+        let read = tokio::select! {
+            read = read_message(reader, magic) => read,
+            reason = peer.disconnected() => return Err(PeerError::Disconnected(reason)),
+        };
+
+        let message = match read {
+            Ok((message, size)) => {
+                peer.bytes_received
+                    .fetch_add(size as u64, Ordering::Relaxed);
+                peer.last_seen.store(unix_now(), Ordering::Relaxed);
+                message
+            }
+            // sent by a newer node, it is not for us
+            Err(MessageError::UnknownCommand(_)) => continue,
+            Err(MessageError::Io(err)) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        handle_message(state, peer, message).await?;
+    }
+}
+
+async fn write_loop(
+    mut writer: impl AsyncWrite + Unpin,
+    mut queue: mpsc::Receiver<Message>,
+    peer: Arc<Peer>,
+    magic: [u8; 4],
+) {
+    let mut ping_timer = tokio::time::interval(PING_INTERVAL);
+    loop {
+        let message = tokio::select! {
+            message = queue.recv() => match message {
+                Some(message) => message,
+                None => return,
+            },
+            _ = ping_timer.tick() => match peer.next_ping() {
+                Ok(Some(ping)) => ping,
+                Ok(None) => continue,
+                Err(err) => return peer.disconnect(&err.to_string()),
+            },
+        };
+
+        let frame = message.to_frame(magic);
+        if let Err(err) = writer.write_all(&frame).await {
+            return peer.disconnect(&err.to_string());
+        }
+        peer.bytes_sent
+            .fetch_add(frame.len() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{p2p::message::MessageError, simulator::Simulator};
+
+    use super::*;
+
+    const SETTLE_TIME: Duration = Duration::from_secs(5);
+
+    // Opens an inbound connection to `state` and sends `version` with `magic` over it
+    async fn handshake_with(
+        state: &NodeState,
+        version: VersionMessage,
+        magic: [u8; 4],
+    ) -> Result<(), PeerError> {
+        let (stream, mut remote) = tokio::io::duplex(64 * 1024);
+        let address = "10.0.0.9:50000".parse().unwrap();
+        let connection = tokio::spawn(run_connection(
+            state.clone(),
+            stream,
+            address,
+            Direction::Inbound,
+        ));
+        remote
+            .write_all(&Message::Version(version).to_frame(magic))
+            .await
+            .unwrap();
+        connection.await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake() {
+        let mut simulator = Simulator::new(2).await;
+        simulator.connect(0, 1);
+        simulator.run_for(SETTLE_TIME).await;
+
+        for (node, other) in [(0, 1), (1, 0)] {
+            let peers = simulator.state(node).p2p.peers();
+            assert_eq!(peers.len(), 1);
+            assert_eq!(peers[0].version.nonce, simulator.state(other).p2p.nonce());
+        }
+        let outbound = &simulator.state(0).p2p.peers()[0];
+        assert_eq!(outbound.direction, Direction::Outbound);
+        assert_eq!(outbound.address, "10.0.0.2:9989".parse().unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_rejects_other_networks_and_ourselves() {
+        let simulator = Simulator::new(1).await;
+        let state = simulator.state(0);
+        let mut version = state.p2p.version_message(0);
+
+        // our own nonce coming back
+        let result = handshake_with(state, version.clone(), state.p2p.magic()).await;
+        assert!(matches!(result, Err(PeerError::SelfConnection)));
+
+        version.nonce = state.p2p.nonce().wrapping_add(1);
+        let result = handshake_with(state, version, *b"nope").await;
+        assert!(matches!(
+            result,
+            Err(PeerError::Message(MessageError::WrongMagic(magic))) if magic == *b"nope"
+        ));
+        assert!(state.p2p.peers().is_empty());
+    }
+}
@@ -0,0 +1,89 @@
+// Blocks and transactions new to this node, whether submitted through the API or received
+// from a peer, are validated once and announced to every other peer with an `inv`; peers
+// that don't have them yet ask for them with `getdata`. Peers that support compact blocks
+// get new blocks pushed as `cmpctblock` instead (see p2p/compact.rs).
+//
+// Hashes already seen are acknowledged without being validated or announced again, so
+// announcements don't loop between peers.
+
+use blockchain::{block::Block, blockchain::BlockchainError};
+use wallet_crypto::transaction::Transaction;
+
+use crate::{
+    api::types::NodeState,
+    p2p::{message::Inventory, peer::PeerId},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accepted {
+    Added,
+    AlreadyKnown,
+    // a block beyond our tip, the blocks in between are synced first
+    Ahead,
+}
+
+/// Adds a transaction to the mempool and announces it to every peer but `source`.
+pub async fn accept_transaction(
+    state: &NodeState,
+    tx: Transaction,
+    source: Option<PeerId>,
+) -> Result<Accepted, BlockchainError> {
+    let tx_id = tx.id;
+    if !state.seen.lock().await.insert(tx_id) {
+        return Ok(Accepted::AlreadyKnown);
+    }
+
+    let result = state.blockchain.write().await.add_transaction(tx).await;
+    match result {
+        Ok(_) => {
+            state.p2p.announce(Inventory::Transaction(tx_id), source);
+            Ok(Accepted::Added)
+        }
+        Err(err) => {
+            state.seen.lock().await.remove(&tx_id);
+            Err(err)
+        }
+    }
+}
+
+/// Adds a block that extends our tip and passes it on to every peer but `source`.
+pub async fn accept_block(
+    state: &NodeState,
+    block: Block,
+    source: Option<PeerId>,
+) -> Result<Accepted, BlockchainError> {
+    let hash = block.hash;
+    if !state.seen.lock().await.insert(hash) {
+        return Ok(Accepted::AlreadyKnown);
+    }
+
+    let (result, above_tip) = {
+        let mut blockchain = state.blockchain.write().await;
+        let tip_height = blockchain.tip().height;
+        // blocks beyond our tip mean we are behind, the sync fetches what is missing
+        if block.height > tip_height + 1 {
+            state.seen.lock().await.remove(&hash);
+            state.sync.wake();
+            return Ok(Accepted::Ahead);
+        }
+        let above_tip = block.height > tip_height;
+        (blockchain.add_block(block).await, above_tip)
+    };
+    match result {
+        Ok(block) => {
+            state.p2p.announce_block(&block, source);
+            Ok(Accepted::Added)
+        }
+        Err(err) => {
+            // a valid block with this hash may still arrive, e.g. after a mutated copy
+            state.seen.lock().await.remove(&hash);
+            // one above our tip on another branch, which is now the longer one
+            if let BlockchainError::DoesNotExtendTip(_) = err
+                && above_tip
+            {
+                state.sync.wake();
+            }
+            Err(err)
+        }
+    }
+}
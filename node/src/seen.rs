@@ -0,0 +1,124 @@
+// Hashes of blocks and transactions this node already has or is processing.
+//
+// Relayed data comes back from every peer that got it too, so anything already seen is
+// acknowledged without being validated or relayed again. The set is bounded; the oldest
+// hashes are forgotten first.
+//
+// Removing a hash leaves its entry in the eviction order behind as a tombstone, skipped when
+// it comes up. Entries carry the insertion they belong to, so the tombstone of a hash that was
+// seen again doesn't evict the new sighting.
+
+use std::collections::{HashMap, VecDeque};
+
+use wallet_crypto::keys::BlockchainHash;
+
+pub const SEEN_CAPACITY: usize = 50_000;
+
+pub struct SeenSet {
+    capacity: usize,
+    // by the insertion that added them
+    hashes: HashMap<BlockchainHash, u64>,
+    // oldest first, including tombstones
+    order: VecDeque<(BlockchainHash, u64)>,
+    insertions: u64,
+}
+
+impl SeenSet {
+    pub fn new(capacity: usize) -> Self {
+        SeenSet {
+            capacity,
+            hashes: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            insertions: 0,
+        }
+    }
+
+    /// Marks `hash` as seen; false if it already was.
+    pub fn insert(&mut self, hash: BlockchainHash) -> bool {
+        if self.hashes.contains_key(&hash) {
+            return false;
+        }
+
+        self.insertions += 1;
+        self.hashes.insert(hash, self.insertions);
+        self.order.push_back((hash, self.insertions));
+        while self.hashes.len() > self.capacity {
+            let (oldest, insertion) = self.order.pop_front().expect("Over capacity");
+            if self.hashes.get(&oldest) == Some(&insertion) {
+                self.hashes.remove(&oldest);
+            }
+        }
+
+        // tombstones alone never reach the front when hashes are removed as fast as they
+        // are added, so they are dropped once they take up as much room as live entries
+        if self.order.len() > 2 * self.capacity {
+            let hashes = &self.hashes;
+            self.order
+                .retain(|(seen, insertion)| hashes.get(seen) == Some(insertion));
+        }
+
+        true
+    }
+
+    pub fn contains(&self, hash: &BlockchainHash) -> bool {
+        self.hashes.contains_key(hash)
+    }
+
+    /// Forgets `hash`, e.g. when it failed validation and a valid copy may still arrive.
+    pub fn remove(&mut self, hash: &BlockchainHash) {
+        self.hashes.remove(hash);
+    }
+}
+
+impl Default for SeenSet {
+    fn default() -> Self {
+        SeenSet::new(SEEN_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oldest_hashes_are_forgotten() {
+        let mut seen = SeenSet::new(2);
+        let hashes: Vec<_> = (0..3u8).map(|idx| BlockchainHash::new([idx; 32])).collect();
+
+        assert!(seen.insert(hashes[0]));
+        assert!(!seen.insert(hashes[0]));
+        assert!(seen.insert(hashes[1]));
+        assert!(seen.insert(hashes[2]));
+        assert!(!seen.insert(hashes[2]));
+
+        seen.remove(&hashes[2]);
+        assert!(seen.insert(hashes[2]));
+        // hashes[1] is now the oldest, hashes[0] was already forgotten
+        assert!(seen.insert(hashes[0]));
+        assert!(seen.insert(hashes[1]));
+    }
+
+    #[test]
+    fn test_tombstones_do_not_evict_a_hash_seen_again() {
+        let mut seen = SeenSet::new(2);
+        let hashes: Vec<_> = (0..3u8).map(|idx| BlockchainHash::new([idx; 32])).collect();
+
+        seen.insert(hashes[0]);
+        seen.remove(&hashes[0]);
+        seen.insert(hashes[1]);
+        seen.insert(hashes[0]);
+        // evicts hashes[1]; the tombstone of the first hashes[0] is skipped
+        seen.insert(hashes[2]);
+        assert!(seen.contains(&hashes[0]));
+        assert!(!seen.contains(&hashes[1]));
+        assert!(seen.contains(&hashes[2]));
+
+        // removing as fast as inserting keeps the order bounded
+        for idx in 0..100u8 {
+            let hash = BlockchainHash::new([idx; 32]);
+            seen.insert(hash);
+            seen.remove(&hash);
+        }
+        assert!(seen.order.len() <= 4);
+    }
+}
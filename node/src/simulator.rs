@@ -0,0 +1,413 @@
+// A network of nodes in one process, for tests.
+//
+// Every node is a full `NodeState` (chain, peer manager, sync) on its own temporary
+// storage, and nodes talk through the same connection code as over TCP. Links are pairs of
+// in-memory streams joined by a task that holds every chunk for the link's latency, so
+// messages keep their order but arrive late. Cutting a link closes both ends, as a broken
+// TCP connection would; healing opens a new connection.
+//
+// Tests run on tokio's paused clock (`#[tokio::test(start_paused = true)]`): `run_for`
+// lets the network work for a span of virtual time, which jumps ahead whenever every node
+// is idle, so latencies, pings and sync timeouts behave the same on every run.
+//
+// When both sides of a partition mine, the lighter side switches to the heavier branch once
+// healed (see sync.rs).
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use blockchain::{
+    block::Block,
+    blockchain::{Blockchain, BlockchainError},
+    chain_params::{ChainParams, Network},
+    data::storage::SledStorage,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    sync::{RwLock, mpsc},
+    task::JoinHandle,
+    time::Instant,
+};
+use wallet_crypto::{
+    keys::{BlockchainHash, PublicKeyHash},
+    transaction::Transaction,
+};
+
+use crate::{
+    api::types::NodeState,
+    p2p::{
+        addresses::AddressBook,
+        bans::BanList,
+        manager::PeerManager,
+        peer::{Direction, run_connection},
+    },
+    relay::{self, Accepted},
+    seen::SeenSet,
+    sync::ChainSync,
+};
+
+const P2P_PORT: u16 = 9989;
+// Port the accepting side sees a connection come from
+const INBOUND_PORT: u16 = 50000;
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+pub struct Simulator {
+    nodes: Vec<SimulatedNode>,
+    // by the dialing node and the node it dials
+    links: HashMap<(usize, usize), Link>,
+}
+
+struct SimulatedNode {
+    state: NodeState,
+    address: SocketAddr,
+    data_dir: PathBuf,
+}
+
+struct Link {
+    latency: Arc<Mutex<Duration>>,
+    // none while the link is cut
+    carrier: Option<JoinHandle<()>>,
+}
+
+impl Simulator {
+    /// Starts `count` regtest nodes with nothing connected yet.
+    pub async fn new(count: usize) -> Self {
+        let run = rand::random::<u32>();
+        let mut nodes = Vec::new();
+        for index in 0..count {
+            let data_dir = std::env::temp_dir().join(format!("simulator-{}-{}", run, index));
+            let storage = SledStorage::new(data_dir.to_str().expect("Temp dir is UTF-8")).unwrap();
+            let blockchain = Blockchain::new(storage)
+                .with_params(ChainParams::for_network(Network::Regtest))
+                .init()
+                .await
+                .unwrap();
+            let p2p = PeerManager::new(
+                Network::Regtest.magic(),
+                P2P_PORT,
+                AddressBook::load(data_dir.join("addresses.json")),
+                Vec::new(),
+                BanList::load(data_dir.join("bans.json")),
+            );
+
+            let state = NodeState {
+                blockchain: Arc::new(RwLock::new(blockchain)),
+                p2p: Arc::new(p2p),
+                seen: Arc::new(tokio::sync::Mutex::new(SeenSet::default())),
+                sync: Arc::new(ChainSync::new()),
+            };
+            tokio::spawn(state.sync.clone().run(state.clone()));
+
+            let ip = Ipv4Addr::new(10, 0, 0, index as u8 + 1);
+            nodes.push(SimulatedNode {
+                state,
+                address: SocketAddr::new(IpAddr::V4(ip), P2P_PORT),
+                data_dir,
+            });
+        }
+
+        Simulator {
+            nodes,
+            links: HashMap::new(),
+        }
+    }
+
+    pub fn state(&self, node: usize) -> &NodeState {
+        &self.nodes[node].state
+    }
+
+    /// Links two nodes, `from` dialing `to`, without latency.
+    pub fn connect(&mut self, from: usize, to: usize) {
+        let latency = Arc::new(Mutex::new(Duration::ZERO));
+        let carrier = self.open(from, to, latency.clone());
+        self.links.insert(
+            (from, to),
+            Link {
+                latency,
+                carrier: Some(carrier),
+            },
+        );
+    }
+
+    /// Delays everything sent between `a` and `b` by `latency` from now on.
+    pub fn set_latency(&mut self, a: usize, b: usize, latency: Duration) {
+        let link = self
+            .links
+            .get(&(a, b))
+            .or_else(|| self.links.get(&(b, a)))
+            .expect("Nodes are linked");
+        *link.latency.lock().unwrap() = latency;
+    }
+
+    /// Cuts every link between `group` and the other nodes.
+    pub fn partition(&mut self, group: &[usize]) {
+        for (&(from, to), link) in &mut self.links {
+            if group.contains(&from) != group.contains(&to)
+                && let Some(carrier) = link.carrier.take()
+            {
+                carrier.abort();
+            }
+        }
+    }
+
+    /// Reconnects every link that was cut.
+    pub fn heal(&mut self) {
+        let cut: Vec<_> = self
+            .links
+            .iter()
+            .filter(|(_, link)| link.carrier.is_none())
+            .map(|(&nodes, link)| (nodes, link.latency.clone()))
+            .collect();
+        for ((from, to), latency) in cut {
+            let carrier = self.open(from, to, latency);
+            self.links
+                .get_mut(&(from, to))
+                .expect("Listed above")
+                .carrier = Some(carrier);
+        }
+    }
+
+    /// Lets the network run for `duration` of virtual time.
+    pub async fn run_for(&self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+
+    /// Mines the mempool of `node` into a block and relays it, as `/mine` does.
+    pub async fn mine(&self, node: usize, miner_address: Option<PublicKeyHash>) -> Block {
+        let state = self.state(node);
+        let block = state
+            .blockchain
+            .write()
+            .await
+            .mine_pending_transactions(miner_address)
+            .await
+            .unwrap();
+
+        state.seen.lock().await.insert(block.hash);
+        state.p2p.announce_block(&block, None);
+        block
+    }
+
+    /// Submits `tx` to `node`, as `/transactions` does.
+    pub async fn submit_transaction(
+        &self,
+        node: usize,
+        tx: Transaction,
+    ) -> Result<Accepted, BlockchainError> {
+        relay::accept_transaction(self.state(node), tx, None).await
+    }
+
+    /// Hash of every node's tip, in node order.
+    pub async fn tips(&self) -> Vec<BlockchainHash> {
+        let mut tips = Vec::new();
+        for node in &self.nodes {
+            tips.push(node.state.blockchain.read().await.tip().hash());
+        }
+        tips
+    }
+
+    pub async fn has_transaction(&self, node: usize, id: &BlockchainHash) -> bool {
+        let blockchain = self.state(node).blockchain.read().await;
+        blockchain.mempool_transactions().any(|tx| tx.id == *id)
+    }
+
+    // Starts a connection between the nodes and the task carrying its data
+    fn open(&self, from: usize, to: usize, latency: Arc<Mutex<Duration>>) -> JoinHandle<()> {
+        let (from, to) = (&self.nodes[from], &self.nodes[to]);
+        let (outbound, from_end) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+        let (inbound, to_end) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+
+        tokio::spawn(run_connection(
+            from.state.clone(),
+            outbound,
+            to.address,
+            Direction::Outbound,
+        ));
+        tokio::spawn(run_connection(
+            to.state.clone(),
+            inbound,
+            SocketAddr::new(from.address.ip(), INBOUND_PORT),
+            Direction::Inbound,
+        ));
+        tokio::spawn(carry(from_end, to_end, latency))
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        for node in &self.nodes {
+            let _ = std::fs::remove_dir_all(&node.data_dir);
+        }
+    }
+}
+
+// Carries data both ways until either end closes, then closes the other
+async fn carry(a: DuplexStream, b: DuplexStream, latency: Arc<Mutex<Duration>>) {
+    let (a_reader, a_writer) = tokio::io::split(a);
+    let (b_reader, b_writer) = tokio::io::split(b);
+    tokio::select! {
+        _ = forward(a_reader, b_writer, latency.clone()) => {}
+        _ = forward(b_reader, a_writer, latency) => {}
+    }
+}
+
+async fn forward(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    latency: Arc<Mutex<Duration>>,
+) {
+    let (in_flight, mut arriving) = mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+    let send = async move {
+        let mut buffer = vec![0; STREAM_BUFFER_SIZE];
+        while let Ok(size @ 1..) = reader.read(&mut buffer).await {
+            let arrival = Instant::now() + *latency.lock().unwrap();
+            let _ = in_flight.send((arrival, buffer[..size].to_vec()));
+        }
+    };
+    // data still in flight when the sender closes is delivered first
+    let deliver = async move {
+        while let Some((arrival, data)) = arriving.recv().await {
+            tokio::time::sleep_until(arrival).await;
+            if writer.write_all(&data).await.is_err() {
+                return;
+            }
+        }
+    };
+    tokio::join!(send, deliver);
+}
+
+#[cfg(test)]
+mod tests {
+    use wallet_crypto::{
+        keys::KeyPair,
+        scripts::Script,
+        timelock::SEQUENCE_FINAL,
+        transaction::{DraftTransaction, TxOut, UnsignedTxIn},
+    };
+
+    use super::*;
+
+    const SETTLE_TIME: Duration = Duration::from_secs(5);
+
+    // Spends the coinbase of `block`, which pays to `key`
+    fn spend_coinbase(block: &Block, key: &KeyPair) -> Transaction {
+        let coinbase = &block.transactions[0];
+        let spent_output = coinbase.outputs[0].clone();
+        let draft = DraftTransaction::new(
+            vec![UnsignedTxIn {
+                prev_tx_id: coinbase.id,
+                prev_out_idx: 0,
+                sequence: SEQUENCE_FINAL,
+            }],
+            vec![TxOut {
+                value: spent_output.value - 1,
+                script_pubkey: Script::PayToPublicKeyHash {
+                    pub_key_hash: KeyPair::generate().public_key.to_address(),
+                },
+            }],
+        );
+        draft.sign(key, &[spent_output]).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_blocks_and_transactions_propagate() {
+        // a line, so everything has to be relayed
+        let mut simulator = Simulator::new(4).await;
+        for node in 0..3 {
+            simulator.connect(node, node + 1);
+            simulator.set_latency(node, node + 1, Duration::from_millis(200));
+        }
+        simulator.run_for(SETTLE_TIME).await;
+
+        let key = KeyPair::generate();
+        let block = simulator.mine(0, Some(key.public_key.to_address())).await;
+        simulator.run_for(SETTLE_TIME).await;
+        assert_eq!(simulator.tips().await, vec![block.hash; 4]);
+
+        let tx = spend_coinbase(&block, &key);
+        assert_eq!(
+            simulator.submit_transaction(3, tx.clone()).await.unwrap(),
+            Accepted::Added
+        );
+        simulator.run_for(SETTLE_TIME).await;
+        for node in 0..4 {
+            assert!(simulator.has_transaction(node, &tx.id).await);
+        }
+
+        // mined on the other end of the line, rebuilt from every mempool
+        let block = simulator.mine(0, None).await;
+        assert!(block.transactions.contains(&tx));
+        simulator.run_for(SETTLE_TIME).await;
+        assert_eq!(simulator.tips().await, vec![block.hash; 4]);
+        assert!(!simulator.has_transaction(3, &tx.id).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partitioned_node_catches_up() {
+        let mut simulator = Simulator::new(3).await;
+        simulator.connect(0, 1);
+        simulator.connect(0, 2);
+        simulator.connect(1, 2);
+        simulator.run_for(SETTLE_TIME).await;
+        let genesis = simulator.tips().await[2];
+
+        simulator.partition(&[2]);
+        let mut tip = genesis;
+        for _ in 0..3 {
+            tip = simulator.mine(0, None).await.hash;
+            simulator.run_for(Duration::from_secs(1)).await;
+        }
+        assert_eq!(simulator.tips().await, vec![tip, tip, genesis]);
+
+        simulator.heal();
+        simulator.run_for(SETTLE_TIME).await;
+        assert_eq!(simulator.tips().await, vec![tip; 3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_healed_partition_converges_on_the_heavier_branch() {
+        let mut simulator = Simulator::new(4).await;
+        for (from, to) in [(0, 1), (2, 3), (1, 2)] {
+            simulator.connect(from, to);
+            simulator.set_latency(from, to, Duration::from_millis(100));
+        }
+        simulator.run_for(SETTLE_TIME).await;
+        let shared = simulator.mine(0, None).await.hash;
+        simulator.run_for(SETTLE_TIME).await;
+        assert_eq!(simulator.tips().await, vec![shared; 4]);
+
+        // the lighter side has a pending spend of a coinbase from its own branch
+        simulator.partition(&[0, 1]);
+        let key = KeyPair::generate();
+        let light = simulator.mine(0, Some(key.public_key.to_address())).await;
+        let tx = spend_coinbase(&light, &key);
+        simulator.submit_transaction(0, tx.clone()).await.unwrap();
+        let mut heavy = shared;
+        for _ in 0..3 {
+            heavy = simulator.mine(3, None).await.hash;
+            simulator.run_for(Duration::from_secs(1)).await;
+        }
+        simulator.run_for(SETTLE_TIME).await;
+        let tips = simulator.tips().await;
+        assert_eq!(tips, vec![light.hash, light.hash, heavy, heavy]);
+        assert!(simulator.has_transaction(1, &tx.id).await);
+
+        simulator.heal();
+        simulator.run_for(SETTLE_TIME).await;
+        assert_eq!(simulator.tips().await, vec![heavy; 4]);
+        // the coinbase it spent is gone with the branch
+        for node in 0..4 {
+            assert!(!simulator.has_transaction(node, &tx.id).await);
+        }
+        for node in 0..2 {
+            let progress = simulator.state(node).sync.progress().await;
+            assert_eq!(progress.height, 4);
+            assert!(progress.last_error.is_none());
+        }
+    }
+}
@@ -0,0 +1,538 @@
+// Initial block download: catching up with peers that have a longer chain.
+//
+// Sync is headers first. Peers report their height in the handshake and with every block
+// they send, and the round follows the highest peer above us. Its chain is first walked back
+// from our tip, with exponentially growing steps, to a block both chains have. When that is
+// below our tip the peer is on another branch: the blocks it takes to outweigh ours are
+// downloaded from it and handed to `Blockchain::reorganize`, up to `MAX_REORG_DEPTH` blocks
+// deep.
+//
+// The rest of its chain comes a page of `MAX_HEADERS_PER_REQUEST` headers at a time, each
+// page checked (linkage and proof of work) before any of its blocks is downloaded, so only
+// one page is held in memory. The bodies are split into batches and fetched by hash from
+// every peer whose chain covers them, several at once. A peer that does not deliver a batch
+// within `STALL_TIMEOUT` is dropped for the rest of the round and its batch goes to another
+// peer. A page that doesn't extend the chain ends the round.
+//
+// Batches may arrive out of order but are connected in order through
+// `Blockchain::add_block`, the same path relayed blocks take. Blocks are stored as they are
+// added, so an interrupted sync resumes from the stored tip on the next round.
+//
+// Peers that send headers without proof of work, or blocks that fail validation, are
+// punished like relayed invalid data (see p2p/bans.rs), and so are peers that run out of
+// headers below the height they claimed.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use blockchain::{
+    block::{Block, BlockHeader},
+    blockchain::BlockchainError,
+};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::{
+    sync::{Mutex, Notify},
+    task::JoinSet,
+};
+use wallet_crypto::header::{HeaderError, block_work, verify_headers_extend};
+
+use crate::{
+    api::types::NodeState,
+    p2p::{
+        bans::{BAN_THRESHOLD, FALSE_HEIGHT_SCORE, invalid_block_score},
+        peer::{Peer, PeerError},
+    },
+};
+
+// How often peers are checked for a longer chain when nothing wakes the sync earlier
+const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// A batch not delivered within this time is given to another peer
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+pub const MAX_HEADERS_PER_REQUEST: u64 = 2000;
+pub const MAX_BLOCKS_PER_REQUEST: u64 = 100;
+const BLOCKS_PER_BATCH: usize = 32;
+// Batches downloaded ahead of the next one to connect, bounds the blocks held in memory
+const DOWNLOAD_WINDOW: usize = 16;
+// Blocks of ours a branch may replace; the whole branch is held in memory until it's checked
+const MAX_REORG_DEPTH: u64 = 1000;
+
+#[derive(Debug, Error)]
+pub enum SyncError {
+    #[error(transparent)]
+    Blockchain(#[from] BlockchainError),
+    #[error("Peer {0} failed: {1}")]
+    Peer(SocketAddr, PeerError),
+    #[error("Peer {0} sent an invalid header chain: {1}")]
+    InvalidHeaders(SocketAddr, HeaderError),
+    #[error("No peer left to download blocks from at height {0}")]
+    NoPeers(u64),
+    #[error("Peer {0} claimed height {1} but has no headers after {2}")]
+    FalseHeight(SocketAddr, u64, u64),
+    #[error("Peer {0} shares no block with us")]
+    UnrelatedChain(SocketAddr),
+    #[error("Peer {0} is on a branch {1} blocks below our tip")]
+    ReorgTooDeep(SocketAddr, u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPhase {
+    Idle,
+    Headers,
+    Blocks,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncProgress {
+    pub phase: SyncPhase,
+    // the peers being synced from, the header source first
+    pub peers: Vec<SocketAddr>,
+    pub height: u64,
+    pub target_height: u64,
+    pub last_error: Option<String>,
+}
+
+pub struct ChainSync {
+    progress: Mutex<SyncProgress>,
+    wake: Notify,
+}
+
+// A range of headers whose blocks are downloaded with one request
+struct Batch {
+    index: usize,
+    headers: Vec<BlockHeader>,
+}
+
+impl Batch {
+    fn last_height(&self) -> u64 {
+        self.headers.last().expect("Batches are not empty").height
+    }
+}
+
+impl ChainSync {
+    pub fn new() -> Self {
+        ChainSync {
+            progress: Mutex::new(SyncProgress {
+                phase: SyncPhase::Idle,
+                peers: Vec::new(),
+                height: 0,
+                target_height: 0,
+                last_error: None,
+            }),
+            wake: Notify::new(),
+        }
+    }
+
+    pub async fn progress(&self) -> SyncProgress {
+        self.progress.lock().await.clone()
+    }
+
+    /// Starts a sync round now instead of at the next interval, e.g. after a peer sent a
+    /// block beyond our tip.
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Syncs on startup and then every `SYNC_INTERVAL` or when woken, for the node's lifetime.
+    pub async fn run(self: Arc<Self>, state: NodeState) {
+        loop {
+            if let Err(err) = self.sync_once(&state).await {
+                eprintln!("Sync failed: {}", err);
+                self.progress.lock().await.last_error = Some(err.to_string());
+            }
+
+            let mut progress = self.progress.lock().await;
+            progress.phase = SyncPhase::Idle;
+            progress.peers.clear();
+            drop(progress);
+
+            tokio::select! {
+                _ = tokio::time::sleep(SYNC_INTERVAL) => {}
+                _ = self.wake.notified() => {}
+            }
+        }
+    }
+
+    async fn sync_once(&self, state: &NodeState) -> Result<(), SyncError> {
+        let tip = state.blockchain.read().await.tip();
+        self.progress.lock().await.height = tip.height;
+
+        let peers = peers_ahead(state, tip.height);
+        let Some(best_peer) = peers.first() else {
+            return Ok(());
+        };
+        let target_height = best_peer.best_height();
+        println!(
+            "Syncing headers from {} at height {} to {}",
+            best_peer.address, tip.height, target_height
+        );
+        self.set_phase(SyncPhase::Headers, vec![best_peer.address], target_height)
+            .await;
+
+        let fork_point = find_fork_point(state, best_peer, &tip).await?;
+        let reorganized =
+            fork_point.height < tip.height && self.reorganize(state, best_peer, fork_point).await?;
+
+        let mut last = state.blockchain.read().await.tip();
+        while last.height < target_height {
+            self.set_phase(SyncPhase::Headers, vec![best_peer.address], target_height)
+                .await;
+            let page = fetch_headers(best_peer, last.height + 1, MAX_HEADERS_PER_REQUEST).await?;
+            if page.is_empty() {
+                return Err(false_height(state, best_peer, last.height));
+            }
+            verify_headers(state, best_peer, &last, &page)?;
+
+            let addresses = peers.iter().map(|peer| peer.address).collect();
+            self.set_phase(SyncPhase::Blocks, addresses, target_height)
+                .await;
+            last = *page.last().expect("Checked above");
+            self.download_blocks(state, peers.clone(), page).await?;
+        }
+
+        // peers still on the branch we left follow from the new tip
+        if reorganized {
+            let blockchain = state.blockchain.read().await;
+            if let Some(tip) = blockchain.get_block(&blockchain.tip().hash()).await? {
+                state.p2p.announce_block(&tip, None);
+            }
+        }
+
+        self.progress.lock().await.last_error = None;
+        Ok(())
+    }
+
+    // Switches to the branch of `peer` after `fork_point`, downloading as many of its blocks
+    // as it takes to outweigh ours. The rest of the branch follows page by page. Returns
+    // whether it switched.
+    async fn reorganize(
+        &self,
+        state: &NodeState,
+        peer: &Arc<Peer>,
+        fork_point: BlockHeader,
+    ) -> Result<bool, SyncError> {
+        let tip_height = state.blockchain.read().await.tip().height;
+        let depth = tip_height - fork_point.height;
+        if depth > MAX_REORG_DEPTH {
+            return Err(SyncError::ReorgTooDeep(peer.address, depth));
+        }
+        let ours = state
+            .blockchain
+            .read()
+            .await
+            .get_headers(fork_point.height + 1, depth)
+            .await?;
+        let theirs = fetch_headers(peer, fork_point.height + 1, MAX_HEADERS_PER_REQUEST).await?;
+        verify_headers(state, peer, &fork_point, &theirs)?;
+
+        // the fork point is only a lower bound, the branches may share more blocks
+        let shared = ours
+            .iter()
+            .zip(&theirs)
+            .take_while(|(ours, theirs)| ours.hash() == theirs.hash())
+            .count();
+        let our_work: u128 = ours[shared..]
+            .iter()
+            .map(|header| block_work(header.bits))
+            .sum();
+        let mut branch_work = 0;
+        let branch: Vec<_> = theirs[shared..]
+            .iter()
+            .take_while(|header| {
+                let outweighed = branch_work > our_work;
+                branch_work += block_work(header.bits);
+                !outweighed
+            })
+            .cloned()
+            .collect();
+        if branch_work <= our_work {
+            return Ok(false);
+        }
+        println!(
+            "Switching to the branch of {} after height {}",
+            peer.address,
+            branch[0].height - 1
+        );
+
+        let mut blocks = Vec::with_capacity(branch.len());
+        for (index, headers) in branch.chunks(BLOCKS_PER_BATCH).enumerate() {
+            let batch = Batch {
+                index,
+                headers: headers.to_vec(),
+            };
+            let (_, _, result) = download_batch(peer.clone(), batch).await;
+            blocks.extend(result.map_err(|err| SyncError::Peer(peer.address, err))?);
+        }
+
+        let mut blockchain = state.blockchain.write().await;
+        for block in &blocks {
+            state.seen.lock().await.insert(block.hash);
+        }
+        let tip = blockchain.reorganize(blocks).await.inspect_err(|err| {
+            state
+                .p2p
+                .punish(peer, invalid_block_score(err), &err.to_string())
+        })?;
+        self.progress.lock().await.height = tip.height;
+
+        Ok(true)
+    }
+
+    // Downloads the blocks of `headers` from `peers` in parallel and connects them in order
+    async fn download_blocks(
+        &self,
+        state: &NodeState,
+        peers: Vec<Arc<Peer>>,
+        headers: Vec<BlockHeader>,
+    ) -> Result<(), SyncError> {
+        let mut pending: VecDeque<Batch> = headers
+            .chunks(BLOCKS_PER_BATCH)
+            .enumerate()
+            .map(|(index, headers)| Batch {
+                index,
+                headers: headers.to_vec(),
+            })
+            .collect();
+        let batch_count = pending.len();
+
+        let mut idle_peers = peers;
+        let mut downloads = JoinSet::new();
+        let mut downloaded: BTreeMap<usize, (Arc<Peer>, Vec<Block>)> = BTreeMap::new();
+        let mut next_to_connect = 0;
+
+        while next_to_connect < batch_count {
+            // hand out batches within the window to idle peers that have them
+            while pending
+                .front()
+                .is_some_and(|batch| batch.index < next_to_connect + DOWNLOAD_WINDOW)
+            {
+                let batch = pending.pop_front().expect("Checked above");
+                let Some(position) = idle_peers
+                    .iter()
+                    .position(|peer| peer.best_height() >= batch.last_height())
+                else {
+                    pending.push_front(batch);
+                    break;
+                };
+                let peer = idle_peers.swap_remove(position);
+                downloads.spawn(download_batch(peer, batch));
+            }
+
+            let Some(finished) = downloads.join_next().await else {
+                let height = pending.front().map_or(0, |batch| batch.headers[0].height);
+                return Err(SyncError::NoPeers(height));
+            };
+            let (peer, batch, result) = finished.expect("Download tasks don't panic");
+
+            match result {
+                Ok(blocks) => {
+                    downloaded.insert(batch.index, (peer.clone(), blocks));
+                    idle_peers.push(peer);
+                }
+                // the peer is not asked again this round, another one gets the batch
+                Err(err) => {
+                    eprintln!("Dropping {} from this sync round: {}", peer.address, err);
+                    let position = pending
+                        .iter()
+                        .position(|pending| pending.index > batch.index)
+                        .unwrap_or(pending.len());
+                    pending.insert(position, batch);
+                }
+            }
+
+            while let Some((peer, blocks)) = downloaded.remove(&next_to_connect) {
+                self.connect_blocks(state, &peer, blocks).await?;
+                next_to_connect += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn connect_blocks(
+        &self,
+        state: &NodeState,
+        peer: &Peer,
+        blocks: Vec<Block>,
+    ) -> Result<(), SyncError> {
+        let mut blockchain = state.blockchain.write().await;
+        for block in blocks {
+            // a relayed copy may have been added in the meantime
+            if block.height <= blockchain.tip().height {
+                continue;
+            }
+
+            state.seen.lock().await.insert(block.hash);
+            let block = blockchain.add_block(block).await.inspect_err(|err| {
+                state
+                    .p2p
+                    .punish(peer, invalid_block_score(err), &err.to_string())
+            })?;
+            self.progress.lock().await.height = block.height;
+        }
+
+        Ok(())
+    }
+
+    async fn set_phase(&self, phase: SyncPhase, peers: Vec<SocketAddr>, target_height: u64) {
+        let mut progress = self.progress.lock().await;
+        progress.phase = phase;
+        progress.peers = peers;
+        progress.target_height = target_height;
+    }
+}
+
+// Connected peers with a chain above `height`, highest first
+fn peers_ahead(state: &NodeState, height: u64) -> Vec<Arc<Peer>> {
+    let mut ahead: Vec<_> = state
+        .p2p
+        .peers()
+        .into_iter()
+        .filter(|peer| peer.best_height() > height)
+        .collect();
+
+    ahead.sort_by_key(|peer| std::cmp::Reverse(peer.best_height()));
+    ahead
+}
+
+// Our highest header that the chain of `peer` also has, found by stepping back from `tip`
+// further each time; the blocks both chains have may go on above it.
+async fn find_fork_point(
+    state: &NodeState,
+    peer: &Peer,
+    tip: &BlockHeader,
+) -> Result<BlockHeader, SyncError> {
+    let mut candidate = *tip;
+    let mut step = 1;
+    loop {
+        match fetch_headers(peer, candidate.height + 1, 1).await?.first() {
+            Some(next) if next.prev_block_hash == candidate.hash() => return Ok(candidate),
+            Some(_) => {}
+            // its chain is above our tip, it must have a header here
+            None => return Err(false_height(state, peer, candidate.height)),
+        }
+        if candidate.height == 0 {
+            return Err(SyncError::UnrelatedChain(peer.address));
+        }
+
+        let height = candidate.height.saturating_sub(step);
+        step *= 2;
+        candidate = state
+            .blockchain
+            .read()
+            .await
+            .get_headers(height, 1)
+            .await?
+            .pop()
+            .ok_or(BlockchainError::InconsistentStorage)?;
+    }
+}
+
+// Up to `count` headers of the chain of `peer` from height `from`
+async fn fetch_headers(peer: &Peer, from: u64, count: u64) -> Result<Vec<BlockHeader>, SyncError> {
+    tokio::time::timeout(REQUEST_TIMEOUT, peer.request_headers(from, count))
+        .await
+        .unwrap_or(Err(PeerError::Timeout("headers")))
+        .map_err(|err| SyncError::Peer(peer.address, err))
+}
+
+// Checks that the headers of `peer` follow `last`, punishing it for invalid proof of work
+fn verify_headers(
+    state: &NodeState,
+    peer: &Peer,
+    last: &BlockHeader,
+    headers: &[BlockHeader],
+) -> Result<(), SyncError> {
+    verify_headers_extend(last, headers).map_err(|err| {
+        // headers that don't link up may just be another branch
+        if let HeaderError::InvalidProofOfWork(_) | HeaderError::WrongDifficulty { .. } = err {
+            state.p2p.punish(peer, BAN_THRESHOLD, &err.to_string());
+        }
+        SyncError::InvalidHeaders(peer.address, err)
+    })
+}
+
+// Punishes `peer` for running out of headers after `height`, below the height it claimed
+fn false_height(state: &NodeState, peer: &Peer, height: u64) -> SyncError {
+    let err = SyncError::FalseHeight(peer.address, peer.best_height(), height);
+    state.p2p.punish(peer, FALSE_HEIGHT_SCORE, &err.to_string());
+    err
+}
+
+// Fetches the blocks of `batch` from `peer`. Returns everything needed to reassign the batch
+// if it fails.
+async fn download_batch(
+    peer: Arc<Peer>,
+    batch: Batch,
+) -> (Arc<Peer>, Batch, Result<Vec<Block>, PeerError>) {
+    let hashes = batch.headers.iter().map(BlockHeader::hash).collect();
+    let result = tokio::time::timeout(STALL_TIMEOUT, peer.request_blocks(hashes))
+        .await
+        .unwrap_or(Err(PeerError::Timeout("blocks")));
+
+    (peer, batch, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Simulator;
+
+    const SETTLE_TIME: Duration = Duration::from_secs(5);
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sync_downloads_every_page() {
+        let mut simulator = Simulator::new(2).await;
+        let height = MAX_HEADERS_PER_REQUEST + 10;
+        let mut tip = simulator.tips().await[1];
+        for _ in 0..height {
+            tip = simulator.mine(1, None).await.hash;
+        }
+
+        simulator.connect(0, 1);
+        simulator.run_for(SETTLE_TIME).await;
+        assert_eq!(simulator.tips().await, vec![tip; 2]);
+
+        let state = simulator.state(0);
+        let progress = state.sync.progress().await;
+        assert_eq!((progress.phase, progress.height), (SyncPhase::Idle, height));
+        assert!(progress.last_error.is_none());
+        let peer = &state.p2p.peers()[0];
+        assert_eq!(state.p2p.misbehavior(peer.address.ip()), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peer_claiming_a_false_height_is_punished() {
+        let mut simulator = Simulator::new(2).await;
+        let mut tip = simulator.tips().await[1];
+        for _ in 0..3 {
+            tip = simulator.mine(1, None).await.hash;
+        }
+        simulator.connect(0, 1);
+        simulator.run_for(SETTLE_TIME).await;
+        assert_eq!(simulator.tips().await, vec![tip; 2]);
+
+        // as if it had announced blocks it doesn't have
+        let state = simulator.state(0);
+        let peer = state.p2p.peers()[0].clone();
+        peer.update_best_height(50);
+        state.sync.wake();
+        simulator.run_for(SETTLE_TIME).await;
+
+        assert_eq!(state.p2p.misbehavior(peer.address.ip()), FALSE_HEIGHT_SCORE);
+        let progress = state.sync.progress().await;
+        assert_eq!(progress.height, 3);
+        assert!(
+            progress
+                .last_error
+                .is_some_and(|err| err.contains("claimed height 50"))
+        );
+        assert_eq!(simulator.tips().await, vec![tip; 2]);
+    }
+}
@@ -1,7 +1,7 @@
 use ripemd::Ripemd160;
 use sha2::{Digest, Sha256};
 
-use crate::keys::PublicKeyHash;
+use crate::keys::{PublicKeyHash, ScriptHash};
 
 // Parses the scriptSig for k256 P2PKH: [DER_Signature] [Compressed_PublicKey]
 // The sizes can vary, especially for DER signatures.
@@ -22,12 +22,59 @@ pub fn parse_p2pkh_script_sig_k256(script_sig_bytes: &[u8]) -> Result<(Vec<u8>,
     Ok((signature, public_key))
 }
 
+// Encodes a list of byte strings as length-prefixed pushes: [u16 LE length] [bytes] for each item.
+// Used by script signatures that carry more than one element (e.g. multisig).
+pub fn encode_pushes(items: &[&[u8]]) -> Vec<u8> {
+    let mut result = Vec::new();
+    for item in items {
+        result.extend_from_slice(&(item.len() as u16).to_le_bytes());
+        result.extend_from_slice(item);
+    }
+
+    result
+}
+
+// Parses bytes produced by `encode_pushes` back into its items.
+pub fn parse_pushes(mut bytes: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut items = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 2 {
+            return Err("Truncated push length".to_string());
+        }
+        let len = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+        bytes = &bytes[2..];
+
+        if bytes.len() < len {
+            return Err(format!("Push of {} bytes exceeds remaining data", len));
+        }
+        items.push(bytes[..len].to_vec());
+        bytes = &bytes[len..];
+    }
+
+    Ok(items)
+}
+
+/// BIP 340 tagged hash: SHA256(SHA256(tag) || SHA256(tag) || data), with the data still to be
+/// added. Different tags keep hashes of different kinds of data apart.
+pub fn tagged_hash(tag: &[u8]) -> Sha256 {
+    let tag_hash = Sha256::digest(tag);
+    Sha256::new().chain_update(tag_hash).chain_update(tag_hash)
+}
+
 /// Calculates the Bitcoin-style P2PKH hash (RIPEMD160(SHA256(PublicKey))).
 /// Takes the raw public key bytes (e.g., 33 bytes for compressed k256).
 /// Returns the 20-byte PublicKeyHash type.
 pub fn calculate_p2pkh_hash(public_key_bytes: &[u8]) -> PublicKeyHash {
-    let sha256_hash_bytes = Sha256::digest(public_key_bytes);
-    let ripemd160_hash_bytes: [u8; 20] = Ripemd160::digest(sha256_hash_bytes).into();
+    hash160(public_key_bytes).into()
+}
+
+/// Calculates the P2SH hash of a serialized redeem script, using the same
+/// RIPEMD160(SHA256(..)) construction as P2PKH.
+pub fn calculate_script_hash(script_bytes: &[u8]) -> ScriptHash {
+    hash160(script_bytes).into()
+}
 
-    ripemd160_hash_bytes.into()
+fn hash160(data: &[u8]) -> [u8; 20] {
+    let sha256_hash_bytes = Sha256::digest(data);
+    Ripemd160::digest(sha256_hash_bytes).into()
 }
@@ -0,0 +1,370 @@
+// Canonical binary encoding of consensus data, independent of serde and bincode.
+//
+// Wire format, version 1:
+//
+//   integers        fixed width, little endian (u8, u32, u64, u128)
+//   varint          Bitcoin's CompactSize: < 0xfd as one byte, then 0xfd + u16, 0xfe + u32,
+//                   0xff + u64. Only the shortest form is accepted.
+//   var bytes       varint length, then the bytes
+//   list            varint count, then each item
+//   hashes, keys    raw fixed-size bytes (32 byte hashes, 20 byte key/script hashes,
+//                   33 byte compressed SEC1 public keys, 32 byte x-only keys)
+//   script          one tag byte per `Script` variant followed by its fields, see scripts.rs
+//   TxIn            prev_tx_id | prev_out_idx u32 | script_sig var bytes | sequence u32
+//   TxOut           value u64 | script as var bytes
+//   transaction     list of TxIn | list of TxOut | timestamp u128 | lock_time u32
+//   draft / txid    as a transaction, with the script_sig left out of every input
+//   block header    `BLOCK_HEADER_SIZE` (104) bytes, see `header::BlockHeader`: version u32 |
+//                   height u64 | prev_block_hash | merkle_root | timestamp u128 | bits u32 |
+//                   nonce u64
+//   block           header | list of transactions
+//
+// Derived values (transaction ids, block hashes) are not encoded; they are recomputed when
+// decoding. Decoding is strict: every byte must be consumed. Stored and transmitted payloads
+// start with `ENCODING_VERSION` so the format can change without misreading old data; hashes
+// are computed over the unversioned encoding.
+
+use crate::keys::{
+    BlockchainHash, PublicKey, PublicKeyHash, ScriptHash, Signature, XOnlyPublicKey,
+};
+
+pub const ENCODING_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum EncodingError {
+    #[error("Unexpected end of data, {0} more bytes needed")]
+    UnexpectedEnd(usize),
+    #[error("{0} trailing bytes after the encoded value")]
+    TrailingBytes(usize),
+    #[error("Varint is not minimally encoded")]
+    NonCanonicalVarint,
+    #[error("Length {0} exceeds the remaining data")]
+    LengthTooLarge(u64),
+    #[error("Unsupported encoding version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Invalid value: {0}")]
+    InvalidValue(String),
+}
+
+pub trait Encodable {
+    fn encode(&self, out: &mut Vec<u8>);
+}
+
+pub trait Decodable: Sized {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError>;
+}
+
+pub fn serialize<T: Encodable + ?Sized>(value: &T) -> Vec<u8> {
+    let mut out = Vec::new();
+    value.encode(&mut out);
+    out
+}
+
+/// Decodes a value that must take up all of `bytes`.
+pub fn deserialize<T: Decodable>(bytes: &[u8]) -> Result<T, EncodingError> {
+    let mut reader = Reader::new(bytes);
+    let value = T::decode(&mut reader)?;
+    reader.finish()?;
+
+    Ok(value)
+}
+
+/// Encoding prefixed with `ENCODING_VERSION`, for storage and network payloads.
+pub fn serialize_versioned<T: Encodable + ?Sized>(value: &T) -> Vec<u8> {
+    let mut out = vec![ENCODING_VERSION];
+    value.encode(&mut out);
+    out
+}
+
+pub fn deserialize_versioned<T: Decodable>(bytes: &[u8]) -> Result<T, EncodingError> {
+    match bytes.split_first() {
+        Some((&ENCODING_VERSION, rest)) => deserialize(rest),
+        Some((&version, _)) => Err(EncodingError::UnsupportedVersion(version)),
+        None => Err(EncodingError::UnexpectedEnd(1)),
+    }
+}
+
+pub fn write_varint(out: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0xfc => out.push(value as u8),
+        0xfd..=0xffff => {
+            out.push(0xfd);
+            out.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xfe);
+            out.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        _ => {
+            out.push(0xff);
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+pub fn write_var_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Cursor over encoded bytes.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Fails if anything is left to read.
+    pub fn finish(&self) -> Result<(), EncodingError> {
+        match self.remaining() {
+            0 => Ok(()),
+            trailing => Err(EncodingError::TrailingBytes(trailing)),
+        }
+    }
+
+    pub fn read_slice(&mut self, len: usize) -> Result<&'a [u8], EncodingError> {
+        if len > self.bytes.len() {
+            return Err(EncodingError::UnexpectedEnd(len - self.bytes.len()));
+        }
+
+        let (slice, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(slice)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], EncodingError> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.read_slice(N)?);
+        Ok(array)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, EncodingError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, EncodingError> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, EncodingError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, EncodingError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u128(&mut self) -> Result<u128, EncodingError> {
+        Ok(u128::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64, EncodingError> {
+        let (value, min) = match self.read_u8()? {
+            0xfd => (self.read_u16()? as u64, 0xfd),
+            0xfe => (self.read_u32()? as u64, 0x1_0000),
+            0xff => (self.read_u64()?, 0x1_0000_0000),
+            small => return Ok(small as u64),
+        };
+
+        if value < min {
+            return Err(EncodingError::NonCanonicalVarint);
+        }
+        Ok(value)
+    }
+
+    pub fn read_var_bytes(&mut self) -> Result<&'a [u8], EncodingError> {
+        let len = self.read_length(1)?;
+        self.read_slice(len)
+    }
+
+    /// Reads a list length, rejecting counts the remaining data cannot hold when every
+    /// item takes at least `min_item_size` bytes, so a bogus length can't force a huge allocation.
+    pub fn read_length(&mut self, min_item_size: usize) -> Result<usize, EncodingError> {
+        let len = self.read_varint()?;
+        let max = (self.remaining() / min_item_size.max(1)) as u64;
+        if len > max {
+            return Err(EncodingError::LengthTooLarge(len));
+        }
+
+        Ok(len as usize)
+    }
+}
+
+impl Encodable for u8 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self);
+    }
+}
+
+impl Decodable for u8 {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        reader.read_u8()
+    }
+}
+
+impl Encodable for u32 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Decodable for u32 {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        reader.read_u32()
+    }
+}
+
+impl Encodable for u64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Decodable for u64 {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        reader.read_u64()
+    }
+}
+
+impl Encodable for u128 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Decodable for u128 {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        reader.read_u128()
+    }
+}
+
+impl<T: Encodable> Encodable for [T] {
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        for item in self {
+            item.encode(out);
+        }
+    }
+}
+
+impl<T: Encodable> Encodable for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_slice().encode(out);
+    }
+}
+
+impl<T: Decodable> Decodable for Vec<T> {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        let len = reader.read_length(1)?;
+        (0..len).map(|_| T::decode(reader)).collect()
+    }
+}
+
+impl<T: Encodable + ?Sized> Encodable for &T {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (**self).encode(out);
+    }
+}
+
+// Hashes and keys are written as their raw bytes
+macro_rules! impl_fixed_bytes {
+    ($type:ty, $len:literal, $as_bytes:ident) => {
+        impl Encodable for $type {
+            fn encode(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(self.$as_bytes());
+            }
+        }
+
+        impl Decodable for $type {
+            fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+                Ok(<$type>::new(reader.read_array::<$len>()?))
+            }
+        }
+    };
+}
+
+impl_fixed_bytes!(BlockchainHash, 32, as_ref);
+impl_fixed_bytes!(PublicKeyHash, 20, as_ref);
+impl_fixed_bytes!(ScriptHash, 20, as_bytes);
+impl_fixed_bytes!(XOnlyPublicKey, 32, as_bytes);
+
+pub const PUBLIC_KEY_LEN: usize = 33;
+
+impl Encodable for PublicKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_bytes());
+    }
+}
+
+impl Decodable for PublicKey {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        PublicKey::from_bytes(reader.read_slice(PUBLIC_KEY_LEN)?)
+            .map_err(|err| EncodingError::InvalidValue(err.to_string()))
+    }
+}
+
+impl Encodable for Signature {
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_var_bytes(out, self.as_bytes());
+    }
+}
+
+impl Decodable for Signature {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        Ok(Signature::from_bytes(reader.read_var_bytes()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint_vectors() {
+        let vectors: [(u64, &str); 7] = [
+            (0, "00"),
+            (0xfc, "fc"),
+            (0xfd, "fdfd00"),
+            (0xffff, "fdffff"),
+            (0x1_0000, "fe00000100"),
+            (0xffff_ffff, "feffffffff"),
+            (0x1_0000_0000, "ff0000000001000000"),
+        ];
+
+        for (value, expected) in vectors {
+            let mut out = Vec::new();
+            write_varint(&mut out, value);
+            assert_eq!(hex::encode(&out), expected);
+            assert_eq!(Reader::new(&out).read_varint().unwrap(), value);
+        }
+
+        // the same values in a longer form than needed are rejected
+        for non_canonical in ["fd0100", "fefc000000", "ff0000010000000000"] {
+            let bytes = hex::decode(non_canonical).unwrap();
+            assert_eq!(
+                Reader::new(&bytes).read_varint(),
+                Err(EncodingError::NonCanonicalVarint)
+            );
+        }
+
+        assert_eq!(
+            deserialize::<u32>(&[1, 0, 0, 0, 0]),
+            Err(EncodingError::TrailingBytes(1))
+        );
+        assert_eq!(
+            deserialize::<Vec<u8>>(&hex::decode("fe00000100").unwrap()),
+            Err(EncodingError::LengthTooLarge(0x1_0000))
+        );
+        assert_eq!(
+            deserialize_versioned::<u32>(&[2, 1, 0, 0, 0]),
+            Err(EncodingError::UnsupportedVersion(2))
+        );
+    }
+}
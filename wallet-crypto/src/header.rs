@@ -0,0 +1,198 @@
+// Block headers, shared by full nodes and light clients.
+//
+// A header commits to the block's transactions only through the merkle root, so a client
+// holding headers can check proof of work and, with a merkle branch (see merkle.rs), that a
+// transaction was confirmed, without downloading block bodies.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+    encoding::{Decodable, Encodable, EncodingError, Reader},
+    keys::BlockchainHash,
+};
+
+/// Encoded size of a block header: version, height, previous hash, merkle root, timestamp,
+/// bits, nonce.
+pub const BLOCK_HEADER_SIZE: usize = 4 + 8 + 32 + 32 + 16 + 4 + 8;
+
+/// Difficulty of every block, as leading zero bits of its hash. There is no retargeting.
+pub const BLOCK_BITS: u32 = 2;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum HeaderError {
+    #[error("Header {0} does not meet its proof of work target")]
+    InvalidProofOfWork(BlockchainHash),
+    #[error("Header at height {0} does not extend the header before it")]
+    Disconnected(u64),
+    #[error("Header at height {height} has bits {bits}, expected {expected}")]
+    WrongDifficulty {
+        height: u64,
+        bits: u32,
+        expected: u32,
+    },
+}
+
+/// The part of a block that proof of work covers.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub height: u64,
+    pub prev_block_hash: BlockchainHash,
+    pub merkle_root: BlockchainHash,
+    pub timestamp: u128,
+    pub bits: u32,
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    pub fn hash(&self) -> BlockchainHash {
+        let mut encoded_bytes = Vec::with_capacity(BLOCK_HEADER_SIZE);
+        self.encode(&mut encoded_bytes);
+
+        let first_hash = Sha256::digest(&encoded_bytes); // Hash the bytes
+        let second_hash = Sha256::digest(first_hash);
+
+        BlockchainHash::new(second_hash.into())
+    }
+
+    /// Checks that `bits` is the difficulty the chain requires at this height, then that the
+    /// hash is below its target. A header can't lower its own difficulty.
+    pub fn verify_proof_of_work(&self) -> Result<(), HeaderError> {
+        let expected = required_bits(self.height);
+        if self.bits != expected {
+            return Err(HeaderError::WrongDifficulty {
+                height: self.height,
+                bits: self.bits,
+                expected,
+            });
+        }
+
+        let hash = self.hash();
+        if hash.is_zero_hash() || hash > difficulty_target(self.bits) {
+            return Err(HeaderError::InvalidProofOfWork(hash));
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that `headers`, starting at genesis, link up and that each one after genesis has
+/// valid proof of work. Genesis is not mined, clients take it as given.
+pub fn verify_header_chain(headers: &[BlockHeader]) -> Result<(), HeaderError> {
+    match headers.split_first() {
+        Some((genesis, rest)) if genesis.height == 0 => verify_headers_extend(genesis, rest),
+        Some(_) => Err(HeaderError::Disconnected(0)),
+        None => Ok(()),
+    }
+}
+
+/// Checks that `headers` follow `tip` one by one, each with valid proof of work.
+pub fn verify_headers_extend(
+    tip: &BlockHeader,
+    headers: &[BlockHeader],
+) -> Result<(), HeaderError> {
+    let mut prev = tip;
+    for header in headers {
+        if header.height != prev.height + 1 || header.prev_block_hash != prev.hash() {
+            return Err(HeaderError::Disconnected(header.height));
+        }
+
+        header.verify_proof_of_work()?;
+        prev = header;
+    }
+
+    Ok(())
+}
+
+/// `bits` of the block at `height`, by the chain's difficulty rule.
+pub fn required_bits(_height: u64) -> u32 {
+    BLOCK_BITS
+}
+
+/// Hashes it takes on average to find a header with `bits`, the weight of a block when
+/// comparing branches.
+pub fn block_work(bits: u32) -> u128 {
+    1u128 << bits.min(127)
+}
+
+/// Target a header hash must not exceed; `bits` is the number of leading zero bits.
+pub fn difficulty_target(bits: u32) -> BlockchainHash {
+    let mut target_bytes = [0xFF; 32];
+    let num_leading_zeros = (bits as usize).min(256);
+    for i in 0..num_leading_zeros {
+        target_bytes[i / 8] &= !(1 << (7 - (i % 8)));
+    }
+    BlockchainHash::new(target_bytes)
+}
+
+impl Encodable for BlockHeader {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.version.encode(out);
+        self.height.encode(out);
+        self.prev_block_hash.encode(out);
+        self.merkle_root.encode(out);
+        self.timestamp.encode(out);
+        self.bits.encode(out);
+        self.nonce.encode(out);
+    }
+}
+
+impl Decodable for BlockHeader {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        Ok(BlockHeader {
+            version: reader.read_u32()?,
+            height: reader.read_u64()?,
+            prev_block_hash: BlockchainHash::decode(reader)?,
+            merkle_root: BlockchainHash::decode(reader)?,
+            timestamp: reader.read_u128()?,
+            bits: reader.read_u32()?,
+            nonce: reader.read_u64()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Grinds the nonce until the header meets the target of its bits
+    fn mine(mut header: BlockHeader) -> BlockHeader {
+        while header.hash() > difficulty_target(header.bits) {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    #[test]
+    fn test_headers_must_have_the_required_difficulty() {
+        let genesis = BlockHeader {
+            version: 0x20000000,
+            height: 0,
+            prev_block_hash: BlockchainHash::default(),
+            merkle_root: BlockchainHash::default(),
+            timestamp: 0,
+            bits: required_bits(0),
+            nonce: 0,
+        };
+        let next = mine(BlockHeader {
+            height: 1,
+            prev_block_hash: genesis.hash(),
+            bits: required_bits(1),
+            ..genesis
+        });
+        assert_eq!(verify_header_chain(&[genesis, next]), Ok(()));
+
+        // any hash meets a target of no leading zeros
+        let easier = mine(BlockHeader { bits: 0, ..next });
+        assert_eq!(
+            verify_headers_extend(&genesis, &[easier]),
+            Err(HeaderError::WrongDifficulty {
+                height: 1,
+                bits: 0,
+                expected: BLOCK_BITS
+            })
+        );
+    }
+}
@@ -0,0 +1,344 @@
+// Hash time-locked contracts (HTLC) and the transactions around them.
+//
+// An HTLC output pays `recipient` once they reveal the preimage of `payment_hash`, or goes back
+// to `refund` after `timeout`. Two HTLCs locked to the same hash on different chains make an
+// atomic swap: claiming one side publishes the preimage, which then unlocks the other side.
+
+use rand::{RngCore, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+    crypto::{encode_pushes, parse_pushes},
+    keys::{BlockchainHash, KeyPair, PublicKeyHash, Signature, SignatureError},
+    scripts::{HTLC_PREIMAGE_LEN, Script},
+    sighash::SigHashType,
+    timelock::{SEQUENCE_ENABLE_LOCKTIME, SEQUENCE_FINAL},
+    transaction::{DraftTransaction, Transaction, TxOut, UTXO, UnsignedTxIn},
+};
+
+#[derive(Error, Debug)]
+pub enum HtlcError {
+    #[error("Insufficient funds: available {available}, required {required}")]
+    InsufficientFunds { available: u64, required: u64 },
+    #[error("Invalid preimage: {0}")]
+    InvalidPreimage(String),
+    #[error("Output is not an HTLC: {0}")]
+    NotAnHtlc(String),
+    #[error(transparent)]
+    Signature(#[from] SignatureError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HtlcContract {
+    pub payment_hash: [u8; 32],
+    pub recipient: PublicKeyHash,
+    pub refund: PublicKeyHash,
+    // Block height (or unix time) from which the refund branch can be used
+    pub timeout: u32,
+}
+
+impl HtlcContract {
+    pub fn new(
+        payment_hash: [u8; 32],
+        recipient: PublicKeyHash,
+        refund: PublicKeyHash,
+        timeout: u32,
+    ) -> Self {
+        HtlcContract {
+            payment_hash,
+            recipient,
+            refund,
+            timeout,
+        }
+    }
+
+    pub fn script(&self) -> Script {
+        Script::HashTimeLock {
+            payment_hash: self.payment_hash,
+            recipient: self.recipient,
+            refund: self.refund,
+            timeout: self.timeout,
+        }
+    }
+
+    pub fn from_script(script: &Script) -> Option<Self> {
+        match script {
+            Script::HashTimeLock {
+                payment_hash,
+                recipient,
+                refund,
+                timeout,
+            } => Some(HtlcContract::new(*payment_hash, *recipient, *refund, *timeout)),
+            _ => None,
+        }
+    }
+}
+
+/// Random swap secret and its payment hash.
+pub fn generate_preimage() -> ([u8; HTLC_PREIMAGE_LEN], [u8; 32]) {
+    let mut preimage = [0u8; HTLC_PREIMAGE_LEN];
+    OsRng.fill_bytes(&mut preimage);
+
+    (preimage, payment_hash(&preimage))
+}
+
+pub fn payment_hash(preimage: &[u8]) -> [u8; 32] {
+    Sha256::digest(preimage).into()
+}
+
+/// Locks `amount` from P2PKH `utxos` owned by `key` into an HTLC output. Change above `fee`
+/// goes back to the key's address as the second output.
+pub fn funding_transaction(
+    key: &KeyPair,
+    utxos: &[UTXO],
+    contract: &HtlcContract,
+    amount: u64,
+    fee: u64,
+) -> Result<Transaction, HtlcError> {
+    let available: u64 = utxos.iter().map(|utxo| utxo.value).sum();
+    let required = amount + fee;
+
+    if amount == 0 || available < required {
+        return Err(HtlcError::InsufficientFunds {
+            available,
+            required,
+        });
+    }
+
+    let own_script = Script::PayToPublicKeyHash {
+        pub_key_hash: key.public_key.to_address(),
+    };
+    let spent_outputs: Vec<TxOut> = utxos
+        .iter()
+        .map(|utxo| TxOut {
+            value: utxo.value,
+            script_pubkey: own_script.clone(),
+        })
+        .collect();
+
+    let inputs = utxos
+        .iter()
+        .map(|utxo| UnsignedTxIn {
+            prev_tx_id: utxo.prev_tx_id,
+            prev_out_idx: utxo.prev_out_idx,
+            sequence: SEQUENCE_FINAL,
+        })
+        .collect();
+
+    let mut outputs = vec![TxOut {
+        value: amount,
+        script_pubkey: contract.script(),
+    }];
+
+    if available > required {
+        outputs.push(TxOut {
+            value: available - required,
+            script_pubkey: own_script,
+        });
+    }
+
+    Ok(DraftTransaction::new(inputs, outputs).sign(key, &spent_outputs)?)
+}
+
+/// Locates the output of `tx` locked to `contract`, so the counterparty of a swap can check
+/// the funding before locking their own side.
+pub fn find_htlc_output(tx: &Transaction, contract: &HtlcContract) -> Result<UTXO, HtlcError> {
+    let script = contract.script();
+
+    tx.outputs
+        .iter()
+        .position(|output| output.script_pubkey == script)
+        .map(|idx| UTXO {
+            prev_tx_id: tx.id,
+            prev_out_idx: idx as u32,
+            value: tx.outputs[idx].value,
+        })
+        .ok_or_else(|| {
+            HtlcError::NotAnHtlc(format!("Transaction {} does not fund the contract", tx.id))
+        })
+}
+
+/// Spends the HTLC output `htlc` to `destination` through the claim branch, revealing `preimage`.
+/// `key` must belong to the contract's recipient.
+pub fn claim_transaction(
+    key: &KeyPair,
+    htlc: &UTXO,
+    contract: &HtlcContract,
+    preimage: &[u8],
+    fee: u64,
+    destination: Script,
+) -> Result<Transaction, HtlcError> {
+    if preimage.len() != HTLC_PREIMAGE_LEN || payment_hash(preimage) != contract.payment_hash {
+        return Err(HtlcError::InvalidPreimage(
+            "Preimage does not match the contract payment hash".to_string(),
+        ));
+    }
+
+    let draft = spend_draft(htlc, fee, destination, SEQUENCE_FINAL)?;
+    let signature = sign_input(&draft, htlc, contract, key)?;
+
+    Ok(draft.into_transaction(vec![Signature::from_bytes(&encode_pushes(&[
+        signature.as_bytes(),
+        preimage,
+    ]))])?)
+}
+
+/// Spends the HTLC output `htlc` back to `destination` through the refund branch. The
+/// transaction carries the contract timeout as its lock time, so it can only be mined after it.
+pub fn refund_transaction(
+    key: &KeyPair,
+    htlc: &UTXO,
+    contract: &HtlcContract,
+    fee: u64,
+    destination: Script,
+) -> Result<Transaction, HtlcError> {
+    let draft = spend_draft(htlc, fee, destination, SEQUENCE_ENABLE_LOCKTIME)?
+        .with_lock_time(contract.timeout);
+    let signature = sign_input(&draft, htlc, contract, key)?;
+
+    Ok(draft.into_transaction(vec![Signature::from_bytes(&encode_pushes(&[
+        signature.as_bytes(),
+    ]))])?)
+}
+
+/// Finds the claim of `htlc_tx_id:htlc_out_idx` in `tx` and returns the revealed preimage.
+pub fn extract_preimage(
+    tx: &Transaction,
+    htlc_tx_id: &BlockchainHash,
+    htlc_out_idx: u32,
+    contract: &HtlcContract,
+) -> Option<[u8; HTLC_PREIMAGE_LEN]> {
+    tx.inputs
+        .iter()
+        .filter(|input| input.prev_tx_id == *htlc_tx_id && input.prev_out_idx == htlc_out_idx)
+        .filter_map(|input| parse_pushes(input.script_sig.as_bytes()).ok())
+        .find_map(|pushes| match pushes.as_slice() {
+            [_, preimage] if payment_hash(preimage) == contract.payment_hash => {
+                preimage.as_slice().try_into().ok()
+            }
+            _ => None,
+        })
+}
+
+fn spend_draft(
+    htlc: &UTXO,
+    fee: u64,
+    destination: Script,
+    sequence: u32,
+) -> Result<DraftTransaction, HtlcError> {
+    if htlc.value <= fee {
+        return Err(HtlcError::InsufficientFunds {
+            available: htlc.value,
+            required: fee + 1,
+        });
+    }
+
+    Ok(DraftTransaction::new(
+        vec![UnsignedTxIn {
+            prev_tx_id: htlc.prev_tx_id,
+            prev_out_idx: htlc.prev_out_idx,
+            sequence,
+        }],
+        vec![TxOut {
+            value: htlc.value - fee,
+            script_pubkey: destination,
+        }],
+    ))
+}
+
+// P2PKH-style script_sig (signature || public key) for the draft's only input, spending `htlc`
+fn sign_input(
+    draft: &DraftTransaction,
+    htlc: &UTXO,
+    contract: &HtlcContract,
+    key: &KeyPair,
+) -> Result<Signature, HtlcError> {
+    let spent_output = TxOut {
+        value: htlc.value,
+        script_pubkey: contract.script(),
+    };
+    let signature = draft.sign_input(0, &spent_output, key, SigHashType::ALL)?;
+
+    Ok(Signature::build(signature, &key.public_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_htlc_claim_and_refund() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+        let (preimage, hash) = generate_preimage();
+        let contract = HtlcContract::new(
+            hash,
+            bob.public_key.to_address(),
+            alice.public_key.to_address(),
+            100,
+        );
+        let script = contract.script();
+        let spent = TxOut {
+            value: 30,
+            script_pubkey: script.clone(),
+        };
+
+        let funding = funding_transaction(
+            &alice,
+            &[UTXO {
+                prev_tx_id: BlockchainHash::default(),
+                prev_out_idx: 0,
+                value: 50,
+            }],
+            &contract,
+            30,
+            1,
+        )
+        .unwrap();
+        assert_eq!(funding.outputs[0].script_pubkey, script);
+        assert_eq!(funding.outputs[1].value, 19);
+
+        let htlc = find_htlc_output(&funding, &contract).unwrap();
+        assert_eq!(htlc.value, 30);
+        let to_bob = Script::PayToPublicKeyHash {
+            pub_key_hash: bob.public_key.to_address(),
+        };
+        let to_alice = Script::PayToPublicKeyHash {
+            pub_key_hash: alice.public_key.to_address(),
+        };
+
+        // claim needs the right preimage and the recipient's key
+        assert!(claim_transaction(&bob, &htlc, &contract, &[0u8; 32], 1, to_bob.clone()).is_err());
+        let stolen = claim_transaction(&alice, &htlc, &contract, &preimage, 1, to_alice.clone());
+        assert!(stolen.unwrap().verify_signatures(std::slice::from_ref(&spent)).is_err());
+
+        let claim = claim_transaction(&bob, &htlc, &contract, &preimage, 1, to_bob).unwrap();
+        assert!(claim.verify_signatures(std::slice::from_ref(&spent)).is_ok());
+        assert_eq!(
+            extract_preimage(&claim, &funding.id, 0, &contract),
+            Some(preimage)
+        );
+
+        // refund is locked until the timeout and only for the refund key
+        let refund = refund_transaction(&alice, &htlc, &contract, 1, to_alice.clone()).unwrap();
+        assert!(refund.verify_signatures(std::slice::from_ref(&spent)).is_ok());
+        assert!(!refund.is_final(100, 0));
+        assert!(refund.is_final(101, 0));
+
+        let early = spend_draft(&htlc, 1, to_alice.clone(), SEQUENCE_ENABLE_LOCKTIME)
+            .unwrap()
+            .with_lock_time(99);
+        let signature = sign_input(&early, &htlc, &contract, &alice).unwrap();
+        let early = early
+            .into_transaction(vec![Signature::from_bytes(&encode_pushes(&[
+                signature.as_bytes(),
+            ]))])
+            .unwrap();
+        assert!(early.verify_signatures(std::slice::from_ref(&spent)).is_err());
+
+        let not_refund_key = refund_transaction(&bob, &htlc, &contract, 1, to_alice).unwrap();
+        assert!(not_refund_key.verify_signatures(&[spent]).is_err());
+    }
+}
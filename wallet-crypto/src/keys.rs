@@ -1,21 +1,32 @@
 use std::fmt::{self, Debug};
 
-use crate::crypto::calculate_p2pkh_hash;
-use k256::ecdsa::{
-    Signature as EcdsaSignature, SigningKey, VerifyingKey,
-    signature::{Signer, Verifier},
+use crate::{crypto::calculate_p2pkh_hash, sighash::SigHashType};
+use k256::{
+    ecdsa::{
+        Signature as EcdsaSignature, SigningKey, VerifyingKey,
+        signature::{Signer, Verifier},
+    },
+    schnorr,
 };
-use rand::rngs::OsRng;
+use rand::{RngCore, rngs::OsRng};
 
+mod address;
 mod blockchain_hash;
 mod public_key_hash;
+mod script_hash;
 mod signature;
 mod serialization;
+mod x_only_public_key;
 
+pub use address::Address;
 pub use blockchain_hash::BlockchainHash;
 pub use public_key_hash::PublicKeyHash;
+pub use script_hash::ScriptHash;
 use serde::{Deserialize, Serialize};
-pub use signature::{Signature, SignatureError};
+pub use signature::{
+    ECDSA_SIGNATURE_LEN, Signature, SignatureError, parse_strict_ecdsa_signature,
+};
+pub use x_only_public_key::XOnlyPublicKey;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(transparent)]
@@ -37,6 +48,22 @@ impl PublicKey {
         enc.as_bytes().to_vec()
     }
 
+    /// The key's x coordinate, as used by Schnorr outputs.
+    pub fn to_x_only(&self) -> XOnlyPublicKey {
+        let enc = self.0.to_encoded_point(true);
+        let mut x = [0u8; 32];
+        x.copy_from_slice(&enc.as_bytes()[1..]);
+        XOnlyPublicKey::new(x)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SignatureError> {
+        VerifyingKey::from_sec1_bytes(bytes)
+            .map(PublicKey)
+            .map_err(|e| {
+                SignatureError::InvalidPublicKeyFormat(format!("Invalid public key format: {}", e))
+            })
+    }
+
     pub fn verify(&self, message: &[u8], signature: &EcdsaSignature) -> bool {
         self.0.verify(message, signature).is_ok()
     }
@@ -55,6 +82,8 @@ pub struct PublicKeyWithSignature {
     pub pub_key_hash: PublicKeyHash,
     public_key: PublicKey,
     signature: EcdsaSignature,
+    // which signature hash `verify` must be given
+    pub sighash_type: SigHashType,
 }
 
 impl PublicKeyWithSignature {
@@ -62,11 +91,13 @@ impl PublicKeyWithSignature {
         pub_key_hash: PublicKeyHash,
         public_key: PublicKey,
         signature: EcdsaSignature,
+        sighash_type: SigHashType,
     ) -> Self {
         PublicKeyWithSignature {
             pub_key_hash,
             public_key,
             signature,
+            sighash_type,
         }
     }
 
@@ -120,12 +151,36 @@ impl KeyPair {
 
     pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, String> {
         let signature: EcdsaSignature = self.secret_key.0.sign(message);
+        // only low-S signatures are valid, see `parse_strict_ecdsa_signature`
+        let signature = signature.normalize_s().unwrap_or(signature);
         Ok(signature.to_vec())
     }
 
     pub fn verify(&self, message: &[u8], signature: &EcdsaSignature) -> bool {
         self.public_key.verify(message, signature)
     }
+
+    /// The key as used for Schnorr signatures, see `crate::schnorr`.
+    pub fn x_only_public_key(&self) -> XOnlyPublicKey {
+        XOnlyPublicKey::new(self.schnorr_signing_key().verifying_key().to_bytes().into())
+    }
+
+    /// BIP 340 Schnorr signature of `message`.
+    pub fn sign_schnorr(&self, message: &[u8]) -> Result<Vec<u8>, String> {
+        let mut aux_rand = [0u8; 32];
+        OsRng.fill_bytes(&mut aux_rand);
+
+        let signature = self
+            .schnorr_signing_key()
+            .sign_raw(message, &aux_rand)
+            .map_err(|e| e.to_string())?;
+        Ok(signature.to_bytes().to_vec())
+    }
+
+    // BIP 340 negates the secret when its public point has an odd y, k256 handles that
+    fn schnorr_signing_key(&self) -> schnorr::SigningKey {
+        schnorr::SigningKey::from(*self.secret_key.0.as_nonzero_scalar())
+    }
 }
 
 #[cfg(test)]
@@ -179,11 +234,22 @@ mod tests {
             },
         };
 
+        // The output Alice spends
+        let spent = TxOut {
+            value: 200_000_000,
+            script_pubkey: Script::PayToPublicKeyHash {
+                pub_key_hash: keypair_alice.public_key.to_address(),
+            },
+        };
+
         let tx = DraftTransaction::new(vec![tx_in], vec![tx_out]);
-        let tx = tx.sign(&keypair_alice);
+        let tx = tx.sign(&keypair_alice, std::slice::from_ref(&spent))?;
+
+        let verifier = tx.inputs[0].script_sig.get_verifier()?;
+        assert_eq!(verifier.pub_key_hash, keypair_alice.public_key.to_address());
+        verifier.verify(tx.signature_hash(0, &spent, verifier.sighash_type)?.as_ref())?;
 
-        // Verify the transaction
-        tx.verify_signatures()?;
+        tx.verify_signatures(&[spent])?;
 
         println!("Transaction ID: {}", tx.id);
 
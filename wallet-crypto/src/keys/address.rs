@@ -0,0 +1,149 @@
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    keys::{PublicKeyHash, ScriptHash, XOnlyPublicKey},
+    scripts::Script,
+};
+
+// Version byte of script hash addresses (same value Bitcoin uses for P2SH).
+const SCRIPT_HASH_VERSION: u8 = 0x05;
+// Version byte of Schnorr key addresses.
+const SCHNORR_KEY_VERSION: u8 = 0x01;
+const CHECKSUM_LEN: usize = 4;
+
+/// A payment destination that can be shared as a string.
+///
+/// Public key hash addresses keep the original format: 20 bytes as 40 hex chars.
+/// Script hash addresses are hex of `[version byte][20 byte hash][4 byte checksum]`,
+/// where the checksum is the start of SHA256(SHA256(version + hash)). Schnorr key addresses
+/// use the same layout around the 32 byte x-only key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Address {
+    PublicKeyHash(PublicKeyHash),
+    ScriptHash(ScriptHash),
+    SchnorrKey(XOnlyPublicKey),
+}
+
+impl Address {
+    pub fn try_from_string(data: &str) -> Result<Self, &'static str> {
+        let bytes = hex::decode(data).map_err(|_| "Incorrect hex string")?;
+
+        match bytes.len() {
+            20 => Ok(Address::PublicKeyHash(PublicKeyHash::from_slice(&bytes)?)),
+            25 | 37 => {
+                let (payload, checksum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+                if checksum != address_checksum(payload) {
+                    return Err("Invalid address checksum");
+                }
+
+                match payload[0] {
+                    SCRIPT_HASH_VERSION => {
+                        Ok(Address::ScriptHash(ScriptHash::from_slice(&payload[1..])?))
+                    }
+                    SCHNORR_KEY_VERSION => Ok(Address::SchnorrKey(XOnlyPublicKey::from_slice(
+                        &payload[1..],
+                    )?)),
+                    _ => Err("Unknown address version"),
+                }
+            }
+            _ => Err("Unsupported address length"),
+        }
+    }
+
+    pub fn to_string_owned(&self) -> String {
+        match self {
+            Address::PublicKeyHash(pub_key_hash) => pub_key_hash.to_string_owned(),
+            Address::ScriptHash(script_hash) => {
+                encode_versioned(SCRIPT_HASH_VERSION, script_hash.as_bytes())
+            }
+            Address::SchnorrKey(x_only_key) => {
+                encode_versioned(SCHNORR_KEY_VERSION, x_only_key.as_bytes())
+            }
+        }
+    }
+
+    /// Locking script paying to this address.
+    pub fn script_pubkey(&self) -> Script {
+        match self {
+            Address::PublicKeyHash(pub_key_hash) => Script::PayToPublicKeyHash {
+                pub_key_hash: *pub_key_hash,
+            },
+            Address::ScriptHash(script_hash) => Script::PayToScriptHash {
+                script_hash: *script_hash,
+            },
+            Address::SchnorrKey(x_only_key) => Script::PayToSchnorrKey {
+                x_only_key: *x_only_key,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_string_owned())
+    }
+}
+
+impl From<PublicKeyHash> for Address {
+    fn from(pub_key_hash: PublicKeyHash) -> Self {
+        Address::PublicKeyHash(pub_key_hash)
+    }
+}
+
+impl From<ScriptHash> for Address {
+    fn from(script_hash: ScriptHash) -> Self {
+        Address::ScriptHash(script_hash)
+    }
+}
+
+impl From<XOnlyPublicKey> for Address {
+    fn from(x_only_key: XOnlyPublicKey) -> Self {
+        Address::SchnorrKey(x_only_key)
+    }
+}
+
+fn encode_versioned(version: u8, bytes: &[u8]) -> String {
+    let mut payload = vec![version];
+    payload.extend_from_slice(bytes);
+    let checksum = address_checksum(&payload);
+    payload.extend_from_slice(&checksum);
+
+    hex::encode(payload)
+}
+
+fn address_checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let hash = Sha256::digest(Sha256::digest(payload));
+    let mut checksum = [0u8; CHECKSUM_LEN];
+    checksum.copy_from_slice(&hash[..CHECKSUM_LEN]);
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_string_round_trip() {
+        let pkh = Address::PublicKeyHash(PublicKeyHash::new([7u8; 20]));
+        assert_eq!(pkh.to_string_owned().len(), 40);
+        assert_eq!(Address::try_from_string(&pkh.to_string_owned()), Ok(pkh));
+
+        let sh = Address::ScriptHash(ScriptHash::new([9u8; 20]));
+        let encoded = sh.to_string_owned();
+        assert_eq!(Address::try_from_string(&encoded), Ok(sh));
+
+        let schnorr = Address::SchnorrKey(XOnlyPublicKey::new([3u8; 32]));
+        assert_eq!(
+            Address::try_from_string(&schnorr.to_string_owned()),
+            Ok(schnorr)
+        );
+
+        // flipping a hash character must break the checksum
+        let mut corrupted = encoded.into_bytes();
+        corrupted[10] = if corrupted[10] == b'0' { b'1' } else { b'0' };
+        let corrupted = String::from_utf8(corrupted).unwrap();
+        assert!(Address::try_from_string(&corrupted).is_err());
+    }
+}
@@ -1,7 +1,5 @@
 use std::fmt;
 
-use bincode::{Decode, Encode};
-
 #[derive(
     Debug,
     Clone,
@@ -13,8 +11,6 @@ use bincode::{Decode, Encode};
     Hash,
     serde::Serialize,
     serde::Deserialize,
-    Encode,
-    Decode,
 )]
 pub struct BlockchainHash([u8; 32]);
 
@@ -22,6 +18,10 @@ impl BlockchainHash {
     pub fn new(bytes: [u8; 32]) -> Self {
         BlockchainHash(bytes)
     }
+    pub fn try_from_string(data: &str) -> Result<Self, &'static str> {
+        let result = hex::decode(data).map_err(|_| "Incorrect hex string")?;
+        Self::from_slice(result.as_slice())
+    }
     pub fn from_slice(slice: &[u8]) -> Result<Self, &'static str> {
         if slice.len() == 32 {
             let mut bytes = [0u8; 32];
@@ -1,7 +1,5 @@
 use std::{fmt};
 
-use bincode::{Decode, Encode};
-
 #[derive(
     Debug,
     Clone,
@@ -13,8 +11,6 @@ use bincode::{Decode, Encode};
     Hash,
     serde::Serialize,
     serde::Deserialize,
-    Encode,
-    Decode,
 )]
 pub struct PublicKeyHash([u8; 20]);
 
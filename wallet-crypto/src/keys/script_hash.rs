@@ -0,0 +1,48 @@
+use std::fmt;
+
+/// RIPEMD160(SHA256(redeem script)) committed to by a pay-to-script-hash output.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct ScriptHash([u8; 20]);
+
+impl ScriptHash {
+    pub fn new(bytes: [u8; 20]) -> Self {
+        ScriptHash(bytes)
+    }
+
+    pub fn from_slice(slice: &[u8]) -> Result<Self, &'static str> {
+        if slice.len() == 20 {
+            let mut bytes = [0u8; 20];
+            bytes.copy_from_slice(slice);
+            Ok(ScriptHash(bytes))
+        } else {
+            Err("Slice length must be 20 bytes for ScriptHash")
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+}
+
+impl fmt::Display for ScriptHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl From<[u8; 20]> for ScriptHash {
+    fn from(bytes: [u8; 20]) -> Self {
+        ScriptHash(bytes)
+    }
+}
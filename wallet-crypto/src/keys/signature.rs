@@ -1,9 +1,9 @@
-use bincode::{Decode, Encode};
 use k256::ecdsa::{Signature as EcdsaSignature, VerifyingKey};
 
 use crate::{
     crypto::{calculate_p2pkh_hash, parse_p2pkh_script_sig_k256},
     keys::{PublicKey, PublicKeyWithSignature},
+    sighash::split_signature,
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -18,6 +18,33 @@ pub enum SignatureError {
     InvalidScript(String),
 }
 
+/// Length of a compact `r || s` ECDSA signature, the only encoding accepted.
+pub const ECDSA_SIGNATURE_LEN: usize = 64;
+
+/// Parses an ECDSA signature strictly: exactly 64 bytes of `r || s` with a low S value (BIP 62).
+/// Any other encoding of a valid signature would let third parties alter transactions.
+pub fn parse_strict_ecdsa_signature(bytes: &[u8]) -> Result<EcdsaSignature, SignatureError> {
+    if bytes.len() != ECDSA_SIGNATURE_LEN {
+        return Err(SignatureError::InvalidSignatureFormat(format!(
+            "Expected a {} byte signature, got {} bytes",
+            ECDSA_SIGNATURE_LEN,
+            bytes.len()
+        )));
+    }
+
+    let signature = EcdsaSignature::from_slice(bytes).map_err(|e| {
+        SignatureError::InvalidSignatureFormat(format!("Invalid signature format: {}", e))
+    })?;
+
+    if signature.normalize_s().is_some() {
+        return Err(SignatureError::InvalidSignatureFormat(
+            "Signature has a high S value".to_string(),
+        ));
+    }
+
+    Ok(signature)
+}
+
 #[derive(
     Debug,
     Clone,
@@ -28,8 +55,6 @@ pub enum SignatureError {
     Hash,
     serde::Serialize,
     serde::Deserialize,
-    Encode,
-    Decode,
 )]
 pub struct Signature(Vec<u8>);
 
@@ -43,6 +68,11 @@ impl Signature {
         Signature(bytes.to_vec())
     }
 
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Splits a P2PKH script_sig into its public key, signature and sighash type.
     pub fn get_verifier(&self) -> Result<PublicKeyWithSignature, SignatureError> {
         let (signature_bytes, public_key_bytes) =
             parse_p2pkh_script_sig_k256(&self.0).map_err(|e| {
@@ -58,9 +88,9 @@ impl Signature {
             SignatureError::InvalidPublicKeyFormat(format!("Invalid public key format: {}", e))
         })?;
 
-        let signature = EcdsaSignature::from_slice(&signature_bytes).map_err(|e| {
-            SignatureError::InvalidSignatureFormat(format!("Invalid signature format: {}", e))
-        })?;
+        // the sighash type byte follows the 64 signature bytes
+        let (signature_bytes, sighash_type) = split_signature(&signature_bytes)?;
+        let signature = parse_strict_ecdsa_signature(signature_bytes)?;
 
         let public_key = PublicKey(verifying_key);
 
@@ -68,6 +98,7 @@ impl Signature {
             derived_pub_key_hash,
             public_key,
             signature,
+            sighash_type,
         ))
     }
 }
@@ -0,0 +1,49 @@
+use std::fmt;
+
+/// BIP 340 public key: the x coordinate of a point whose y coordinate is even.
+/// Locks `Script::PayToSchnorrKey` outputs.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct XOnlyPublicKey([u8; 32]);
+
+impl XOnlyPublicKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        XOnlyPublicKey(bytes)
+    }
+
+    pub fn from_slice(slice: &[u8]) -> Result<Self, &'static str> {
+        if slice.len() == 32 {
+            let mut bytes = [0u8; 32];
+            bytes.copy_from_slice(slice);
+            Ok(XOnlyPublicKey(bytes))
+        } else {
+            Err("Slice length must be 32 bytes for XOnlyPublicKey")
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for XOnlyPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl From<[u8; 32]> for XOnlyPublicKey {
+    fn from(bytes: [u8; 32]) -> Self {
+        XOnlyPublicKey(bytes)
+    }
+}
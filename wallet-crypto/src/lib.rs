@@ -1,4 +1,13 @@
 pub mod crypto;
+pub mod encoding;
+pub mod header;
+pub mod htlc;
 pub mod keys;
+pub mod merkle;
+pub mod psbt;
+pub mod schnorr;
 pub mod scripts;
+pub mod sighash;
+pub mod signer;
+pub mod timelock;
 pub mod transaction;
@@ -0,0 +1,227 @@
+// Merkle trees over the witness ids of a block's transactions.
+//
+// Each level hashes pairs of nodes; a level with an odd count pairs its last node with
+// itself. A `MerkleProof` is the branch of sibling hashes from one leaf up to the root,
+// which is all a light client needs to check a transaction against a block header.
+//
+// Leaves and inner nodes are hashed with different tags, so a 64 byte leaf can never pass
+// for a pair of children. Pairing the last node with itself still lets [a, b, c] and
+// [a, b, c, c] share a root (CVE-2012-2459), so the root is computed together with a flag
+// for trees where a real pair has two equal children; such a tree is always a mutation of
+// another one.
+
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use thiserror::Error;
+
+use crate::{
+    crypto::tagged_hash,
+    header::{BlockHeader, HeaderError},
+    keys::BlockchainHash,
+    transaction::Transaction,
+};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MerkleError {
+    #[error(transparent)]
+    Header(#[from] HeaderError),
+    #[error("Transaction id {0} does not match the transaction")]
+    TransactionIdMismatch(BlockchainHash),
+    #[error("Merkle branch does not lead to the header's merkle root")]
+    RootMismatch,
+}
+
+const LEAF_TAG: &[u8] = b"Merkle/leaf";
+const NODE_TAG: &[u8] = b"Merkle/node";
+
+fn hash_leaf(leaf: &BlockchainHash) -> BlockchainHash {
+    let hasher = tagged_hash(LEAF_TAG).chain_update(leaf.as_ref());
+    BlockchainHash::new(hasher.finalize().into())
+}
+
+fn hash_pair(left: &BlockchainHash, right: &BlockchainHash) -> BlockchainHash {
+    let hasher = tagged_hash(NODE_TAG)
+        .chain_update(left.as_ref())
+        .chain_update(right.as_ref());
+    BlockchainHash::new(hasher.finalize().into())
+}
+
+// Returns the next level up and whether any real pair had two equal children
+fn next_level(level: &[BlockchainHash]) -> (Vec<BlockchainHash>, bool) {
+    let mut mutated = false;
+    let next = level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => {
+                mutated |= left == right;
+                hash_pair(left, right)
+            }
+            [last] => hash_pair(last, last),
+            _ => unreachable!("chunks of two"),
+        })
+        .collect();
+
+    (next, mutated)
+}
+
+/// Root of the tree over `leaves` and whether the tree is mutated, `None` if there are no
+/// leaves. A mutated tree means duplicated leaves, not necessarily a wrong root: the same
+/// root is also reached by the list without the duplicates.
+pub fn merkle_root_checked(leaves: &[BlockchainHash]) -> Option<(BlockchainHash, bool)> {
+    let mut level: Vec<_> = leaves.iter().map(hash_leaf).collect();
+    let mut mutated = false;
+    while level.len() > 1 {
+        let (next, level_mutated) = next_level(&level);
+        level = next;
+        mutated |= level_mutated;
+    }
+    level.first().map(|root| (*root, mutated))
+}
+
+/// Root of the tree over `leaves`, `None` if there are none.
+pub fn merkle_root(leaves: &[BlockchainHash]) -> Option<BlockchainHash> {
+    merkle_root_checked(leaves).map(|(root, _)| root)
+}
+
+/// Branch from the leaf at `index` to the root of a tree with `leaf_count` leaves. Siblings
+/// are listed bottom up; a node paired with itself has no entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub index: u32,
+    pub leaf_count: u32,
+    pub branch: Vec<BlockchainHash>,
+}
+
+impl MerkleProof {
+    pub fn new(leaves: &[BlockchainHash], index: usize) -> Option<Self> {
+        if index >= leaves.len() {
+            return None;
+        }
+
+        let mut level: Vec<_> = leaves.iter().map(hash_leaf).collect();
+        let mut position = index;
+        let mut branch = Vec::new();
+        while level.len() > 1 {
+            if let Some(sibling) = level.get(position ^ 1) {
+                branch.push(*sibling);
+            }
+            level = next_level(&level).0;
+            position /= 2;
+        }
+
+        Some(MerkleProof {
+            index: index as u32,
+            leaf_count: leaves.len() as u32,
+            branch,
+        })
+    }
+
+    /// Root implied by `leaf` and this branch, `None` if the branch has the wrong shape.
+    pub fn compute_root(&self, leaf: &BlockchainHash) -> Option<BlockchainHash> {
+        if self.index >= self.leaf_count {
+            return None;
+        }
+
+        let mut siblings = self.branch.iter();
+        let mut hash = hash_leaf(leaf);
+        let mut position = self.index;
+        let mut level_len = self.leaf_count;
+        while level_len > 1 {
+            hash = if position % 2 == 1 {
+                hash_pair(siblings.next()?, &hash)
+            } else if position + 1 < level_len {
+                hash_pair(&hash, siblings.next()?)
+            } else {
+                hash_pair(&hash, &hash)
+            };
+            position /= 2;
+            level_len = level_len.div_ceil(2);
+        }
+
+        siblings.next().is_none().then_some(hash)
+    }
+
+    pub fn verify(&self, leaf: &BlockchainHash, merkle_root: &BlockchainHash) -> bool {
+        self.compute_root(leaf).as_ref() == Some(merkle_root)
+    }
+}
+
+/// Everything a light client needs to confirm a transaction against a header it trusts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionProof {
+    pub transaction: Transaction,
+    pub header: BlockHeader,
+    pub proof: MerkleProof,
+}
+
+impl TransactionProof {
+    /// Checks the transaction's id, the header's proof of work and the branch between them.
+    /// Whether the header belongs to the best chain is up to the caller.
+    pub fn verify(&self) -> Result<(), MerkleError> {
+        let tx = &self.transaction;
+        if tx.calculate_id() != tx.id {
+            return Err(MerkleError::TransactionIdMismatch(tx.id));
+        }
+
+        self.header.verify_proof_of_work()?;
+
+        if !self
+            .proof
+            .verify(&tx.calculate_witness_id(), &self.header.merkle_root)
+        {
+            return Err(MerkleError::RootMismatch);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proofs_and_mutations() {
+        for leaf_count in 1..=9u8 {
+            let leaves: Vec<_> = (0..leaf_count)
+                .map(|idx| BlockchainHash::new([idx; 32]))
+                .collect();
+            let root = merkle_root(&leaves).unwrap();
+
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = MerkleProof::new(&leaves, index).unwrap();
+                assert!(proof.verify(leaf, &root));
+
+                // the same branch does not work for another leaf or position
+                assert!(!proof.verify(&BlockchainHash::new([0xAA; 32]), &root));
+                if leaf_count > 1 {
+                    let moved = MerkleProof {
+                        index: (proof.index + 1) % leaf_count as u32,
+                        ..proof.clone()
+                    };
+                    assert!(!moved.verify(leaf, &root));
+                }
+
+                // nor with a sibling too many
+                let mut extended = proof;
+                extended.branch.push(root);
+                assert!(extended.compute_root(leaf).is_none());
+            }
+        }
+
+        // duplicating the trailing leaves keeps the root but is detected
+        let leaves: Vec<_> = (0..6u8).map(|idx| BlockchainHash::new([idx; 32])).collect();
+        let (root, mutated) = merkle_root_checked(&leaves[..5]).unwrap();
+        assert!(!mutated);
+        let duplicated = [&leaves[..5], &leaves[4..5]].concat();
+        assert_eq!(merkle_root_checked(&duplicated).unwrap(), (root, true));
+        assert!(!merkle_root_checked(&leaves).unwrap().1);
+
+        // an inner node is not a valid leaf
+        let inner = hash_pair(&hash_leaf(&leaves[0]), &hash_leaf(&leaves[1]));
+        assert_ne!(merkle_root(&leaves[..2]), merkle_root(&[inner]));
+
+        assert!(merkle_root(&[]).is_none());
+        assert!(MerkleProof::new(&[BlockchainHash::default()], 1).is_none());
+    }
+}
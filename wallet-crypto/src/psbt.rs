@@ -0,0 +1,462 @@
+// Partially signed transactions, modelled on Bitcoin's PSBT (BIP 174).
+//
+// A PSBT carries a draft together with everything signers need but cannot look up themselves:
+// the output each input spends, the redeem script of P2SH inputs and the sighash type to sign
+// with. It moves between roles, each of which may run on a different machine:
+//   creator    builds it from a draft                          `PartiallySignedTransaction::new`
+//   updater    adds spent outputs and redeem scripts           `update_input`, `set_redeem_script`
+//   signer     adds signatures for the keys it holds           `sign`
+//   combiner   merges copies signed by different parties       `combine`
+//   finalizer  turns the signatures into script_sigs           `finalize`
+//   extractor  produces the transaction to broadcast           `extract`
+// An offline signer only needs the PSBT and its keys, so keys can stay on a cold machine.
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bincode::config::standard;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    keys::{PublicKey, Signature, SignatureError},
+    scripts::{MultisigInput, Script},
+    sighash::SigHashType,
+    signer::Signer,
+    transaction::{DraftTransaction, Transaction, TxOut},
+};
+
+/// Prefix of the binary encoding, the same magic bytes Bitcoin uses.
+pub const PSBT_MAGIC: &[u8; 5] = b"psbt\xff";
+
+#[derive(Error, Debug)]
+pub enum PsbtError {
+    #[error("Invalid PSBT encoding: {0}")]
+    Encoding(String),
+    #[error("Input {0} does not exist")]
+    InputOutOfRange(usize),
+    #[error("Input {0} has no spent output")]
+    MissingSpentOutput(usize),
+    #[error("Redeem script does not match the P2SH output spent by input {0}")]
+    RedeemScriptMismatch(usize),
+    #[error("Cannot combine PSBTs of different transactions")]
+    DifferentTransactions,
+    #[error("Inputs {0:?} are not finalized")]
+    NotFinalized(Vec<usize>),
+    #[error(transparent)]
+    Signature(#[from] SignatureError),
+}
+
+/// What is known about one input of the draft.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PsbtInput {
+    pub spent_output: Option<TxOut>,
+    // script the P2SH output commits to, needed to sign for it
+    pub redeem_script: Option<Script>,
+    pub sighash_type: SigHashType,
+    pub partial_signatures: Vec<(PublicKey, Vec<u8>)>,
+    pub final_script_sig: Option<Signature>,
+}
+
+impl PsbtInput {
+    fn partial_signature(&self, public_key: &PublicKey) -> Option<&[u8]> {
+        self.partial_signatures
+            .iter()
+            .find(|(key, _)| key == public_key)
+            .map(|(_, signature)| signature.as_slice())
+    }
+
+    fn add_partial_signature(&mut self, public_key: &PublicKey, signature: &[u8]) {
+        if self.partial_signature(public_key).is_none() {
+            self.partial_signatures
+                .push((public_key.clone(), signature.to_vec()));
+        }
+    }
+
+    // The script the signatures have to satisfy: the redeem script for P2SH outputs,
+    // with timelock wrappers removed since they do not change who signs.
+    fn signing_script(&self) -> Option<&Script> {
+        let mut script = match &self.spent_output.as_ref()?.script_pubkey {
+            Script::PayToScriptHash { .. } => self.redeem_script.as_ref()?,
+            script => script,
+        };
+
+        while let Script::AbsoluteTimeLock { script: inner, .. }
+        | Script::RelativeTimeLock { script: inner, .. } = script
+        {
+            script = inner.as_ref();
+        }
+
+        Some(script)
+    }
+
+    // Builds the script_sig from the partial signatures, if there are enough of them
+    fn final_script_sig(&self) -> Result<Option<Signature>, SignatureError> {
+        let Some(script) = self.signing_script() else {
+            return Ok(None);
+        };
+
+        let script_sig = match script {
+            Script::PayToPublicKeyHash { pub_key_hash } => self
+                .partial_signatures
+                .iter()
+                .find(|(public_key, _)| public_key.to_address() == *pub_key_hash)
+                .map(|(public_key, signature)| Signature::build(signature.clone(), public_key)),
+            Script::Multisig { .. } => {
+                let mut multisig = MultisigInput::new(script)?;
+                for (public_key, signature) in &self.partial_signatures {
+                    multisig.add_signature(public_key, signature.clone())?;
+                }
+                multisig.is_complete().then(|| multisig.to_script_sig())
+            }
+            Script::PayToSchnorrKey { x_only_key } => self
+                .partial_signatures
+                .iter()
+                .find(|(public_key, _)| public_key.to_x_only() == *x_only_key)
+                .map(|(_, signature)| Signature::from_bytes(signature)),
+            // other scripts, e.g. HTLCs, are finalized by their own helpers
+            _ => None,
+        };
+
+        Ok(match (&self.redeem_script, script_sig) {
+            (Some(redeem_script), Some(script_sig)) => {
+                Some(redeem_script.to_p2sh_script_sig(&script_sig))
+            }
+            (_, script_sig) => script_sig,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartiallySignedTransaction {
+    pub draft: DraftTransaction,
+    pub inputs: Vec<PsbtInput>,
+}
+
+impl PartiallySignedTransaction {
+    pub fn new(draft: DraftTransaction) -> Self {
+        let inputs = vec![PsbtInput::default(); draft.inputs.len()];
+        PartiallySignedTransaction { draft, inputs }
+    }
+
+    /// Creates a PSBT whose spent outputs, in input order, are already known.
+    pub fn with_spent_outputs(
+        draft: DraftTransaction,
+        spent_outputs: Vec<TxOut>,
+    ) -> Result<Self, PsbtError> {
+        let mut psbt = Self::new(draft);
+        for (input_index, spent_output) in spent_outputs.into_iter().enumerate() {
+            psbt.update_input(input_index, spent_output)?;
+        }
+
+        Ok(psbt)
+    }
+
+    pub fn update_input(
+        &mut self,
+        input_index: usize,
+        spent_output: TxOut,
+    ) -> Result<(), PsbtError> {
+        self.input_mut(input_index)?.spent_output = Some(spent_output);
+        Ok(())
+    }
+
+    /// Sets the redeem script of an input spending a P2SH output; it must match the output.
+    pub fn set_redeem_script(
+        &mut self,
+        input_index: usize,
+        redeem_script: Script,
+    ) -> Result<(), PsbtError> {
+        let input = self.input_mut(input_index)?;
+        let spent_output = input
+            .spent_output
+            .as_ref()
+            .ok_or(PsbtError::MissingSpentOutput(input_index))?;
+
+        if redeem_script.to_p2sh() != spent_output.script_pubkey {
+            return Err(PsbtError::RedeemScriptMismatch(input_index));
+        }
+
+        input.redeem_script = Some(redeem_script);
+        Ok(())
+    }
+
+    pub fn set_sighash_type(
+        &mut self,
+        input_index: usize,
+        sighash_type: SigHashType,
+    ) -> Result<(), PsbtError> {
+        self.input_mut(input_index)?.sighash_type = sighash_type;
+        Ok(())
+    }
+
+    /// Adds a signature for every input the signer holds a key for and returns their indexes.
+    /// Inputs without a spent output, or already finalized, are skipped.
+    pub fn sign(&mut self, signer: &impl Signer) -> Result<Vec<usize>, PsbtError> {
+        let mut signed = Vec::new();
+
+        for (input_index, input) in self.inputs.iter_mut().enumerate() {
+            let (Some(spent_output), None) = (input.spent_output.clone(), &input.final_script_sig)
+            else {
+                continue;
+            };
+
+            if let Some(Script::PayToSchnorrKey { x_only_key }) = input.signing_script() {
+                let Some(key) = signer.schnorr_key_for(x_only_key) else {
+                    continue;
+                };
+                if input.partial_signature(&key.public_key).is_none() {
+                    let signature = self.draft.sign_input_schnorr(
+                        input_index,
+                        &spent_output,
+                        key,
+                        input.sighash_type,
+                    )?;
+                    input.add_partial_signature(&key.public_key, &signature);
+                    signed.push(input_index);
+                }
+                continue;
+            }
+
+            let pub_key_hashes = match input.signing_script() {
+                Some(Script::PayToPublicKeyHash { pub_key_hash }) => vec![*pub_key_hash],
+                Some(Script::Multisig { public_keys, .. }) => {
+                    public_keys.iter().map(PublicKey::to_address).collect()
+                }
+                _ => continue,
+            };
+
+            for pub_key_hash in pub_key_hashes {
+                let Some(key) = signer.key_for(&pub_key_hash) else {
+                    continue;
+                };
+                if input.partial_signature(&key.public_key).is_some() {
+                    continue;
+                }
+
+                let signature =
+                    self.draft
+                        .sign_input(input_index, &spent_output, key, input.sighash_type)?;
+                input.add_partial_signature(&key.public_key, &signature);
+
+                if signed.last() != Some(&input_index) {
+                    signed.push(input_index);
+                }
+            }
+        }
+
+        Ok(signed)
+    }
+
+    /// Merges what another party added to a copy of the same PSBT.
+    pub fn combine(&mut self, other: &PartiallySignedTransaction) -> Result<(), PsbtError> {
+        if self.draft != other.draft || self.inputs.len() != other.inputs.len() {
+            return Err(PsbtError::DifferentTransactions);
+        }
+
+        for (input, other_input) in self.inputs.iter_mut().zip(&other.inputs) {
+            if input.spent_output.is_none() {
+                input.spent_output.clone_from(&other_input.spent_output);
+            }
+            if input.redeem_script.is_none() {
+                input.redeem_script.clone_from(&other_input.redeem_script);
+            }
+            if input.final_script_sig.is_none() {
+                input
+                    .final_script_sig
+                    .clone_from(&other_input.final_script_sig);
+            }
+
+            for (public_key, signature) in &other_input.partial_signatures {
+                input.add_partial_signature(public_key, signature);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds the script_sig of every input that has enough signatures and returns the
+    /// indexes of inputs that still cannot be finalized.
+    pub fn finalize(&mut self) -> Result<Vec<usize>, PsbtError> {
+        for input in self.inputs.iter_mut() {
+            if input.final_script_sig.is_some() {
+                continue;
+            }
+
+            if let Some(script_sig) = input.final_script_sig()? {
+                input.final_script_sig = Some(script_sig);
+                input.partial_signatures.clear();
+            }
+        }
+
+        Ok(self.unfinalized_inputs())
+    }
+
+    pub fn unfinalized_inputs(&self) -> Vec<usize> {
+        self.inputs
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, input)| input.final_script_sig.is_none().then_some(idx))
+            .collect()
+    }
+
+    /// The signed transaction, once every input is finalized. Its signatures are verified
+    /// against the spent outputs.
+    pub fn extract(self) -> Result<Transaction, PsbtError> {
+        let unfinalized = self.unfinalized_inputs();
+        if !unfinalized.is_empty() {
+            return Err(PsbtError::NotFinalized(unfinalized));
+        }
+
+        let mut spent_outputs = Vec::with_capacity(self.inputs.len());
+        let mut script_sigs = Vec::with_capacity(self.inputs.len());
+        for (input_index, input) in self.inputs.into_iter().enumerate() {
+            spent_outputs.push(
+                input
+                    .spent_output
+                    .ok_or(PsbtError::MissingSpentOutput(input_index))?,
+            );
+            script_sigs.extend(input.final_script_sig);
+        }
+
+        let tx = self.draft.into_transaction(script_sigs)?;
+        tx.verify_signatures(&spent_outputs)?;
+
+        Ok(tx)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let encoded = bincode::serde::encode_to_vec(self, standard())
+            .expect("Failed to serialize PSBT. This should not happen.");
+
+        [PSBT_MAGIC.as_slice(), &encoded].concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PsbtError> {
+        let encoded = bytes
+            .strip_prefix(PSBT_MAGIC.as_slice())
+            .ok_or_else(|| PsbtError::Encoding("Missing PSBT magic bytes".to_string()))?;
+
+        let (psbt, read): (PartiallySignedTransaction, usize) =
+            bincode::serde::decode_from_slice(encoded, standard())
+                .map_err(|e| PsbtError::Encoding(e.to_string()))?;
+
+        if read != encoded.len() {
+            return Err(PsbtError::Encoding("Trailing bytes after PSBT".to_string()));
+        }
+
+        if psbt.inputs.len() != psbt.draft.inputs.len() {
+            return Err(PsbtError::Encoding(format!(
+                "PSBT has {} inputs but its transaction has {}",
+                psbt.inputs.len(),
+                psbt.draft.inputs.len()
+            )));
+        }
+
+        Ok(psbt)
+    }
+
+    pub fn to_base64(&self) -> String {
+        STANDARD.encode(self.to_bytes())
+    }
+
+    pub fn from_base64(data: &str) -> Result<Self, PsbtError> {
+        let bytes = STANDARD
+            .decode(data.trim())
+            .map_err(|e| PsbtError::Encoding(e.to_string()))?;
+
+        Self::from_bytes(&bytes)
+    }
+
+    fn input_mut(&mut self, input_index: usize) -> Result<&mut PsbtInput, PsbtError> {
+        self.inputs
+            .get_mut(input_index)
+            .ok_or(PsbtError::InputOutOfRange(input_index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        keys::{BlockchainHash, KeyPair, PublicKeyHash},
+        signer::KeyStore,
+        timelock::SEQUENCE_FINAL,
+        transaction::UnsignedTxIn,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_cold_and_multi_party_signing() {
+        let hot = KeyPair::generate();
+        let cold = KeyPair::generate();
+        let cosigners: Vec<KeyPair> = (0..3).map(|_| KeyPair::generate()).collect();
+
+        let redeem_script = Script::multisig(
+            2,
+            cosigners.iter().map(|key| key.public_key.clone()).collect(),
+        )
+        .unwrap();
+        let spent_outputs = vec![
+            TxOut {
+                value: 10,
+                script_pubkey: Script::PayToPublicKeyHash {
+                    pub_key_hash: cold.public_key.to_address(),
+                },
+            },
+            TxOut {
+                value: 10,
+                script_pubkey: redeem_script.to_p2sh(),
+            },
+        ];
+
+        let inputs = (0..2)
+            .map(|idx| UnsignedTxIn {
+                prev_tx_id: BlockchainHash::default(),
+                prev_out_idx: idx,
+                sequence: SEQUENCE_FINAL,
+            })
+            .collect();
+        let outputs = vec![TxOut {
+            value: 19,
+            script_pubkey: Script::PayToPublicKeyHash {
+                pub_key_hash: PublicKeyHash::new([1u8; 20]),
+            },
+        }];
+
+        // a watch-only wallet creates the PSBT, nobody else needs chain access
+        let mut psbt = PartiallySignedTransaction::with_spent_outputs(
+            DraftTransaction::new(inputs, outputs),
+            spent_outputs,
+        )
+        .unwrap();
+        psbt.set_redeem_script(1, redeem_script).unwrap();
+        let encoded = psbt.to_base64();
+
+        // keys that own nothing in the PSBT sign nothing
+        assert!(psbt.sign(&hot).unwrap().is_empty());
+
+        let mut cold_copy = PartiallySignedTransaction::from_base64(&encoded).unwrap();
+        assert_eq!(cold_copy.sign(&cold).unwrap(), vec![0]);
+
+        let mut first_cosigner = PartiallySignedTransaction::from_bytes(&psbt.to_bytes()).unwrap();
+        assert_eq!(first_cosigner.sign(&cosigners[0]).unwrap(), vec![1]);
+
+        psbt.combine(&cold_copy).unwrap();
+        psbt.combine(&first_cosigner).unwrap();
+        assert_eq!(psbt.finalize().unwrap(), vec![1]);
+        assert!(psbt.clone().extract().is_err());
+
+        let mut second_cosigner = PartiallySignedTransaction::from_base64(&encoded).unwrap();
+        let keys: KeyStore = [cosigners[2].clone()].into_iter().collect();
+        second_cosigner.sign(&keys).unwrap();
+        psbt.combine(&second_cosigner).unwrap();
+        assert!(psbt.finalize().unwrap().is_empty());
+
+        let tx = PartiallySignedTransaction::from_base64(&psbt.to_base64())
+            .unwrap()
+            .extract()
+            .unwrap();
+        assert_eq!(tx.inputs.len(), 2);
+
+        assert!(PartiallySignedTransaction::from_base64("cHNidA==").is_err());
+    }
+}
@@ -0,0 +1,221 @@
+// Schnorr signatures (BIP 340) over x-only public keys.
+//
+// A `Script::PayToSchnorrKey` output is spent with a 64 byte Schnorr signature plus the sighash
+// type byte. Schnorr signatures are linear, so many of them can be checked together: with
+// random weights a_i, every (R_i, s_i) over key P_i and challenge e_i is valid only if
+//     (sum a_i * s_i) * G == sum a_i * R_i + sum (a_i * e_i) * P_i
+// which is a single multi-scalar multiplication instead of one verification per signature.
+
+use k256::{
+    NonZeroScalar, ProjectivePoint, Scalar, U256,
+    elliptic_curve::{
+        Field,
+        ops::{LinearCombinationExt, Reduce},
+    },
+    schnorr::{Signature as SchnorrSignature, VerifyingKey},
+};
+use rand::rngs::OsRng;
+use sha2::Digest;
+
+use crate::{
+    crypto::tagged_hash,
+    keys::{BlockchainHash, SignatureError, XOnlyPublicKey},
+};
+
+pub const SCHNORR_SIGNATURE_LEN: usize = 64;
+
+const CHALLENGE_TAG: &[u8] = b"BIP0340/challenge";
+
+/// Verifies one Schnorr signature of `message` by `public_key`.
+pub fn verify_schnorr(
+    public_key: &XOnlyPublicKey,
+    message: &BlockchainHash,
+    signature: &[u8],
+) -> Result<(), SignatureError> {
+    let verifying_key = parse_x_only_key(public_key)?;
+    let signature = parse_signature(signature)?;
+
+    verifying_key
+        .verify_raw(message.as_ref(), &signature)
+        .map_err(|_| {
+            SignatureError::InvalidScript(
+                "Invalid Schnorr signature for transaction input".to_string(),
+            )
+        })
+}
+
+/// Schnorr signatures collected for verification in one go, e.g. all of a block's.
+#[derive(Debug, Clone, Default)]
+pub struct SchnorrBatch {
+    items: Vec<BatchItem>,
+}
+
+#[derive(Debug, Clone)]
+struct BatchItem {
+    public_key: XOnlyPublicKey,
+    message: BlockchainHash,
+    signature: [u8; SCHNORR_SIGNATURE_LEN],
+}
+
+impl SchnorrBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a signature; malformed encodings are rejected right away.
+    pub fn push(
+        &mut self,
+        public_key: &XOnlyPublicKey,
+        message: &BlockchainHash,
+        signature: &[u8],
+    ) -> Result<(), SignatureError> {
+        parse_signature(signature)?;
+
+        let mut signature_bytes = [0u8; SCHNORR_SIGNATURE_LEN];
+        signature_bytes.copy_from_slice(signature);
+        self.items.push(BatchItem {
+            public_key: *public_key,
+            message: *message,
+            signature: signature_bytes,
+        });
+
+        Ok(())
+    }
+
+    pub fn append(&mut self, other: SchnorrBatch) {
+        self.items.extend(other.items);
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Succeeds only if every queued signature is valid. When the batch fails, the signatures
+    /// are checked one by one so the error names the first invalid one.
+    pub fn verify(&self) -> Result<(), SignatureError> {
+        if self.items.len() > 1 && self.verify_batch()? {
+            return Ok(());
+        }
+
+        self.items.iter().enumerate().try_for_each(|(idx, item)| {
+            verify_schnorr(&item.public_key, &item.message, &item.signature).map_err(|_| {
+                SignatureError::InvalidScript(format!(
+                    "Schnorr signature {} of batch is invalid",
+                    idx
+                ))
+            })
+        })
+    }
+
+    fn verify_batch(&self) -> Result<bool, SignatureError> {
+        let mut terms = Vec::with_capacity(2 * self.items.len() + 1);
+        let mut generator_scalar = Scalar::ZERO;
+
+        for (idx, item) in self.items.iter().enumerate() {
+            let (r_bytes, s_bytes) = item.signature.split_at(SCHNORR_SIGNATURE_LEN / 2);
+
+            // R is the point with x coordinate r and even y, lifted like an x-only key
+            let r_point = VerifyingKey::from_bytes(r_bytes).map_err(|_| {
+                SignatureError::InvalidSignatureFormat(
+                    "Schnorr nonce is not on the curve".to_string(),
+                )
+            })?;
+            let public_key = parse_x_only_key(&item.public_key)?;
+            let s = NonZeroScalar::try_from(s_bytes).map_err(|_| {
+                SignatureError::InvalidSignatureFormat("Schnorr s is out of range".to_string())
+            })?;
+
+            let challenge = <Scalar as Reduce<U256>>::reduce_bytes(
+                &tagged_hash(CHALLENGE_TAG)
+                    .chain_update(r_bytes)
+                    .chain_update(item.public_key.as_bytes())
+                    .chain_update(item.message.as_ref())
+                    .finalize(),
+            );
+
+            // the first weight can be 1 without weakening the check
+            let weight = if idx == 0 {
+                Scalar::ONE
+            } else {
+                Scalar::random(&mut OsRng)
+            };
+
+            generator_scalar += weight * *s;
+            terms.push((ProjectivePoint::from(*r_point.as_affine()), -weight));
+            terms.push((
+                ProjectivePoint::from(*public_key.as_affine()),
+                -(weight * challenge),
+            ));
+        }
+
+        terms.push((ProjectivePoint::GENERATOR, generator_scalar));
+
+        Ok(ProjectivePoint::lincomb_ext(terms.as_slice()) == ProjectivePoint::IDENTITY)
+    }
+}
+
+fn parse_x_only_key(public_key: &XOnlyPublicKey) -> Result<VerifyingKey, SignatureError> {
+    VerifyingKey::from_bytes(public_key.as_bytes()).map_err(|_| {
+        SignatureError::InvalidPublicKeyFormat(format!("Invalid x-only public key {}", public_key))
+    })
+}
+
+fn parse_signature(signature: &[u8]) -> Result<SchnorrSignature, SignatureError> {
+    if signature.len() != SCHNORR_SIGNATURE_LEN {
+        return Err(SignatureError::InvalidSignatureFormat(format!(
+            "Expected a {} byte Schnorr signature, got {} bytes",
+            SCHNORR_SIGNATURE_LEN,
+            signature.len()
+        )));
+    }
+
+    SchnorrSignature::try_from(signature).map_err(|_| {
+        SignatureError::InvalidSignatureFormat("Invalid Schnorr signature encoding".to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keys::KeyPair;
+
+    use super::*;
+
+    #[test]
+    fn test_schnorr_batch_verification() {
+        let mut batch = SchnorrBatch::new();
+        let mut signed = Vec::new();
+
+        for idx in 0..5u8 {
+            let key = KeyPair::generate();
+            let message = BlockchainHash::new([idx; 32]);
+            let signature = key.sign_schnorr(message.as_ref()).unwrap();
+
+            verify_schnorr(&key.x_only_public_key(), &message, &signature).unwrap();
+            batch
+                .push(&key.x_only_public_key(), &message, &signature)
+                .unwrap();
+            signed.push((key, message, signature));
+        }
+        assert!(batch.verify_batch().unwrap());
+        assert!(batch.verify().is_ok());
+
+        // a valid signature over the wrong message breaks the whole batch
+        let (key, _, signature) = &signed[2];
+        let wrong_message = BlockchainHash::new([9u8; 32]);
+        batch
+            .push(&key.x_only_public_key(), &wrong_message, signature)
+            .unwrap();
+        assert!(!batch.verify_batch().unwrap());
+        assert!(batch.verify().is_err());
+
+        assert!(
+            SchnorrBatch::new()
+                .push(&key.x_only_public_key(), &wrong_message, &signature[1..])
+                .is_err()
+        );
+    }
+}
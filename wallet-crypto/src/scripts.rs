@@ -1,9 +1,525 @@
-use bincode::Encode;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::keys::PublicKeyHash;
+use crate::{
+    crypto::{
+        calculate_p2pkh_hash, calculate_script_hash, encode_pushes, parse_p2pkh_script_sig_k256,
+        parse_pushes,
+    },
+    encoding::{self, Decodable, Encodable, EncodingError, Reader, write_var_bytes},
+    keys::{
+        Address, PublicKey, PublicKeyHash, ScriptHash, Signature, SignatureError, XOnlyPublicKey,
+    },
+};
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Encode)]
+mod multisig;
+
+pub use multisig::{MAX_MULTISIG_KEYS, MultisigInput};
+
+// HTLC preimages have a fixed size, so a swap can't be claimed on one chain with a
+// preimage the other chain would refuse.
+pub const HTLC_PREIMAGE_LEN: usize = 32;
+
+pub const MAX_DATA_CARRIER_BYTES: usize = 80;
+
+// Timelock scripts wrap other scripts; decoding stops at this nesting depth.
+const MAX_SCRIPT_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Script {
-    PayToPublicKeyHash { pub_key_hash: PublicKeyHash }, // Add other script types (e.g., PayToScriptHash) later if needed
+    PayToPublicKeyHash { pub_key_hash: PublicKeyHash },
+    // Spendable with valid signatures from at least `required` of `public_keys`.
+    Multisig { required: u8, public_keys: Vec<PublicKey> },
+    // Commits to the hash of a redeem script; the spender reveals the script and satisfies it.
+    PayToScriptHash { script_hash: ScriptHash },
+    // `script` that can only be spent by a transaction whose lock_time reaches `lock_time`
+    // (block height or unix time, like CHECKLOCKTIMEVERIFY).
+    AbsoluteTimeLock { lock_time: u32, script: Box<Script> },
+    // `script` that can only be spent once the spending input's sequence enables a relative
+    // lock of at least `sequence` (like CHECKSEQUENCEVERIFY).
+    RelativeTimeLock { sequence: u32, script: Box<Script> },
+    // Hash time-locked contract: `recipient` spends by revealing the SHA256 preimage of
+    // `payment_hash`, or `refund` spends once the transaction lock time reaches `timeout`.
+    HashTimeLock {
+        payment_hash: [u8; 32],
+        recipient: PublicKeyHash,
+        refund: PublicKeyHash,
+        timeout: u32,
+    },
+    // Provably unspendable output embedding up to `MAX_DATA_CARRIER_BYTES` of arbitrary data
+    // (like OP_RETURN). It never enters the UTXO set.
+    DataCarrier { data: Vec<u8> },
+    // Spendable with a BIP 340 Schnorr signature by `x_only_key`, the script_sig is just the
+    // signature (key-path spending).
+    PayToSchnorrKey { x_only_key: XOnlyPublicKey },
+}
+
+/// What a script needs to know about the transaction spending it.
+pub trait SignatureChecker {
+    /// Checks an ECDSA `signature` by `public_key` over the spending transaction.
+    fn check_signature(&self, signature: &[u8], public_key: &PublicKey)
+    -> Result<(), SignatureError>;
+
+    /// Checks a Schnorr `signature` by `public_key` over the spending transaction.
+    fn check_schnorr_signature(
+        &self,
+        signature: &[u8],
+        public_key: &XOnlyPublicKey,
+    ) -> Result<(), SignatureError>;
+
+    /// Whether the transaction lock time satisfies an absolute lock of `lock_time`.
+    fn check_lock_time(&self, lock_time: u32) -> bool;
+
+    /// Whether the spending input's sequence satisfies a relative lock of `sequence`.
+    fn check_sequence(&self, sequence: u32) -> bool;
+}
+
+impl Script {
+    /// Builds an M-of-N multisig locking script, checking the key count and threshold.
+    pub fn multisig(required: u8, public_keys: Vec<PublicKey>) -> Result<Script, SignatureError> {
+        let script = Script::Multisig {
+            required,
+            public_keys,
+        };
+        MultisigInput::new(&script)?;
+
+        Ok(script)
+    }
+
+    /// Builds a data-carrier output script, checking the payload size.
+    pub fn data_carrier(data: &[u8]) -> Result<Script, SignatureError> {
+        if data.len() > MAX_DATA_CARRIER_BYTES {
+            return Err(SignatureError::InvalidScript(format!(
+                "Data carrier payload is {} bytes, at most {} allowed",
+                data.len(),
+                MAX_DATA_CARRIER_BYTES
+            )));
+        }
+
+        Ok(Script::DataCarrier {
+            data: data.to_vec(),
+        })
+    }
+
+    /// Outputs locked by an unspendable script can never be spent and are not tracked as UTXOs.
+    pub fn is_unspendable(&self) -> bool {
+        matches!(self, Script::DataCarrier { .. })
+    }
+
+    /// Canonical serialization used for script hashes and for revealing redeem scripts.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encoding::serialize(self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Script, SignatureError> {
+        encoding::deserialize(bytes)
+            .map_err(|e| SignatureError::InvalidScript(format!("Failed to decode script: {}", e)))
+    }
+
+    pub fn script_hash(&self) -> ScriptHash {
+        calculate_script_hash(&self.to_bytes())
+    }
+
+    /// Pay-to-script-hash output script committing to this redeem script.
+    pub fn to_p2sh(&self) -> Script {
+        Script::PayToScriptHash {
+            script_hash: self.script_hash(),
+        }
+    }
+
+    /// Wraps a script_sig that satisfies this redeem script into one that spends
+    /// the matching P2SH output: pushes of [inner script_sig, redeem script].
+    pub fn to_p2sh_script_sig(&self, script_sig: &Signature) -> Signature {
+        let redeem_script = self.to_bytes();
+        Signature::from_bytes(&encode_pushes(&[script_sig.as_bytes(), &redeem_script]))
+    }
+
+    /// Address that outputs locked by this script pay to, if it has one.
+    pub fn address(&self) -> Option<Address> {
+        match self {
+            Script::PayToPublicKeyHash { pub_key_hash } => Some((*pub_key_hash).into()),
+            Script::PayToScriptHash { script_hash } => Some((*script_hash).into()),
+            Script::PayToSchnorrKey { x_only_key } => Some((*x_only_key).into()),
+            // Bare multisig and timelocked outputs are not owned by a single address,
+            // wrap them in P2SH to get one
+            Script::Multisig { .. }
+            | Script::AbsoluteTimeLock { .. }
+            | Script::RelativeTimeLock { .. }
+            | Script::HashTimeLock { .. } => None,
+            Script::DataCarrier { .. } => None,
+        }
+    }
+
+    /// Checks that `script_sig` unlocks an output locked by this script, using `checker`
+    /// for everything that depends on the spending transaction.
+    pub fn verify(
+        &self,
+        script_sig: &Signature,
+        checker: &impl SignatureChecker,
+    ) -> Result<(), SignatureError> {
+        match self {
+            Script::PayToPublicKeyHash { pub_key_hash } => {
+                let (signature, public_key_bytes) =
+                    parse_p2pkh_script_sig_k256(script_sig.as_bytes()).map_err(|e| {
+                        SignatureError::ScriptSigParseError(format!(
+                            "Failed to parse P2PKH script_sig: {}",
+                            e
+                        ))
+                    })?;
+
+                if &calculate_p2pkh_hash(&public_key_bytes) != pub_key_hash {
+                    return Err(SignatureError::InvalidScript(
+                        "Public key hash mismatch in P2PKH script".to_string(),
+                    ));
+                }
+
+                let public_key = PublicKey::from_bytes(&public_key_bytes)?;
+                checker.check_signature(&signature, &public_key)
+            }
+            Script::Multisig { .. } => {
+                MultisigInput::from_script_sig(self, script_sig)?.verify(checker)
+            }
+            Script::PayToSchnorrKey { x_only_key } => {
+                checker.check_schnorr_signature(script_sig.as_bytes(), x_only_key)
+            }
+            Script::PayToScriptHash { script_hash } => {
+                let pushes = parse_pushes(script_sig.as_bytes()).map_err(|e| {
+                    SignatureError::ScriptSigParseError(format!(
+                        "Failed to parse P2SH script_sig: {}",
+                        e
+                    ))
+                })?;
+
+                let [inner_script_sig, redeem_script_bytes] = pushes.as_slice() else {
+                    return Err(SignatureError::ScriptSigParseError(
+                        "P2SH script_sig must contain a script_sig and a redeem script".to_string(),
+                    ));
+                };
+
+                if &calculate_script_hash(redeem_script_bytes) != script_hash {
+                    return Err(SignatureError::InvalidScript(
+                        "Redeem script does not match P2SH script hash".to_string(),
+                    ));
+                }
+
+                let redeem_script = Script::from_bytes(redeem_script_bytes)?;
+                if let Script::PayToScriptHash { .. } = redeem_script {
+                    return Err(SignatureError::InvalidScript(
+                        "Nested P2SH redeem scripts are not allowed".to_string(),
+                    ));
+                }
+
+                redeem_script.verify(&Signature::from_bytes(inner_script_sig), checker)
+            }
+            Script::AbsoluteTimeLock { lock_time, script } => {
+                if !checker.check_lock_time(*lock_time) {
+                    return Err(SignatureError::InvalidScript(format!(
+                        "Absolute timelock {} is not satisfied by the transaction",
+                        lock_time
+                    )));
+                }
+
+                script.verify(script_sig, checker)
+            }
+            Script::RelativeTimeLock { sequence, script } => {
+                if !checker.check_sequence(*sequence) {
+                    return Err(SignatureError::InvalidScript(format!(
+                        "Relative timelock {:#x} is not satisfied by the input sequence",
+                        sequence
+                    )));
+                }
+
+                script.verify(script_sig, checker)
+            }
+            Script::HashTimeLock {
+                payment_hash,
+                recipient,
+                refund,
+                timeout,
+            } => {
+                let pushes = parse_pushes(script_sig.as_bytes()).map_err(|e| {
+                    SignatureError::ScriptSigParseError(format!(
+                        "Failed to parse HTLC script_sig: {}",
+                        e
+                    ))
+                })?;
+
+                match pushes.as_slice() {
+                    // claim: [P2PKH script_sig, preimage]
+                    [signature, preimage] => {
+                        if preimage.len() != HTLC_PREIMAGE_LEN
+                            || Sha256::digest(preimage).as_slice() != payment_hash
+                        {
+                            return Err(SignatureError::InvalidScript(
+                                "Preimage does not match HTLC payment hash".to_string(),
+                            ));
+                        }
+
+                        Script::PayToPublicKeyHash {
+                            pub_key_hash: *recipient,
+                        }
+                        .verify(&Signature::from_bytes(signature), checker)
+                    }
+                    // refund: [P2PKH script_sig]
+                    [signature] => {
+                        if !checker.check_lock_time(*timeout) {
+                            return Err(SignatureError::InvalidScript(format!(
+                                "HTLC refund is locked until {}",
+                                timeout
+                            )));
+                        }
+
+                        Script::PayToPublicKeyHash {
+                            pub_key_hash: *refund,
+                        }
+                        .verify(&Signature::from_bytes(signature), checker)
+                    }
+                    _ => Err(SignatureError::ScriptSigParseError(
+                        "HTLC script_sig must be a claim or a refund".to_string(),
+                    )),
+                }
+            }
+            Script::DataCarrier { .. } => Err(SignatureError::InvalidScript(
+                "Data carrier outputs are unspendable".to_string(),
+            )),
+        }
+    }
+}
+
+// Each script starts with a tag byte naming the variant, followed by its fields in order.
+impl Encodable for Script {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Script::PayToPublicKeyHash { pub_key_hash } => {
+                out.push(0);
+                pub_key_hash.encode(out);
+            }
+            Script::Multisig {
+                required,
+                public_keys,
+            } => {
+                out.push(1);
+                required.encode(out);
+                public_keys.encode(out);
+            }
+            Script::PayToScriptHash { script_hash } => {
+                out.push(2);
+                script_hash.encode(out);
+            }
+            Script::AbsoluteTimeLock { lock_time, script } => {
+                out.push(3);
+                lock_time.encode(out);
+                script.encode(out);
+            }
+            Script::RelativeTimeLock { sequence, script } => {
+                out.push(4);
+                sequence.encode(out);
+                script.encode(out);
+            }
+            Script::HashTimeLock {
+                payment_hash,
+                recipient,
+                refund,
+                timeout,
+            } => {
+                out.push(5);
+                out.extend_from_slice(payment_hash);
+                recipient.encode(out);
+                refund.encode(out);
+                timeout.encode(out);
+            }
+            Script::DataCarrier { data } => {
+                out.push(6);
+                write_var_bytes(out, data);
+            }
+            Script::PayToSchnorrKey { x_only_key } => {
+                out.push(7);
+                x_only_key.encode(out);
+            }
+        }
+    }
+}
+
+impl Decodable for Script {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        decode_script(reader, 0)
+    }
+}
+
+fn decode_script(reader: &mut Reader<'_>, depth: usize) -> Result<Script, EncodingError> {
+    if depth > MAX_SCRIPT_DEPTH {
+        return Err(EncodingError::InvalidValue(
+            "Script is nested too deeply".to_string(),
+        ));
+    }
+
+    Ok(match reader.read_u8()? {
+        0 => Script::PayToPublicKeyHash {
+            pub_key_hash: PublicKeyHash::decode(reader)?,
+        },
+        1 => Script::Multisig {
+            required: reader.read_u8()?,
+            public_keys: Vec::decode(reader)?,
+        },
+        2 => Script::PayToScriptHash {
+            script_hash: ScriptHash::decode(reader)?,
+        },
+        3 => Script::AbsoluteTimeLock {
+            lock_time: reader.read_u32()?,
+            script: Box::new(decode_script(reader, depth + 1)?),
+        },
+        4 => Script::RelativeTimeLock {
+            sequence: reader.read_u32()?,
+            script: Box::new(decode_script(reader, depth + 1)?),
+        },
+        5 => Script::HashTimeLock {
+            payment_hash: reader.read_array()?,
+            recipient: PublicKeyHash::decode(reader)?,
+            refund: PublicKeyHash::decode(reader)?,
+            timeout: reader.read_u32()?,
+        },
+        6 => Script::DataCarrier {
+            data: reader.read_var_bytes()?.to_vec(),
+        },
+        7 => Script::PayToSchnorrKey {
+            x_only_key: XOnlyPublicKey::decode(reader)?,
+        },
+        tag => {
+            return Err(EncodingError::InvalidValue(format!(
+                "Unknown script tag {}",
+                tag
+            )));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        keys::{BlockchainHash, KeyPair},
+        sighash::SigHashType,
+        timelock::{LOCKTIME_THRESHOLD, SEQUENCE_FINAL},
+        transaction::{DraftTransaction, Transaction, TxOut, UnsignedTxIn},
+    };
+
+    use super::*;
+
+    fn draft_spending_one_output(sequence: u32, lock_time: u32) -> DraftTransaction {
+        let input = UnsignedTxIn {
+            prev_tx_id: BlockchainHash::default(),
+            prev_out_idx: 0,
+            sequence,
+        };
+        let output = TxOut {
+            value: 10,
+            script_pubkey: Script::PayToPublicKeyHash {
+                pub_key_hash: PublicKeyHash::new([1u8; 20]),
+            },
+        };
+
+        DraftTransaction::new(vec![input], vec![output]).with_lock_time(lock_time)
+    }
+
+    // output spent by the single input of the test drafts
+    fn spent(script: &Script) -> TxOut {
+        TxOut {
+            value: 20,
+            script_pubkey: script.clone(),
+        }
+    }
+
+    fn sign_p2pkh(draft: &DraftTransaction, key: &KeyPair, spent: &TxOut) -> Signature {
+        let signature = draft.sign_input(0, spent, key, SigHashType::ALL).unwrap();
+        Signature::build(signature, &key.public_key)
+    }
+
+    fn finish(draft: DraftTransaction, script_sig: Signature) -> Transaction {
+        draft.into_transaction(vec![script_sig]).unwrap()
+    }
+
+    #[test]
+    fn test_p2sh_multisig_spend() {
+        let keys: Vec<KeyPair> = (0..3).map(|_| KeyPair::generate()).collect();
+        let redeem_script =
+            Script::multisig(2, keys.iter().map(|key| key.public_key.clone()).collect()).unwrap();
+        let p2sh = redeem_script.to_p2sh();
+
+        let p2sh = spent(&p2sh);
+
+        let draft = draft_spending_one_output(SEQUENCE_FINAL, 0);
+
+        let mut input = MultisigInput::new(&redeem_script).unwrap();
+        input
+            .sign(&keys[0], &draft, 0, &p2sh, SigHashType::ALL)
+            .unwrap();
+        input
+            .sign(&keys[1], &draft, 0, &p2sh, SigHashType::ALL)
+            .unwrap();
+
+        let script_sig = redeem_script.to_p2sh_script_sig(&input.to_script_sig());
+        let tx = finish(draft.clone(), script_sig);
+        assert!(tx.verify_signatures(std::slice::from_ref(&p2sh)).is_ok());
+
+        // revealing a different script than the one committed to must fail
+        let other_script =
+            Script::multisig(1, keys.iter().map(|key| key.public_key.clone()).collect()).unwrap();
+        let wrong_script_sig = other_script.to_p2sh_script_sig(&input.to_script_sig());
+        let tx = finish(draft, wrong_script_sig);
+        assert!(tx.verify_signatures(&[p2sh]).is_err());
+    }
+
+    #[test]
+    fn test_script_bytes_round_trip() {
+        let key = KeyPair::generate();
+        let script = Script::multisig(1, vec![key.public_key]).unwrap();
+
+        let bytes = script.to_bytes();
+        assert_eq!(Script::from_bytes(&bytes).unwrap(), script);
+
+        let mut with_trailing = bytes.clone();
+        with_trailing.push(0);
+        assert!(Script::from_bytes(&with_trailing).is_err());
+    }
+
+    #[test]
+    fn test_absolute_timelock_script() {
+        let key = KeyPair::generate();
+        let unlock_time = LOCKTIME_THRESHOLD + 1_000;
+        let script = Script::AbsoluteTimeLock {
+            lock_time: unlock_time,
+            script: Box::new(Script::PayToPublicKeyHash {
+                pub_key_hash: key.public_key.to_address(),
+            }),
+        };
+
+        let spend = |sequence, lock_time| {
+            let draft = draft_spending_one_output(sequence, lock_time);
+            let script_sig = sign_p2pkh(&draft, &key, &spent(&script));
+            finish(draft, script_sig).verify_signatures(&[spent(&script)])
+        };
+
+        assert!(spend(0, unlock_time).is_ok());
+        assert!(spend(0, unlock_time - 1).is_err());
+        // a final sequence disables the transaction lock time
+        assert!(spend(SEQUENCE_FINAL, unlock_time).is_err());
+        // a height lock cannot satisfy a time lock
+        assert!(spend(0, 100).is_err());
+    }
+
+    #[test]
+    fn test_data_carrier_is_unspendable() {
+        assert!(Script::data_carrier(&[0u8; MAX_DATA_CARRIER_BYTES + 1]).is_err());
+
+        let script = Script::data_carrier(b"document hash").unwrap();
+        assert!(script.is_unspendable());
+        assert_eq!(script.address(), None);
+
+        let draft = draft_spending_one_output(SEQUENCE_FINAL, 0);
+        let script_sig = sign_p2pkh(&draft, &KeyPair::generate(), &spent(&script));
+        assert!(
+            finish(draft, script_sig)
+                .verify_signatures(&[spent(&script)])
+                .is_err()
+        );
+    }
 }
@@ -0,0 +1,247 @@
+use crate::{
+    crypto::{encode_pushes, parse_pushes},
+    keys::{KeyPair, PublicKey, Signature, SignatureError},
+    scripts::{Script, SignatureChecker},
+    sighash::SigHashType,
+    transaction::{DraftTransaction, TxOut},
+};
+
+pub const MAX_MULTISIG_KEYS: usize = 15;
+
+/// Signatures collected for an input spending a `Script::Multisig` output.
+///
+/// Keeps one slot per public key (in script order), so signers can sign independently
+/// and their partial inputs can be combined. The script_sig is the list of slots encoded
+/// as pushes, where an empty push marks a key that did not sign.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultisigInput {
+    required: u8,
+    public_keys: Vec<PublicKey>,
+    signatures: Vec<Option<Vec<u8>>>,
+}
+
+impl MultisigInput {
+    pub fn new(script: &Script) -> Result<Self, SignatureError> {
+        let Script::Multisig {
+            required,
+            public_keys,
+        } = script
+        else {
+            return Err(SignatureError::InvalidScript(
+                "Script is not a multisig script".to_string(),
+            ));
+        };
+
+        if public_keys.is_empty() || public_keys.len() > MAX_MULTISIG_KEYS {
+            return Err(SignatureError::InvalidScript(format!(
+                "Multisig must have between 1 and {} public keys, got {}",
+                MAX_MULTISIG_KEYS,
+                public_keys.len()
+            )));
+        }
+
+        if *required == 0 || *required as usize > public_keys.len() {
+            return Err(SignatureError::InvalidScript(format!(
+                "Invalid multisig threshold {} of {}",
+                required,
+                public_keys.len()
+            )));
+        }
+
+        for (idx, public_key) in public_keys.iter().enumerate() {
+            if public_keys[idx + 1..].contains(public_key) {
+                return Err(SignatureError::InvalidScript(format!(
+                    "Duplicate public key in multisig script: {:x}",
+                    public_key
+                )));
+            }
+        }
+
+        Ok(MultisigInput {
+            required: *required,
+            public_keys: public_keys.clone(),
+            signatures: vec![None; public_keys.len()],
+        })
+    }
+
+    pub fn from_script_sig(script: &Script, script_sig: &Signature) -> Result<Self, SignatureError> {
+        let mut input = Self::new(script)?;
+
+        let pushes = parse_pushes(script_sig.as_bytes()).map_err(|e| {
+            SignatureError::ScriptSigParseError(format!("Failed to parse multisig script_sig: {}", e))
+        })?;
+
+        if pushes.len() != input.public_keys.len() {
+            return Err(SignatureError::ScriptSigParseError(format!(
+                "Expected {} multisig signature slots, found {}",
+                input.public_keys.len(),
+                pushes.len()
+            )));
+        }
+
+        input.signatures = pushes
+            .into_iter()
+            .map(|push| (!push.is_empty()).then_some(push))
+            .collect();
+
+        Ok(input)
+    }
+
+    /// Adds the signature of `key` for input `input_index` of `draft`, which spends `spent_output`.
+    /// The key must be one of the script's public keys.
+    pub fn sign(
+        &mut self,
+        key: &KeyPair,
+        draft: &DraftTransaction,
+        input_index: usize,
+        spent_output: &TxOut,
+        sighash_type: SigHashType,
+    ) -> Result<(), SignatureError> {
+        let signature = draft.sign_input(input_index, spent_output, key, sighash_type)?;
+        self.add_signature(&key.public_key, signature)
+    }
+
+    /// Puts a signature made elsewhere, e.g. one carried in a PSBT, into the slot of `public_key`.
+    pub fn add_signature(
+        &mut self,
+        public_key: &PublicKey,
+        signature: Vec<u8>,
+    ) -> Result<(), SignatureError> {
+        let position = self
+            .public_keys
+            .iter()
+            .position(|key| key == public_key)
+            .ok_or_else(|| {
+                SignatureError::InvalidScript("Key is not part of the multisig script".to_string())
+            })?;
+
+        self.signatures[position] = Some(signature);
+
+        Ok(())
+    }
+
+    /// Merges signatures gathered by another signer for the same script.
+    pub fn combine(&mut self, other: &MultisigInput) -> Result<(), SignatureError> {
+        if self.required != other.required || self.public_keys != other.public_keys {
+            return Err(SignatureError::InvalidScript(
+                "Cannot combine signatures for different multisig scripts".to_string(),
+            ));
+        }
+
+        for (slot, other_slot) in self.signatures.iter_mut().zip(&other.signatures) {
+            if slot.is_none() {
+                slot.clone_from(other_slot);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn signature_count(&self) -> usize {
+        self.signatures.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.signature_count() >= self.required as usize
+    }
+
+    pub fn to_script_sig(&self) -> Signature {
+        let items: Vec<&[u8]> = self
+            .signatures
+            .iter()
+            .map(|slot| slot.as_deref().unwrap_or_default())
+            .collect();
+
+        Signature::from_bytes(&encode_pushes(&items))
+    }
+
+    /// Every present signature must be valid for its key, and at least `required` must be present.
+    pub fn verify(&self, checker: &impl SignatureChecker) -> Result<(), SignatureError> {
+        for (public_key, signature) in self.public_keys.iter().zip(&self.signatures) {
+            let Some(signature) = signature else {
+                continue;
+            };
+
+            checker.check_signature(signature, public_key)?;
+        }
+
+        if !self.is_complete() {
+            return Err(SignatureError::InvalidScript(format!(
+                "Multisig requires {} signatures, found {}",
+                self.required,
+                self.signature_count()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        keys::{BlockchainHash, PublicKeyHash},
+        timelock::SEQUENCE_FINAL,
+        transaction::UnsignedTxIn,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_two_of_three_partial_signing_and_combining() {
+        let keys: Vec<KeyPair> = (0..3).map(|_| KeyPair::generate()).collect();
+        let script = Script::multisig(
+            2,
+            keys.iter().map(|key| key.public_key.clone()).collect(),
+        )
+        .unwrap();
+
+        let draft = DraftTransaction::new(
+            vec![UnsignedTxIn {
+                prev_tx_id: BlockchainHash::default(),
+                prev_out_idx: 0,
+                sequence: SEQUENCE_FINAL,
+            }],
+            vec![TxOut {
+                value: 10,
+                script_pubkey: Script::PayToPublicKeyHash {
+                    pub_key_hash: PublicKeyHash::new([1u8; 20]),
+                },
+            }],
+        );
+        let spent = TxOut {
+            value: 20,
+            script_pubkey: script.clone(),
+        };
+
+        let mut first = MultisigInput::new(&script).unwrap();
+        first.sign(&keys[0], &draft, 0, &spent, SigHashType::ALL).unwrap();
+        assert!(!first.is_complete());
+        let tx = draft.clone().into_transaction(vec![first.to_script_sig()]).unwrap();
+        assert!(tx.verify_signatures(std::slice::from_ref(&spent)).is_err());
+
+        let mut second = MultisigInput::new(&script).unwrap();
+        second.sign(&keys[2], &draft, 0, &spent, SigHashType::ALL).unwrap();
+
+        first.combine(&second).unwrap();
+        assert_eq!(first.signature_count(), 2);
+        let tx = draft.into_transaction(vec![first.to_script_sig()]).unwrap();
+        assert!(tx.verify_signatures(std::slice::from_ref(&spent)).is_ok());
+
+        // signatures are bound to the transaction they were made for
+        let mut other_tx = tx.clone();
+        other_tx.outputs[0].value = 11;
+        assert!(other_tx.verify_signatures(&[spent]).is_err());
+    }
+
+    #[test]
+    fn test_rejects_invalid_multisig_scripts() {
+        let key = KeyPair::generate();
+
+        assert!(Script::multisig(2, vec![key.public_key.clone()]).is_err());
+        assert!(Script::multisig(0, vec![key.public_key.clone()]).is_err());
+        assert!(
+            Script::multisig(1, vec![key.public_key.clone(), key.public_key.clone()]).is_err()
+        );
+    }
+}
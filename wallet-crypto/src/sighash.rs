@@ -0,0 +1,305 @@
+// Signature hash types, following Bitcoin's SIGHASH flags.
+//
+// Every input signature commits to its own hash of the transaction: the input index, the value
+// and script of the output being spent, and the parts of the transaction selected by the hash
+// type. The type is appended to the signature as its last byte, so each signer picks their own.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    encoding::{Encodable, write_var_bytes},
+    keys::{BlockchainHash, KeyPair, SignatureError},
+    transaction::{TxOut, UnsignedTxIn},
+};
+
+pub const SIGHASH_ALL: u8 = 0x01;
+pub const SIGHASH_NONE: u8 = 0x02;
+pub const SIGHASH_SINGLE: u8 = 0x03;
+pub const SIGHASH_ANYONECANPAY: u8 = 0x80;
+
+/// Which outputs a signature commits to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SigHashOutputs {
+    All,
+    // none, anyone may decide where the coins go
+    None,
+    // only the output with the same index as the input
+    Single,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SigHashType {
+    pub outputs: SigHashOutputs,
+    // commit to the signed input only, so others can add inputs
+    pub anyone_can_pay: bool,
+}
+
+impl SigHashType {
+    pub const ALL: SigHashType = SigHashType {
+        outputs: SigHashOutputs::All,
+        anyone_can_pay: false,
+    };
+    pub const NONE: SigHashType = SigHashType {
+        outputs: SigHashOutputs::None,
+        anyone_can_pay: false,
+    };
+    pub const SINGLE: SigHashType = SigHashType {
+        outputs: SigHashOutputs::Single,
+        anyone_can_pay: false,
+    };
+
+    pub fn with_anyone_can_pay(self) -> Self {
+        SigHashType {
+            anyone_can_pay: true,
+            ..self
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        let base = match self.outputs {
+            SigHashOutputs::All => SIGHASH_ALL,
+            SigHashOutputs::None => SIGHASH_NONE,
+            SigHashOutputs::Single => SIGHASH_SINGLE,
+        };
+
+        if self.anyone_can_pay {
+            base | SIGHASH_ANYONECANPAY
+        } else {
+            base
+        }
+    }
+
+    /// Strict decoding: unknown flag combinations are rejected.
+    pub fn from_byte(byte: u8) -> Result<Self, SignatureError> {
+        let outputs = match byte & !SIGHASH_ANYONECANPAY {
+            SIGHASH_ALL => SigHashOutputs::All,
+            SIGHASH_NONE => SigHashOutputs::None,
+            SIGHASH_SINGLE => SigHashOutputs::Single,
+            _ => {
+                return Err(SignatureError::InvalidSignatureFormat(format!(
+                    "Unknown sighash type {:#04x}",
+                    byte
+                )));
+            }
+        };
+
+        Ok(SigHashType {
+            outputs,
+            anyone_can_pay: byte & SIGHASH_ANYONECANPAY != 0,
+        })
+    }
+}
+
+impl Default for SigHashType {
+    fn default() -> Self {
+        SigHashType::ALL
+    }
+}
+
+/// Transaction fields an input signature hash is computed from.
+pub(crate) struct SigHashParts<'a> {
+    pub inputs: &'a [UnsignedTxIn],
+    pub outputs: &'a [TxOut],
+    pub timestamp: u128,
+    pub lock_time: u32,
+}
+
+impl SigHashParts<'_> {
+    /// Hash the signature of input `input_index`, spending `spent_output`, commits to.
+    pub fn signature_hash(
+        &self,
+        input_index: usize,
+        spent_output: &TxOut,
+        sighash_type: SigHashType,
+    ) -> Result<BlockchainHash, SignatureError> {
+        let input = self.inputs.get(input_index).ok_or_else(|| {
+            SignatureError::InvalidScript(format!("Input {} does not exist", input_index))
+        })?;
+
+        let inputs = if sighash_type.anyone_can_pay {
+            vec![input.clone()]
+        } else {
+            self.inputs
+                .iter()
+                .enumerate()
+                .map(|(idx, other)| match sighash_type.outputs {
+                    // other signers may still update their sequence when outputs are not fixed
+                    SigHashOutputs::None | SigHashOutputs::Single if idx != input_index => {
+                        UnsignedTxIn {
+                            sequence: 0,
+                            ..other.clone()
+                        }
+                    }
+                    _ => other.clone(),
+                })
+                .collect()
+        };
+
+        let outputs = match sighash_type.outputs {
+            SigHashOutputs::All => self.outputs.iter().collect(),
+            SigHashOutputs::None => vec![],
+            SigHashOutputs::Single => {
+                let output = self.outputs.get(input_index).ok_or_else(|| {
+                    SignatureError::InvalidScript(format!(
+                        "SIGHASH_SINGLE input {} has no matching output",
+                        input_index
+                    ))
+                })?;
+                vec![output]
+            }
+        };
+
+        let mut encoded_bytes = vec![sighash_type.to_byte()];
+        // not committed with ANYONECANPAY, the input may be moved when others add theirs
+        if !sighash_type.anyone_can_pay {
+            (input_index as u32).encode(&mut encoded_bytes);
+        }
+        inputs.encode(&mut encoded_bytes);
+        outputs.encode(&mut encoded_bytes);
+        self.timestamp.encode(&mut encoded_bytes);
+        self.lock_time.encode(&mut encoded_bytes);
+        spent_output.value.encode(&mut encoded_bytes);
+        write_var_bytes(&mut encoded_bytes, &spent_output.script_pubkey.to_bytes());
+
+        let first_hash = Sha256::digest(&encoded_bytes);
+        let second_hash = Sha256::digest(first_hash);
+
+        Ok(BlockchainHash::new(second_hash.into()))
+    }
+}
+
+/// Signs `hash` with `key` and appends the sighash type byte.
+pub fn sign_hash(
+    key: &KeyPair,
+    hash: &BlockchainHash,
+    sighash_type: SigHashType,
+) -> Result<Vec<u8>, SignatureError> {
+    let mut signature = key
+        .sign(hash.as_ref())
+        .map_err(SignatureError::InvalidSignatureFormat)?;
+    signature.push(sighash_type.to_byte());
+
+    Ok(signature)
+}
+
+/// Schnorr-signs `hash` with `key` and appends the sighash type byte.
+pub fn sign_hash_schnorr(
+    key: &KeyPair,
+    hash: &BlockchainHash,
+    sighash_type: SigHashType,
+) -> Result<Vec<u8>, SignatureError> {
+    let mut signature = key
+        .sign_schnorr(hash.as_ref())
+        .map_err(SignatureError::InvalidSignatureFormat)?;
+    signature.push(sighash_type.to_byte());
+
+    Ok(signature)
+}
+
+/// Splits a signature into the ECDSA signature and its sighash type.
+pub fn split_signature(signature: &[u8]) -> Result<(&[u8], SigHashType), SignatureError> {
+    let (type_byte, signature) = signature.split_last().ok_or_else(|| {
+        SignatureError::InvalidSignatureFormat("Signature is empty".to_string())
+    })?;
+
+    Ok((signature, SigHashType::from_byte(*type_byte)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        keys::{PublicKeyHash, Signature},
+        scripts::Script,
+        transaction::DraftTransaction,
+    };
+
+    use super::*;
+
+    fn input(prev_out_idx: u32) -> UnsignedTxIn {
+        UnsignedTxIn {
+            prev_tx_id: BlockchainHash::default(),
+            prev_out_idx,
+            sequence: 0xFFFFFFFF,
+        }
+    }
+
+    fn output(value: u64) -> TxOut {
+        TxOut {
+            value,
+            script_pubkey: Script::PayToPublicKeyHash {
+                pub_key_hash: PublicKeyHash::new([1u8; 20]),
+            },
+        }
+    }
+
+    fn p2pkh(key: &KeyPair) -> TxOut {
+        TxOut {
+            value: 10,
+            script_pubkey: Script::PayToPublicKeyHash {
+                pub_key_hash: key.public_key.to_address(),
+            },
+        }
+    }
+
+    fn script_sig(
+        draft: &DraftTransaction,
+        idx: usize,
+        key: &KeyPair,
+        sighash_type: SigHashType,
+    ) -> Signature {
+        let signature = draft.sign_input(idx, &p2pkh(key), key, sighash_type).unwrap();
+        Signature::build(signature, &key.public_key)
+    }
+
+    #[test]
+    fn test_signatures_cannot_be_replayed_across_inputs() {
+        let key = KeyPair::generate();
+        let draft = DraftTransaction::new(vec![input(0), input(1)], vec![output(15)]);
+
+        let first = script_sig(&draft, 0, &key, SigHashType::ALL);
+        let second = script_sig(&draft, 1, &key, SigHashType::ALL);
+        let spent = [p2pkh(&key), p2pkh(&key)];
+
+        let tx = draft.clone().into_transaction(vec![first.clone(), second]).unwrap();
+        assert!(tx.verify_signatures(&spent).is_ok());
+
+        let replayed = draft.into_transaction(vec![first.clone(), first]).unwrap();
+        assert!(replayed.verify_signatures(&spent).is_err());
+    }
+
+    #[test]
+    fn test_collaborative_sighash_types() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+
+        // Alice commits to her input and all outputs, Bob adds his input afterwards
+        let draft = DraftTransaction::new(vec![input(0)], vec![output(15)]);
+        let alice_sig = script_sig(&draft, 0, &alice, SigHashType::ALL.with_anyone_can_pay());
+
+        let mut draft = draft;
+        draft.inputs.push(input(1));
+        let bob_sig = script_sig(&draft, 1, &bob, SigHashType::ALL);
+        let tx = draft
+            .clone()
+            .into_transaction(vec![alice_sig.clone(), bob_sig])
+            .unwrap();
+        assert!(tx.verify_signatures(&[p2pkh(&alice), p2pkh(&bob)]).is_ok());
+
+        // SINGLE only fixes the output paired with the input
+        let draft = DraftTransaction::new(vec![input(0)], vec![output(5), output(5)]);
+        let single = script_sig(&draft, 0, &alice, SigHashType::SINGLE);
+        let mut tx = draft.into_transaction(vec![single]).unwrap();
+        tx.outputs[1].value = 6;
+        assert!(tx.verify_signatures(&[p2pkh(&alice)]).is_ok());
+        tx.outputs[0].value = 6;
+        assert!(tx.verify_signatures(&[p2pkh(&alice)]).is_err());
+
+        assert!(SigHashType::from_byte(0x00).is_err());
+        assert!(SigHashType::from_byte(0x84).is_err());
+        assert_eq!(
+            SigHashType::from_byte(0x83).unwrap(),
+            SigHashType::SINGLE.with_anyone_can_pay()
+        );
+    }
+}
@@ -0,0 +1,224 @@
+// Signing transactions whose inputs belong to several keys.
+//
+// A `Signer` hands out the key for a public key hash. `DraftTransaction::sign_with` signs every
+// input whose spent output is locked to one of its keys and leaves the rest unsigned, so the
+// partial result can be passed to other parties and merged before the transaction is finalized.
+
+use std::collections::HashMap;
+
+use crate::{
+    keys::{KeyPair, PublicKeyHash, Signature, SignatureError, XOnlyPublicKey},
+    scripts::Script,
+};
+
+/// Source of signing keys, looked up by the public key hash an output is locked to.
+pub trait Signer {
+    fn key_for(&self, pub_key_hash: &PublicKeyHash) -> Option<&KeyPair>;
+
+    /// Key for outputs locked to a Schnorr key, see `Script::PayToSchnorrKey`.
+    fn schnorr_key_for(&self, x_only_key: &XOnlyPublicKey) -> Option<&KeyPair>;
+}
+
+impl Signer for KeyPair {
+    fn key_for(&self, pub_key_hash: &PublicKeyHash) -> Option<&KeyPair> {
+        (self.public_key.to_address() == *pub_key_hash).then_some(self)
+    }
+
+    fn schnorr_key_for(&self, x_only_key: &XOnlyPublicKey) -> Option<&KeyPair> {
+        (self.x_only_public_key() == *x_only_key).then_some(self)
+    }
+}
+
+/// In-memory set of keys, e.g. every account of a wallet.
+#[derive(Debug, Clone, Default)]
+pub struct KeyStore {
+    keys: HashMap<PublicKeyHash, KeyPair>,
+}
+
+impl KeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, key: KeyPair) {
+        self.keys.insert(key.public_key.to_address(), key);
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl FromIterator<KeyPair> for KeyStore {
+    fn from_iter<I: IntoIterator<Item = KeyPair>>(keys: I) -> Self {
+        let mut store = KeyStore::new();
+        keys.into_iter().for_each(|key| store.add(key));
+        store
+    }
+}
+
+impl Signer for KeyStore {
+    fn key_for(&self, pub_key_hash: &PublicKeyHash) -> Option<&KeyPair> {
+        self.keys.get(pub_key_hash)
+    }
+
+    fn schnorr_key_for(&self, x_only_key: &XOnlyPublicKey) -> Option<&KeyPair> {
+        self.keys
+            .values()
+            .find(|key| key.x_only_public_key() == *x_only_key)
+    }
+}
+
+/// Script signatures collected so far for a draft, one slot per input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputSignatures {
+    script_sigs: Vec<Option<Signature>>,
+}
+
+impl InputSignatures {
+    pub fn new(inputs: usize) -> Self {
+        InputSignatures {
+            script_sigs: vec![None; inputs],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.script_sigs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.script_sigs.is_empty()
+    }
+
+    pub fn get(&self, input_index: usize) -> Option<&Signature> {
+        self.script_sigs.get(input_index)?.as_ref()
+    }
+
+    /// Sets the script_sig of one input, e.g. one produced by a multisig or HTLC helper.
+    pub fn set(&mut self, input_index: usize, script_sig: Signature) -> Result<(), SignatureError> {
+        let slot = self.script_sigs.get_mut(input_index).ok_or_else(|| {
+            SignatureError::InvalidScript(format!("Input {} does not exist", input_index))
+        })?;
+        *slot = Some(script_sig);
+
+        Ok(())
+    }
+
+    pub fn unsigned_inputs(&self) -> Vec<usize> {
+        self.script_sigs
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, script_sig)| script_sig.is_none().then_some(idx))
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.script_sigs.iter().all(Option::is_some)
+    }
+
+    /// Takes the script signatures another party produced for inputs still unsigned here.
+    pub fn merge(&mut self, other: &InputSignatures) -> Result<(), SignatureError> {
+        if self.len() != other.len() {
+            return Err(SignatureError::InvalidScript(
+                "Cannot merge signatures for transactions with different inputs".to_string(),
+            ));
+        }
+
+        for (slot, other_slot) in self.script_sigs.iter_mut().zip(&other.script_sigs) {
+            if slot.is_none() {
+                slot.clone_from(other_slot);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn into_script_sigs(self) -> Result<Vec<Signature>, SignatureError> {
+        let unsigned = self.unsigned_inputs();
+        if !unsigned.is_empty() {
+            return Err(SignatureError::InvalidScript(format!(
+                "Inputs {:?} are not signed",
+                unsigned
+            )));
+        }
+
+        Ok(self.script_sigs.into_iter().flatten().collect())
+    }
+}
+
+/// Public key hash whose key signs for `script`, for scripts satisfied by a P2PKH script_sig.
+pub fn signing_key_hash(script: &Script) -> Option<PublicKeyHash> {
+    match script {
+        Script::PayToPublicKeyHash { pub_key_hash } => Some(*pub_key_hash),
+        Script::AbsoluteTimeLock { script, .. } | Script::RelativeTimeLock { script, .. } => {
+            signing_key_hash(script)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        keys::BlockchainHash,
+        sighash::SigHashType,
+        timelock::SEQUENCE_FINAL,
+        transaction::{DraftTransaction, TxOut, UnsignedTxIn},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_signing_inputs_of_several_keys() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+        let carol = KeyPair::generate();
+
+        let owned_by = |key: &KeyPair| TxOut {
+            value: 10,
+            script_pubkey: Script::PayToPublicKeyHash {
+                pub_key_hash: key.public_key.to_address(),
+            },
+        };
+        let spent_outputs = vec![owned_by(&alice), owned_by(&carol), owned_by(&bob)];
+
+        let inputs = (0..3)
+            .map(|idx| UnsignedTxIn {
+                prev_tx_id: BlockchainHash::default(),
+                prev_out_idx: idx,
+                sequence: SEQUENCE_FINAL,
+            })
+            .collect();
+        let draft = DraftTransaction::new(inputs, vec![owned_by(&alice)]);
+
+        // the wallet holds Alice's and Bob's keys, Carol signs separately
+        let wallet: KeyStore = [alice.clone(), bob].into_iter().collect();
+        let mut signatures = InputSignatures::new(3);
+        draft
+            .sign_with(&wallet, &spent_outputs, SigHashType::ALL, &mut signatures)
+            .unwrap();
+        assert_eq!(signatures.unsigned_inputs(), vec![1]);
+        assert!(draft.clone().finalize(signatures.clone()).is_err());
+
+        let mut carol_signatures = InputSignatures::new(3);
+        draft
+            .sign_with(
+                &carol,
+                &spent_outputs,
+                SigHashType::ALL,
+                &mut carol_signatures,
+            )
+            .unwrap();
+        assert_eq!(carol_signatures.unsigned_inputs(), vec![0, 2]);
+
+        signatures.merge(&carol_signatures).unwrap();
+        assert!(signatures.is_complete());
+
+        let tx = draft.finalize(signatures).unwrap();
+        assert!(tx.verify_signatures(&spent_outputs).is_ok());
+    }
+}
@@ -0,0 +1,130 @@
+// Lock time and sequence rules, following Bitcoin's nLockTime / BIP68 semantics.
+//
+// A transaction `lock_time` below `LOCKTIME_THRESHOLD` is a block height, otherwise a unix
+// timestamp in seconds. A relative lock lives in an input's `sequence`: it is disabled when
+// the top bit is set, the type flag selects time (512 second units) over blocks, and the
+// low 16 bits hold the value.
+
+pub const SEQUENCE_FINAL: u32 = 0xFFFFFFFF;
+// Non-final sequence without a relative lock, enables the transaction lock time only
+pub const SEQUENCE_ENABLE_LOCKTIME: u32 = SEQUENCE_FINAL - 1;
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000FFFF;
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9; // 2^9 = 512 seconds
+
+/// Sequence value that locks an input until `blocks` blocks after the spent output was confirmed.
+pub fn relative_height_lock(blocks: u16) -> u32 {
+    blocks as u32
+}
+
+/// Sequence value that locks an input for at least `seconds` after the spent output was
+/// confirmed, rounded up to the 512 second granularity.
+pub fn relative_time_lock(seconds: u32) -> u32 {
+    let units = seconds.div_ceil(1 << SEQUENCE_LOCKTIME_GRANULARITY);
+    SEQUENCE_LOCKTIME_TYPE_FLAG | units.min(SEQUENCE_LOCKTIME_MASK)
+}
+
+pub fn is_height_lock(lock_time: u32) -> bool {
+    lock_time < LOCKTIME_THRESHOLD
+}
+
+/// Whether a transaction lock time allows inclusion in a block at `height` with time `time_secs`.
+pub fn lock_time_satisfied(lock_time: u32, height: u64, time_secs: u64) -> bool {
+    if lock_time == 0 {
+        return true;
+    }
+
+    if is_height_lock(lock_time) {
+        (lock_time as u64) < height
+    } else {
+        (lock_time as u64) < time_secs
+    }
+}
+
+/// Whether an input with `sequence`, spending an output confirmed in a block at `coin_height`
+/// with time `coin_time_secs`, may be included in a block at `height` with time `time_secs`.
+pub fn sequence_lock_satisfied(
+    sequence: u32,
+    coin_height: u64,
+    coin_time_secs: u64,
+    height: u64,
+    time_secs: u64,
+) -> bool {
+    if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+        return true;
+    }
+
+    let value = (sequence & SEQUENCE_LOCKTIME_MASK) as u64;
+
+    if sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+        time_secs >= coin_time_secs + (value << SEQUENCE_LOCKTIME_GRANULARITY)
+    } else {
+        height >= coin_height + value
+    }
+}
+
+/// Script-level absolute lock check: the spending transaction's `tx_lock_time` must be of the
+/// same kind as `required` and not lower, and the input must not opt out with a final sequence.
+pub fn check_lock_time(required: u32, tx_lock_time: u32, input_sequence: u32) -> bool {
+    if is_height_lock(required) != is_height_lock(tx_lock_time) {
+        return false;
+    }
+
+    required <= tx_lock_time && input_sequence != SEQUENCE_FINAL
+}
+
+/// Script-level relative lock check: the input's sequence must enable a relative lock of the
+/// same kind as `required` and at least as long.
+pub fn check_sequence(required: u32, input_sequence: u32) -> bool {
+    // a disabled requirement behaves as a no-op, like OP_CHECKSEQUENCEVERIFY
+    if required & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+        return true;
+    }
+
+    if input_sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+        return false;
+    }
+
+    if required & SEQUENCE_LOCKTIME_TYPE_FLAG != input_sequence & SEQUENCE_LOCKTIME_TYPE_FLAG {
+        return false;
+    }
+
+    (required & SEQUENCE_LOCKTIME_MASK) <= (input_sequence & SEQUENCE_LOCKTIME_MASK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_time_by_height_and_time() {
+        assert!(lock_time_satisfied(0, 0, 0));
+        assert!(!lock_time_satisfied(10, 10, u64::MAX));
+        assert!(lock_time_satisfied(10, 11, 0));
+
+        let time_lock = LOCKTIME_THRESHOLD + 1_000;
+        assert!(!lock_time_satisfied(time_lock, u64::MAX, time_lock as u64));
+        assert!(lock_time_satisfied(time_lock, 0, time_lock as u64 + 1));
+    }
+
+    #[test]
+    fn test_relative_locks() {
+        let blocks = relative_height_lock(5);
+        assert!(!sequence_lock_satisfied(blocks, 100, 0, 104, 0));
+        assert!(sequence_lock_satisfied(blocks, 100, 0, 105, 0));
+
+        let time = relative_time_lock(1024);
+        assert!(!sequence_lock_satisfied(time, 0, 1_000, u64::MAX, 2_000));
+        assert!(sequence_lock_satisfied(time, 0, 1_000, 0, 2_024));
+
+        assert!(sequence_lock_satisfied(SEQUENCE_FINAL, 100, 0, 0, 0));
+
+        assert!(check_sequence(blocks, relative_height_lock(6)));
+        assert!(!check_sequence(blocks, relative_height_lock(4)));
+        assert!(!check_sequence(blocks, time));
+        assert!(!check_sequence(blocks, SEQUENCE_FINAL));
+    }
+}
@@ -1,33 +1,41 @@
-use bincode::{Encode, config};
+use std::cell::RefCell;
+
 use chrono::Utc;
-use core;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use crate::{
+    encoding::{self, Decodable, Encodable, EncodingError, Reader, write_var_bytes, write_varint},
     keys::{
-        BlockchainHash, KeyPair, PublicKeyHash, PublicKeyWithSignature, Signature, SignatureError,
+        BlockchainHash, KeyPair, PublicKey, PublicKeyHash, Signature, SignatureError,
+        XOnlyPublicKey, parse_strict_ecdsa_signature,
     },
-    scripts::Script,
+    schnorr::SchnorrBatch,
+    scripts::{Script, SignatureChecker},
+    sighash::{SigHashParts, SigHashType, sign_hash, sign_hash_schnorr, split_signature},
+    signer::{InputSignatures, Signer, signing_key_hash},
+    timelock,
 };
 
 const GENESIS_ADDR: &'static str = "8dd45dc1a355c066d89e551db6cd9469513eb4dd";
 const GENESIS_BLOCK_REWARD: u64 = 120;
+// Fixed so that every node starts from the same genesis block
+const GENESIS_TIMESTAMP: u128 = 1231006505;
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Encode)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UTXO {
     pub prev_tx_id: BlockchainHash,
     pub prev_out_idx: u32,
     pub value: u64
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Encode)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TxOut {
     pub value: u64,
     pub script_pubkey: Script,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Encode)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TxIn {
     pub prev_tx_id: BlockchainHash,
     pub prev_out_idx: u32,
@@ -35,26 +43,29 @@ pub struct TxIn {
     pub sequence: u32,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Encode)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UnsignedTxIn {
     pub prev_tx_id: BlockchainHash,
     pub prev_out_idx: u32,
     pub sequence: u32,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Encode)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DraftTransaction {
     pub inputs: Vec<UnsignedTxIn>,
     pub outputs: Vec<TxOut>,
     pub timestamp: u128,
+    // Block height or unix time (seconds) before which the transaction cannot be mined, 0 = none
+    pub lock_time: u32,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Encode)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Transaction {
     pub id: BlockchainHash,
     pub inputs: Vec<TxIn>,
     pub outputs: Vec<TxOut>,
     pub timestamp: u128,
+    pub lock_time: u32,
 }
 
 impl DraftTransaction {
@@ -65,110 +76,222 @@ impl DraftTransaction {
             inputs,
             outputs,
             timestamp,
+            lock_time: 0,
         }
     }
 
-    pub fn sign(self, key: &KeyPair) -> Transaction {
-        Transaction::new(self, key)
+    pub fn with_lock_time(mut self, lock_time: u32) -> Self {
+        self.lock_time = lock_time;
+        self
     }
 
-    fn calculate_id(&self) -> BlockchainHash {
-        let encoded_bytes = bincode::encode_to_vec(self, config::standard())
-            .expect("Failed to serialize transaction for hashing. This should not happen.");
+    /// Signs every input with `key` and `SIGHASH_ALL`. Fails unless the key owns every
+    /// spent output; use `sign_with` for inputs of several keys.
+    pub fn sign(self, key: &KeyPair, spent_outputs: &[TxOut]) -> Result<Transaction, SignatureError> {
+        let mut signatures = InputSignatures::new(self.inputs.len());
+        self.sign_with(key, spent_outputs, SigHashType::ALL, &mut signatures)?;
+
+        self.finalize(signatures)
+    }
+
+    /// Signs every input whose spent output is locked to a key of `signer` and adds the
+    /// script_sigs to `signatures`. Inputs that are already signed, or that no key of
+    /// `signer` owns, are left as they are for other parties.
+    pub fn sign_with(
+        &self,
+        signer: &impl Signer,
+        spent_outputs: &[TxOut],
+        sighash_type: SigHashType,
+        signatures: &mut InputSignatures,
+    ) -> Result<(), SignatureError> {
+        check_spent_outputs(self.inputs.len(), spent_outputs)?;
+
+        if signatures.len() != self.inputs.len() {
+            return Err(SignatureError::InvalidScript(format!(
+                "Expected signature slots for {} inputs, got {}",
+                self.inputs.len(),
+                signatures.len()
+            )));
+        }
+
+        for (input_index, spent_output) in spent_outputs.iter().enumerate() {
+            if signatures.get(input_index).is_some() {
+                continue;
+            }
+
+            let script_sig = match &spent_output.script_pubkey {
+                Script::PayToSchnorrKey { x_only_key } => {
+                    let Some(key) = signer.schnorr_key_for(x_only_key) else {
+                        continue;
+                    };
+                    let signature =
+                        self.sign_input_schnorr(input_index, spent_output, key, sighash_type)?;
+                    Signature::from_bytes(&signature)
+                }
+                script => {
+                    let Some(key) = signing_key_hash(script)
+                        .and_then(|pub_key_hash| signer.key_for(&pub_key_hash))
+                    else {
+                        continue;
+                    };
+                    let signature =
+                        self.sign_input(input_index, spent_output, key, sighash_type)?;
+                    Signature::build(signature, &key.public_key)
+                }
+            };
+
+            signatures.set(input_index, script_sig)?;
+        }
+
+        Ok(())
+    }
 
-        let first_hash = Sha256::digest(&encoded_bytes); // Hash the bytes
-        let second_hash = Sha256::digest(first_hash);
+    /// Assembles the transaction once every input is signed; the error lists unsigned inputs.
+    pub fn finalize(self, signatures: InputSignatures) -> Result<Transaction, SignatureError> {
+        self.into_transaction(signatures.into_script_sigs()?)
+    }
 
-        BlockchainHash::new(second_hash.into())
+    /// Hash the signature of input `input_index`, spending `spent_output`, commits to.
+    pub fn signature_hash(
+        &self,
+        input_index: usize,
+        spent_output: &TxOut,
+        sighash_type: SigHashType,
+    ) -> Result<BlockchainHash, SignatureError> {
+        SigHashParts {
+            inputs: &self.inputs,
+            outputs: &self.outputs,
+            timestamp: self.timestamp,
+            lock_time: self.lock_time,
+        }
+        .signature_hash(input_index, spent_output, sighash_type)
     }
-}
 
-impl Transaction {
-    fn new(draft: DraftTransaction, key: &KeyPair) -> Self {
-        let draft_hash = draft.calculate_id();
+    /// Signature of `key` for one input, with the sighash type byte appended.
+    pub fn sign_input(
+        &self,
+        input_index: usize,
+        spent_output: &TxOut,
+        key: &KeyPair,
+        sighash_type: SigHashType,
+    ) -> Result<Vec<u8>, SignatureError> {
+        let hash = self.signature_hash(input_index, spent_output, sighash_type)?;
+        sign_hash(key, &hash, sighash_type)
+    }
+
+    /// Schnorr signature of `key` for one input, with the sighash type byte appended.
+    /// This is the whole script_sig of a `Script::PayToSchnorrKey` input.
+    pub fn sign_input_schnorr(
+        &self,
+        input_index: usize,
+        spent_output: &TxOut,
+        key: &KeyPair,
+        sighash_type: SigHashType,
+    ) -> Result<Vec<u8>, SignatureError> {
+        let hash = self.signature_hash(input_index, spent_output, sighash_type)?;
+        sign_hash_schnorr(key, &hash, sighash_type)
+    }
+
+    /// Id the signed transaction will have; signatures do not change it.
+    pub fn txid(&self) -> BlockchainHash {
+        transaction_id(&self.inputs, &self.outputs, self.timestamp, self.lock_time)
+    }
 
-        let signed_inputs: Vec<TxIn> = draft
+    /// Assembles the final transaction from externally produced script signatures,
+    /// one per input (e.g. collected from several multisig signers).
+    pub fn into_transaction(self, script_sigs: Vec<Signature>) -> Result<Transaction, SignatureError> {
+        if script_sigs.len() != self.inputs.len() {
+            return Err(SignatureError::InvalidScript(format!(
+                "Expected {} script signatures, got {}",
+                self.inputs.len(),
+                script_sigs.len()
+            )));
+        }
+
+        let inputs = self
             .inputs
             .into_iter()
-            .map(|input| {
-                let signature = key.sign(draft_hash.as_ref()).unwrap();
-                TxIn {
-                    prev_tx_id: input.prev_tx_id,
-                    prev_out_idx: input.prev_out_idx,
-                    sequence: input.sequence,
-                    script_sig: Signature::build(signature, &key.public_key),
-                }
+            .zip(script_sigs)
+            .map(|(input, script_sig)| TxIn {
+                prev_tx_id: input.prev_tx_id,
+                prev_out_idx: input.prev_out_idx,
+                sequence: input.sequence,
+                script_sig,
             })
             .collect();
 
         let mut tx = Transaction {
             id: BlockchainHash::default(),
-            inputs: signed_inputs,
-            outputs: draft.outputs,
-            timestamp: draft.timestamp,
+            inputs,
+            outputs: self.outputs,
+            timestamp: self.timestamp,
+            lock_time: self.lock_time,
         };
 
         tx.id = tx.calculate_id();
 
-        tx
+        Ok(tx)
     }
+}
 
-    fn calculate_signing_id(&self) -> BlockchainHash {
-        #[derive(Serialize, Encode)]
-        struct TxForHashing<'a> {
-            inputs: &'a Vec<UnsignedTxIn>,
-            outputs: &'a Vec<TxOut>,
-            timestamp: u128,
-        }
-
-        let temp_tx = TxForHashing {
-            inputs: &self
-                .inputs
-                .iter()
-                .map(|input| UnsignedTxIn {
-                    prev_out_idx: input.prev_out_idx,
-                    prev_tx_id: input.prev_tx_id,
-                    sequence: input.sequence,
-                })
-                .collect(),
+impl Transaction {
+    /// Hash the signature of input `input_index`, spending `spent_output`, commits to.
+    pub fn signature_hash(
+        &self,
+        input_index: usize,
+        spent_output: &TxOut,
+        sighash_type: SigHashType,
+    ) -> Result<BlockchainHash, SignatureError> {
+        SigHashParts {
+            inputs: &self.unsigned_inputs(),
             outputs: &self.outputs,
             timestamp: self.timestamp,
-        };
-
-        let encoded_bytes = bincode::encode_to_vec(&temp_tx, config::standard())
-            .expect("Failed to serialize transaction for hashing. This should not happen.");
-
-        let first_hash = Sha256::digest(&encoded_bytes); // Hash the bytes
-        let second_hash = Sha256::digest(first_hash);
-
-        BlockchainHash::new(second_hash.into())
+            lock_time: self.lock_time,
+        }
+        .signature_hash(input_index, spent_output, sighash_type)
     }
 
+    /// Transaction id. Like a segwit txid it leaves out the script_sigs, so changing how an
+    /// input is signed cannot change the id, and the id is known before signing.
     pub fn calculate_id(&self) -> BlockchainHash {
-        #[derive(Serialize, Encode)]
-        struct TxForHashing<'a> {
-            inputs: &'a Vec<TxIn>,
-            outputs: &'a Vec<TxOut>,
-            timestamp: u128,
-        }
-
-        let temp_tx = TxForHashing {
-            inputs: &self.inputs,
-            outputs: &self.outputs,
-            timestamp: self.timestamp,
-        };
+        transaction_id(
+            &self.unsigned_inputs(),
+            &self.outputs,
+            self.timestamp,
+            self.lock_time,
+        )
+    }
 
-        let encoded_bytes = bincode::encode_to_vec(&temp_tx, config::standard())
-            .expect("Failed to serialize transaction for hashing. This should not happen.");
+    /// Hash over the whole transaction including script_sigs, which blocks commit to so
+    /// that the signatures are covered by proof of work as well.
+    pub fn calculate_witness_id(&self) -> BlockchainHash {
+        let mut encoded = Vec::new();
+        self.calculate_id().encode(&mut encoded);
+        write_varint(&mut encoded, self.inputs.len() as u64);
+        for input in &self.inputs {
+            input.script_sig.encode(&mut encoded);
+        }
 
-        let first_hash = Sha256::digest(&encoded_bytes); // Hash the bytes
-        let second_hash = Sha256::digest(first_hash);
+        double_sha256(&encoded)
+    }
 
-        BlockchainHash::new(second_hash.into())
+    pub fn unsigned_inputs(&self) -> Vec<UnsignedTxIn> {
+        self.inputs
+            .iter()
+            .map(|input| UnsignedTxIn {
+                prev_tx_id: input.prev_tx_id,
+                prev_out_idx: input.prev_out_idx,
+                sequence: input.sequence,
+            })
+            .collect()
     }
 
     pub fn genesis_transaction() -> Transaction {
-        Self::coinbase_transaction(GENESIS_ADDR, GENESIS_BLOCK_REWARD)
+        let mut tx = Self::coinbase_transaction(GENESIS_ADDR, GENESIS_BLOCK_REWARD);
+        tx.timestamp = GENESIS_TIMESTAMP;
+        tx.id = tx.calculate_id();
+
+        tx
     }
 
     pub fn coinbase_transaction(miner_addr: &str, fee: u64) -> Transaction {
@@ -193,6 +316,7 @@ impl Transaction {
             }],
             outputs: vec![initial_reward_output],
             timestamp: Utc::now().timestamp_millis() as u128,
+            lock_time: 0,
         };
 
         tx.id = tx.calculate_id();
@@ -200,18 +324,445 @@ impl Transaction {
         tx
     }
 
-    pub fn verify_signatures(&self) -> Result<(), SignatureError> {
-        let message = self.calculate_signing_id();
+    /// Verifies every input against the output it spends.
+    /// `spent_outputs` must list those outputs in input order.
+    pub fn verify_signatures(&self, spent_outputs: &[TxOut]) -> Result<(), SignatureError> {
+        let mut schnorr_batch = SchnorrBatch::new();
+        self.verify_signatures_deferred(spent_outputs, &mut schnorr_batch)?;
 
-        for tx_in in &self.inputs {
-            let verifier: PublicKeyWithSignature = (&tx_in.script_sig).get_verifier()?;
-            verifier.verify(message.as_ref())?;
+        schnorr_batch.verify()
+    }
+
+    /// Like `verify_signatures`, but Schnorr signatures are only checked for a valid encoding
+    /// and added to `schnorr_batch`; the caller verifies the batch, e.g. once per block.
+    pub fn verify_signatures_deferred(
+        &self,
+        spent_outputs: &[TxOut],
+        schnorr_batch: &mut SchnorrBatch,
+    ) -> Result<(), SignatureError> {
+        check_spent_outputs(self.inputs.len(), spent_outputs)?;
+
+        for (input_index, spent_output) in spent_outputs.iter().enumerate() {
+            self.verify_input_deferred(input_index, spent_output, schnorr_batch)?;
         }
 
         Ok(())
     }
 
+    /// Checks a single input against the output it spends, queueing Schnorr signatures in
+    /// `schnorr_batch`. Inputs are independent, so they can be checked on different threads.
+    pub fn verify_input_deferred(
+        &self,
+        input_index: usize,
+        spent_output: &TxOut,
+        schnorr_batch: &mut SchnorrBatch,
+    ) -> Result<(), SignatureError> {
+        let tx_in = self.inputs.get(input_index).ok_or_else(|| {
+            SignatureError::InvalidScript(format!("Input {} does not exist", input_index))
+        })?;
+
+        let checker = TransactionSignatureChecker {
+            tx: self,
+            input_index,
+            spent_output,
+            schnorr_batch: RefCell::new(schnorr_batch),
+        };
+        spent_output
+            .script_pubkey
+            .verify(&tx_in.script_sig, &checker)
+    }
+
+    /// Whether the transaction lock time allows it in a block at `height` with time `time_secs`.
+    /// Like Bitcoin, the lock time is ignored when every input has a final sequence.
+    pub fn is_final(&self, height: u64, time_secs: u64) -> bool {
+        timelock::lock_time_satisfied(self.lock_time, height, time_secs)
+            || self
+                .inputs
+                .iter()
+                .all(|input| input.sequence == timelock::SEQUENCE_FINAL)
+    }
+
+    /// Payloads of the transaction's data-carrier outputs, in output order.
+    pub fn data_payloads(&self) -> Vec<&[u8]> {
+        self.outputs
+            .iter()
+            .filter_map(|output| match &output.script_pubkey {
+                Script::DataCarrier { data } => Some(data.as_slice()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Coinbases have no inputs, or the single null input `coinbase_transaction` creates
     pub fn is_coinbase(&self) -> bool {
-        self.inputs.is_empty()
+        match self.inputs.as_slice() {
+            [] => true,
+            [input] => {
+                input.prev_tx_id == BlockchainHash::default() && input.prev_out_idx == 0xFFFFFFFF
+            }
+            _ => false,
+        }
+    }
+}
+
+fn transaction_id(
+    inputs: &[UnsignedTxIn],
+    outputs: &[TxOut],
+    timestamp: u128,
+    lock_time: u32,
+) -> BlockchainHash {
+    let mut encoded = Vec::new();
+    encode_transaction_parts(&mut encoded, inputs, outputs, timestamp, lock_time);
+
+    double_sha256(&encoded)
+}
+
+// Shared layout of drafts and transactions, which differ only in their input type
+fn encode_transaction_parts<I: Encodable>(
+    out: &mut Vec<u8>,
+    inputs: &[I],
+    outputs: &[TxOut],
+    timestamp: u128,
+    lock_time: u32,
+) {
+    inputs.encode(out);
+    outputs.encode(out);
+    timestamp.encode(out);
+    lock_time.encode(out);
+}
+
+fn double_sha256(encoded_bytes: &[u8]) -> BlockchainHash {
+    let first_hash = Sha256::digest(encoded_bytes);
+    let second_hash = Sha256::digest(first_hash);
+
+    BlockchainHash::new(second_hash.into())
+}
+
+fn check_spent_outputs(inputs: usize, spent_outputs: &[TxOut]) -> Result<(), SignatureError> {
+    if spent_outputs.len() != inputs {
+        return Err(SignatureError::InvalidScript(format!(
+            "Expected {} spent outputs, got {}",
+            inputs,
+            spent_outputs.len()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Checks scripts of one transaction input: signatures against the input's signature hash,
+/// timelocks against the transaction lock time and the input's sequence.
+pub struct TransactionSignatureChecker<'a> {
+    tx: &'a Transaction,
+    input_index: usize,
+    spent_output: &'a TxOut,
+    schnorr_batch: RefCell<&'a mut SchnorrBatch>,
+}
+
+impl SignatureChecker for TransactionSignatureChecker<'_> {
+    fn check_signature(
+        &self,
+        signature: &[u8],
+        public_key: &PublicKey,
+    ) -> Result<(), SignatureError> {
+        let (signature, sighash_type) = split_signature(signature)?;
+        let signature = parse_strict_ecdsa_signature(signature)?;
+
+        let message = self
+            .tx
+            .signature_hash(self.input_index, self.spent_output, sighash_type)?;
+
+        if public_key.verify(message.as_ref(), &signature) {
+            Ok(())
+        } else {
+            Err(SignatureError::InvalidScript(
+                "Invalid signature for transaction input".to_string(),
+            ))
+        }
+    }
+
+    fn check_schnorr_signature(
+        &self,
+        signature: &[u8],
+        public_key: &XOnlyPublicKey,
+    ) -> Result<(), SignatureError> {
+        let (signature, sighash_type) = split_signature(signature)?;
+        let message = self
+            .tx
+            .signature_hash(self.input_index, self.spent_output, sighash_type)?;
+
+        self.schnorr_batch
+            .borrow_mut()
+            .push(public_key, &message, signature)
+    }
+
+    fn check_lock_time(&self, lock_time: u32) -> bool {
+        let sequence = self.tx.inputs[self.input_index].sequence;
+        timelock::check_lock_time(lock_time, self.tx.lock_time, sequence)
+    }
+
+    fn check_sequence(&self, sequence: u32) -> bool {
+        timelock::check_sequence(sequence, self.tx.inputs[self.input_index].sequence)
+    }
+}
+
+impl Encodable for UnsignedTxIn {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.prev_tx_id.encode(out);
+        self.prev_out_idx.encode(out);
+        self.sequence.encode(out);
+    }
+}
+
+impl Decodable for UnsignedTxIn {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        Ok(UnsignedTxIn {
+            prev_tx_id: BlockchainHash::decode(reader)?,
+            prev_out_idx: reader.read_u32()?,
+            sequence: reader.read_u32()?,
+        })
+    }
+}
+
+impl Encodable for TxIn {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.prev_tx_id.encode(out);
+        self.prev_out_idx.encode(out);
+        self.script_sig.encode(out);
+        self.sequence.encode(out);
+    }
+}
+
+impl Decodable for TxIn {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        Ok(TxIn {
+            prev_tx_id: BlockchainHash::decode(reader)?,
+            prev_out_idx: reader.read_u32()?,
+            script_sig: Signature::decode(reader)?,
+            sequence: reader.read_u32()?,
+        })
+    }
+}
+
+// The script is length-prefixed, so an output can be skipped without parsing its script
+impl Encodable for TxOut {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.value.encode(out);
+        write_var_bytes(out, &self.script_pubkey.to_bytes());
+    }
+}
+
+impl Decodable for TxOut {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        Ok(TxOut {
+            value: reader.read_u64()?,
+            script_pubkey: encoding::deserialize(reader.read_var_bytes()?)?,
+        })
+    }
+}
+
+impl Encodable for DraftTransaction {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_transaction_parts(
+            out,
+            &self.inputs,
+            &self.outputs,
+            self.timestamp,
+            self.lock_time,
+        );
+    }
+}
+
+impl Decodable for DraftTransaction {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        Ok(DraftTransaction {
+            inputs: Vec::decode(reader)?,
+            outputs: Vec::decode(reader)?,
+            timestamp: reader.read_u128()?,
+            lock_time: reader.read_u32()?,
+        })
+    }
+}
+
+impl Encodable for Transaction {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_transaction_parts(
+            out,
+            &self.inputs,
+            &self.outputs,
+            self.timestamp,
+            self.lock_time,
+        );
+    }
+}
+
+// The id is not part of the encoding, it is recomputed from the decoded fields
+impl Decodable for Transaction {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, EncodingError> {
+        let mut tx = Transaction {
+            id: BlockchainHash::default(),
+            inputs: Vec::decode(reader)?,
+            outputs: Vec::decode(reader)?,
+            timestamp: reader.read_u128()?,
+            lock_time: reader.read_u32()?,
+        };
+        tx.id = tx.calculate_id();
+
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k256::ecdsa::Signature as EcdsaSignature;
+
+    use crate::keys::ECDSA_SIGNATURE_LEN;
+
+    use super::*;
+
+    #[test]
+    fn test_malleated_signatures_are_rejected_and_txid_is_stable() {
+        let key = KeyPair::generate();
+        let spent = TxOut {
+            value: 20,
+            script_pubkey: Script::PayToPublicKeyHash {
+                pub_key_hash: key.public_key.to_address(),
+            },
+        };
+        let draft = DraftTransaction::new(
+            vec![UnsignedTxIn {
+                prev_tx_id: BlockchainHash::default(),
+                prev_out_idx: 0,
+                sequence: timelock::SEQUENCE_FINAL,
+            }],
+            vec![spent.clone()],
+        );
+        let txid = draft.txid();
+
+        let tx = draft.sign(&key, std::slice::from_ref(&spent)).unwrap();
+        assert_eq!(tx.id, txid);
+        assert!(tx.verify_signatures(std::slice::from_ref(&spent)).is_ok());
+
+        // the same signature with S negated is mathematically valid, but not canonical
+        let script_sig = tx.inputs[0].script_sig.as_bytes();
+        let (r, s) = EcdsaSignature::from_slice(&script_sig[..ECDSA_SIGNATURE_LEN])
+            .unwrap()
+            .split_scalars();
+        let high_s = EcdsaSignature::from_scalars(r.to_bytes(), (-*s.as_ref()).to_bytes()).unwrap();
+
+        let mut malleated = tx.clone();
+        malleated.inputs[0].script_sig = Signature::from_bytes(
+            &[
+                high_s.to_bytes().as_slice(),
+                &script_sig[ECDSA_SIGNATURE_LEN..],
+            ]
+            .concat(),
+        );
+        assert!(
+            malleated
+                .verify_signatures(std::slice::from_ref(&spent))
+                .is_err()
+        );
+
+        // padded signatures are rejected too
+        let mut padded = tx.clone();
+        padded.inputs[0].script_sig = Signature::from_bytes(&[&[0u8], script_sig].concat());
+        assert!(
+            padded
+                .verify_signatures(std::slice::from_ref(&spent))
+                .is_err()
+        );
+
+        // changing a script_sig changes what blocks commit to, not the txid
+        assert_eq!(malleated.calculate_id(), tx.id);
+        assert_ne!(malleated.calculate_witness_id(), tx.calculate_witness_id());
+    }
+
+    #[test]
+    fn test_schnorr_key_path_spending() {
+        let key = KeyPair::generate();
+        let spent = TxOut {
+            value: 20,
+            script_pubkey: Script::PayToSchnorrKey {
+                x_only_key: key.x_only_public_key(),
+            },
+        };
+        let draft = DraftTransaction::new(
+            vec![UnsignedTxIn {
+                prev_tx_id: BlockchainHash::default(),
+                prev_out_idx: 0,
+                sequence: timelock::SEQUENCE_FINAL,
+            }],
+            vec![spent.clone()],
+        );
+
+        let tx = draft.sign(&key, std::slice::from_ref(&spent)).unwrap();
+        assert!(tx.verify_signatures(std::slice::from_ref(&spent)).is_ok());
+
+        // deferred checking only queues the signature
+        let mut batch = SchnorrBatch::new();
+        tx.verify_signatures_deferred(std::slice::from_ref(&spent), &mut batch)
+            .unwrap();
+        assert_eq!(batch.len(), 1);
+        assert!(batch.verify().is_ok());
+
+        let other = TxOut {
+            value: 20,
+            script_pubkey: Script::PayToSchnorrKey {
+                x_only_key: KeyPair::generate().x_only_public_key(),
+            },
+        };
+        assert!(tx.verify_signatures(&[other]).is_err());
+    }
+
+    #[test]
+    fn test_wire_encoding_vector() {
+        let mut tx = Transaction {
+            id: BlockchainHash::default(),
+            inputs: vec![TxIn {
+                prev_tx_id: BlockchainHash::new([0x11; 32]),
+                prev_out_idx: 1,
+                script_sig: Signature::from_bytes(&[0xaa, 0xbb, 0xcc]),
+                sequence: 0xffff_fffe,
+            }],
+            outputs: vec![TxOut {
+                value: 5_000,
+                script_pubkey: Script::PayToPublicKeyHash {
+                    pub_key_hash: PublicKeyHash::new([0x22; 20]),
+                },
+            }],
+            timestamp: 1_700_000_000_000,
+            lock_time: 100,
+        };
+        tx.id = tx.calculate_id();
+
+        let expected = concat!(
+            "01",                                                               // inputs
+            "1111111111111111111111111111111111111111111111111111111111111111", // prev_tx_id
+            "01000000",                                                         // prev_out_idx
+            "03aabbcc",                                                         // script_sig
+            "feffffff",                                                         // sequence
+            "01",                                                               // outputs
+            "8813000000000000",                                                 // value
+            "15002222222222222222222222222222222222222222",                     // P2PKH script
+            "0068e5cf8b0100000000000000000000",                                 // timestamp
+            "64000000",                                                         // lock_time
+        );
+        let encoded = encoding::serialize(&tx);
+        assert_eq!(hex::encode(&encoded), expected);
+        assert_eq!(
+            tx.id.to_string(),
+            "7bd8d6ac6276036a89d5f01a37f3d9792af54598bf2f0ce87b37a30e4e9b5004"
+        );
+        assert_eq!(
+            tx.calculate_witness_id().to_string(),
+            "f93b82906d92f438e1c697925f26a9ef17fda0339655d5690d42a6196ff6eaa1"
+        );
+
+        assert_eq!(encoding::deserialize::<Transaction>(&encoded).unwrap(), tx);
+        assert!(
+            encoding::deserialize::<Transaction>(&[encoded.as_slice(), &[0]].concat()).is_err()
+        );
+        assert!(encoding::deserialize::<Transaction>(&encoded[..encoded.len() - 1]).is_err());
     }
 }
@@ -1,6 +1,7 @@
 import { useAtomValue } from "jotai";
 import { type FC } from "react";
 import { balanceLoadableAtom } from "./data/balance";
+import { useBalanceUpdates } from "./data/events";
 import { Table, TableBody, TableCell, TableHead, TableHeader, TableRow } from '@bc/ui'
 
 function toHexString(byteArray: any) {
@@ -9,6 +10,7 @@ function toHexString(byteArray: any) {
 
 export const Balance: FC = () => {
     const balance = useAtomValue(balanceLoadableAtom)
+    useBalanceUpdates()
 
     if (balance.state === 'loading') {
         return <>Loading...</>
@@ -59,20 +59,30 @@ export const SendBlock: React.FC = () => {
 
     const [recipientAddress, setRecipienAddress] = useState<string>('')
     const [amount, setAmount] = useState<number | undefined>()
+    // optional hex payload (e.g. a document hash) stored on chain in a data output
+    const [data, setData] = useState<string>('')
     const createTransaction = useCallback(async () => {
-        if (!(balance.state === 'hasData') || !amount || !ownAddress) {
+        if (!(balance.state === 'hasData') || !(amount || data) || !ownAddress) {
             return
         }
 
-        const utxoIn = selectUtxoToUse(balance.data, amount)
+        const utxoIn = selectUtxoToUse(balance.data, amount ?? 0)
 
         if (utxoIn.length === 0) {
             throw 'Insufficient Funds'
         }
 
-        await create_transaction(ownAddress, '123', recipientAddress, BigInt(amount), BigInt(FEE_PRICE), utxoIn)
+        await create_transaction(
+            ownAddress,
+            '123',
+            recipientAddress,
+            BigInt(amount ?? 0),
+            BigInt(FEE_PRICE),
+            utxoIn,
+            data || undefined
+        )
         refreshBalance()
-    }, [ownAddress, amount, recipientAddress, balance])
+    }, [ownAddress, amount, recipientAddress, balance, data])
 
     const mineBlock = useCallback(async () => {
         await mine_block()
@@ -110,6 +120,12 @@ export const SendBlock: React.FC = () => {
                     placeholder='Amount (MYC)'
                     className='w-full border rounded px-3 py-2'
                 />
+                <input
+                    onChange={ev => setData(ev.target.value.trim())}
+                    type='text'
+                    placeholder='Data (hex, optional)'
+                    className='w-full border rounded px-3 py-2'
+                />
                 <button
                     type='button'
                     onClick={createTransaction}
@@ -0,0 +1,53 @@
+import { useEffect } from 'react'
+import { useAtomValue, useSetAtom } from 'jotai'
+import { selectedAddressAtom } from './addresses'
+import { refreshABalanceAtom } from './balance'
+
+const EVENTS_URL = 'ws://localhost:8989/events/ws'
+const RECONNECT_DELAY_MS = 5000
+
+// Refreshes the balance as soon as the node reports activity for the selected address,
+// instead of waiting for the user to reload
+export const useBalanceUpdates = () => {
+    const address = useAtomValue(selectedAddressAtom)
+    const refreshBalance = useSetAtom(refreshABalanceAtom)
+
+    useEffect(() => {
+        if (!address) {
+            return
+        }
+
+        let socket: WebSocket | null = null
+        let reconnect: ReturnType<typeof setTimeout> | undefined
+        let closed = false
+
+        const connect = () => {
+            socket = new WebSocket(EVENTS_URL)
+            socket.onopen = () => {
+                socket?.send(JSON.stringify({ subscribe: address }))
+                // whatever happened while disconnected
+                refreshBalance()
+            }
+            socket.onmessage = (message) => {
+                const event = JSON.parse(message.data)
+                // lagged: events were missed, some may have been ours
+                // reorg: coins of the disconnected blocks are gone without any address activity
+                if (event.type === 'address_activity' || event.type === 'lagged' || event.type === 'reorg') {
+                    refreshBalance()
+                }
+            }
+            socket.onclose = () => {
+                if (!closed) {
+                    reconnect = setTimeout(connect, RECONNECT_DELAY_MS)
+                }
+            }
+        }
+        connect()
+
+        return () => {
+            closed = true
+            clearTimeout(reconnect)
+            socket?.close()
+        }
+    }, [address, refreshBalance])
+}
@@ -1,10 +1,23 @@
 use gloo_console::log;
 use gloo_net::{Error, http::Request};
+use serde::{Deserialize, Serialize};
 use wallet_crypto::{
-    keys::PublicKeyHash,
+    header::BlockHeader,
+    keys::{Address, BlockchainHash},
+    merkle::TransactionProof,
     transaction::{Transaction, UTXO},
 };
 
+/// Data-carrier payloads of a confirmed transaction, as returned by the node.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransactionData {
+    pub tx_id: BlockchainHash,
+    pub block_hash: BlockchainHash,
+    pub block_height: u64,
+    pub timestamp: u128,
+    pub data: Vec<String>,
+}
+
 pub struct NodeClient {
     base_url: String,
     next_request_id: u64,
@@ -18,7 +31,7 @@ impl NodeClient {
         }
     }
 
-    pub async fn get_utxos(&self, address: PublicKeyHash) -> Result<Vec<UTXO>, Error> {
+    pub async fn get_utxos(&self, address: Address) -> Result<Vec<UTXO>, Error> {
         let response = Request::get(&format!(
             "{}/{}/{}",
             self.base_url,
@@ -52,4 +65,52 @@ impl NodeClient {
 
         Ok(())
     }
+
+    pub async fn get_transaction_data(
+        &self,
+        tx_id: BlockchainHash,
+    ) -> Result<TransactionData, Error> {
+        let response = Request::get(&format!(
+            "{}/transactions/{}/data",
+            self.base_url,
+            tx_id.to_string_owned()
+        ))
+        .send()
+        .await?;
+
+        let data: TransactionData = response.json().await?;
+
+        Ok(data)
+    }
+
+    /// Up to `count` headers from height `from`; the node caps how many it returns at once.
+    pub async fn get_headers(&self, from: u64, count: u64) -> Result<Vec<BlockHeader>, Error> {
+        let response = Request::get(&format!(
+            "{}/headers?from={}&count={}",
+            self.base_url, from, count
+        ))
+        .send()
+        .await?;
+
+        let headers: Vec<BlockHeader> = response.json().await?;
+
+        Ok(headers)
+    }
+
+    pub async fn get_transaction_proof(
+        &self,
+        tx_id: BlockchainHash,
+    ) -> Result<TransactionProof, Error> {
+        let response = Request::get(&format!(
+            "{}/transactions/{}/proof",
+            self.base_url,
+            tx_id.to_string_owned()
+        ))
+        .send()
+        .await?;
+
+        let proof: TransactionProof = response.json().await?;
+
+        Ok(proof)
+    }
 }
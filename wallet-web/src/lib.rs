@@ -1,7 +1,12 @@
 use gloo_console::log;
+use serde::Serialize;
 use wallet_crypto::{
-    keys::{BlockchainHash, KeyPair, PublicKeyHash},
+    header::verify_header_chain,
+    keys::{Address, BlockchainHash, KeyPair, PublicKeyHash},
+    psbt::PartiallySignedTransaction,
     scripts::Script,
+    sighash::SigHashType,
+    signer::{InputSignatures, KeyStore},
     transaction::{DraftTransaction, TxOut, UTXO, UnsignedTxIn},
 };
 use wasm_bindgen::prelude::*;
@@ -16,6 +21,18 @@ mod api;
 mod crypto;
 mod storage;
 
+// Headers asked for per request by `verify_payment`, the most the node sends at once
+const HEADERS_PAGE_SIZE: u64 = 2000;
+
+/// A payment confirmed by `verify_payment`.
+#[derive(Serialize)]
+pub struct PaymentConfirmation {
+    pub tx_id: BlockchainHash,
+    pub block_hash: BlockchainHash,
+    pub block_height: u64,
+    pub confirmations: u64,
+}
+
 #[wasm_bindgen]
 pub fn generate_new_key_pair() -> Result<JsValue, JsValue> {
     let keypair = KeyPair::generate();
@@ -58,6 +75,8 @@ pub async fn create_transaction(
     amount: u64,
     fee_to_add: u64,
     utxos: JsValue,
+    // optional hex payload embedded in a data-carrier output, e.g. a document hash
+    data: Option<String>,
 ) -> Result<JsValue, JsValue> {
     let key = get_keypair(own_address, password).await.unwrap();
     if let Some(keypair) = key {
@@ -72,15 +91,25 @@ pub async fn create_transaction(
             })
             .collect();
 
-        let tx_out = TxOut {
-            value: amount,
-            script_pubkey: Script::PayToPublicKeyHash {
-                pub_key_hash: PublicKeyHash::try_from_string(recipient)?, //keypair.public_key.to_address(),
-            },
-        };
+        let mut output_utxo = Vec::with_capacity(3);
 
-        let mut output_utxo = Vec::with_capacity(2);
-        output_utxo.push(tx_out);
+        // a data-only transaction has no recipient output
+        if amount > 0 {
+            output_utxo.push(TxOut {
+                value: amount,
+                // recipient may be a public key hash or a script hash address
+                script_pubkey: Address::try_from_string(recipient)?.script_pubkey(),
+            });
+        }
+
+        if let Some(data) = data.filter(|data| !data.is_empty()) {
+            let data =
+                hex::decode(data).map_err(|_| JsValue::from_str("Data must be hex encoded"))?;
+            output_utxo.push(TxOut {
+                value: 0,
+                script_pubkey: Script::data_carrier(&data).map_err(|er| er.to_string())?,
+            });
+        }
 
         let total_amount: u64 = utxos.iter().map(|utxo| utxo.value).sum();
 
@@ -98,11 +127,26 @@ pub async fn create_transaction(
             });
         }
 
+        // all spent outputs belong to own address
+        let own_script = Script::PayToPublicKeyHash {
+            pub_key_hash: keypair.public_key.to_address(),
+        };
+        let spent_outputs: Vec<TxOut> = utxos
+            .iter()
+            .map(|utxo| TxOut {
+                value: utxo.value,
+                script_pubkey: own_script.clone(),
+            })
+            .collect();
+
         let tx = DraftTransaction::new(input_utxo, output_utxo);
-        let tx = tx.sign(&keypair);
+        let tx = tx
+            .sign(&keypair, &spent_outputs)
+            .map_err(|er| er.to_string())?;
 
         // verify correctness
-        tx.verify_signatures().map_err(|er| er.to_string())?;
+        tx.verify_signatures(&spent_outputs)
+            .map_err(|er| er.to_string())?;
 
         let client = NodeClient::new("http://localhost:8989");
         client
@@ -118,9 +162,222 @@ pub async fn create_transaction(
     Err(JsValue::from_str("Unable to create transaction"))
 }
 
+/// Pays `recipient` from the coins of several own accounts; every input is signed with the
+/// key of the account holding it and change goes to `change_address`.
+#[wasm_bindgen]
+pub async fn create_transaction_from_accounts(
+    accounts: Vec<String>,
+    password: &str,
+    recipient: &str,
+    amount: u64,
+    fee_to_add: u64,
+    change_address: &str,
+) -> Result<JsValue, JsValue> {
+    let client = NodeClient::new("http://localhost:8989");
+
+    let mut keys = KeyStore::new();
+    let mut inputs = Vec::new();
+    let mut spent_outputs = Vec::new();
+
+    for account in &accounts {
+        let keypair = get_keypair(account, password)
+            .await
+            .map_err(|err| err.to_string())?
+            .ok_or_else(|| JsValue::from_str(&format!("Unknown account {}", account)))?;
+        let pub_key_hash = keypair.public_key.to_address();
+        keys.add(keypair);
+
+        let utxos = client
+            .get_utxos(Address::from(pub_key_hash))
+            .await
+            .map_err(|err| err.to_string())?;
+
+        for utxo in utxos {
+            inputs.push(UnsignedTxIn {
+                prev_tx_id: utxo.prev_tx_id,
+                prev_out_idx: utxo.prev_out_idx,
+                sequence: 0xFFFFFFFF,
+            });
+            spent_outputs.push(TxOut {
+                value: utxo.value,
+                script_pubkey: Script::PayToPublicKeyHash { pub_key_hash },
+            });
+        }
+    }
+
+    let total_amount: u64 = spent_outputs.iter().map(|output| output.value).sum();
+    if total_amount < amount + fee_to_add {
+        return Err(JsValue::from_str("Insufficient Funds"));
+    }
+
+    let mut outputs = vec![TxOut {
+        value: amount,
+        script_pubkey: Address::try_from_string(recipient)?.script_pubkey(),
+    }];
+
+    let change_amount = total_amount - amount - fee_to_add;
+    if change_amount > 0 {
+        outputs.push(TxOut {
+            value: change_amount,
+            script_pubkey: Address::try_from_string(change_address)?.script_pubkey(),
+        });
+    }
+
+    let draft = DraftTransaction::new(inputs, outputs);
+    let mut signatures = InputSignatures::new(draft.inputs.len());
+    draft
+        .sign_with(&keys, &spent_outputs, SigHashType::ALL, &mut signatures)
+        .map_err(|er| er.to_string())?;
+
+    let tx = draft.finalize(signatures).map_err(|er| er.to_string())?;
+
+    // verify correctness
+    tx.verify_signatures(&spent_outputs)
+        .map_err(|er| er.to_string())?;
+
+    client
+        .post_transaction(&tx)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    Ok(serde_wasm_bindgen::to_value(&tx)?)
+}
+
+/// Creates a base64 PSBT paying `recipient` from every coin of `from_address`, for signing
+/// elsewhere, e.g. by a cold wallet or the other owners of a multisig.
+#[wasm_bindgen]
+pub async fn create_psbt(
+    from_address: &str,
+    recipient: &str,
+    amount: u64,
+    fee_to_add: u64,
+) -> Result<String, JsValue> {
+    let from = Address::try_from_string(from_address)?;
+    let client = NodeClient::new("http://localhost:8989");
+    let utxos = client
+        .get_utxos(from)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let total_amount: u64 = utxos.iter().map(|utxo| utxo.value).sum();
+    if total_amount < amount + fee_to_add {
+        return Err(JsValue::from_str("Insufficient Funds"));
+    }
+
+    let inputs = utxos
+        .iter()
+        .map(|utxo| UnsignedTxIn {
+            prev_tx_id: utxo.prev_tx_id,
+            prev_out_idx: utxo.prev_out_idx,
+            sequence: 0xFFFFFFFF,
+        })
+        .collect();
+    let spent_outputs = utxos
+        .iter()
+        .map(|utxo| TxOut {
+            value: utxo.value,
+            script_pubkey: from.script_pubkey(),
+        })
+        .collect();
+
+    let mut outputs = vec![TxOut {
+        value: amount,
+        script_pubkey: Address::try_from_string(recipient)?.script_pubkey(),
+    }];
+    let change_amount = total_amount - amount - fee_to_add;
+    if change_amount > 0 {
+        outputs.push(TxOut {
+            value: change_amount,
+            script_pubkey: from.script_pubkey(),
+        });
+    }
+
+    let psbt = PartiallySignedTransaction::with_spent_outputs(
+        DraftTransaction::new(inputs, outputs),
+        spent_outputs,
+    )
+    .map_err(|er| er.to_string())?;
+
+    Ok(psbt.to_base64())
+}
+
+/// Adds the hex redeem script of a P2SH input, which `sign_psbt` needs to sign a multisig coin.
+#[wasm_bindgen]
+pub fn update_psbt(
+    psbt: &str,
+    input_index: usize,
+    redeem_script_hex: &str,
+) -> Result<String, JsValue> {
+    let mut psbt = PartiallySignedTransaction::from_base64(psbt).map_err(|er| er.to_string())?;
+
+    let redeem_script_bytes = hex::decode(redeem_script_hex).map_err(|er| er.to_string())?;
+    let redeem_script = Script::from_bytes(&redeem_script_bytes).map_err(|er| er.to_string())?;
+    psbt.set_redeem_script(input_index, redeem_script)
+        .map_err(|er| er.to_string())?;
+
+    Ok(psbt.to_base64())
+}
+
+/// Signs every input of the PSBT owned by one of `accounts` and returns the updated PSBT.
+#[wasm_bindgen]
+pub async fn sign_psbt(
+    psbt: &str,
+    accounts: Vec<String>,
+    password: &str,
+) -> Result<String, JsValue> {
+    let mut psbt = PartiallySignedTransaction::from_base64(psbt).map_err(|er| er.to_string())?;
+
+    let mut keys = KeyStore::new();
+    for account in &accounts {
+        if let Some(keypair) = get_keypair(account, password)
+            .await
+            .map_err(|err| err.to_string())?
+        {
+            keys.add(keypair);
+        }
+    }
+
+    let signed = psbt.sign(&keys).map_err(|er| er.to_string())?;
+    log!(format!("Signed PSBT inputs {:?}", signed));
+
+    Ok(psbt.to_base64())
+}
+
+#[wasm_bindgen]
+pub fn combine_psbts(psbts: Vec<String>) -> Result<String, JsValue> {
+    let (first, rest) = psbts
+        .split_first()
+        .ok_or_else(|| JsValue::from_str("No PSBTs to combine"))?;
+
+    let mut combined =
+        PartiallySignedTransaction::from_base64(first).map_err(|er| er.to_string())?;
+    for psbt in rest {
+        let psbt = PartiallySignedTransaction::from_base64(psbt).map_err(|er| er.to_string())?;
+        combined.combine(&psbt).map_err(|er| er.to_string())?;
+    }
+
+    Ok(combined.to_base64())
+}
+
+/// Finalizes the PSBT and submits its transaction to the node.
+#[wasm_bindgen]
+pub async fn broadcast_psbt(psbt: &str) -> Result<JsValue, JsValue> {
+    let mut psbt = PartiallySignedTransaction::from_base64(psbt).map_err(|er| er.to_string())?;
+    psbt.finalize().map_err(|er| er.to_string())?;
+    let tx = psbt.extract().map_err(|er| er.to_string())?;
+
+    let client = NodeClient::new("http://localhost:8989");
+    client
+        .post_transaction(&tx)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    Ok(serde_wasm_bindgen::to_value(&tx)?)
+}
+
 #[wasm_bindgen]
 pub async fn get_utxos(address: &str) -> Result<JsValue, JsValue> {
-    let address = PublicKeyHash::try_from_string(address)?;
+    let address = Address::try_from_string(address)?;
     let client = NodeClient::new("http://localhost:8989");
     let utxos = client
         .get_utxos(address)
@@ -132,6 +389,62 @@ pub async fn get_utxos(address: &str) -> Result<JsValue, JsValue> {
     Ok(val)
 }
 
+#[wasm_bindgen]
+pub async fn get_transaction_data(tx_id: &str) -> Result<JsValue, JsValue> {
+    let tx_id = BlockchainHash::try_from_string(tx_id)?;
+    let client = NodeClient::new("http://localhost:8989");
+    let data = client
+        .get_transaction_data(tx_id)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    Ok(serde_wasm_bindgen::to_value(&data)?)
+}
+
+/// Confirms a payment the way a light client would: the node's headers must form a valid
+/// chain and the transaction's merkle branch must lead to one of them. Only headers and the
+/// proof are downloaded, never blocks.
+#[wasm_bindgen]
+pub async fn verify_payment(tx_id: &str) -> Result<JsValue, JsValue> {
+    let tx_id = BlockchainHash::try_from_string(tx_id)?;
+    let client = NodeClient::new("http://localhost:8989");
+
+    // the node returns a page of headers at a time, an empty one past its tip
+    let mut headers = Vec::new();
+    loop {
+        let page = client
+            .get_headers(headers.len() as u64, HEADERS_PAGE_SIZE)
+            .await
+            .map_err(|err| err.to_string())?;
+        if page.is_empty() {
+            break;
+        }
+        headers.extend(page);
+    }
+    verify_header_chain(&headers).map_err(|err| err.to_string())?;
+
+    let proof = client
+        .get_transaction_proof(tx_id)
+        .await
+        .map_err(|err| err.to_string())?;
+    if proof.transaction.id != tx_id {
+        return Err(JsValue::from_str("Proof is for another transaction"));
+    }
+    proof.verify().map_err(|err| err.to_string())?;
+
+    let block_height = proof.header.height;
+    if headers.get(block_height as usize) != Some(&proof.header) {
+        return Err(JsValue::from_str("Proof header is not part of the chain"));
+    }
+
+    Ok(serde_wasm_bindgen::to_value(&PaymentConfirmation {
+        tx_id,
+        block_hash: proof.header.hash(),
+        block_height,
+        confirmations: headers.len() as u64 - block_height,
+    })?)
+}
+
 #[wasm_bindgen]
 pub async fn mine_block() -> Result<JsValue, JsValue> {
     let client = NodeClient::new("http://localhost:8989");